@@ -0,0 +1,163 @@
+//! Rules for sharing an incoming order's quantity among the resting orders at one price.
+
+use std::cmp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationPolicy {
+    /// Price-time priority, the oldest order is filled first
+    Fifo,
+    /// In proportion to the open quantity of each resting order
+    ProRata,
+    /// The order at the front of the queue is filled first and the rest is shared pro rata
+    PriorityProRata,
+    /// Pro rata, but a share smaller than the given quantity is not allocated
+    ProRataWithMinimum(u64),
+}
+
+impl Default for AllocationPolicy {
+    fn default() -> Self {
+        AllocationPolicy::Fifo
+    }
+}
+
+/// Splits `qty` among resting orders with the open quantities in `resting`, given in queue
+/// order, and returns the quantity allocated to each.
+///
+/// Pro rata shares are rounded down to whole lots. Whatever is left over after rounding
+/// goes to the orders in queue order, each taking as much as it still has open.
+pub fn allocate(policy: AllocationPolicy, qty: u64, resting: &[u64], lot_size: u64) -> Vec<u64> {
+    let total: u64 = resting.iter().sum();
+    if qty >= total {
+        return resting.to_vec();
+    }
+
+    let mut allocations = vec![0; resting.len()];
+    match policy {
+        AllocationPolicy::Fifo => {}
+        AllocationPolicy::ProRata => pro_rata(qty, resting, lot_size, 0, &mut allocations),
+        AllocationPolicy::PriorityProRata => {
+            allocations[0] = cmp::min(resting[0], qty);
+            pro_rata(
+                qty - allocations[0],
+                resting,
+                lot_size,
+                0,
+                &mut allocations,
+            );
+        }
+        AllocationPolicy::ProRataWithMinimum(min_qty) => {
+            pro_rata(qty, resting, lot_size, min_qty, &mut allocations)
+        }
+    }
+
+    let mut left_over = qty - allocations.iter().sum::<u64>();
+    for (allocation, open) in allocations.iter_mut().zip(resting) {
+        if left_over == 0 {
+            break;
+        }
+        let extra = cmp::min(open - *allocation, left_over);
+        *allocation += extra;
+        left_over -= extra;
+    }
+    allocations
+}
+
+/// Adds to `allocations` each order's share of `qty` in proportion to what it still has
+/// open, rounded down to lots. Shares below `min_qty` are dropped.
+fn pro_rata(qty: u64, resting: &[u64], lot_size: u64, min_qty: u64, allocations: &mut [u64]) {
+    let open: Vec<u64> = resting
+        .iter()
+        .zip(allocations.iter())
+        .map(|(resting, allocated)| resting - allocated)
+        .collect();
+    let total: u64 = open.iter().sum();
+    if total == 0 {
+        return;
+    }
+    for (allocation, open) in allocations.iter_mut().zip(open) {
+        let share = (u128::from(qty) * u128::from(open) / u128::from(total)) as u64;
+        let share = share / lot_size * lot_size;
+        if share >= min_qty {
+            *allocation += share;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_fills_in_queue_order() {
+        assert_eq!(
+            allocate(AllocationPolicy::Fifo, 70, &[50, 30, 40], 1),
+            vec![50, 20, 0]
+        );
+    }
+
+    #[test]
+    fn everything_is_filled_when_incoming_covers_the_level() {
+        for policy in &[
+            AllocationPolicy::Fifo,
+            AllocationPolicy::ProRata,
+            AllocationPolicy::PriorityProRata,
+            AllocationPolicy::ProRataWithMinimum(10),
+        ] {
+            assert_eq!(allocate(*policy, 150, &[50, 30, 40], 1), vec![50, 30, 40]);
+        }
+    }
+
+    #[test]
+    fn pro_rata_rounds_down_and_gives_the_rest_in_queue_order() {
+        // Exact shares are 33.3, 33.3 and 33.3
+        assert_eq!(
+            allocate(AllocationPolicy::ProRata, 100, &[100, 100, 100], 1),
+            vec![34, 33, 33]
+        );
+        // Exact shares are 15, 35 and 50
+        assert_eq!(
+            allocate(AllocationPolicy::ProRata, 100, &[30, 70, 100], 1),
+            vec![15, 35, 50]
+        );
+        // Exact shares are 15, 15 and 20, rounded down to lots of 10
+        assert_eq!(
+            allocate(AllocationPolicy::ProRata, 50, &[30, 30, 40], 10),
+            vec![20, 10, 20]
+        );
+    }
+
+    #[test]
+    fn small_orders_only_get_left_overs() {
+        // Exact shares are 0.98, 0.98 and 97.04
+        assert_eq!(
+            allocate(AllocationPolicy::ProRata, 99, &[1, 1, 99], 1),
+            vec![1, 1, 97]
+        );
+    }
+
+    #[test]
+    fn priority_order_is_filled_first() {
+        // 40 to the top order, the remaining 60 shared 1:3
+        assert_eq!(
+            allocate(AllocationPolicy::PriorityProRata, 100, &[40, 100, 300], 1),
+            vec![40, 15, 45]
+        );
+        assert_eq!(
+            allocate(AllocationPolicy::PriorityProRata, 30, &[40, 100, 300], 1),
+            vec![30, 0, 0]
+        );
+    }
+
+    #[test]
+    fn shares_below_the_minimum_are_reallocated() {
+        // Exact shares are 8, 2 and 10, the 2 goes to the front of the queue instead
+        assert_eq!(
+            allocate(AllocationPolicy::ProRataWithMinimum(5), 20, &[40, 10, 50], 1),
+            vec![10, 0, 10]
+        );
+        assert_eq!(
+            allocate(AllocationPolicy::ProRata, 20, &[40, 10, 50], 1),
+            vec![8, 2, 10]
+        );
+    }
+}
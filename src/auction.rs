@@ -0,0 +1,209 @@
+//! Price discovery for call auctions.
+//!
+//! The auction price is the one that maximizes the executable volume. Ties are broken, in
+//! order, by the smallest surplus, then by market pressure (the highest price when every
+//! remaining candidate has surplus on the buy side, the lowest when it is on the sell
+//! side), then by the price closest to the reference price.
+
+use crate::model::{Price, Side};
+
+/// Outcome of an uncross at one price.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Equilibrium {
+    price: Price,
+    volume: u64,
+    // Quantity left unmatched at the price and the side it is on
+    surplus: u64,
+    surplus_side: Option<Side>,
+}
+
+impl Equilibrium {
+    pub fn price(&self) -> Price {
+        self.price
+    }
+
+    pub fn volume(&self) -> u64 {
+        self.volume
+    }
+
+    pub fn surplus(&self) -> u64 {
+        self.surplus
+    }
+
+    /// Side with quantity left over, None when the book uncrosses exactly.
+    pub fn surplus_side(&self) -> Option<Side> {
+        self.surplus_side
+    }
+}
+
+/// Finds the auction price for limit orders given as `(price, qty)` per side, in any
+/// order, and market orders given as the total quantity per side. Market orders trade at
+/// any price. Returns None if nothing would trade.
+///
+/// Every limit price is a candidate. When there are only market orders the reference
+/// price is the only candidate. Without a reference price the last tie-break aims at the
+/// middle of the remaining candidates. Candidates equally close go to the lower price.
+pub fn equilibrium(
+    bids: &[(Price, u64)],
+    asks: &[(Price, u64)],
+    market_bid_qty: u64,
+    market_ask_qty: u64,
+    reference: Option<Price>,
+) -> Option<Equilibrium> {
+    let mut prices: Vec<Price> = bids.iter().chain(asks).map(|&(price, _)| price).collect();
+    if prices.is_empty() {
+        prices.extend(reference);
+    }
+    prices.sort();
+    prices.dedup();
+
+    let candidates: Vec<Equilibrium> = prices
+        .into_iter()
+        .map(|price| {
+            let demand = market_bid_qty
+                + bids.iter()
+                    .filter(|&&(bid, _)| bid >= price)
+                    .map(|&(_, qty)| qty)
+                    .sum::<u64>();
+            let supply = market_ask_qty
+                + asks.iter()
+                    .filter(|&&(ask, _)| ask <= price)
+                    .map(|&(_, qty)| qty)
+                    .sum::<u64>();
+            let (surplus, surplus_side) = if demand > supply {
+                (demand - supply, Some(Side::Bid))
+            } else if supply > demand {
+                (supply - demand, Some(Side::Ask))
+            } else {
+                (0, None)
+            };
+            Equilibrium {
+                price,
+                volume: demand.min(supply),
+                surplus,
+                surplus_side,
+            }
+        })
+        .collect();
+
+    let volume = candidates.iter().map(|c| c.volume).max().unwrap_or(0);
+    if volume == 0 {
+        return None;
+    }
+    let candidates: Vec<Equilibrium> = candidates
+        .into_iter()
+        .filter(|c| c.volume == volume)
+        .collect();
+    let surplus = candidates.iter().map(|c| c.surplus).min()?;
+    let candidates: Vec<Equilibrium> = candidates
+        .into_iter()
+        .filter(|c| c.surplus == surplus)
+        .collect();
+
+    // Sorted by price, so the first and last candidates are the lowest and highest
+    if candidates.iter().all(|c| c.surplus_side == Some(Side::Bid)) {
+        return candidates.last().cloned();
+    }
+    if candidates.iter().all(|c| c.surplus_side == Some(Side::Ask)) {
+        return candidates.first().cloned();
+    }
+
+    let lowest = candidates.first()?.price.units();
+    let highest = candidates.last()?.price.units();
+    let target = reference.map_or(lowest + (highest - lowest) / 2, |price| price.units());
+    candidates
+        .into_iter()
+        .min_by_key(|c| {
+            let units = c.price.units();
+            if units > target {
+                units - target
+            } else {
+                target - units
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::px;
+
+    #[test]
+    fn maximizes_volume() {
+        let bids = [(px(102), 100), (px(101), 200), (px(100), 300)];
+        let asks = [(px(99), 150), (px(100), 150), (px(101), 100)];
+        let eq = equilibrium(&bids, &asks, 0, 0, None).unwrap();
+        assert_eq!(eq.price(), px(101));
+        assert_eq!(eq.volume(), 300);
+        assert_eq!(eq.surplus(), 100);
+        assert_eq!(eq.surplus_side(), Some(Side::Ask));
+    }
+
+    #[test]
+    fn nothing_crosses() {
+        let bids = [(px(99), 100)];
+        let asks = [(px(100), 100)];
+        assert_eq!(equilibrium(&bids, &asks, 0, 0, Some(px(100))), None);
+        assert_eq!(equilibrium(&[], &[], 100, 0, Some(px(100))), None);
+    }
+
+    #[test]
+    fn smallest_surplus_breaks_volume_ties() {
+        // 100 trades at both prices, the surplus is 50 bought at 100 and 20 sold at 101
+        let bids = [(px(101), 100), (px(100), 50)];
+        let asks = [(px(100), 100), (px(101), 20)];
+        let eq = equilibrium(&bids, &asks, 0, 0, None).unwrap();
+        assert_eq!(eq.price(), px(101));
+        assert_eq!(eq.volume(), 100);
+        assert_eq!(eq.surplus(), 20);
+        assert_eq!(eq.surplus_side(), Some(Side::Ask));
+
+        let bids = [(px(101), 100), (px(100), 30)];
+        let asks = [(px(100), 100), (px(101), 50)];
+        let eq = equilibrium(&bids, &asks, 0, 0, None).unwrap();
+        assert_eq!(eq.price(), px(100));
+        assert_eq!(eq.surplus(), 30);
+        assert_eq!(eq.surplus_side(), Some(Side::Bid));
+    }
+
+    #[test]
+    fn market_pressure_breaks_surplus_ties() {
+        // Buy surplus of 50 at both 100 and 101, the price goes up
+        let bids = [(px(101), 100)];
+        let asks = [(px(100), 50)];
+        let eq = equilibrium(&bids, &asks, 0, 0, Some(px(90))).unwrap();
+        assert_eq!(eq.price(), px(101));
+        assert_eq!(eq.surplus_side(), Some(Side::Bid));
+
+        // Sell surplus, the price goes down
+        let bids = [(px(101), 50)];
+        let asks = [(px(100), 100)];
+        let eq = equilibrium(&bids, &asks, 0, 0, Some(px(110))).unwrap();
+        assert_eq!(eq.price(), px(100));
+        assert_eq!(eq.surplus_side(), Some(Side::Ask));
+    }
+
+    #[test]
+    fn reference_price_breaks_remaining_ties() {
+        let bids = [(px(103), 100)];
+        let asks = [(px(100), 100)];
+        let at = |reference| {
+            equilibrium(&bids, &asks, 0, 0, reference)
+                .unwrap()
+                .price()
+        };
+        assert_eq!(at(Some(px(90))), px(100));
+        assert_eq!(at(Some(px(102))), px(103));
+        assert_eq!(at(Some(px(110))), px(103));
+        assert_eq!(at(None), px(100));
+    }
+
+    #[test]
+    fn market_orders_only_use_reference_price() {
+        let eq = equilibrium(&[], &[], 100, 60, Some(px(100))).unwrap();
+        assert_eq!(eq.price(), px(100));
+        assert_eq!(eq.volume(), 60);
+        assert_eq!(eq.surplus_side(), Some(Side::Bid));
+        assert_eq!(equilibrium(&[], &[], 100, 60, None), None);
+    }
+}
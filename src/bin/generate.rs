@@ -0,0 +1,90 @@
+//! Writes a synthetic order file in the format read by the benchmark and replay tools.
+//!
+//! ```text
+//! generate [--seed N] [--events N] [--symbols N] [--mix LIMIT,MARKET,CANCEL,REPLACE]
+//!          [--walk VOLATILITY,DEPTH] [--size LOT,MEAN_LOTS,MAX_LOTS] [--output FILE]
+//! ```
+//!
+//! Prices are in units of 10^-4 starting at 0.4800, as in `config/orders.csv`.
+
+extern crate oms;
+
+use oms::generator::Generator;
+use oms::loader::{self, OrderRecord};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: generate [--seed N] [--events N] [--symbols N] \
+         [--mix LIMIT,MARKET,CANCEL,REPLACE] [--walk VOLATILITY,DEPTH] \
+         [--size LOT,MEAN_LOTS,MAX_LOTS] [--output FILE]"
+    );
+    process::exit(2);
+}
+
+fn number(arg: Option<String>) -> u64 {
+    arg.and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
+fn numbers(arg: Option<String>, count: usize) -> Vec<u64> {
+    let values: Vec<u64> = arg.unwrap_or_else(|| usage())
+        .split(',')
+        .map(|value| value.parse().unwrap_or_else(|_| usage()))
+        .collect();
+    if values.len() != count {
+        usage();
+    }
+    values
+}
+
+fn main() {
+    let mut seed = 1;
+    let mut events = 100_000;
+    let mut symbols = 1;
+    let mut mix = vec![60, 5, 25, 10];
+    let mut walk = vec![1, 10];
+    let mut size = vec![100, 5, 100];
+    let mut output: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => seed = number(args.next()),
+            "--events" => events = number(args.next()),
+            "--symbols" => symbols = number(args.next()),
+            "--mix" => mix = numbers(args.next(), 4),
+            "--walk" => walk = numbers(args.next(), 2),
+            "--size" => size = numbers(args.next(), 3),
+            "--output" => output = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+    if symbols == 0 || mix[0] == 0 || size[0] == 0 || size[1] == 0 || size[1] > size[2] {
+        usage();
+    }
+
+    let records: Vec<OrderRecord> = Generator::new(seed)
+        .with_symbols(symbols as usize)
+        .with_mix(mix[0] as u32, mix[1] as u32, mix[2] as u32, mix[3] as u32)
+        .with_price(4, 4_800, 1)
+        .with_walk(walk[0], walk[1])
+        .with_size(size[0], size[1], size[2])
+        .take(events as usize)
+        .collect();
+
+    let writer: Box<Write> = match output {
+        Some(ref path) => Box::new(File::create(path).unwrap_or_else(|e| {
+            eprintln!("Cannot create {}: {}", path, e);
+            process::exit(2);
+        })),
+        None => Box::new(io::stdout()),
+    };
+    if let Err(e) = loader::write_records(BufWriter::new(writer), &records) {
+        eprintln!("Cannot write orders: {}", e);
+        process::exit(1);
+    }
+}
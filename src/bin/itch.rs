@@ -0,0 +1,115 @@
+//! Runs an order file through the book, publishes the ITCH feed and rebuilds the book from
+//! it, checking after every event that the rebuilt bids and asks match the book's.
+//!
+//! ```text
+//! itch [--orders FILE] [--output FILE]
+//! ```
+//!
+//! `--output` also writes the encoded feed to a file.
+
+extern crate oms;
+
+use oms::itch::{self, FeedBook, FeedMessage};
+use oms::loader;
+use oms::model::{Instrument, Side};
+use oms::order_book::OrderBook;
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::process;
+
+// Prices in the order file are in units of 10^-4
+const PRICE_SCALE: u8 = 4;
+
+struct Options {
+    orders: String,
+    output: Option<String>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: itch [--orders FILE] [--output FILE]");
+    process::exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        orders: String::from("config/orders.csv"),
+        output: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--orders" => options.orders = args.next().unwrap_or_else(|| usage()),
+            "--output" => options.output = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+    options
+}
+
+fn main() {
+    let options = parse_args();
+    let orders = loader::load_orders(&options.orders, PRICE_SCALE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let mut book = OrderBook::new(Instrument::new("AUDUSD").with_price_scale(PRICE_SCALE));
+    let mut feed = FeedBook::new();
+    let mut buf: Vec<u8> = Vec::new();
+    let mut messages = 0;
+
+    for (n, order) in orders.iter().enumerate() {
+        if let Err(e) = book.event(*order) {
+            eprintln!("Event {} failed: {}", n + 1, e);
+            process::exit(2);
+        }
+        book.drain_reports();
+        book.drain_market_data();
+
+        // Decode from the bytes, as a consumer would
+        let start = buf.len();
+        messages += itch::publish(&mut book, &mut buf);
+        let mut pos = start;
+        while pos < buf.len() {
+            let applied = match FeedMessage::decode(&buf[pos..]) {
+                Ok(Some((message, len))) => {
+                    pos += len;
+                    feed.apply(&message.event()).map_err(|e| e.to_string())
+                }
+                Ok(None) => Err(format!("Truncated message at offset {}", pos)),
+                Err(e) => Err(e.to_string()),
+            };
+            if let Err(e) = applied {
+                println!("Feed is broken at event {}: {}", n + 1, e);
+                process::exit(1);
+            }
+        }
+
+        for side in &[Side::Bid, Side::Ask] {
+            if feed.full_depth(*side) != book.full_depth(*side) {
+                println!(
+                    "Rebuilt {:?} side differs after event {}:\n  book {:?}\n  feed {:?}",
+                    side,
+                    n + 1,
+                    book.full_depth(*side),
+                    feed.full_depth(*side)
+                );
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(ref output) = options.output {
+        let written = File::create(output).and_then(|mut file| file.write_all(&buf));
+        if let Err(e) = written {
+            eprintln!("Cannot write {}: {}", output, e);
+            process::exit(2);
+        }
+    }
+    println!(
+        "{} events published as {} messages in {} bytes, rebuilt book matches",
+        orders.len(),
+        messages,
+        buf.len()
+    );
+}
@@ -0,0 +1,119 @@
+//! Replays an order file through the book and compares every fill and execution report
+//! with a golden output.
+//!
+//! ```text
+//! replay [--orders FILE] [--golden FILE] [--output FILE] [--bless]
+//! ```
+//!
+//! `--bless` writes the current output as the new golden file instead of comparing.
+
+extern crate oms;
+
+use oms::loader;
+use oms::model::Instrument;
+use oms::replay;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process;
+
+// Prices in the order file are in units of 10^-4
+const PRICE_SCALE: u8 = 4;
+
+struct Options {
+    orders: String,
+    golden: String,
+    output: Option<String>,
+    bless: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: replay [--orders FILE] [--golden FILE] [--output FILE] [--bless]");
+    process::exit(2);
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        orders: String::from("config/orders.csv"),
+        golden: String::from("config/orders.golden.csv"),
+        output: None,
+        bless: false,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--orders" => options.orders = args.next().unwrap_or_else(|| usage()),
+            "--golden" => options.golden = args.next().unwrap_or_else(|| usage()),
+            "--output" => options.output = Some(args.next().unwrap_or_else(|| usage())),
+            "--bless" => options.bless = true,
+            _ => usage(),
+        }
+    }
+    options
+}
+
+fn write_lines(path: &str, lines: &[String]) {
+    let file = File::create(path).unwrap_or_else(|e| {
+        eprintln!("Cannot create {}: {}", path, e);
+        process::exit(2);
+    });
+    let mut out = BufWriter::new(file);
+    for line in lines {
+        writeln!(out, "{}", line).unwrap();
+    }
+}
+
+fn read_lines(path: &str) -> Vec<String> {
+    let file = File::open(path).unwrap_or_else(|e| {
+        eprintln!(
+            "Cannot open golden file {}: {}. Run with --bless to create it.",
+            path, e
+        );
+        process::exit(2);
+    });
+    BufReader::new(file)
+        .lines()
+        .collect::<Result<Vec<String>, _>>()
+        .unwrap()
+}
+
+fn main() {
+    let options = parse_args();
+    let orders = loader::load_orders(&options.orders, PRICE_SCALE).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+    let instrument = Instrument::new("AUDUSD").with_price_scale(PRICE_SCALE);
+    let lines = replay::run(instrument, &orders).unwrap_or_else(|e| {
+        eprintln!("Replay failed: {}", e);
+        process::exit(2);
+    });
+    if let Some(ref output) = options.output {
+        write_lines(output, &lines);
+    }
+
+    if options.bless {
+        write_lines(&options.golden, &lines);
+        println!(
+            "Wrote {} lines for {} events to {}",
+            lines.len(),
+            orders.len(),
+            options.golden
+        );
+        return;
+    }
+
+    let golden = read_lines(&options.golden);
+    match replay::first_difference(&golden, &lines) {
+        None => println!(
+            "{} events replayed, {} lines match {}",
+            orders.len(),
+            lines.len(),
+            options.golden
+        ),
+        Some(difference) => {
+            println!("Output differs from {} at {}", options.golden, difference);
+            process::exit(1);
+        }
+    }
+}
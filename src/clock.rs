@@ -0,0 +1,67 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use failure::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Source of time for the order book. Expiry of Day and GTD orders is driven by this
+/// instead of the wall clock so that it can be controlled in tests and replays.
+pub trait Clock: fmt::Debug + Send {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Nanoseconds since the Unix epoch, as the binary protocols send time.
+pub(crate) fn to_nanos(time: DateTime<Utc>) -> i64 {
+    time.timestamp() * NANOS_PER_SEC + i64::from(time.timestamp_subsec_nanos())
+}
+
+pub(crate) fn from_nanos(nanos: i64) -> Result<DateTime<Utc>, Error> {
+    let (mut secs, mut subsec) = (nanos / NANOS_PER_SEC, nanos % NANOS_PER_SEC);
+    if subsec < 0 {
+        secs -= 1;
+        subsec += NANOS_PER_SEC;
+    }
+    match Utc.timestamp_opt(secs, subsec as u32).single() {
+        Some(time) => Ok(time),
+        None => bail!("Timestamp of {} nanoseconds is out of range", nanos),
+    }
+}
@@ -0,0 +1,437 @@
+//! Little-endian binary encoding shared by the journal and snapshot formats.
+
+use crate::model::{OrderEvent, Price, Side, TimeInForce, TradingPhase};
+use crate::price::MAX_SCALE;
+use chrono::{DateTime, TimeZone, Utc};
+use failure::Error;
+
+lazy_static! {
+    static ref CRC32_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 == 1 {
+                    0xEDB8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            *entry = c;
+        }
+        table
+    };
+}
+
+/// CRC-32 (IEEE) of `data`.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc = CRC32_TABLE[((crc ^ u32::from(*byte)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xFFFF_FFFF
+}
+
+pub(crate) fn put_u8(buf: &mut Vec<u8>, value: u8) {
+    buf.push(value);
+}
+
+pub(crate) fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    for i in 0..2 {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+pub(crate) fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+pub(crate) fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    for i in 0..8 {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+pub(crate) fn put_u128(buf: &mut Vec<u8>, value: u128) {
+    put_u64(buf, value as u64);
+    put_u64(buf, (value >> 64) as u64);
+}
+
+pub(crate) fn put_i64(buf: &mut Vec<u8>, value: i64) {
+    put_u64(buf, value as u64);
+}
+
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, value: &[u8]) {
+    put_u32(buf, value.len() as u32);
+    buf.extend_from_slice(value);
+}
+
+pub(crate) fn put_price(buf: &mut Vec<u8>, price: Price) {
+    put_u64(buf, price.units());
+    put_u8(buf, price.scale());
+}
+
+pub(crate) fn put_side(buf: &mut Vec<u8>, side: Side) {
+    put_u8(
+        buf,
+        match side {
+            Side::Bid => 0,
+            Side::Ask => 1,
+        },
+    );
+}
+
+pub(crate) fn put_time(buf: &mut Vec<u8>, time: DateTime<Utc>) {
+    put_i64(buf, time.timestamp());
+    put_u32(buf, time.timestamp_subsec_nanos());
+}
+
+pub(crate) fn put_phase(buf: &mut Vec<u8>, phase: TradingPhase) {
+    put_u8(
+        buf,
+        match phase {
+            TradingPhase::PreOpen => 0,
+            TradingPhase::OpeningAuction => 1,
+            TradingPhase::Continuous => 2,
+            TradingPhase::Halted => 3,
+            TradingPhase::ClosingAuction => 4,
+            TradingPhase::Closed => 5,
+        },
+    );
+}
+
+pub(crate) fn put_tif(buf: &mut Vec<u8>, tif: TimeInForce) {
+    match tif {
+        TimeInForce::GoodTillCancel => put_u8(buf, 0),
+        TimeInForce::ImmediateOrCancel => put_u8(buf, 1),
+        TimeInForce::FillOrKill => put_u8(buf, 2),
+        TimeInForce::Day => put_u8(buf, 3),
+        TimeInForce::GoodTillDate(expire_at) => {
+            put_u8(buf, 4);
+            put_time(buf, expire_at);
+        }
+    }
+}
+
+pub(crate) fn put_event(buf: &mut Vec<u8>, event: &OrderEvent) {
+    match *event {
+        OrderEvent::Market {
+            id,
+            side,
+            qty,
+            owner,
+        } => {
+            put_u8(buf, 1);
+            put_u64(buf, id);
+            put_side(buf, side);
+            put_u64(buf, qty);
+            put_u64(buf, owner);
+        }
+        OrderEvent::Limit {
+            id,
+            side,
+            price,
+            qty,
+            tif,
+            owner,
+        } => {
+            put_u8(buf, 2);
+            put_u64(buf, id);
+            put_side(buf, side);
+            put_price(buf, price);
+            put_u64(buf, qty);
+            put_tif(buf, tif);
+            put_u64(buf, owner);
+        }
+        OrderEvent::Cancel { id } => {
+            put_u8(buf, 3);
+            put_u64(buf, id);
+        }
+        OrderEvent::Replace {
+            id,
+            side,
+            price,
+            qty,
+        } => {
+            put_u8(buf, 4);
+            put_u64(buf, id);
+            put_side(buf, side);
+            put_price(buf, price);
+            put_u64(buf, qty);
+        }
+        OrderEvent::StopMarket {
+            id,
+            side,
+            stop_price,
+            qty,
+            owner,
+        } => {
+            put_u8(buf, 5);
+            put_u64(buf, id);
+            put_side(buf, side);
+            put_price(buf, stop_price);
+            put_u64(buf, qty);
+            put_u64(buf, owner);
+        }
+        OrderEvent::StopLimit {
+            id,
+            side,
+            stop_price,
+            price,
+            qty,
+            owner,
+        } => {
+            put_u8(buf, 6);
+            put_u64(buf, id);
+            put_side(buf, side);
+            put_price(buf, stop_price);
+            put_price(buf, price);
+            put_u64(buf, qty);
+            put_u64(buf, owner);
+        }
+        OrderEvent::Iceberg {
+            id,
+            side,
+            price,
+            qty,
+            display_qty,
+            owner,
+        } => {
+            put_u8(buf, 7);
+            put_u64(buf, id);
+            put_side(buf, side);
+            put_price(buf, price);
+            put_u64(buf, qty);
+            put_u64(buf, display_qty);
+            put_u64(buf, owner);
+        }
+    }
+}
+
+/// Reads values back in the order they were written, failing on truncated input.
+#[derive(Debug)]
+pub(crate) struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.remaining() < len {
+            bail!(
+                "Unexpected end of data at offset {}, wanted {} bytes",
+                self.pos,
+                len
+            );
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn le(&mut self, len: usize) -> Result<u64, Error> {
+        let bytes = self.take(len)?;
+        Ok(bytes
+            .iter()
+            .enumerate()
+            .fold(0u64, |value, (i, b)| value | (u64::from(*b) << (8 * i))))
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.le(1)? as u8)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        Ok(self.le(2)? as u16)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        Ok(self.le(4)? as u32)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        self.le(8)
+    }
+
+    pub fn u128(&mut self) -> Result<u128, Error> {
+        let low = self.u64()?;
+        let high = self.u64()?;
+        Ok(u128::from(high) << 64 | u128::from(low))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, Error> {
+        Ok(self.le(8)? as i64)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn price(&mut self) -> Result<Price, Error> {
+        let units = self.u64()?;
+        let scale = self.u8()?;
+        if scale > MAX_SCALE {
+            bail!("Invalid price scale {}", scale);
+        }
+        Ok(Price::new(units, scale))
+    }
+
+    pub fn side(&mut self) -> Result<Side, Error> {
+        match self.u8()? {
+            0 => Ok(Side::Bid),
+            1 => Ok(Side::Ask),
+            other => bail!("Invalid side {}", other),
+        }
+    }
+
+    pub fn time(&mut self) -> Result<DateTime<Utc>, Error> {
+        let secs = self.i64()?;
+        let nanos = self.u32()?;
+        match Utc.timestamp_opt(secs, nanos).single() {
+            Some(time) => Ok(time),
+            None => bail!("Invalid timestamp {}.{:09}", secs, nanos),
+        }
+    }
+
+    pub fn phase(&mut self) -> Result<TradingPhase, Error> {
+        match self.u8()? {
+            0 => Ok(TradingPhase::PreOpen),
+            1 => Ok(TradingPhase::OpeningAuction),
+            2 => Ok(TradingPhase::Continuous),
+            3 => Ok(TradingPhase::Halted),
+            4 => Ok(TradingPhase::ClosingAuction),
+            5 => Ok(TradingPhase::Closed),
+            other => bail!("Invalid trading phase {}", other),
+        }
+    }
+
+    pub fn tif(&mut self) -> Result<TimeInForce, Error> {
+        match self.u8()? {
+            0 => Ok(TimeInForce::GoodTillCancel),
+            1 => Ok(TimeInForce::ImmediateOrCancel),
+            2 => Ok(TimeInForce::FillOrKill),
+            3 => Ok(TimeInForce::Day),
+            4 => Ok(TimeInForce::GoodTillDate(self.time()?)),
+            other => bail!("Invalid time in force {}", other),
+        }
+    }
+
+    pub fn event(&mut self) -> Result<OrderEvent, Error> {
+        let event = match self.u8()? {
+            1 => OrderEvent::Market {
+                id: self.u64()?,
+                side: self.side()?,
+                qty: self.u64()?,
+                owner: self.u64()?,
+            },
+            2 => OrderEvent::Limit {
+                id: self.u64()?,
+                side: self.side()?,
+                price: self.price()?,
+                qty: self.u64()?,
+                tif: self.tif()?,
+                owner: self.u64()?,
+            },
+            3 => OrderEvent::Cancel { id: self.u64()? },
+            4 => OrderEvent::Replace {
+                id: self.u64()?,
+                side: self.side()?,
+                price: self.price()?,
+                qty: self.u64()?,
+            },
+            5 => OrderEvent::StopMarket {
+                id: self.u64()?,
+                side: self.side()?,
+                stop_price: self.price()?,
+                qty: self.u64()?,
+                owner: self.u64()?,
+            },
+            6 => OrderEvent::StopLimit {
+                id: self.u64()?,
+                side: self.side()?,
+                stop_price: self.price()?,
+                price: self.price()?,
+                qty: self.u64()?,
+                owner: self.u64()?,
+            },
+            7 => OrderEvent::Iceberg {
+                id: self.u64()?,
+                side: self.side()?,
+                price: self.price()?,
+                qty: self.u64()?,
+                display_qty: self.u64()?,
+                owner: self.u64()?,
+            },
+            other => bail!("Invalid order event type {}", other),
+        };
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn event_round_trip() {
+        let expire_at = Utc.ymd(2018, 4, 2).and_hms_milli(17, 0, 0, 250);
+        let events = vec![
+            OrderEvent::market(1, Side::Bid, 100).with_owner(42),
+            OrderEvent::limit_with_tif(
+                2,
+                Side::Ask,
+                Price::new(7512, 4),
+                10,
+                TimeInForce::GoodTillDate(expire_at),
+            ).with_owner(u64::max_value()),
+            OrderEvent::Cancel { id: 3 },
+            OrderEvent::replace(4, Side::Bid, Price::new(7511, 4), 5),
+            OrderEvent::stop_market(5, Side::Ask, Price::new(7400, 4), 7),
+            OrderEvent::stop_limit(6, Side::Bid, Price::new(7600, 4), Price::new(7610, 4), 8),
+            OrderEvent::iceberg(7, Side::Ask, Price::new(7520, 4), 1000, 100).with_owner(3),
+        ];
+
+        let mut buf = Vec::new();
+        for event in &events {
+            put_event(&mut buf, event);
+        }
+        let mut decoder = Decoder::new(&buf);
+        for event in &events {
+            assert_eq!(decoder.event().unwrap(), *event);
+        }
+        assert_eq!(decoder.remaining(), 0);
+        assert!(decoder.event().is_err());
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        let mut buf = Vec::new();
+        put_u64(&mut buf, 7512);
+        put_u8(&mut buf, 19);
+        assert!(Decoder::new(&buf).price().is_err());
+
+        for &(secs, nanos) in &[(0, 2_000_000_000), (i64::max_value(), 0)] {
+            let mut buf = Vec::new();
+            put_i64(&mut buf, secs);
+            put_u32(&mut buf, nanos);
+            assert!(Decoder::new(&buf).time().is_err());
+        }
+    }
+}
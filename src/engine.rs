@@ -0,0 +1,213 @@
+use crate::error::OrderBookError;
+use crate::model::{ExecutionReport, Instrument, OrderEvent, OrderFill, TradingPhase};
+use crate::order_book::OrderBook;
+use std::collections::HashMap;
+
+/// Owns one order book per instrument and routes order events to them by symbol.
+#[derive(Debug, Default)]
+pub struct MatchingEngine {
+    books: HashMap<String, OrderBook>,
+}
+
+impl MatchingEngine {
+    pub fn new() -> Self {
+        Self {
+            books: HashMap::new(),
+        }
+    }
+
+    pub fn add_instrument(&mut self, instrument: Instrument) -> Result<(), OrderBookError> {
+        self.add_book(OrderBook::new(instrument))
+    }
+
+    /// Adds a book built by the caller, e.g. one with its own clock.
+    pub fn add_book(&mut self, book: OrderBook) -> Result<(), OrderBookError> {
+        let symbol = book.instrument().symbol().to_string();
+        if self.books.contains_key(&symbol) {
+            return Err(OrderBookError::DuplicateInstrument(symbol));
+        }
+        info!("Adding instrument {}", symbol);
+        self.books.insert(symbol, book);
+        Ok(())
+    }
+
+    /// Takes the instrument out of the engine, handing back its book.
+    pub fn remove_instrument(&mut self, symbol: &str) -> Result<OrderBook, OrderBookError> {
+        match self.books.remove(symbol) {
+            Some(book) => {
+                info!("Removing instrument {}", symbol);
+                Ok(book)
+            }
+            None => Err(OrderBookError::UnknownInstrument(symbol.to_string())),
+        }
+    }
+
+    /// Moves the instrument's book to another trading phase, returning the fills of an
+    /// uncross.
+    pub fn set_phase(
+        &mut self,
+        symbol: &str,
+        phase: TradingPhase,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        match self.books.get_mut(symbol) {
+            Some(book) => book.set_phase(phase),
+            None => Err(OrderBookError::UnknownInstrument(symbol.to_string())),
+        }
+    }
+
+    /// Stops accepting new orders and amendments for the instrument. Cancels are still
+    /// accepted.
+    pub fn halt(&mut self, symbol: &str) -> Result<(), OrderBookError> {
+        self.set_phase(symbol, TradingPhase::Halted).map(|_| ())
+    }
+
+    /// Resumes continuous trading on a halted instrument.
+    pub fn resume(&mut self, symbol: &str) -> Result<(), OrderBookError> {
+        self.set_phase(symbol, TradingPhase::Continuous).map(|_| ())
+    }
+
+    pub fn is_halted(&self, symbol: &str) -> bool {
+        self.books
+            .get(symbol)
+            .map_or(false, |book| book.phase() == TradingPhase::Halted)
+    }
+
+    pub fn symbols(&self) -> Vec<&str> {
+        let mut symbols: Vec<&str> = self.books.keys().map(|s| s.as_str()).collect();
+        symbols.sort();
+        symbols
+    }
+
+    pub fn book(&self, symbol: &str) -> Option<&OrderBook> {
+        self.books.get(symbol)
+    }
+
+    pub fn book_mut(&mut self, symbol: &str) -> Option<&mut OrderBook> {
+        self.books.get_mut(symbol)
+    }
+
+    /// Sends an event to the instrument's book. Events the book does not accept in its
+    /// trading phase are rejected in its execution reports.
+    pub fn event(
+        &mut self,
+        symbol: &str,
+        event: OrderEvent,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        match self.books.get_mut(symbol) {
+            Some(book) => book.event(event),
+            None => Err(OrderBookError::UnknownInstrument(symbol.to_string())),
+        }
+    }
+
+    /// Takes the execution reports of every book, tagged with the instrument symbol.
+    pub fn drain_reports(&mut self) -> Vec<(String, ExecutionReport)> {
+        let mut reports = Vec::new();
+        for (symbol, book) in &mut self.books {
+            for report in book.drain_reports() {
+                reports.push((symbol.clone(), report));
+            }
+        }
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{px, ExecType, RejectReason, Side};
+
+    fn engine() -> MatchingEngine {
+        ::crate::model::test_setup();
+
+        let mut engine = MatchingEngine::new();
+        engine.add_instrument(Instrument::new("AUDUSD")).unwrap();
+        engine.add_instrument(Instrument::new("EURUSD")).unwrap();
+        engine
+    }
+
+    #[test]
+    fn routes_events_by_symbol() {
+        let mut engine = engine();
+        engine
+            .event("AUDUSD", OrderEvent::limit(1, Side::Ask, px(101), 100))
+            .unwrap();
+
+        let fills = engine
+            .event("EURUSD", OrderEvent::limit(2, Side::Bid, px(101), 100))
+            .unwrap();
+        assert_eq!(fills.len(), 0, "Books must not cross instruments");
+
+        let fills = engine
+            .event("AUDUSD", OrderEvent::limit(3, Side::Bid, px(101), 100))
+            .unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].ord_id_2(), 1);
+        assert_eq!(engine.symbols(), vec!["AUDUSD", "EURUSD"]);
+    }
+
+    #[test]
+    fn rejects_unknown_instruments() {
+        let mut engine = engine();
+        assert!(
+            engine
+                .event("GBPUSD", OrderEvent::limit(1, Side::Ask, px(101), 100))
+                .is_err()
+        );
+        assert!(engine.halt("GBPUSD").is_err());
+        assert!(engine.remove_instrument("GBPUSD").is_err());
+        assert!(engine.add_instrument(Instrument::new("AUDUSD")).is_err());
+    }
+
+    #[test]
+    fn halted_instruments_only_accept_cancels() {
+        let mut engine = engine();
+        engine
+            .event("AUDUSD", OrderEvent::limit(1, Side::Ask, px(101), 100))
+            .unwrap();
+        engine.halt("AUDUSD").unwrap();
+        assert!(engine.is_halted("AUDUSD"));
+
+        engine.drain_reports();
+        assert!(
+            engine
+                .event("AUDUSD", OrderEvent::limit(2, Side::Bid, px(101), 100))
+                .unwrap()
+                .is_empty()
+        );
+        let reports = engine.drain_reports();
+        assert_eq!(reports[0].0, "AUDUSD");
+        assert_eq!(
+            reports[0].1.exec_type(),
+            ExecType::Rejected(RejectReason::NotAcceptedInPhase)
+        );
+        assert!(
+            engine
+                .event("EURUSD", OrderEvent::limit(2, Side::Bid, px(101), 100))
+                .is_ok()
+        );
+        assert!(engine.event("AUDUSD", OrderEvent::Cancel { id: 1 }).is_ok());
+
+        engine.resume("AUDUSD").unwrap();
+        engine
+            .event("AUDUSD", OrderEvent::limit(3, Side::Ask, px(102), 100))
+            .unwrap();
+        let fills = engine
+            .event("AUDUSD", OrderEvent::limit(4, Side::Bid, px(102), 100))
+            .unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].ord_id_2(), 3);
+    }
+
+    #[test]
+    fn removed_instruments_stop_routing() {
+        let mut engine = engine();
+        let book = engine.remove_instrument("EURUSD").unwrap();
+        assert_eq!(book.instrument().symbol(), "EURUSD");
+        assert!(
+            engine
+                .event("EURUSD", OrderEvent::limit(1, Side::Ask, px(101), 100))
+                .is_err()
+        );
+        assert_eq!(engine.symbols(), vec!["AUDUSD"]);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::model::{Price, RejectReason, Side, TradingPhase};
+use std::error;
+use std::fmt;
+
+/// Failure of an order book or matching engine call.
+///
+/// Orders that break the instrument's rules are not errors: the book rejects them with an
+/// execution report. These are the cases where the call itself could not be carried out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderBookError {
+    /// No live order with this id
+    UnknownOrder(u64),
+    /// A live order already has this id
+    DuplicateOrderId(u64),
+    InvalidPrice(Price),
+    InvalidQuantity(u64),
+    /// A stop order was expected
+    NotAStopOrder(u64),
+    UnknownInstrument(String),
+    DuplicateInstrument(String),
+    InvalidPhaseTransition(TradingPhase, TradingPhase),
+    /// A price level the book points at is missing, the book is corrupted
+    MissingLevel(Side, Price),
+}
+
+impl OrderBookError {
+    /// The reason to give a client whose request failed with this error, if there is one.
+    pub fn reject_reason(&self) -> Option<RejectReason> {
+        match *self {
+            OrderBookError::UnknownOrder(_) => Some(RejectReason::UnknownOrder),
+            OrderBookError::InvalidPrice(_) => Some(RejectReason::InvalidPrice),
+            OrderBookError::InvalidQuantity(_) => Some(RejectReason::InvalidQuantity),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for OrderBookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OrderBookError::UnknownOrder(id) => write!(f, "Unknown order {}", id),
+            OrderBookError::DuplicateOrderId(id) => write!(f, "Order id {} is already live", id),
+            OrderBookError::InvalidPrice(price) => write!(f, "Invalid price {}", price),
+            OrderBookError::InvalidQuantity(qty) => write!(f, "Invalid quantity {}", qty),
+            OrderBookError::NotAStopOrder(id) => write!(f, "Order {} is not a stop order", id),
+            OrderBookError::UnknownInstrument(ref symbol) => {
+                write!(f, "Unknown instrument {}", symbol)
+            }
+            OrderBookError::DuplicateInstrument(ref symbol) => {
+                write!(f, "Instrument {} already exists", symbol)
+            }
+            OrderBookError::InvalidPhaseTransition(from, to) => {
+                write!(f, "Cannot move from {:?} to {:?}", from, to)
+            }
+            OrderBookError::MissingLevel(side, price) => {
+                write!(f, "Missing {:?} price level {}", side, price)
+            }
+        }
+    }
+}
+
+impl error::Error for OrderBookError {
+    fn description(&self) -> &str {
+        match *self {
+            OrderBookError::UnknownOrder(_) => "unknown order",
+            OrderBookError::DuplicateOrderId(_) => "duplicate order id",
+            OrderBookError::InvalidPrice(_) => "invalid price",
+            OrderBookError::InvalidQuantity(_) => "invalid quantity",
+            OrderBookError::NotAStopOrder(_) => "not a stop order",
+            OrderBookError::UnknownInstrument(_) => "unknown instrument",
+            OrderBookError::DuplicateInstrument(_) => "duplicate instrument",
+            OrderBookError::InvalidPhaseTransition(..) => "invalid trading phase transition",
+            OrderBookError::MissingLevel(..) => "missing price level",
+        }
+    }
+}
@@ -0,0 +1,541 @@
+//! TCP acceptor for FIX sessions in front of a matching engine.
+
+use super::message::{tags, Message, MAX_MESSAGE_LEN};
+use super::order_entry::{OrderEntry, Outbound};
+use super::session::{Session, SessionConfig, SessionState, BEGIN_STRING};
+use crate::clock::{Clock, SystemClock};
+use crate::engine::MatchingEngine;
+use crate::net;
+use chrono::{DateTime, Utc};
+use failure::Error;
+use std::collections::HashMap;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Sessions are known by the counterparty's CompID
+type Connection = net::Connection<String>;
+
+/// Accepts FIX connections and routes the orders they carry to a matching engine.
+///
+/// Everything runs on the thread calling `poll` or `run`, the sockets are non-blocking.
+/// A counterparty is known by the SenderCompID of its Logon and needs a session added
+/// for it beforehand.
+#[derive(Debug)]
+pub struct FixGateway {
+    listener: TcpListener,
+    engine: MatchingEngine,
+    orders: OrderEntry,
+    // By the counterparty's CompID
+    sessions: HashMap<String, Session>,
+    connections: Vec<Connection>,
+    clock: Box<Clock>,
+}
+
+impl FixGateway {
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: MatchingEngine) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        info!("FIX gateway listening on {}", listener.local_addr()?);
+        Ok(Self {
+            listener,
+            engine,
+            orders: OrderEntry::new(),
+            sessions: HashMap::new(),
+            connections: Vec::new(),
+            clock: Box::new(SystemClock),
+        })
+    }
+
+    /// Replaces the clock used for heartbeats and timestamps, returning the previous one.
+    pub fn set_clock(&mut self, clock: Box<Clock>) -> Box<Clock> {
+        mem::replace(&mut self.clock, clock)
+    }
+
+    pub fn add_session(&mut self, config: SessionConfig) -> Result<(), Error> {
+        let comp_id = config.target_comp_id().to_string();
+        if self.sessions.contains_key(&comp_id) {
+            bail!("A session for {} already exists", comp_id);
+        }
+        self.sessions.insert(comp_id, Session::new(config));
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// The session of the counterparty logging on as `comp_id`.
+    pub fn session(&self, comp_id: &str) -> Option<&Session> {
+        self.sessions.get(comp_id)
+    }
+
+    pub fn engine(&self) -> &MatchingEngine {
+        &self.engine
+    }
+
+    /// The engine, for changes such as halts. Reports they cause go out on the next poll.
+    pub fn engine_mut(&mut self) -> &mut MatchingEngine {
+        &mut self.engine
+    }
+
+    /// Accepts new connections, handles the messages that arrived, sends heartbeats and
+    /// writes the responses. Returns the number of messages received.
+    pub fn poll(&mut self) -> Result<usize, Error> {
+        let now = self.clock.now();
+        self.connections.extend(net::accept(&self.listener, "FIX")?);
+
+        let mut received = Vec::new();
+        for (index, connection) in self.connections.iter_mut().enumerate() {
+            if !connection.read() {
+                connection.closed = true;
+            }
+            loop {
+                match Message::decode(&connection.inbound) {
+                    Ok(Some((message, begin_string, len))) => {
+                        connection.inbound.drain(..len);
+                        received.push((index, message, begin_string));
+                    }
+                    Ok(None) => {
+                        if connection.inbound.len() > MAX_MESSAGE_LEN {
+                            warn!("Dropping {}, message too long", connection.peer);
+                            connection.closed = true;
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Dropping {}, garbled message: {}", connection.peer, e);
+                        connection.closed = true;
+                        break;
+                    }
+                }
+            }
+        }
+        let count = received.len();
+        for (index, message, begin_string) in received {
+            self.receive(index, message, &begin_string, now);
+        }
+
+        let mut outbound = Vec::new();
+        self.orders
+            .drain_reports(&mut self.engine, now, &mut outbound);
+        self.send(outbound, now);
+        for session in self.sessions.values_mut() {
+            session.on_timer(now);
+        }
+        self.flush();
+        Ok(count)
+    }
+
+    /// Polls until `stop` is set, sleeping briefly whenever nothing arrived.
+    pub fn run(&mut self, stop: &AtomicBool) -> Result<(), Error> {
+        while !stop.load(Ordering::Relaxed) {
+            if self.poll()? == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        index: usize,
+        message: Message,
+        begin_string: &str,
+        now: DateTime<Utc>,
+    ) {
+        let comp_id = match self.connections[index].session.clone() {
+            Some(comp_id) => comp_id,
+            None => match self.attach(index, &message, now) {
+                Some(comp_id) => comp_id,
+                None => return,
+            },
+        };
+        let session = self.sessions.get_mut(&comp_id).unwrap(); // Safe, attached above
+        if begin_string != BEGIN_STRING {
+            session.logout("Unsupported BeginString", now);
+            return;
+        }
+        let owner = session.config().owner();
+        for message in session.receive(message, now) {
+            let outbound = self.orders
+                .handle(&comp_id, owner, &message, &mut self.engine, now);
+            self.send(outbound, now);
+        }
+    }
+
+    /// Ties a new connection to the session its first message names, returning the
+    /// session's CompID. Connections for unknown or already connected sessions are closed.
+    fn attach(
+        &mut self,
+        index: usize,
+        message: &Message,
+        now: DateTime<Utc>,
+    ) -> Option<String> {
+        let connection = &mut self.connections[index];
+        if connection.closed {
+            return None;
+        }
+        let comp_id = message.get(tags::SENDER_COMP_ID).unwrap_or_default();
+        let session = match self.sessions.get_mut(comp_id) {
+            Some(session) => session,
+            None => {
+                warn!("No session for {:?}, refusing {}", comp_id, connection.peer);
+                connection.closed = true;
+                return None;
+            }
+        };
+        if session.state() != SessionState::Disconnected {
+            warn!("{} is already connected, refusing {}", comp_id, connection.peer);
+            connection.closed = true;
+            return None;
+        }
+        session.connect(now);
+        connection.session = Some(comp_id.to_string());
+        Some(comp_id.to_string())
+    }
+
+    fn send(&mut self, outbound: Vec<Outbound>, now: DateTime<Utc>) {
+        for (comp_id, message) in outbound {
+            if let Some(session) = self.sessions.get_mut(&comp_id) {
+                session.send(message, now);
+            }
+        }
+    }
+
+    /// Writes what the sessions sent and closes the connections that are done.
+    fn flush(&mut self) {
+        let sessions = &mut self.sessions;
+        for connection in &mut self.connections {
+            if let Some(ref comp_id) = connection.session {
+                if let Some(session) = sessions.get_mut(comp_id) {
+                    for data in session.drain_outbound() {
+                        connection.outbound.extend_from_slice(&data);
+                    }
+                }
+            }
+            if !connection.flush() {
+                connection.closed = true;
+            }
+        }
+
+        self.connections.retain(|connection| {
+            let session = connection
+                .session
+                .as_ref()
+                .and_then(|comp_id| sessions.get_mut(comp_id));
+            let logged_out = session
+                .as_ref()
+                .map_or(false, |session| session.state() == SessionState::LoggedOut);
+            if !connection.closed && !(logged_out && connection.outbound.is_empty()) {
+                return true;
+            }
+            info!("Closing FIX connection from {}", connection.peer);
+            if let Some(session) = session {
+                session.disconnect();
+            }
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Instrument, OrderEvent, Price, Side};
+    use std::io::{self, Read, Write};
+    use std::net::TcpStream;
+
+    struct Client {
+        stream: TcpStream,
+        inbound: Vec<u8>,
+        seq: u64,
+    }
+
+    impl Client {
+        fn connect(gateway: &FixGateway) -> Self {
+            let stream = TcpStream::connect(gateway.local_addr().unwrap()).unwrap();
+            stream.set_nonblocking(true).unwrap();
+            Self {
+                stream,
+                inbound: Vec::new(),
+                seq: 1,
+            }
+        }
+
+        fn send(&mut self, message: Message) {
+            let mut stamped = Message::new(message.msg_type())
+                .with(tags::SENDER_COMP_ID, "CLIENT")
+                .with(tags::TARGET_COMP_ID, "EXCH")
+                .with(tags::MSG_SEQ_NUM, self.seq)
+                .with(tags::SENDING_TIME, "20180402-09:00:00.000");
+            for &(tag, ref value) in message.fields() {
+                stamped.set(tag, value);
+            }
+            self.seq += 1;
+            self.stream
+                .write_all(&stamped.encode(BEGIN_STRING))
+                .unwrap();
+        }
+
+        /// Polls the gateway until a message arrives, None if the connection was closed.
+        fn receive(&mut self, gateway: &mut FixGateway) -> Option<Message> {
+            let mut buf = [0u8; 4096];
+            for _ in 0..2_000 {
+                if let Some((message, _, len)) = Message::decode(&self.inbound).unwrap() {
+                    self.inbound.drain(..len);
+                    return Some(message);
+                }
+                gateway.poll().unwrap();
+                match self.stream.read(&mut buf) {
+                    Ok(0) => return None,
+                    Ok(len) => self.inbound.extend_from_slice(&buf[..len]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    Err(e) => panic!("Read failed: {}", e),
+                }
+            }
+            panic!("Nothing received from the gateway");
+        }
+
+        fn expect(&mut self, gateway: &mut FixGateway, msg_type: &str) -> Message {
+            let message = self.receive(gateway).expect("Connection closed");
+            assert_eq!(message.msg_type(), msg_type, "{:?}", message);
+            message
+        }
+    }
+
+    fn gateway() -> FixGateway {
+        ::crate::model::test_setup();
+        let mut engine = MatchingEngine::new();
+        engine
+            .add_instrument(Instrument::new("AUDUSD").with_price_scale(4))
+            .unwrap();
+        let mut gateway = FixGateway::bind("127.0.0.1:0", engine).unwrap();
+        gateway
+            .add_session(SessionConfig::new("EXCH", "CLIENT").with_owner(7))
+            .unwrap();
+        gateway
+    }
+
+    fn logon() -> Message {
+        Message::new("A")
+            .with(tags::ENCRYPT_METHOD, 0)
+            .with(tags::HEART_BT_INT, 30)
+            .with(tags::RESET_SEQ_NUM_FLAG, "Y")
+    }
+
+    fn new_order(cl_ord_id: &str, side: &str, qty: u64, price: &str) -> Message {
+        Message::new("D")
+            .with(tags::CL_ORD_ID, cl_ord_id)
+            .with(tags::SYMBOL, "AUDUSD")
+            .with(tags::SIDE, side)
+            .with(tags::ORDER_QTY, qty)
+            .with(tags::ORD_TYPE, "2")
+            .with(tags::PRICE, price)
+            .with(tags::TIME_IN_FORCE, "1")
+    }
+
+    fn summary(message: &Message, fields: &[u32]) -> Vec<Option<String>> {
+        fields
+            .iter()
+            .map(|tag| message.get(*tag).map(|value| value.to_string()))
+            .collect()
+    }
+
+    fn values(values: &[&str]) -> Vec<Option<String>> {
+        values
+            .iter()
+            .map(|value| match *value {
+                "" => None,
+                value => Some(value.to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn enters_replaces_and_cancels_orders() {
+        let mut gateway = gateway();
+        let mut client = Client::connect(&gateway);
+        client.send(logon());
+        client.expect(&mut gateway, "A");
+        assert_eq!(
+            gateway.session("CLIENT").unwrap().state(),
+            SessionState::Active
+        );
+
+        client.send(new_order("B1", "1", 100, "0.7512"));
+        let report = client.expect(&mut gateway, "8");
+        let fields = [tags::CL_ORD_ID, tags::EXEC_TYPE, tags::ORD_STATUS, tags::PRICE];
+        assert_eq!(summary(&report, &fields), values(&["B1", "0", "0", "0.7512"]));
+        let order_id = report.get(tags::ORDER_ID).unwrap().to_string();
+
+        client.send(new_order("S1", "2", 40, "0.7510"));
+        let mut reports: Vec<Vec<Option<String>>> = (0..3)
+            .map(|_| {
+                let report = client.expect(&mut gateway, "8");
+                let fields = [
+                    tags::CL_ORD_ID,
+                    tags::EXEC_TYPE,
+                    tags::ORD_STATUS,
+                    tags::LAST_QTY,
+                    tags::LAST_PX,
+                    tags::CUM_QTY,
+                ];
+                summary(&report, &fields)
+            })
+            .collect();
+        reports.sort();
+        assert_eq!(
+            reports,
+            vec![
+                values(&["B1", "F", "1", "40", "0.7512", "40"]),
+                values(&["S1", "0", "0", "", "", "0"]),
+                values(&["S1", "F", "2", "40", "0.7512", "40"]),
+            ]
+        );
+
+        // The new order quantity includes what already traded, so nothing would be left
+        let replace = |cl_ord_id: &str, order_qty: u64| {
+            Message::new("G")
+                .with(tags::CL_ORD_ID, cl_ord_id)
+                .with(tags::ORIG_CL_ORD_ID, "B1")
+                .with(tags::SYMBOL, "AUDUSD")
+                .with(tags::SIDE, "1")
+                .with(tags::ORDER_QTY, order_qty)
+                .with(tags::ORD_TYPE, "2")
+                .with(tags::PRICE, "0.7511")
+        };
+        client.send(replace("R0", 40));
+        let reject = client.expect(&mut gateway, "9");
+        let fields = [
+            tags::CL_ORD_ID,
+            tags::ORIG_CL_ORD_ID,
+            tags::CXL_REJ_RESPONSE_TO,
+            tags::CXL_REJ_REASON,
+        ];
+        assert_eq!(summary(&reject, &fields), values(&["R0", "B1", "2", "99"]));
+
+        client.send(replace("R1", 80));
+        let report = client.expect(&mut gateway, "8");
+        let fields = [
+            tags::ORDER_ID,
+            tags::CL_ORD_ID,
+            tags::ORIG_CL_ORD_ID,
+            tags::EXEC_TYPE,
+            tags::ORDER_QTY,
+            tags::PRICE,
+            tags::LEAVES_QTY,
+        ];
+        assert_eq!(
+            summary(&report, &fields),
+            values(&[order_id.as_str(), "R1", "B1", "5", "80", "0.7511", "40"])
+        );
+
+        let cancel = |cl_ord_id: &str, orig_cl_ord_id: &str| {
+            Message::new("F")
+                .with(tags::CL_ORD_ID, cl_ord_id)
+                .with(tags::ORIG_CL_ORD_ID, orig_cl_ord_id)
+                .with(tags::SYMBOL, "AUDUSD")
+                .with(tags::SIDE, "1")
+        };
+        client.send(cancel("C1", "R1"));
+        let report = client.expect(&mut gateway, "8");
+        let fields = [
+            tags::CL_ORD_ID,
+            tags::ORIG_CL_ORD_ID,
+            tags::EXEC_TYPE,
+            tags::ORD_STATUS,
+            tags::CUM_QTY,
+        ];
+        assert_eq!(summary(&report, &fields), values(&["C1", "R1", "4", "4", "40"]));
+
+        // The order is gone
+        client.send(cancel("C2", "R1"));
+        let reject = client.expect(&mut gateway, "9");
+        let fields = [tags::CL_ORD_ID, tags::CXL_REJ_RESPONSE_TO, tags::CXL_REJ_REASON];
+        assert_eq!(summary(&reject, &fields), values(&["C2", "1", "1"]));
+
+        client.send(new_order("B2", "1", 100, "0.7512").with(tags::SYMBOL, "EURUSD"));
+        let reject = client.expect(&mut gateway, "8");
+        let fields = [tags::CL_ORD_ID, tags::EXEC_TYPE, tags::ORD_REJ_REASON];
+        assert_eq!(summary(&reject, &fields), values(&["B2", "8", "1"]));
+
+        let mut missing_qty = new_order("B3", "1", 100, "0.7512");
+        missing_qty.remove(tags::ORDER_QTY);
+        client.send(missing_qty);
+        let reject = client.expect(&mut gateway, "3");
+        let fields = [tags::REF_TAG_ID, tags::SESSION_REJECT_REASON];
+        assert_eq!(summary(&reject, &fields), values(&["38", "1"]));
+
+        client.send(Message::new("5"));
+        client.expect(&mut gateway, "5");
+        assert!(client.receive(&mut gateway).is_none());
+        assert_eq!(
+            gateway.session("CLIENT").unwrap().state(),
+            SessionState::Disconnected
+        );
+    }
+
+    #[test]
+    fn reports_fills_to_the_session_after_it_reconnects() {
+        let mut gateway = gateway();
+        let mut client = Client::connect(&gateway);
+        client.send(logon());
+        client.expect(&mut gateway, "A");
+        client.send(new_order("B1", "1", 100, "0.7512"));
+        client.expect(&mut gateway, "8");
+        drop(client);
+        while gateway.session("CLIENT").unwrap().state() != SessionState::Disconnected {
+            gateway.poll().unwrap();
+        }
+
+        // Filled by an order that did not come over FIX while the client was away
+        gateway
+            .engine_mut()
+            .event(
+                "AUDUSD",
+                OrderEvent::limit(1_000, Side::Ask, Price::new(7512, 4), 100),
+            )
+            .unwrap();
+        gateway.poll().unwrap();
+
+        let mut client = Client::connect(&gateway);
+        client.seq = 3;
+        client.send(
+            Message::new("A")
+                .with(tags::ENCRYPT_METHOD, 0)
+                .with(tags::HEART_BT_INT, 30),
+        );
+        let logon = client.expect(&mut gateway, "A");
+        assert_eq!(logon.get(tags::MSG_SEQ_NUM), Some("4"));
+        client.send(
+            Message::new("2")
+                .with(tags::BEGIN_SEQ_NO, 3)
+                .with(tags::END_SEQ_NO, 0),
+        );
+        let fill = client.expect(&mut gateway, "8");
+        let fields = [
+            tags::MSG_SEQ_NUM,
+            tags::POSS_DUP_FLAG,
+            tags::CL_ORD_ID,
+            tags::EXEC_TYPE,
+            tags::ORD_STATUS,
+        ];
+        assert_eq!(summary(&fill, &fields), values(&["3", "Y", "B1", "F", "2"]));
+        let gap_fill = client.expect(&mut gateway, "4");
+        assert_eq!(gap_fill.get(tags::NEW_SEQ_NO), Some("5"));
+    }
+
+    #[test]
+    fn refuses_unknown_counterparties() {
+        ::crate::model::test_setup();
+        let mut gateway = FixGateway::bind("127.0.0.1:0", MatchingEngine::new()).unwrap();
+        let mut client = Client::connect(&gateway);
+        client.send(logon());
+        assert!(client.receive(&mut gateway).is_none());
+    }
+}
@@ -0,0 +1,335 @@
+//! FIX tag=value messages and their framing on the wire.
+
+use chrono::{DateTime, TimeZone, Utc};
+use failure::Error;
+use std::str;
+
+pub const SOH: u8 = 0x01;
+
+/// Longest message accepted off the wire.
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H:%M:%S%.3f";
+
+/// A FIX message without its BeginString, BodyLength and CheckSum fields, which only
+/// exist on the wire. Fields keep the order they were added or received in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    msg_type: String,
+    fields: Vec<(u32, String)>,
+}
+
+impl Message {
+    pub fn new(msg_type: &str) -> Self {
+        Self {
+            msg_type: msg_type.to_string(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn msg_type(&self) -> &str {
+        &self.msg_type
+    }
+
+    pub fn fields(&self) -> &[(u32, String)] {
+        &self.fields
+    }
+
+    /// Adds a field, replacing an earlier value of the same tag.
+    pub fn with<V: ToString>(mut self, tag: u32, value: V) -> Self {
+        self.set(tag, value);
+        self
+    }
+
+    pub fn set<V: ToString>(&mut self, tag: u32, value: V) {
+        let value = value.to_string();
+        match self.fields.iter_mut().find(|field| field.0 == tag) {
+            Some(field) => field.1 = value,
+            None => self.fields.push((tag, value)),
+        }
+    }
+
+    pub fn remove(&mut self, tag: u32) {
+        self.fields.retain(|&(t, _)| t != tag);
+    }
+
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|&&(t, _)| t == tag)
+            .map(|&(_, ref value)| value.as_str())
+    }
+
+    /// Value of a field that has to be present.
+    pub fn require(&self, tag: u32) -> Result<&str, Error> {
+        self.get(tag)
+            .ok_or_else(|| format_err!("Required tag {} missing", tag))
+    }
+
+    pub fn get_u64(&self, tag: u32) -> Result<Option<u64>, Error> {
+        match self.get(tag) {
+            Some(value) => match value.parse() {
+                Ok(number) => Ok(Some(number)),
+                Err(_) => bail!("Tag {} has an invalid value {:?}", tag, value),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_bool(&self, tag: u32) -> bool {
+        self.get(tag) == Some("Y")
+    }
+
+    pub fn seq_num(&self) -> Option<u64> {
+        self.get(tags::MSG_SEQ_NUM).and_then(|value| value.parse().ok())
+    }
+
+    /// Whether this is a session level message rather than an application one.
+    pub fn is_admin(&self) -> bool {
+        match self.msg_type.as_str() {
+            "0" | "1" | "2" | "3" | "4" | "5" | "A" => true,
+            _ => false,
+        }
+    }
+
+    /// Serializes the message with the standard header fields in front, computing the
+    /// BodyLength and CheckSum.
+    pub fn encode(&self, begin_string: &str) -> Vec<u8> {
+        let mut body = Vec::with_capacity(128);
+        put_field(&mut body, tags::MSG_TYPE, &self.msg_type);
+        for &(tag, ref value) in &self.fields {
+            put_field(&mut body, tag, value);
+        }
+
+        let mut data = Vec::with_capacity(body.len() + 32);
+        put_field(&mut data, tags::BEGIN_STRING, begin_string);
+        put_field(&mut data, tags::BODY_LENGTH, &body.len().to_string());
+        data.extend_from_slice(&body);
+        let checksum = checksum(&data);
+        put_field(&mut data, tags::CHECK_SUM, &format!("{:03}", checksum));
+        data
+    }
+
+    /// Decodes the message at the start of `data`, returning it with its BeginString and
+    /// the number of bytes it took, or None if the message is not complete yet.
+    pub fn decode(data: &[u8]) -> Result<Option<(Message, String, usize)>, Error> {
+        let mut reader = FieldReader { data, pos: 0 };
+        let begin_string = match reader.next()? {
+            Some((tags::BEGIN_STRING, value)) => value.to_string(),
+            Some((tag, _)) => bail!("Message starts with tag {}", tag),
+            None => return Ok(None),
+        };
+        let body_length: usize = match reader.next()? {
+            Some((tags::BODY_LENGTH, value)) => value
+                .parse()
+                .map_err(|_| format_err!("Invalid body length {:?}", value))?,
+            Some((tag, _)) => bail!("Expected body length, found tag {}", tag),
+            None => return Ok(None),
+        };
+        if body_length > MAX_MESSAGE_LEN {
+            bail!("Body length {} is over {}", body_length, MAX_MESSAGE_LEN);
+        }
+        let body_start = reader.pos;
+        let body_end = body_start + body_length;
+        // The checksum field is always "10=nnn" and a delimiter
+        if data.len() < body_end + 7 {
+            return Ok(None);
+        }
+
+        reader.data = &data[..body_end];
+        let msg_type = match reader.next()? {
+            Some((tags::MSG_TYPE, value)) => value.to_string(),
+            Some((tag, _)) => bail!("Expected message type, found tag {}", tag),
+            None => bail!("Message has no message type"),
+        };
+        let mut message = Message::new(&msg_type);
+        while let Some((tag, value)) = reader.next()? {
+            message.fields.push((tag, value.to_string()));
+        }
+        if reader.pos != body_end {
+            bail!("Body length {} does not end on a field", body_length);
+        }
+
+        let mut trailer = FieldReader {
+            data: &data[body_end..body_end + 7],
+            pos: 0,
+        };
+        match trailer.next()? {
+            Some((tags::CHECK_SUM, value)) => {
+                let expected = checksum(&data[..body_end]);
+                if value.parse::<u8>().ok() != Some(expected) {
+                    bail!("Checksum {} does not match {:03}", value, expected);
+                }
+            }
+            _ => bail!("Message has no checksum after its body"),
+        }
+        Ok(Some((message, begin_string, body_end + 7)))
+    }
+}
+
+struct FieldReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    /// Next `tag=value` field, None when the data runs out before the delimiter.
+    fn next(&mut self) -> Result<Option<(u32, &'a str)>, Error> {
+        let rest = &self.data[self.pos..];
+        let end = match rest.iter().position(|b| *b == SOH) {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let field = str::from_utf8(&rest[..end])?;
+        let equals = field
+            .find('=')
+            .ok_or_else(|| format_err!("Field {:?} has no tag", field))?;
+        let tag = field[..equals]
+            .parse()
+            .map_err(|_| format_err!("Invalid tag in {:?}", field))?;
+        self.pos += end + 1;
+        Ok(Some((tag, &field[equals + 1..])))
+    }
+}
+
+fn put_field(buf: &mut Vec<u8>, tag: u32, value: &str) {
+    buf.extend_from_slice(tag.to_string().as_bytes());
+    buf.push(b'=');
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(SOH);
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Formats a time as a FIX UTCTimestamp with milliseconds.
+pub fn format_timestamp(time: DateTime<Utc>) -> String {
+    time.format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Parses a FIX UTCTimestamp, with or without fractional seconds.
+pub fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, Error> {
+    Utc.datetime_from_str(value, "%Y%m%d-%H:%M:%S%.f")
+        .map_err(|_| format_err!("Invalid timestamp {:?}", value))
+}
+
+/// Tags used by the session and order entry messages.
+pub mod tags {
+    pub const AVG_PX: u32 = 6;
+    pub const BEGIN_SEQ_NO: u32 = 7;
+    pub const BEGIN_STRING: u32 = 8;
+    pub const BODY_LENGTH: u32 = 9;
+    pub const CHECK_SUM: u32 = 10;
+    pub const CL_ORD_ID: u32 = 11;
+    pub const CUM_QTY: u32 = 14;
+    pub const END_SEQ_NO: u32 = 16;
+    pub const EXEC_ID: u32 = 17;
+    pub const LAST_PX: u32 = 31;
+    pub const LAST_QTY: u32 = 32;
+    pub const MSG_SEQ_NUM: u32 = 34;
+    pub const MSG_TYPE: u32 = 35;
+    pub const NEW_SEQ_NO: u32 = 36;
+    pub const ORDER_ID: u32 = 37;
+    pub const ORDER_QTY: u32 = 38;
+    pub const ORD_STATUS: u32 = 39;
+    pub const ORD_TYPE: u32 = 40;
+    pub const ORIG_CL_ORD_ID: u32 = 41;
+    pub const POSS_DUP_FLAG: u32 = 43;
+    pub const PRICE: u32 = 44;
+    pub const REF_SEQ_NUM: u32 = 45;
+    pub const SENDER_COMP_ID: u32 = 49;
+    pub const SENDING_TIME: u32 = 52;
+    pub const SIDE: u32 = 54;
+    pub const SYMBOL: u32 = 55;
+    pub const TARGET_COMP_ID: u32 = 56;
+    pub const TEXT: u32 = 58;
+    pub const TIME_IN_FORCE: u32 = 59;
+    pub const TRANSACT_TIME: u32 = 60;
+    pub const ENCRYPT_METHOD: u32 = 98;
+    pub const STOP_PX: u32 = 99;
+    pub const CXL_REJ_REASON: u32 = 102;
+    pub const ORD_REJ_REASON: u32 = 103;
+    pub const HEART_BT_INT: u32 = 108;
+    pub const MAX_FLOOR: u32 = 111;
+    pub const TEST_REQ_ID: u32 = 112;
+    pub const ORIG_SENDING_TIME: u32 = 122;
+    pub const GAP_FILL_FLAG: u32 = 123;
+    pub const EXPIRE_TIME: u32 = 126;
+    pub const RESET_SEQ_NUM_FLAG: u32 = 141;
+    pub const EXEC_TYPE: u32 = 150;
+    pub const LEAVES_QTY: u32 = 151;
+    pub const REF_TAG_ID: u32 = 371;
+    pub const REF_MSG_TYPE: u32 = 372;
+    pub const SESSION_REJECT_REASON: u32 = 373;
+    pub const BUSINESS_REJECT_REF_ID: u32 = 379;
+    pub const BUSINESS_REJECT_REASON: u32 = 380;
+    pub const CXL_REJ_RESPONSE_TO: u32 = 434;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wire(fields: &str) -> Vec<u8> {
+        fields.replace('|', "\u{1}").into_bytes()
+    }
+
+    #[test]
+    fn encodes_length_and_checksum() {
+        let message = Message::new("0")
+            .with(tags::SENDER_COMP_ID, "EXCH")
+            .with(tags::TARGET_COMP_ID, "CLIENT")
+            .with(tags::MSG_SEQ_NUM, 2);
+        let data = message.encode("FIX.4.4");
+        let body = "35=0|49=EXCH|56=CLIENT|34=2|";
+        let head = format!("8=FIX.4.4|9={}|{}", body.len(), body);
+        let sum = wire(&head).iter().fold(0u32, |sum, b| sum + u32::from(*b)) % 256;
+        assert_eq!(data, wire(&format!("{}10={:03}|", head, sum)));
+
+        let (decoded, begin_string, len) = Message::decode(&data).unwrap().unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(begin_string, "FIX.4.4");
+        assert_eq!(len, data.len());
+        assert_eq!(decoded.seq_num(), Some(2));
+        assert!(decoded.is_admin());
+    }
+
+    #[test]
+    fn waits_for_complete_messages() {
+        let mut data = Message::new("D").with(tags::CL_ORD_ID, "A1").encode("FIX.4.4");
+        let second = Message::new("F").with(tags::CL_ORD_ID, "A2").encode("FIX.4.4");
+        let first_len = data.len();
+        data.extend_from_slice(&second);
+        for end in 0..first_len {
+            assert_eq!(Message::decode(&data[..end]).unwrap(), None);
+        }
+        let (first, _, len) = Message::decode(&data).unwrap().unwrap();
+        assert_eq!(first.get(tags::CL_ORD_ID), Some("A1"));
+        let (second, _, _) = Message::decode(&data[len..]).unwrap().unwrap();
+        assert_eq!(second.msg_type(), "F");
+    }
+
+    #[test]
+    fn rejects_damaged_messages() {
+        let mut data = Message::new("D").with(tags::CL_ORD_ID, "A1").encode("FIX.4.4");
+        let pos = data.len() - 10;
+        data[pos] = b'B';
+        assert!(Message::decode(&data).is_err());
+        assert!(Message::decode(&wire("35=D|8=FIX.4.4|")).is_err());
+        assert!(Message::decode(&wire("8=FIX.4.4|9=x|")).is_err());
+        assert!(Message::decode(&wire("8=FIX.4.4|9=18446744073709551615|35=0|")).is_err());
+    }
+
+    #[test]
+    fn timestamps() {
+        let time = Utc.ymd(2018, 4, 2).and_hms_milli(9, 30, 5, 250);
+        assert_eq!(format_timestamp(time), "20180402-09:30:05.250");
+        assert_eq!(parse_timestamp("20180402-09:30:05.250").unwrap(), time);
+        assert_eq!(
+            parse_timestamp("20180402-09:30:05").unwrap(),
+            Utc.ymd(2018, 4, 2).and_hms(9, 30, 5)
+        );
+        assert!(parse_timestamp("2018-04-02").is_err());
+    }
+}
@@ -0,0 +1,15 @@
+//! FIX 4.4 order entry over TCP.
+//!
+//! Clients log on with a FIX session and enter orders with NewOrderSingle (D), cancel them
+//! with OrderCancelRequest (F) and amend them with OrderCancelReplaceRequest (G). They get
+//! ExecutionReport (8) and OrderCancelReject (9) messages back. The session layer takes
+//! care of heartbeats, sequence numbers, resend requests and gap fills.
+
+pub mod gateway;
+pub mod message;
+pub mod order_entry;
+pub mod session;
+
+pub use self::gateway::FixGateway;
+pub use self::message::Message;
+pub use self::session::{Session, SessionConfig, SessionState};
@@ -0,0 +1,629 @@
+//! Translation between FIX order entry messages and the matching engine.
+//!
+//! NewOrderSingle, OrderCancelRequest and OrderCancelReplaceRequest become order events.
+//! The engine's execution reports go back to the session that entered the order as
+//! ExecutionReport or OrderCancelReject messages. Order ids in the engine are assigned
+//! here, clients refer to their orders by ClOrdID.
+
+use super::message::{format_timestamp, parse_timestamp, tags, Message};
+use super::session::{self, REQUIRED_TAG_MISSING, VALUE_IS_INCORRECT};
+use crate::engine::MatchingEngine;
+use crate::error::OrderBookError;
+use crate::model::{ExecType, ExecutionReport, OrderEvent, Price, RejectReason, Side,
+                   TimeInForce};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::mem;
+
+// OrdRejReason (tag 103)
+const UNKNOWN_SYMBOL: u32 = 1;
+const EXCHANGE_CLOSED: u32 = 2;
+const ORDER_EXCEEDS_LIMIT: u32 = 3;
+const UNKNOWN_ORDER: u32 = 5;
+const DUPLICATE_ORDER: u32 = 6;
+const UNSUPPORTED_ORDER_CHARACTERISTIC: u32 = 11;
+const INCORRECT_QUANTITY: u32 = 13;
+const PRICE_OUTSIDE_BAND: u32 = 16;
+const OTHER: u32 = 99;
+
+// CxlRejReason (tag 102)
+const CXL_UNKNOWN_ORDER: u32 = 1;
+const CXL_DUPLICATE_CL_ORD_ID: u32 = 6;
+
+// BusinessRejectReason (tag 380)
+const UNSUPPORTED_MESSAGE_TYPE: u32 = 3;
+
+/// A message to send on the session of a counterparty.
+pub type Outbound = (String, Message);
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum RequestKind {
+    Cancel,
+    Replace { order_qty: u64 },
+}
+
+/// A cancel or replace sent to the engine and not yet answered.
+#[derive(Debug, Clone)]
+struct PendingRequest {
+    kind: RequestKind,
+    cl_ord_id: String,
+}
+
+#[derive(Debug, Clone)]
+struct Order {
+    session: String,
+    symbol: String,
+    cl_ord_id: String,
+    side: Side,
+    order_qty: u64,
+    pending: Option<PendingRequest>,
+}
+
+/// A field that is missing or does not parse.
+#[derive(Debug)]
+struct InvalidField {
+    tag: u32,
+    missing: bool,
+}
+
+/// Live orders entered over FIX and the ClOrdIDs they are known by.
+#[derive(Debug, Default)]
+pub struct OrderEntry {
+    orders: HashMap<u64, Order>,
+    // (session, ClOrdID) of each live order
+    cl_ord_ids: HashMap<(String, String), u64>,
+    next_order_id: u64,
+    next_exec_id: u64,
+}
+
+impl OrderEntry {
+    pub fn new() -> Self {
+        Self {
+            orders: HashMap::new(),
+            cl_ord_ids: HashMap::new(),
+            next_order_id: 1,
+            next_exec_id: 1,
+        }
+    }
+
+    pub fn open_orders(&self) -> usize {
+        self.orders.len()
+    }
+
+    /// Handles an application message from `session`, returning the messages to send in
+    /// response, including reports on other sessions' orders that traded.
+    pub fn handle(
+        &mut self,
+        session: &str,
+        owner: u64,
+        message: &Message,
+        engine: &mut MatchingEngine,
+        now: DateTime<Utc>,
+    ) -> Vec<Outbound> {
+        let mut outbound = Vec::new();
+        let result = match message.msg_type() {
+            "D" => self.new_order(session, owner, message, engine, now, &mut outbound),
+            "F" => self.cancel(session, message, engine, &mut outbound),
+            "G" => self.replace(session, message, engine, now, &mut outbound),
+            msg_type => {
+                let mut reject = Message::new("j")
+                    .with(tags::REF_MSG_TYPE, msg_type)
+                    .with(tags::BUSINESS_REJECT_REASON, UNSUPPORTED_MESSAGE_TYPE)
+                    .with(tags::TEXT, "Unsupported message type");
+                if let Some(seq) = message.seq_num() {
+                    reject.set(tags::REF_SEQ_NUM, seq);
+                }
+                outbound.push((session.to_string(), reject));
+                Ok(())
+            }
+        };
+        if let Err(field) = result {
+            let (reason, text) = if field.missing {
+                (REQUIRED_TAG_MISSING, "Required tag missing")
+            } else {
+                (VALUE_IS_INCORRECT, "Value is incorrect for this tag")
+            };
+            let reject = session::reject(message, reason, Some(field.tag), text);
+            outbound.push((session.to_string(), reject));
+        }
+        self.drain_reports(engine, now, &mut outbound);
+        outbound
+    }
+
+    /// Turns the engine's execution reports into messages for the sessions that own the
+    /// orders. Reports on orders that were not entered over FIX are dropped.
+    pub fn drain_reports(
+        &mut self,
+        engine: &mut MatchingEngine,
+        now: DateTime<Utc>,
+        outbound: &mut Vec<Outbound>,
+    ) {
+        for (_, report) in engine.drain_reports() {
+            outbound.extend(self.report(&report, now));
+        }
+    }
+
+    fn new_order(
+        &mut self,
+        session: &str,
+        owner: u64,
+        message: &Message,
+        engine: &mut MatchingEngine,
+        now: DateTime<Utc>,
+        outbound: &mut Vec<Outbound>,
+    ) -> Result<(), InvalidField> {
+        let cl_ord_id = required(message, tags::CL_ORD_ID)?;
+        let symbol = required(message, tags::SYMBOL)?;
+        let side = parse_side(message)?;
+        let qty = parse_qty(message, tags::ORDER_QTY)?;
+        let ord_type = required(message, tags::ORD_TYPE)?;
+        let tif = parse_tif(message)?;
+
+        let key = (session.to_string(), cl_ord_id.to_string());
+        if self.cl_ord_ids.contains_key(&key) {
+            let reject = self.rejected(message, DUPLICATE_ORDER, "Duplicate ClOrdID", now);
+            outbound.push((session.to_string(), reject));
+            return Ok(());
+        }
+        let price_scale = match engine.book(symbol) {
+            Some(book) => book.instrument().price_scale(),
+            None => {
+                let reject = self.rejected(message, UNKNOWN_SYMBOL, "Unknown symbol", now);
+                outbound.push((session.to_string(), reject));
+                return Ok(());
+            }
+        };
+
+        let id = self.next_order_id;
+        let resting_tif = match tif {
+            None | Some(TimeInForce::GoodTillCancel) => true,
+            _ => false,
+        };
+        let event = match ord_type {
+            "1" => OrderEvent::market(id, side, qty),
+            "2" => {
+                let price = parse_price(message, tags::PRICE, price_scale)?;
+                match message.get(tags::MAX_FLOOR) {
+                    Some(_) if resting_tif => {
+                        let max_floor = parse_qty(message, tags::MAX_FLOOR)?;
+                        OrderEvent::iceberg(id, side, price, qty, max_floor)
+                    }
+                    Some(_) => return self.unsupported(session, message, now, outbound),
+                    // FIX orders without a time in force are day orders
+                    None => OrderEvent::limit_with_tif(
+                        id,
+                        side,
+                        price,
+                        qty,
+                        tif.unwrap_or(TimeInForce::Day),
+                    ),
+                }
+            }
+            "3" | "4" if !resting_tif => {
+                return self.unsupported(session, message, now, outbound);
+            }
+            "3" => {
+                let stop_price = parse_price(message, tags::STOP_PX, price_scale)?;
+                OrderEvent::stop_market(id, side, stop_price, qty)
+            }
+            "4" => {
+                let stop_price = parse_price(message, tags::STOP_PX, price_scale)?;
+                let price = parse_price(message, tags::PRICE, price_scale)?;
+                OrderEvent::stop_limit(id, side, stop_price, price, qty)
+            }
+            _ => return Err(invalid(tags::ORD_TYPE)),
+        };
+
+        self.next_order_id += 1;
+        self.orders.insert(
+            id,
+            Order {
+                session: session.to_string(),
+                symbol: symbol.to_string(),
+                cl_ord_id: cl_ord_id.to_string(),
+                side,
+                order_qty: qty,
+                pending: None,
+            },
+        );
+        self.cl_ord_ids.insert(key, id);
+        if let Err(e) = engine.event(symbol, event.with_owner(owner)) {
+            self.forget(id);
+            let reason = match e {
+                OrderBookError::UnknownInstrument(_) => UNKNOWN_SYMBOL,
+                _ => OTHER,
+            };
+            let reject = self.rejected(message, reason, &e.to_string(), now);
+            outbound.push((session.to_string(), reject));
+        }
+        Ok(())
+    }
+
+    fn cancel(
+        &mut self,
+        session: &str,
+        message: &Message,
+        engine: &mut MatchingEngine,
+        outbound: &mut Vec<Outbound>,
+    ) -> Result<(), InvalidField> {
+        let cl_ord_id = required(message, tags::CL_ORD_ID)?;
+        required(message, tags::ORIG_CL_ORD_ID)?;
+        parse_side(message)?;
+        let id = match self.request_target(session, message, RequestKind::Cancel, outbound) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        let symbol = self.orders[&id].symbol.clone();
+        self.set_pending(id, RequestKind::Cancel, cl_ord_id);
+        if let Err(e) = engine.event(&symbol, OrderEvent::Cancel { id }) {
+            self.cancel_failed(id, &e.to_string(), outbound);
+        }
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        session: &str,
+        message: &Message,
+        engine: &mut MatchingEngine,
+        now: DateTime<Utc>,
+        outbound: &mut Vec<Outbound>,
+    ) -> Result<(), InvalidField> {
+        let cl_ord_id = required(message, tags::CL_ORD_ID)?;
+        required(message, tags::ORIG_CL_ORD_ID)?;
+        let side = parse_side(message)?;
+        let order_qty = parse_qty(message, tags::ORDER_QTY)?;
+        if required(message, tags::ORD_TYPE)? != "2" {
+            return self.unsupported(session, message, now, outbound);
+        }
+        let kind = RequestKind::Replace { order_qty };
+        let id = match self.request_target(session, message, kind, outbound) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        let symbol = self.orders[&id].symbol.clone();
+        let (price_scale, cum_qty) = match engine.book(&symbol) {
+            Some(book) => (
+                book.instrument().price_scale(),
+                book.order(id).map_or(0, |order| order.cum_qty()),
+            ),
+            None => (0, 0),
+        };
+        let price = parse_price(message, tags::PRICE, price_scale)?;
+        self.set_pending(id, kind, cl_ord_id);
+        // OrderQty is the new total, the book takes the quantity left to trade. Nothing
+        // left would make the replace a cancel.
+        if order_qty <= cum_qty {
+            self.cancel_failed(id, "OrderQty must be above CumQty", outbound);
+            return Ok(());
+        }
+        let leaves_qty = order_qty - cum_qty;
+        let event = OrderEvent::replace(id, side, price, leaves_qty);
+        if let Err(e) = engine.event(&symbol, event) {
+            self.cancel_failed(id, &e.to_string(), outbound);
+        }
+        Ok(())
+    }
+
+    /// Finds the live order a cancel or replace refers to, answering with an
+    /// OrderCancelReject if there is none.
+    fn request_target(
+        &self,
+        session: &str,
+        message: &Message,
+        kind: RequestKind,
+        outbound: &mut Vec<Outbound>,
+    ) -> Option<u64> {
+        let cl_ord_id = message.get(tags::CL_ORD_ID).unwrap_or_default();
+        let orig_cl_ord_id = message.get(tags::ORIG_CL_ORD_ID).unwrap_or_default();
+        let reason = if self.cl_ord_ids
+            .contains_key(&(session.to_string(), cl_ord_id.to_string()))
+        {
+            (CXL_DUPLICATE_CL_ORD_ID, "Duplicate ClOrdID")
+        } else {
+            match self.cl_ord_ids
+                .get(&(session.to_string(), orig_cl_ord_id.to_string()))
+            {
+                Some(&id) if self.orders[&id].pending.is_some() => {
+                    (OTHER, "A cancel or replace is already pending")
+                }
+                Some(&id) => return Some(id),
+                None => (CXL_UNKNOWN_ORDER, "Unknown order"),
+            }
+        };
+        let reject = Message::new("9")
+            .with(tags::ORDER_ID, "NONE")
+            .with(tags::CL_ORD_ID, cl_ord_id)
+            .with(tags::ORIG_CL_ORD_ID, orig_cl_ord_id)
+            .with(tags::ORD_STATUS, "8")
+            .with(tags::CXL_REJ_RESPONSE_TO, response_to(kind))
+            .with(tags::CXL_REJ_REASON, reason.0)
+            .with(tags::TEXT, reason.1);
+        outbound.push((session.to_string(), reject));
+        None
+    }
+
+    fn set_pending(&mut self, id: u64, kind: RequestKind, cl_ord_id: &str) {
+        if let Some(order) = self.orders.get_mut(&id) {
+            order.pending = Some(PendingRequest {
+                kind,
+                cl_ord_id: cl_ord_id.to_string(),
+            });
+        }
+    }
+
+    /// Answers a pending cancel or replace the engine could not act on.
+    fn cancel_failed(&mut self, id: u64, text: &str, outbound: &mut Vec<Outbound>) {
+        if let Some(order) = self.orders.get_mut(&id) {
+            if let Some(pending) = order.pending.take() {
+                let reject = cancel_reject(id, order, &pending, OTHER, text);
+                outbound.push((order.session.clone(), reject));
+            }
+        }
+    }
+
+    /// The message for an execution report on one of our orders, with the session it
+    /// goes to. Orders that are done are forgotten.
+    fn report(&mut self, report: &ExecutionReport, now: DateTime<Utc>) -> Option<Outbound> {
+        let id = report.order_id();
+        let mut order = self.orders.remove(&id)?;
+        let mut orig_cl_ord_id = None;
+        match (report.exec_type(), order.pending.take()) {
+            (ExecType::Rejected(reason), Some(pending)) => {
+                let cxl_rej_reason = match reason {
+                    RejectReason::UnknownOrder => CXL_UNKNOWN_ORDER,
+                    _ => OTHER,
+                };
+                let text = format!("{:?}", reason);
+                let reject = cancel_reject(id, &order, &pending, cxl_rej_reason, &text);
+                let session = order.session.clone();
+                self.orders.insert(id, order);
+                return Some((session, reject));
+            }
+            (ExecType::Cancelled, Some(pending)) | (ExecType::Replaced, Some(pending)) => {
+                if let RequestKind::Replace { order_qty } = pending.kind {
+                    order.order_qty = order_qty;
+                }
+                let old_key = (order.session.clone(), order.cl_ord_id.clone());
+                self.cl_ord_ids.remove(&old_key);
+                orig_cl_ord_id = Some(mem::replace(&mut order.cl_ord_id, pending.cl_ord_id));
+                self.cl_ord_ids
+                    .insert((order.session.clone(), order.cl_ord_id.clone()), id);
+            }
+            (_, pending) => order.pending = pending,
+        }
+        if report.exec_type() == ExecType::Restated {
+            order.order_qty = report.cum_qty() + report.leaves_qty();
+        }
+
+        let message = self.execution_report(&order, orig_cl_ord_id, report, now);
+        let session = order.session.clone();
+        match report.exec_type() {
+            ExecType::Fill | ExecType::Cancelled | ExecType::Expired | ExecType::Rejected(_) => {
+                self.cl_ord_ids.remove(&(order.session, order.cl_ord_id));
+            }
+            _ => {
+                self.orders.insert(id, order);
+            }
+        }
+        Some((session, message))
+    }
+
+    fn execution_report(
+        &mut self,
+        order: &Order,
+        orig_cl_ord_id: Option<String>,
+        report: &ExecutionReport,
+        now: DateTime<Utc>,
+    ) -> Message {
+        let exec_id = self.next_exec_id;
+        self.next_exec_id += 1;
+        let mut message = Message::new("8")
+            .with(tags::ORDER_ID, report.order_id())
+            .with(tags::CL_ORD_ID, &order.cl_ord_id);
+        if let Some(orig_cl_ord_id) = orig_cl_ord_id {
+            message.set(tags::ORIG_CL_ORD_ID, orig_cl_ord_id);
+        }
+        message.set(tags::EXEC_ID, exec_id);
+        message.set(tags::EXEC_TYPE, exec_type(report.exec_type()));
+        message.set(tags::ORD_STATUS, ord_status(report));
+        message.set(tags::SYMBOL, &order.symbol);
+        message.set(tags::SIDE, side_code(order.side));
+        message.set(tags::ORDER_QTY, order.order_qty);
+        if !report.price().is_zero() {
+            message.set(tags::PRICE, report.price());
+        }
+        if report.last_qty() > 0 {
+            message.set(tags::LAST_QTY, report.last_qty());
+            message.set(tags::LAST_PX, report.last_price());
+        }
+        message.set(tags::LEAVES_QTY, report.leaves_qty());
+        message.set(tags::CUM_QTY, report.cum_qty());
+        message.set(tags::AVG_PX, report.avg_price());
+        match report.exec_type() {
+            ExecType::Rejected(reason) => {
+                message.set(tags::ORD_REJ_REASON, ord_rej_reason(reason));
+                message.set(tags::TEXT, format!("{:?}", reason));
+            }
+            ExecType::Triggered => message.set(tags::TEXT, "Triggered"),
+            _ => {}
+        }
+        message.set(tags::TRANSACT_TIME, format_timestamp(now));
+        message
+    }
+
+    /// An ExecutionReport rejecting a new order the engine never saw.
+    fn rejected(
+        &mut self,
+        message: &Message,
+        reason: u32,
+        text: &str,
+        now: DateTime<Utc>,
+    ) -> Message {
+        let exec_id = self.next_exec_id;
+        self.next_exec_id += 1;
+        let mut reject = Message::new("8")
+            .with(tags::ORDER_ID, "NONE")
+            .with(tags::CL_ORD_ID, message.get(tags::CL_ORD_ID).unwrap_or_default())
+            .with(tags::EXEC_ID, exec_id)
+            .with(tags::EXEC_TYPE, "8")
+            .with(tags::ORD_STATUS, "8");
+        for tag in &[tags::SYMBOL, tags::SIDE, tags::ORDER_QTY] {
+            if let Some(value) = message.get(*tag) {
+                reject.set(*tag, value);
+            }
+        }
+        reject
+            .with(tags::LEAVES_QTY, 0)
+            .with(tags::CUM_QTY, 0)
+            .with(tags::AVG_PX, 0)
+            .with(tags::ORD_REJ_REASON, reason)
+            .with(tags::TEXT, text)
+            .with(tags::TRANSACT_TIME, format_timestamp(now))
+    }
+
+    fn unsupported(
+        &mut self,
+        session: &str,
+        message: &Message,
+        now: DateTime<Utc>,
+        outbound: &mut Vec<Outbound>,
+    ) -> Result<(), InvalidField> {
+        let text = "Unsupported combination of order type and time in force";
+        let reject = self.rejected(message, UNSUPPORTED_ORDER_CHARACTERISTIC, text, now);
+        outbound.push((session.to_string(), reject));
+        Ok(())
+    }
+
+    fn forget(&mut self, id: u64) {
+        if let Some(order) = self.orders.remove(&id) {
+            self.cl_ord_ids.remove(&(order.session, order.cl_ord_id));
+        }
+    }
+}
+
+fn cancel_reject(
+    id: u64,
+    order: &Order,
+    pending: &PendingRequest,
+    reason: u32,
+    text: &str,
+) -> Message {
+    Message::new("9")
+        .with(tags::ORDER_ID, id)
+        .with(tags::CL_ORD_ID, &pending.cl_ord_id)
+        .with(tags::ORIG_CL_ORD_ID, &order.cl_ord_id)
+        .with(tags::ORD_STATUS, "0")
+        .with(tags::CXL_REJ_RESPONSE_TO, response_to(pending.kind))
+        .with(tags::CXL_REJ_REASON, reason)
+        .with(tags::TEXT, text)
+}
+
+fn response_to(kind: RequestKind) -> &'static str {
+    match kind {
+        RequestKind::Cancel => "1",
+        RequestKind::Replace { .. } => "2",
+    }
+}
+
+fn exec_type(exec_type: ExecType) -> &'static str {
+    match exec_type {
+        ExecType::New => "0",
+        ExecType::PartialFill | ExecType::Fill => "F",
+        ExecType::Cancelled => "4",
+        ExecType::Replaced => "5",
+        ExecType::Expired => "C",
+        // FIX 4.4 has no exec type for a triggered stop, it is reported as a restatement
+        ExecType::Triggered | ExecType::Restated => "D",
+        ExecType::Rejected(_) => "8",
+    }
+}
+
+fn ord_status(report: &ExecutionReport) -> &'static str {
+    match report.exec_type() {
+        ExecType::Fill => "2",
+        ExecType::Cancelled => "4",
+        ExecType::Expired => "C",
+        ExecType::Rejected(_) => "8",
+        _ if report.cum_qty() > 0 => "1",
+        _ => "0",
+    }
+}
+
+fn ord_rej_reason(reason: RejectReason) -> u32 {
+    match reason {
+        RejectReason::UnknownOrder => UNKNOWN_ORDER,
+        RejectReason::DuplicateOrderId => DUPLICATE_ORDER,
+        RejectReason::InvalidQuantity
+        | RejectReason::QtyNotOnLot
+        | RejectReason::QtyBelowMinimum
+        | RejectReason::QtyAboveMaximum => INCORRECT_QUANTITY,
+        RejectReason::PriceOutsideBand => PRICE_OUTSIDE_BAND,
+        RejectReason::NotAcceptedInPhase => EXCHANGE_CLOSED,
+        RejectReason::Risk(_) => ORDER_EXCEEDS_LIMIT,
+        _ => OTHER,
+    }
+}
+
+fn side_code(side: Side) -> &'static str {
+    match side {
+        Side::Bid => "1",
+        Side::Ask => "2",
+    }
+}
+
+fn invalid(tag: u32) -> InvalidField {
+    InvalidField {
+        tag,
+        missing: false,
+    }
+}
+
+fn required(message: &Message, tag: u32) -> Result<&str, InvalidField> {
+    message.get(tag).ok_or(InvalidField { tag, missing: true })
+}
+
+fn parse_side(message: &Message) -> Result<Side, InvalidField> {
+    match required(message, tags::SIDE)? {
+        "1" => Ok(Side::Bid),
+        "2" => Ok(Side::Ask),
+        _ => Err(invalid(tags::SIDE)),
+    }
+}
+
+/// Parses a whole quantity, allowing a zero fraction such as "100.00".
+fn parse_qty(message: &Message, tag: u32) -> Result<u64, InvalidField> {
+    let value = required(message, tag)?;
+    let whole = match value.find('.') {
+        Some(pos) if value[pos + 1..].chars().all(|c| c == '0') => &value[..pos],
+        Some(_) => return Err(invalid(tag)),
+        None => value,
+    };
+    whole.parse().map_err(|_| invalid(tag))
+}
+
+fn parse_price(message: &Message, tag: u32, price_scale: u8) -> Result<Price, InvalidField> {
+    Price::parse(required(message, tag)?, price_scale).map_err(|_| invalid(tag))
+}
+
+/// The time in force of a new order, None when the message has none.
+fn parse_tif(message: &Message) -> Result<Option<TimeInForce>, InvalidField> {
+    let tif = match message.get(tags::TIME_IN_FORCE) {
+        None => return Ok(None),
+        Some("0") => TimeInForce::Day,
+        Some("1") => TimeInForce::GoodTillCancel,
+        Some("3") => TimeInForce::ImmediateOrCancel,
+        Some("4") => TimeInForce::FillOrKill,
+        Some("6") => {
+            let expire_time = required(message, tags::EXPIRE_TIME)?;
+            match parse_timestamp(expire_time) {
+                Ok(expire_at) => TimeInForce::GoodTillDate(expire_at),
+                Err(_) => return Err(invalid(tags::EXPIRE_TIME)),
+            }
+        }
+        Some(_) => return Err(invalid(tags::TIME_IN_FORCE)),
+    };
+    Ok(Some(tif))
+}
@@ -0,0 +1,762 @@
+//! FIX session layer: logon, heartbeats, sequence numbers and message recovery.
+//!
+//! A session outlives its TCP connections. Sequence numbers carry over a reconnect unless
+//! the counterparty logs on with ResetSeqNumFlag, and application messages sent while it
+//! was away are kept so that a resend request can recover them. Only the last
+//! `RESEND_WINDOW` application messages are kept, older ones are resent as a gap fill.
+
+use super::message::{format_timestamp, tags, Message};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+
+pub const BEGIN_STRING: &str = "FIX.4.4";
+
+/// Session reject reasons (tag 373) used by the gateway.
+pub const REQUIRED_TAG_MISSING: u32 = 1;
+pub const VALUE_IS_INCORRECT: u32 = 5;
+pub const COMP_ID_PROBLEM: u32 = 9;
+
+/// Application messages kept for resend requests.
+pub const RESEND_WINDOW: usize = 10_000;
+
+// Longest HeartBtInt accepted at logon, in seconds. Zero turns heartbeats off.
+const MAX_HEART_BT_INT: u64 = 3_600;
+
+// Highest sequence number a SequenceReset may move to
+const MAX_SEQ_NUM: u64 = ::std::u32::MAX as u64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionConfig {
+    sender_comp_id: String,
+    target_comp_id: String,
+    owner: u64,
+}
+
+impl SessionConfig {
+    /// A session in which we are `sender_comp_id` and the counterparty logs on as
+    /// `target_comp_id`.
+    pub fn new(sender_comp_id: &str, target_comp_id: &str) -> Self {
+        Self {
+            sender_comp_id: sender_comp_id.to_string(),
+            target_comp_id: target_comp_id.to_string(),
+            owner: 0,
+        }
+    }
+
+    /// Sets the owner given to the orders entered over the session, for self-trade
+    /// prevention.
+    pub fn with_owner(mut self, owner: u64) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    pub fn sender_comp_id(&self) -> &str {
+        &self.sender_comp_id
+    }
+
+    pub fn target_comp_id(&self) -> &str {
+        &self.target_comp_id
+    }
+
+    pub fn owner(&self) -> u64 {
+        self.owner
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum SessionState {
+    Disconnected,
+    /// Connected, waiting for the counterparty's Logon
+    AwaitingLogon,
+    Active,
+    /// Logged out, the connection closes once the pending messages are written
+    LoggedOut,
+}
+
+#[derive(Debug)]
+pub struct Session {
+    config: SessionConfig,
+    state: SessionState,
+    next_out_seq: u64,
+    next_in_seq: u64,
+    heartbeat: Duration,
+    last_sent: DateTime<Utc>,
+    last_received: DateTime<Utc>,
+    // When a test request went out without an answer yet
+    test_request: Option<DateTime<Utc>>,
+    // Application messages by sequence number with their original sending time
+    sent: BTreeMap<u64, (DateTime<Utc>, Message)>,
+    // Messages received ahead of a gap, waiting for the resend to fill it
+    queued: BTreeMap<u64, Message>,
+    resend_requested: bool,
+    outbound: Vec<Vec<u8>>,
+}
+
+impl Session {
+    pub fn new(config: SessionConfig) -> Self {
+        Self {
+            config,
+            state: SessionState::Disconnected,
+            next_out_seq: 1,
+            next_in_seq: 1,
+            heartbeat: Duration::seconds(30),
+            last_sent: Utc::now(),
+            last_received: Utc::now(),
+            test_request: None,
+            sent: BTreeMap::new(),
+            queued: BTreeMap::new(),
+            resend_requested: false,
+            outbound: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Sequence number of the next message we send.
+    pub fn next_out_seq(&self) -> u64 {
+        self.next_out_seq
+    }
+
+    /// Sequence number expected on the next message received.
+    pub fn next_in_seq(&self) -> u64 {
+        self.next_in_seq
+    }
+
+    /// A connection came up for the session, the counterparty has to log on next.
+    pub fn connect(&mut self, now: DateTime<Utc>) {
+        self.state = SessionState::AwaitingLogon;
+        self.last_sent = now;
+        self.last_received = now;
+        self.test_request = None;
+        self.queued.clear();
+        self.resend_requested = false;
+    }
+
+    /// The connection went away. Messages sent from now on are kept for a resend.
+    pub fn disconnect(&mut self) {
+        if self.state != SessionState::Disconnected {
+            info!("Session {} disconnected", self.config.target_comp_id);
+        }
+        self.state = SessionState::Disconnected;
+        self.outbound.clear();
+    }
+
+    /// Handles a message from the counterparty, returning the application messages that
+    /// are ready to be processed, in sequence order.
+    pub fn receive(&mut self, message: Message, now: DateTime<Utc>) -> Vec<Message> {
+        let mut delivered = Vec::new();
+        if self.state != SessionState::AwaitingLogon && self.state != SessionState::Active {
+            return delivered;
+        }
+        self.last_received = now;
+        self.test_request = None;
+
+        if message.get(tags::SENDER_COMP_ID) != Some(self.config.target_comp_id.as_str())
+            || message.get(tags::TARGET_COMP_ID) != Some(self.config.sender_comp_id.as_str())
+        {
+            let reject = reject(&message, COMP_ID_PROBLEM, None, "CompID problem");
+            self.send(reject, now);
+            self.logout("Incorrect SenderCompID or TargetCompID", now);
+            return delivered;
+        }
+        let seq = match message.seq_num() {
+            Some(seq) => seq,
+            None => {
+                self.logout("MsgSeqNum missing or invalid", now);
+                return delivered;
+            }
+        };
+
+        if self.state == SessionState::AwaitingLogon {
+            if message.msg_type() != "A" {
+                self.logout("First message is not a Logon", now);
+            } else {
+                self.logon(&message, seq, now);
+            }
+            return delivered;
+        }
+
+        // Reset mode moves the sequence number without regard to the one on the message
+        if message.msg_type() == "4" && !message.get_bool(tags::GAP_FILL_FLAG) {
+            self.sequence_reset(&message, now);
+            self.process_queued(&mut delivered, now);
+            return delivered;
+        }
+        if seq < self.next_in_seq {
+            if !message.get_bool(tags::POSS_DUP_FLAG) {
+                let text = format!(
+                    "MsgSeqNum too low, expecting {} but received {}",
+                    self.next_in_seq, seq
+                );
+                self.logout(&text, now);
+            }
+            return delivered;
+        }
+        if seq > self.next_in_seq {
+            if message.msg_type() == "2" {
+                // Answered at once, if both sides have a gap neither would get its resend.
+                // A heartbeat stands in for it to use up the sequence number.
+                self.on_resend_request(&message, now);
+                self.queued.insert(seq, Message::new("0"));
+            } else {
+                self.queued.insert(seq, message);
+            }
+            self.request_resend(now);
+            return delivered;
+        }
+
+        self.process(message, &mut delivered, now);
+        self.process_queued(&mut delivered, now);
+        delivered
+    }
+
+    /// Sends a message, filling in the standard header. Application messages sent while
+    /// the counterparty is not logged on are only kept for a resend.
+    pub fn send(&mut self, message: Message, now: DateTime<Utc>) {
+        let seq = self.next_out_seq;
+        self.next_out_seq += 1;
+        if !message.is_admin() {
+            self.sent.insert(seq, (now, message.clone()));
+            if self.sent.len() > RESEND_WINDOW {
+                let oldest = *self.sent.keys().next().unwrap();
+                self.sent.remove(&oldest);
+            }
+        }
+        let transmit = match self.state {
+            SessionState::Active => true,
+            SessionState::AwaitingLogon => message.is_admin(),
+            SessionState::Disconnected | SessionState::LoggedOut => false,
+        };
+        if transmit {
+            self.transmit(&message, seq, now, None);
+        }
+    }
+
+    /// Ends the session with a Logout. The connection should be closed once the pending
+    /// messages are written.
+    pub fn logout(&mut self, text: &str, now: DateTime<Utc>) {
+        if self.state == SessionState::Disconnected || self.state == SessionState::LoggedOut {
+            return;
+        }
+        info!("Logging out {}: {}", self.config.target_comp_id, text);
+        self.send(Message::new("5").with(tags::TEXT, text), now);
+        self.state = SessionState::LoggedOut;
+    }
+
+    /// Sends heartbeats and test requests when the connection has been quiet, and logs out
+    /// a counterparty that does not answer a test request in time.
+    pub fn on_timer(&mut self, now: DateTime<Utc>) {
+        if self.state != SessionState::Active || self.heartbeat <= Duration::zero() {
+            return;
+        }
+        if now.signed_duration_since(self.last_sent) >= self.heartbeat {
+            self.send(Message::new("0"), now);
+        }
+        match self.test_request {
+            Some(sent) if now.signed_duration_since(sent) >= self.heartbeat => {
+                warn!("{} did not answer a test request", self.config.target_comp_id);
+                self.logout("Test request not answered", now);
+            }
+            Some(_) => {}
+            None => {
+                let quiet = now.signed_duration_since(self.last_received);
+                if quiet >= self.heartbeat + self.heartbeat / 5 {
+                    let test_req_id = format!("TEST{}", self.next_out_seq);
+                    self.send(Message::new("1").with(tags::TEST_REQ_ID, test_req_id), now);
+                    self.test_request = Some(now);
+                }
+            }
+        }
+    }
+
+    /// Takes the encoded messages waiting to be written to the connection.
+    pub fn drain_outbound(&mut self) -> Vec<Vec<u8>> {
+        self.outbound.drain(..).collect()
+    }
+
+    fn transmit(
+        &mut self,
+        message: &Message,
+        seq: u64,
+        now: DateTime<Utc>,
+        orig_sending_time: Option<DateTime<Utc>>,
+    ) {
+        let mut stamped = Message::new(message.msg_type())
+            .with(tags::SENDER_COMP_ID, &self.config.sender_comp_id)
+            .with(tags::TARGET_COMP_ID, &self.config.target_comp_id)
+            .with(tags::MSG_SEQ_NUM, seq);
+        if orig_sending_time.is_some() {
+            stamped.set(tags::POSS_DUP_FLAG, "Y");
+        }
+        stamped.set(tags::SENDING_TIME, format_timestamp(now));
+        if let Some(orig_sending_time) = orig_sending_time {
+            stamped.set(tags::ORIG_SENDING_TIME, format_timestamp(orig_sending_time));
+        }
+        for &(tag, ref value) in message.fields() {
+            stamped.set(tag, value);
+        }
+        self.outbound.push(stamped.encode(BEGIN_STRING));
+        self.last_sent = now;
+    }
+
+    fn logon(&mut self, message: &Message, seq: u64, now: DateTime<Utc>) {
+        let heart_bt_int = match message.get_u64(tags::HEART_BT_INT) {
+            Ok(Some(heart_bt_int)) if heart_bt_int <= MAX_HEART_BT_INT => heart_bt_int,
+            _ => {
+                self.logout("HeartBtInt missing or invalid", now);
+                return;
+            }
+        };
+        let reset = message.get_bool(tags::RESET_SEQ_NUM_FLAG);
+        if reset {
+            self.next_in_seq = 1;
+            self.next_out_seq = 1;
+            self.sent.clear();
+        }
+        if seq < self.next_in_seq {
+            let text = format!(
+                "MsgSeqNum too low, expecting {} but received {}",
+                self.next_in_seq, seq
+            );
+            self.logout(&text, now);
+            return;
+        }
+
+        info!("{} logged on", self.config.target_comp_id);
+        self.heartbeat = Duration::seconds(heart_bt_int as i64);
+        self.state = SessionState::Active;
+        let mut reply = Message::new("A")
+            .with(tags::ENCRYPT_METHOD, 0)
+            .with(tags::HEART_BT_INT, heart_bt_int);
+        if reset {
+            reply.set(tags::RESET_SEQ_NUM_FLAG, "Y");
+        }
+        self.send(reply, now);
+        if seq == self.next_in_seq {
+            self.next_in_seq += 1;
+        } else {
+            self.request_resend(now);
+        }
+    }
+
+    fn process(&mut self, message: Message, delivered: &mut Vec<Message>, now: DateTime<Utc>) {
+        match message.msg_type() {
+            "0" | "A" => {}
+            "1" => {
+                let mut heartbeat = Message::new("0");
+                if let Some(test_req_id) = message.get(tags::TEST_REQ_ID) {
+                    heartbeat.set(tags::TEST_REQ_ID, test_req_id);
+                }
+                self.send(heartbeat, now);
+            }
+            "2" => self.on_resend_request(&message, now),
+            "3" => warn!(
+                "{} rejected message {:?}: {:?}",
+                self.config.target_comp_id,
+                message.get(tags::REF_SEQ_NUM),
+                message.get(tags::TEXT)
+            ),
+            "4" => {
+                // Gap fill, the sequence number moves instead of being incremented
+                self.sequence_reset(&message, now);
+                return;
+            }
+            "5" => {
+                if self.state == SessionState::Active {
+                    self.send(Message::new("5"), now);
+                    self.state = SessionState::LoggedOut;
+                }
+            }
+            _ => delivered.push(message),
+        }
+        self.next_in_seq += 1;
+    }
+
+    /// Handles messages that were waiting for a gap to be filled.
+    fn process_queued(&mut self, delivered: &mut Vec<Message>, now: DateTime<Utc>) {
+        while let Some(message) = self.queued.remove(&self.next_in_seq) {
+            self.process(message, delivered, now);
+        }
+        self.queued = self.queued.split_off(&self.next_in_seq);
+        if self.queued.is_empty() {
+            self.resend_requested = false;
+        }
+    }
+
+    fn sequence_reset(&mut self, message: &Message, now: DateTime<Utc>) {
+        match message.get_u64(tags::NEW_SEQ_NO) {
+            Ok(Some(new_seq)) if new_seq > MAX_SEQ_NUM => {
+                let reject = reject(
+                    message,
+                    VALUE_IS_INCORRECT,
+                    Some(tags::NEW_SEQ_NO),
+                    "NewSeqNo is too high",
+                );
+                self.send(reject, now);
+            }
+            Ok(Some(new_seq)) if new_seq >= self.next_in_seq => self.next_in_seq = new_seq,
+            Ok(Some(_)) => {
+                let reject = reject(
+                    message,
+                    VALUE_IS_INCORRECT,
+                    Some(tags::NEW_SEQ_NO),
+                    "NewSeqNo would move the sequence number back",
+                );
+                self.send(reject, now);
+            }
+            _ => {
+                let reject = reject(
+                    message,
+                    REQUIRED_TAG_MISSING,
+                    Some(tags::NEW_SEQ_NO),
+                    "NewSeqNo is required",
+                );
+                self.send(reject, now);
+            }
+        }
+    }
+
+    fn on_resend_request(&mut self, message: &Message, now: DateTime<Utc>) {
+        let begin = message.get_u64(tags::BEGIN_SEQ_NO);
+        let end = message.get_u64(tags::END_SEQ_NO);
+        match (begin, end) {
+            (Ok(Some(begin)), Ok(Some(end))) => self.resend(begin, end, now),
+            _ => {
+                let reject = reject(
+                    message,
+                    REQUIRED_TAG_MISSING,
+                    None,
+                    "BeginSeqNo and EndSeqNo are required",
+                );
+                self.send(reject, now);
+            }
+        }
+    }
+
+    fn request_resend(&mut self, now: DateTime<Utc>) {
+        if self.resend_requested {
+            return;
+        }
+        info!(
+            "Requesting resend from {} starting at {}",
+            self.config.target_comp_id, self.next_in_seq
+        );
+        let request = Message::new("2")
+            .with(tags::BEGIN_SEQ_NO, self.next_in_seq)
+            .with(tags::END_SEQ_NO, 0);
+        self.send(request, now);
+        self.resend_requested = true;
+    }
+
+    /// Sends the application messages in `begin..=end` again, with gap fills in place of
+    /// session messages. An `end` of zero means up to the last message sent.
+    fn resend(&mut self, begin: u64, end: u64, now: DateTime<Utc>) {
+        let last = self.next_out_seq - 1;
+        let end = if end == 0 || end > last { last } else { end };
+        info!(
+            "Resending {}..{} to {}",
+            begin, end, self.config.target_comp_id
+        );
+        let mut gap_start = None;
+        for seq in begin.max(1)..=end {
+            match self.sent.get(&seq).cloned() {
+                Some((orig_sending_time, message)) => {
+                    if let Some(start) = gap_start.take() {
+                        self.gap_fill(start, seq, now);
+                    }
+                    self.transmit(&message, seq, now, Some(orig_sending_time));
+                }
+                None => if gap_start.is_none() {
+                    gap_start = Some(seq);
+                },
+            }
+        }
+        if let Some(start) = gap_start {
+            self.gap_fill(start, end + 1, now);
+        }
+    }
+
+    fn gap_fill(&mut self, seq: u64, new_seq: u64, now: DateTime<Utc>) {
+        let gap_fill = Message::new("4")
+            .with(tags::GAP_FILL_FLAG, "Y")
+            .with(tags::NEW_SEQ_NO, new_seq);
+        self.transmit(&gap_fill, seq, now, Some(now));
+    }
+}
+
+/// A session level Reject of `message`.
+pub fn reject(message: &Message, reason: u32, tag: Option<u32>, text: &str) -> Message {
+    let mut reject = Message::new("3");
+    if let Some(seq) = message.seq_num() {
+        reject.set(tags::REF_SEQ_NUM, seq);
+    }
+    if let Some(tag) = tag {
+        reject.set(tags::REF_TAG_ID, tag);
+    }
+    reject
+        .with(tags::REF_MSG_TYPE, message.msg_type())
+        .with(tags::SESSION_REJECT_REASON, reason)
+        .with(tags::TEXT, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn start() -> DateTime<Utc> {
+        Utc.ymd(2018, 4, 2).and_hms(9, 0, 0)
+    }
+
+    fn from_client(msg_type: &str, seq: u64) -> Message {
+        Message::new(msg_type)
+            .with(tags::SENDER_COMP_ID, "CLIENT")
+            .with(tags::TARGET_COMP_ID, "EXCH")
+            .with(tags::MSG_SEQ_NUM, seq)
+    }
+
+    fn sent(session: &mut Session) -> Vec<Message> {
+        session
+            .drain_outbound()
+            .iter()
+            .map(|data| Message::decode(data).unwrap().unwrap().0)
+            .collect()
+    }
+
+    fn logged_on() -> Session {
+        let mut session = Session::new(SessionConfig::new("EXCH", "CLIENT"));
+        session.connect(start());
+        let logon = from_client("A", 1)
+            .with(tags::ENCRYPT_METHOD, 0)
+            .with(tags::HEART_BT_INT, 30)
+            .with(tags::RESET_SEQ_NUM_FLAG, "Y");
+        assert!(session.receive(logon, start()).is_empty());
+        assert_eq!(session.state(), SessionState::Active);
+        let replies = sent(&mut session);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].msg_type(), "A");
+        assert_eq!(replies[0].get(tags::MSG_SEQ_NUM), Some("1"));
+        assert_eq!(replies[0].get(tags::RESET_SEQ_NUM_FLAG), Some("Y"));
+        session
+    }
+
+    #[test]
+    fn logon_is_required_first() {
+        let mut session = Session::new(SessionConfig::new("EXCH", "CLIENT"));
+        session.connect(start());
+        assert!(session.receive(from_client("D", 1), start()).is_empty());
+        assert_eq!(session.state(), SessionState::LoggedOut);
+        assert_eq!(sent(&mut session)[0].msg_type(), "5");
+
+        let mut session = Session::new(SessionConfig::new("EXCH", "CLIENT"));
+        session.connect(start());
+        let logon = from_client("A", 1)
+            .with(tags::HEART_BT_INT, 30)
+            .with(tags::SENDER_COMP_ID, "OTHER");
+        session.receive(logon, start());
+        let replies = sent(&mut session);
+        assert_eq!(replies[0].msg_type(), "3");
+        assert_eq!(
+            replies[0].get(tags::SESSION_REJECT_REASON),
+            Some(COMP_ID_PROBLEM.to_string().as_str())
+        );
+        assert_eq!(replies[1].msg_type(), "5");
+
+        let mut session = Session::new(SessionConfig::new("EXCH", "CLIENT"));
+        session.connect(start());
+        let logon = from_client("A", 1).with(tags::HEART_BT_INT, u64::max_value());
+        session.receive(logon, start());
+        assert_eq!(session.state(), SessionState::LoggedOut);
+        assert_eq!(sent(&mut session)[0].msg_type(), "5");
+    }
+
+    #[test]
+    fn delivers_application_messages_in_order() {
+        let mut session = logged_on();
+        let delivered = session.receive(from_client("D", 2), start());
+        assert_eq!(delivered.len(), 1);
+
+        // 3 is missing, 4 waits for it
+        assert!(session.receive(from_client("D", 4), start()).is_empty());
+        let request = sent(&mut session);
+        assert_eq!(request.len(), 1);
+        assert_eq!(request[0].msg_type(), "2");
+        assert_eq!(request[0].get(tags::BEGIN_SEQ_NO), Some("3"));
+        assert_eq!(request[0].get(tags::END_SEQ_NO), Some("0"));
+        assert!(session.receive(from_client("F", 5), start()).is_empty());
+        assert!(sent(&mut session).is_empty());
+
+        let resent = from_client("D", 3).with(tags::POSS_DUP_FLAG, "Y");
+        let delivered = session.receive(resent, start());
+        let seqs: Vec<Option<u64>> = delivered.iter().map(|m| m.seq_num()).collect();
+        assert_eq!(seqs, vec![Some(3), Some(4), Some(5)]);
+        assert_eq!(session.next_in_seq(), 6);
+
+        // A duplicate is ignored, a lower number without PossDupFlag ends the session
+        let duplicate = from_client("D", 4).with(tags::POSS_DUP_FLAG, "Y");
+        assert!(session.receive(duplicate, start()).is_empty());
+        assert_eq!(session.state(), SessionState::Active);
+        assert!(session.receive(from_client("D", 4), start()).is_empty());
+        assert_eq!(session.state(), SessionState::LoggedOut);
+    }
+
+    #[test]
+    fn gap_fill_moves_the_sequence_number() {
+        let mut session = logged_on();
+        let gap_fill = from_client("4", 2)
+            .with(tags::GAP_FILL_FLAG, "Y")
+            .with(tags::NEW_SEQ_NO, 10);
+        session.receive(gap_fill, start());
+        assert_eq!(session.next_in_seq(), 10);
+        assert_eq!(session.receive(from_client("D", 10), start()).len(), 1);
+
+        let reset = from_client("4", 1).with(tags::NEW_SEQ_NO, 20);
+        session.receive(reset, start());
+        assert_eq!(session.next_in_seq(), 20);
+
+        let reset = from_client("4", 20).with(tags::NEW_SEQ_NO, u64::max_value());
+        session.receive(reset, start());
+        assert_eq!(session.next_in_seq(), 20);
+        assert_eq!(sent(&mut session).last().unwrap().msg_type(), "3");
+    }
+
+    #[test]
+    fn resends_application_messages_and_fills_gaps() {
+        let mut session = logged_on();
+        session.send(Message::new("8").with(tags::CL_ORD_ID, "A"), start());
+        session.send(Message::new("0"), start());
+        session.send(Message::new("8").with(tags::CL_ORD_ID, "B"), start());
+        sent(&mut session);
+
+        let request = from_client("2", 2)
+            .with(tags::BEGIN_SEQ_NO, 1)
+            .with(tags::END_SEQ_NO, 0);
+        session.receive(request, start() + Duration::seconds(1));
+        let resent: Vec<(String, Option<String>, Option<String>)> = sent(&mut session)
+            .iter()
+            .map(|m| {
+                (
+                    m.msg_type().to_string(),
+                    m.get(tags::MSG_SEQ_NUM).map(|s| s.to_string()),
+                    m.get(tags::NEW_SEQ_NO).map(|s| s.to_string()),
+                )
+            })
+            .collect();
+        let entry = |msg_type: &str, seq: &str, new_seq: Option<&str>| {
+            (
+                msg_type.to_string(),
+                Some(seq.to_string()),
+                new_seq.map(|s| s.to_string()),
+            )
+        };
+        assert_eq!(
+            resent,
+            vec![
+                entry("4", "1", Some("2")),
+                entry("8", "2", None),
+                entry("4", "3", Some("4")),
+                entry("8", "4", None),
+            ]
+        );
+        assert_eq!(session.next_out_seq(), 5);
+    }
+
+    #[test]
+    fn keeps_a_bounded_resend_window() {
+        let mut session = logged_on();
+        for _ in 0..RESEND_WINDOW + 1 {
+            session.send(Message::new("8"), start());
+        }
+        sent(&mut session);
+
+        let request = from_client("2", 2)
+            .with(tags::BEGIN_SEQ_NO, 2)
+            .with(tags::END_SEQ_NO, 3);
+        session.receive(request, start());
+        let resent = sent(&mut session);
+        assert_eq!(resent.len(), 2);
+        assert_eq!(resent[0].msg_type(), "4");
+        assert_eq!(resent[0].get(tags::NEW_SEQ_NO), Some("3"));
+        assert_eq!(resent[1].msg_type(), "8");
+    }
+
+    #[test]
+    fn answers_resend_request_across_a_gap() {
+        let mut session = logged_on();
+        session.send(Message::new("8").with(tags::CL_ORD_ID, "A"), start());
+        sent(&mut session);
+
+        // Both sides lost a message: the client asks for our 2 after its own 2 went missing
+        let request = from_client("2", 3)
+            .with(tags::BEGIN_SEQ_NO, 2)
+            .with(tags::END_SEQ_NO, 0);
+        assert!(session.receive(request, start()).is_empty());
+        let replies = sent(&mut session);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].get(tags::CL_ORD_ID), Some("A"));
+        assert_eq!(replies[0].get(tags::POSS_DUP_FLAG), Some("Y"));
+        assert_eq!(replies[1].msg_type(), "2");
+        assert_eq!(replies[1].get(tags::BEGIN_SEQ_NO), Some("2"));
+
+        // Filling our gap does not answer the request a second time
+        let resent = from_client("D", 2).with(tags::POSS_DUP_FLAG, "Y");
+        assert_eq!(session.receive(resent, start()).len(), 1);
+        assert_eq!(session.next_in_seq(), 4);
+        assert!(sent(&mut session).is_empty());
+    }
+
+    #[test]
+    fn heartbeats_and_test_requests() {
+        let mut session = logged_on();
+        let test_request = from_client("1", 2).with(tags::TEST_REQ_ID, "T1");
+        session.receive(test_request, start());
+        let replies = sent(&mut session);
+        assert_eq!(replies[0].msg_type(), "0");
+        assert_eq!(replies[0].get(tags::TEST_REQ_ID), Some("T1"));
+
+        session.on_timer(start() + Duration::seconds(29));
+        assert!(sent(&mut session).is_empty());
+        session.on_timer(start() + Duration::seconds(30));
+        assert_eq!(sent(&mut session)[0].msg_type(), "0");
+
+        // Nothing received for the interval and a bit more
+        session.on_timer(start() + Duration::seconds(36));
+        assert_eq!(sent(&mut session)[0].msg_type(), "1");
+        session.on_timer(start() + Duration::seconds(50));
+        assert!(sent(&mut session).is_empty());
+        session.on_timer(start() + Duration::seconds(66));
+        let replies = sent(&mut session);
+        assert_eq!(replies.last().unwrap().msg_type(), "5");
+        assert_eq!(session.state(), SessionState::LoggedOut);
+    }
+
+    #[test]
+    fn keeps_messages_sent_while_disconnected() {
+        let mut session = logged_on();
+        session.disconnect();
+        session.send(Message::new("8").with(tags::CL_ORD_ID, "A"), start());
+        assert!(sent(&mut session).is_empty());
+
+        session.connect(start());
+        let logon = from_client("A", 2).with(tags::HEART_BT_INT, 30);
+        session.receive(logon, start());
+        let replies = sent(&mut session);
+        assert_eq!(replies[0].msg_type(), "A");
+        assert_eq!(replies[0].get(tags::MSG_SEQ_NUM), Some("3"));
+
+        let request = from_client("2", 3)
+            .with(tags::BEGIN_SEQ_NO, 2)
+            .with(tags::END_SEQ_NO, 2);
+        session.receive(request, start());
+        let resent = sent(&mut session);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].get(tags::CL_ORD_ID), Some("A"));
+        assert_eq!(resent[0].get(tags::POSS_DUP_FLAG), Some("Y"));
+    }
+}
@@ -0,0 +1,359 @@
+//! Seeded generator of synthetic order flow. The same seed and settings always produce the
+//! same stream, so generated files can be regenerated instead of stored.
+//!
+//! Each instrument's mid price follows a random walk in ticks. Limit orders are placed up
+//! to `depth` ticks behind the mid on their side and start crossing as the mid moves.
+//! Sizes are a whole number of lots with a geometric distribution. Cancels and replaces
+//! pick one of the orders previously sent for the instrument, which may already have
+//! filled.
+
+use crate::loader::OrderRecord;
+use crate::model::{OrderEvent, Price, Side};
+
+/// xorshift64* pseudo random numbers. Fast and good enough for test data, not for
+/// anything that needs to be unpredictable.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // The all-zero state is a fixed point
+        let state = if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed };
+        Self { state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number in `0..n`. `n` must be positive.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// A number in `[0, 1)`.
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn side(&mut self) -> Side {
+        if self.below(2) == 0 {
+            Side::Bid
+        } else {
+            Side::Ask
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct InstrumentState {
+    symbol: String,
+    // Mid price in units
+    mid: u64,
+    // Orders sent so far that a cancel or replace can refer to
+    live: Vec<(u64, Side)>,
+}
+
+// Orders remembered per instrument for cancels and replaces
+const MAX_LIVE_ORDERS: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct Generator {
+    rng: Rng,
+    // Relative weights of limit, market, cancel and replace events
+    mix: [u32; 4],
+    symbol_count: usize,
+    price_scale: u8,
+    start_price: u64,
+    tick_size: u64,
+    volatility: u64,
+    depth: u64,
+    lot_size: u64,
+    mean_lots: u64,
+    max_lots: u64,
+    traders: u64,
+    next_id: u64,
+    instruments: Vec<InstrumentState>,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            mix: [60, 5, 25, 10],
+            symbol_count: 1,
+            price_scale: 4,
+            start_price: 4_800,
+            tick_size: 1,
+            volatility: 1,
+            depth: 10,
+            lot_size: 100,
+            mean_lots: 5,
+            max_lots: 100,
+            traders: 10,
+            next_id: 1,
+            instruments: Vec::new(),
+        }
+    }
+
+    /// Relative weights of limit, market, cancel and replace events. The share of cancels
+    /// is `cancel / (limit + market + cancel + replace)`.
+    pub fn with_mix(mut self, limit: u32, market: u32, cancel: u32, replace: u32) -> Self {
+        assert!(limit > 0, "The mix needs limit orders");
+        self.mix = [limit, market, cancel, replace];
+        self
+    }
+
+    /// Number of instruments, named `SYM1`, `SYM2` and so on.
+    pub fn with_symbols(mut self, symbol_count: usize) -> Self {
+        assert!(symbol_count > 0, "At least one instrument is needed");
+        self.symbol_count = symbol_count;
+        self
+    }
+
+    /// Starting mid price in units of `price_scale` decimals and the tick size in units.
+    pub fn with_price(mut self, price_scale: u8, start_price: u64, tick_size: u64) -> Self {
+        assert!(tick_size > 0, "Tick size must be positive");
+        self.price_scale = price_scale;
+        self.start_price = start_price;
+        self.tick_size = tick_size;
+        self
+    }
+
+    /// Largest move of the mid per event and the furthest a limit order is placed from
+    /// the mid, both in ticks.
+    pub fn with_walk(mut self, volatility: u64, depth: u64) -> Self {
+        self.volatility = volatility;
+        self.depth = depth;
+        self
+    }
+
+    /// Order sizes in lots of `lot_size`, averaging `mean_lots` and capped at `max_lots`.
+    pub fn with_size(mut self, lot_size: u64, mean_lots: u64, max_lots: u64) -> Self {
+        assert!(lot_size > 0, "Lot size must be positive");
+        assert!(
+            mean_lots >= 1 && mean_lots <= max_lots,
+            "Mean lots must be between 1 and max lots"
+        );
+        self.lot_size = lot_size;
+        self.mean_lots = mean_lots;
+        self.max_lots = max_lots;
+        self
+    }
+
+    pub fn with_traders(mut self, traders: u64) -> Self {
+        assert!(traders > 0, "At least one trader is needed");
+        self.traders = traders;
+        self
+    }
+
+    pub fn symbols(&self) -> Vec<String> {
+        (1..self.symbol_count + 1)
+            .map(|n| format!("SYM{}", n))
+            .collect()
+    }
+
+    fn init(&mut self) {
+        let mid = self.start_price.max((self.depth + 1) * self.tick_size);
+        self.instruments = self.symbols()
+            .into_iter()
+            .map(|symbol| InstrumentState {
+                symbol,
+                mid,
+                live: Vec::new(),
+            })
+            .collect();
+    }
+
+    fn qty(&mut self) -> u64 {
+        // Geometric number of lots with the configured mean
+        let lots = if self.mean_lots == 1 {
+            1
+        } else {
+            let p = 1.0 / self.mean_lots as f64;
+            1 + ((1.0 - self.rng.unit()).ln() / (1.0 - p).ln()) as u64
+        };
+        lots.min(self.max_lots) * self.lot_size
+    }
+
+    fn price(&mut self, mid: u64, side: Side) -> Price {
+        let offset = self.rng.below(self.depth + 1) * self.tick_size;
+        let units = match side {
+            Side::Bid => mid - offset,
+            Side::Ask => mid + offset,
+        };
+        Price::new(units, self.price_scale)
+    }
+
+    fn new_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn next_record(&mut self) -> OrderRecord {
+        if self.instruments.is_empty() {
+            self.init();
+        }
+        let index = self.rng.below(self.instruments.len() as u64) as usize;
+
+        // Walk the mid, keeping every limit price above zero
+        let floor = (self.depth + 1) * self.tick_size;
+        let step = self.rng.below(2 * self.volatility + 1) * self.tick_size;
+        let up = self.volatility * self.tick_size;
+        let mid = (self.instruments[index].mid + step)
+            .saturating_sub(up)
+            .max(floor);
+        self.instruments[index].mid = mid;
+
+        let total: u32 = self.mix.iter().sum();
+        let mut pick = self.rng.below(u64::from(total)) as u32;
+        let mut kind = 0;
+        while pick >= self.mix[kind] {
+            pick -= self.mix[kind];
+            kind += 1;
+        }
+        if kind >= 2 && self.instruments[index].live.is_empty() {
+            kind = 0;
+        }
+
+        let trader = 1 + self.rng.below(self.traders);
+        let event = match kind {
+            0 => {
+                let side = self.rng.side();
+                let price = self.price(mid, side);
+                let qty = self.qty();
+                let id = self.new_id();
+                let live = &mut self.instruments[index].live;
+                if live.len() == MAX_LIVE_ORDERS {
+                    live.remove(0);
+                }
+                live.push((id, side));
+                OrderEvent::limit(id, side, price, qty)
+            }
+            1 => {
+                let side = self.rng.side();
+                let qty = self.qty();
+                OrderEvent::market(self.new_id(), side, qty)
+            }
+            2 => {
+                let live_count = self.instruments[index].live.len() as u64;
+                let target = self.rng.below(live_count) as usize;
+                let (id, _) = self.instruments[index].live.swap_remove(target);
+                OrderEvent::Cancel { id }
+            }
+            _ => {
+                let live_count = self.instruments[index].live.len() as u64;
+                let target = self.rng.below(live_count) as usize;
+                let (id, side) = self.instruments[index].live[target];
+                let price = self.price(mid, side);
+                let qty = self.qty();
+                OrderEvent::replace(id, side, price, qty)
+            }
+        };
+        OrderRecord {
+            trader,
+            symbol: Some(self.instruments[index].symbol.clone()),
+            event: event.with_owner(trader),
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = OrderRecord;
+
+    fn next(&mut self) -> Option<OrderRecord> {
+        Some(self.next_record())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::MatchingEngine;
+    use crate::loader;
+    use crate::model::Instrument;
+    use std::collections::HashSet;
+
+    #[test]
+    fn same_seed_same_stream() {
+        let a: Vec<OrderRecord> = Generator::new(7).with_symbols(3).take(1_000).collect();
+        let b: Vec<OrderRecord> = Generator::new(7).with_symbols(3).take(1_000).collect();
+        let c: Vec<OrderRecord> = Generator::new(8).with_symbols(3).take(1_000).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn follows_the_configured_shape() {
+        let records: Vec<OrderRecord> = Generator::new(42)
+            .with_mix(50, 10, 30, 10)
+            .with_price(2, 10_000, 5)
+            .with_size(10, 3, 20)
+            .take(20_000)
+            .collect();
+
+        let mut sent: HashSet<u64> = HashSet::new();
+        let mut counts = [0usize; 4];
+        for record in &records {
+            match record.event {
+                OrderEvent::Limit { id, price, qty, .. } => {
+                    counts[0] += 1;
+                    assert!(sent.insert(id));
+                    assert_eq!(price.scale(), 2);
+                    assert_eq!(price.units() % 5, 0);
+                    assert!(!price.is_zero());
+                    assert!(qty > 0 && qty % 10 == 0 && qty <= 200);
+                }
+                OrderEvent::Market { id, qty, .. } => {
+                    counts[1] += 1;
+                    assert!(sent.insert(id));
+                    assert!(qty > 0 && qty % 10 == 0 && qty <= 200);
+                }
+                OrderEvent::Cancel { id } => {
+                    counts[2] += 1;
+                    assert!(sent.contains(&id));
+                }
+                OrderEvent::Replace { id, .. } => {
+                    counts[3] += 1;
+                    assert!(sent.contains(&id));
+                }
+                other => panic!("Unexpected event {:?}", other),
+            }
+        }
+        let share = |count: usize| count as f64 / records.len() as f64;
+        assert!((share(counts[2]) - 0.3).abs() < 0.03);
+        assert!((share(counts[1]) - 0.1).abs() < 0.02);
+        assert!(counts[3] > 0);
+    }
+
+    #[test]
+    fn generated_flow_runs_through_the_engine() {
+        let generator = Generator::new(3).with_symbols(2);
+        let mut engine = MatchingEngine::new();
+        for symbol in generator.symbols() {
+            engine
+                .add_instrument(Instrument::new(&symbol).with_price_scale(4))
+                .unwrap();
+        }
+        let records: Vec<OrderRecord> = generator.take(5_000).collect();
+
+        let mut fills = 0;
+        for record in &records {
+            let symbol = record.symbol.as_ref().unwrap();
+            fills += engine.event(symbol, record.event).unwrap().len();
+        }
+        assert!(fills > 0);
+
+        let mut data = Vec::new();
+        loader::write_records(&mut data, &records).unwrap();
+        assert_eq!(loader::read_records(&data[..], 4).unwrap(), records);
+    }
+}
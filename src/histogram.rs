@@ -0,0 +1,220 @@
+//! Latency histogram in the style of HdrHistogram: values are counted in buckets whose
+//! width grows with the value, so every recorded value is kept to a fixed relative
+//! precision over the whole `u64` range in constant memory.
+
+/// Default number of significant bits kept per value, about 0.1% relative error.
+const DEFAULT_PRECISION_BITS: u32 = 11;
+
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    precision_bits: u32,
+    counts: Vec<u64>,
+    total: u64,
+    min: u64,
+    max: u64,
+    sum: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new(DEFAULT_PRECISION_BITS)
+    }
+}
+
+impl Histogram {
+    /// A histogram keeping `precision_bits` significant bits of each value. Values below
+    /// `2^precision_bits` are counted exactly.
+    pub fn new(precision_bits: u32) -> Self {
+        assert!(
+            precision_bits >= 1 && precision_bits <= 16,
+            "Precision must be between 1 and 16 bits"
+        );
+        let half = 1usize << (precision_bits - 1);
+        Self {
+            precision_bits,
+            counts: vec![0; (66 - precision_bits as usize) * half],
+            total: 0,
+            min: u64::max_value(),
+            max: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn half(&self) -> u64 {
+        1 << (self.precision_bits - 1)
+    }
+
+    fn index(&self, value: u64) -> usize {
+        let significant = 64 - value.leading_zeros();
+        if significant <= self.precision_bits {
+            return value as usize;
+        }
+        let shift = u64::from(significant - self.precision_bits);
+        (shift * self.half() + (value >> shift)) as usize
+    }
+
+    /// The lowest and highest values counted in the bucket at `index`.
+    fn bucket_range(&self, index: usize) -> (u64, u64) {
+        let index = index as u64;
+        let half = self.half();
+        if index < 2 * half {
+            return (index, index);
+        }
+        let shift = index / half - 1;
+        let low = (index % half + half) << shift;
+        (low, low + ((1 << shift) - 1))
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let index = self.index(value);
+        self.counts[index] += 1;
+        self.total += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as f64;
+    }
+
+    /// Adds all values recorded in `other`, which must have the same precision.
+    pub fn merge(&mut self, other: &Histogram) {
+        assert_eq!(self.precision_bits, other.precision_bits);
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += *other_count;
+        }
+        self.total += other.total;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn min(&self) -> u64 {
+        if self.total == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.sum / self.total as f64
+        }
+    }
+
+    /// The value below or at which a `quantile` (0.0 to 1.0) of the recorded values fall,
+    /// reported as the highest value of its bucket. Zero if nothing has been recorded.
+    pub fn value_at_quantile(&self, quantile: f64) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        let quantile = quantile.max(0.0).min(1.0);
+        let rank = ((quantile * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen >= rank {
+                let (low, high) = self.bucket_range(index);
+                return high.min(self.max).max(low);
+            }
+        }
+        self.max
+    }
+
+    pub fn value_at_percentile(&self, percentile: f64) -> u64 {
+        self.value_at_quantile(percentile / 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values_are_exact() {
+        let mut histogram = Histogram::new(4);
+        for value in 0..16 {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count(), 16);
+        assert_eq!(histogram.min(), 0);
+        assert_eq!(histogram.max(), 15);
+        assert_eq!(histogram.value_at_quantile(0.5), 7);
+        assert_eq!(histogram.value_at_quantile(1.0), 15);
+        assert_eq!(histogram.mean(), 7.5);
+    }
+
+    #[test]
+    fn buckets_cover_values_without_gaps() {
+        let histogram = Histogram::new(4);
+        let mut next = 0;
+        for index in 0..histogram.counts.len() {
+            let (low, high) = histogram.bucket_range(index);
+            assert_eq!(low, next, "bucket {}", index);
+            assert_eq!(histogram.index(low), index);
+            assert_eq!(histogram.index(high), index);
+            if high == u64::max_value() {
+                assert_eq!(index, histogram.counts.len() - 1);
+                return;
+            }
+            next = high + 1;
+        }
+        panic!("Buckets end at {}", next);
+    }
+
+    #[test]
+    fn large_values_keep_relative_precision() {
+        let mut histogram = Histogram::default();
+        for value in &[3_000u64, 1_234_567, 987_654_321_000, u64::max_value() / 3] {
+            let mut single = Histogram::default();
+            single.record(*value);
+            histogram.record(*value);
+            let (low, high) = single.bucket_range(single.index(*value));
+            assert!(low <= *value && *value <= high);
+            assert!((high - low) as f64 / *value as f64 <= 1.0 / 1024.0);
+        }
+        assert_eq!(histogram.max(), u64::max_value() / 3);
+    }
+
+    #[test]
+    fn percentiles_of_uniform_values() {
+        let mut histogram = Histogram::default();
+        for value in 1..=100_000u64 {
+            histogram.record(value);
+        }
+        let close = |actual: u64, expected: u64| {
+            let error = (actual as f64 - expected as f64).abs() / expected as f64;
+            assert!(error < 0.001, "{} is not close to {}", actual, expected);
+        };
+        close(histogram.value_at_percentile(50.0), 50_000);
+        close(histogram.value_at_percentile(90.0), 90_000);
+        close(histogram.value_at_percentile(99.0), 99_000);
+        close(histogram.value_at_percentile(99.9), 99_900);
+        assert_eq!(histogram.value_at_percentile(100.0), 100_000);
+        assert_eq!(histogram.value_at_percentile(0.0), 1);
+    }
+
+    #[test]
+    fn merge_and_empty() {
+        let mut a = Histogram::default();
+        assert_eq!(a.value_at_quantile(0.99), 0);
+        assert_eq!(a.min(), 0);
+        let mut b = Histogram::default();
+        a.record(10);
+        b.record(5_000);
+        b.record(20);
+        a.merge(&b);
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.min(), 10);
+        assert_eq!(a.max(), 5_000);
+        assert_eq!(a.value_at_quantile(0.5), 20);
+    }
+}
@@ -0,0 +1,175 @@
+//! A book rebuilt from the order feed, as a feed consumer would keep it.
+
+use crate::model::{Level, OrderFeedEvent, Price, Side, TradingPhase};
+use failure::Error;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FeedOrder {
+    side: Side,
+    price: Price,
+    qty: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct FeedBook {
+    orders: HashMap<u64, FeedOrder>,
+    // Order references at each price, in queue order
+    bids: BTreeMap<Price, Vec<u64>>,
+    asks: BTreeMap<Price, Vec<u64>>,
+    phase: TradingPhase,
+    last_traded_price: Option<Price>,
+}
+
+impl FeedBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies one feed event. Fails on an event for an order the feed never added, or on
+    /// an add for one it already has, which means messages were lost or reordered.
+    pub fn apply(&mut self, event: &OrderFeedEvent) -> Result<(), Error> {
+        match *event {
+            OrderFeedEvent::Add {
+                id,
+                side,
+                price,
+                qty,
+            } => {
+                if self.orders.contains_key(&id) {
+                    bail!("Order {} is already on the book", id);
+                }
+                self.insert(id, FeedOrder { side, price, qty });
+            }
+            OrderFeedEvent::Executed {
+                id,
+                qty,
+                price,
+                printable,
+            } => {
+                self.reduce(id, qty)?;
+                if printable {
+                    self.last_traded_price = Some(price);
+                }
+            }
+            OrderFeedEvent::Cancel { id, qty } => self.reduce(id, qty)?,
+            OrderFeedEvent::Delete { id } => {
+                self.remove(id)?;
+            }
+            OrderFeedEvent::Replace { id, price, qty } => {
+                let order = self.remove(id)?;
+                self.insert(
+                    id,
+                    FeedOrder {
+                        side: order.side,
+                        price,
+                        qty,
+                    },
+                );
+            }
+            OrderFeedEvent::Trade { price, .. } => self.last_traded_price = Some(price),
+            OrderFeedEvent::PhaseChange { phase } => self.phase = phase,
+        }
+        Ok(())
+    }
+
+    pub fn phase(&self) -> TradingPhase {
+        self.phase
+    }
+
+    pub fn last_traded_price(&self) -> Option<Price> {
+        self.last_traded_price
+    }
+
+    /// Displayed quantity of an order on the book.
+    pub fn order_qty(&self, id: u64) -> Option<u64> {
+        self.orders.get(&id).map(|order| order.qty)
+    }
+
+    /// The best `levels` price levels of one side, best first.
+    pub fn depth(&self, side: Side, levels: usize) -> Vec<Level> {
+        let to_level = |(price, ids): (&Price, &Vec<u64>)| {
+            let qty = ids.iter().map(|id| self.orders[id].qty).sum();
+            Level::new(*price, qty, ids.len())
+        };
+        match side {
+            Side::Bid => self.bids.iter().rev().map(to_level).take(levels).collect(),
+            Side::Ask => self.asks.iter().map(to_level).take(levels).collect(),
+        }
+    }
+
+    pub fn full_depth(&self, side: Side) -> Vec<Level> {
+        self.depth(side, usize::max_value())
+    }
+
+    /// The events that build this book from an empty one: the trading phase, the last
+    /// traded price as a trade of no quantity, then every order in queue order.
+    pub fn snapshot(&self) -> Vec<OrderFeedEvent> {
+        let mut events = vec![OrderFeedEvent::PhaseChange { phase: self.phase }];
+        if let Some(price) = self.last_traded_price {
+            events.push(OrderFeedEvent::Trade { price, qty: 0 });
+        }
+        for ids in self.bids.values().chain(self.asks.values()) {
+            for id in ids {
+                let order = self.orders[id];
+                events.push(OrderFeedEvent::Add {
+                    id: *id,
+                    side: order.side,
+                    price: order.price,
+                    qty: order.qty,
+                });
+            }
+        }
+        events
+    }
+
+    fn levels(&mut self, side: Side) -> &mut BTreeMap<Price, Vec<u64>> {
+        match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        }
+    }
+
+    fn insert(&mut self, id: u64, order: FeedOrder) {
+        self.levels(order.side)
+            .entry(order.price)
+            .or_insert_with(Vec::new)
+            .push(id);
+        self.orders.insert(id, order);
+    }
+
+    fn remove(&mut self, id: u64) -> Result<FeedOrder, Error> {
+        let order = match self.orders.remove(&id) {
+            Some(order) => order,
+            None => bail!("Order {} is not on the book", id),
+        };
+        let levels = self.levels(order.side);
+        let empty = match levels.get_mut(&order.price) {
+            Some(ids) => {
+                ids.retain(|i| *i != id);
+                ids.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            levels.remove(&order.price);
+        }
+        Ok(order)
+    }
+
+    /// Takes `qty` off an order, removing it when nothing is left.
+    fn reduce(&mut self, id: u64, qty: u64) -> Result<(), Error> {
+        let order = match self.orders.get_mut(&id) {
+            Some(order) => order,
+            None => bail!("Order {} is not on the book", id),
+        };
+        if order.qty < qty {
+            bail!("Cannot take {} off order {} of {}", qty, id, order.qty);
+        }
+        order.qty -= qty;
+        if order.qty == 0 {
+            self.remove(id)?;
+        }
+        Ok(())
+    }
+}
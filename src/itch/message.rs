@@ -0,0 +1,337 @@
+//! Binary framing of feed messages.
+
+use crate::clock::{from_nanos, to_nanos};
+use crate::model::{OrderFeedEvent, Price, Side, TradingPhase};
+use crate::price::MAX_SCALE;
+use chrono::{DateTime, Utc};
+use failure::Error;
+
+// Message type, timestamp
+const HEADER_LEN: usize = 9;
+
+/// One order feed event and the time the book published it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeedMessage {
+    timestamp: DateTime<Utc>,
+    event: OrderFeedEvent,
+}
+
+impl FeedMessage {
+    pub fn new(timestamp: DateTime<Utc>, event: OrderFeedEvent) -> Self {
+        Self { timestamp, event }
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn event(&self) -> OrderFeedEvent {
+        self.event
+    }
+
+    /// The ASCII message type on the wire.
+    pub fn msg_type(&self) -> u8 {
+        match self.event {
+            OrderFeedEvent::PhaseChange { .. } => b'S',
+            OrderFeedEvent::Add { .. } => b'A',
+            OrderFeedEvent::Executed { .. } => b'E',
+            OrderFeedEvent::Cancel { .. } => b'X',
+            OrderFeedEvent::Delete { .. } => b'D',
+            OrderFeedEvent::Replace { .. } => b'U',
+            OrderFeedEvent::Trade { .. } => b'P',
+        }
+    }
+
+    /// Appends the message to `buf`, length prefix included.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let mut body = Vec::with_capacity(48);
+        body.push(self.msg_type());
+        put_i64(&mut body, to_nanos(self.timestamp));
+        match self.event {
+            OrderFeedEvent::PhaseChange { phase } => body.push(phase_code(phase)),
+            OrderFeedEvent::Add {
+                id,
+                side,
+                price,
+                qty,
+            } => {
+                put_u64(&mut body, id);
+                body.push(match side {
+                    Side::Bid => b'B',
+                    Side::Ask => b'S',
+                });
+                put_u64(&mut body, qty);
+                put_price(&mut body, price);
+            }
+            OrderFeedEvent::Executed {
+                id,
+                qty,
+                price,
+                printable,
+            } => {
+                put_u64(&mut body, id);
+                put_u64(&mut body, qty);
+                body.push(if printable { b'Y' } else { b'N' });
+                put_price(&mut body, price);
+            }
+            OrderFeedEvent::Cancel { id, qty } => {
+                put_u64(&mut body, id);
+                put_u64(&mut body, qty);
+            }
+            OrderFeedEvent::Delete { id } => put_u64(&mut body, id),
+            OrderFeedEvent::Replace { id, price, qty } => {
+                put_u64(&mut body, id);
+                put_u64(&mut body, qty);
+                put_price(&mut body, price);
+            }
+            OrderFeedEvent::Trade { price, qty } => {
+                put_u64(&mut body, qty);
+                put_price(&mut body, price);
+            }
+        }
+        put_u16(buf, body.len() as u16);
+        buf.extend_from_slice(&body);
+    }
+
+    /// Reads the first message in `data`. Returns the message and the number of bytes it
+    /// took, or None if `data` does not hold a whole message yet.
+    pub fn decode(data: &[u8]) -> Result<Option<(FeedMessage, usize)>, Error> {
+        if data.len() < 2 {
+            return Ok(None);
+        }
+        let len = (usize::from(data[0]) << 8) | usize::from(data[1]);
+        if data.len() < 2 + len {
+            return Ok(None);
+        }
+        if len < HEADER_LEN {
+            bail!("Message of {} bytes is shorter than its header", len);
+        }
+        let mut reader = Reader {
+            data: &data[2..2 + len],
+            pos: 0,
+        };
+        let msg_type = reader.u8()?;
+        let timestamp = from_nanos(reader.u64()? as i64)?;
+        let event = match msg_type {
+            b'S' => OrderFeedEvent::PhaseChange {
+                phase: phase_from_code(reader.u8()?)?,
+            },
+            b'A' => OrderFeedEvent::Add {
+                id: reader.u64()?,
+                side: match reader.u8()? {
+                    b'B' => Side::Bid,
+                    b'S' => Side::Ask,
+                    other => bail!("Invalid side {:?}", other as char),
+                },
+                qty: reader.u64()?,
+                price: reader.price()?,
+            },
+            b'E' => OrderFeedEvent::Executed {
+                id: reader.u64()?,
+                qty: reader.u64()?,
+                printable: match reader.u8()? {
+                    b'Y' => true,
+                    b'N' => false,
+                    other => bail!("Invalid printable flag {:?}", other as char),
+                },
+                price: reader.price()?,
+            },
+            b'X' => OrderFeedEvent::Cancel {
+                id: reader.u64()?,
+                qty: reader.u64()?,
+            },
+            b'D' => OrderFeedEvent::Delete { id: reader.u64()? },
+            b'U' => OrderFeedEvent::Replace {
+                id: reader.u64()?,
+                qty: reader.u64()?,
+                price: reader.price()?,
+            },
+            b'P' => OrderFeedEvent::Trade {
+                qty: reader.u64()?,
+                price: reader.price()?,
+            },
+            other => bail!("Unknown message type {:?}", other as char),
+        };
+        if reader.pos != len {
+            bail!(
+                "Message type {:?} has {} bytes left over",
+                msg_type as char,
+                len - reader.pos
+            );
+        }
+        Ok(Some((FeedMessage::new(timestamp, event), 2 + len)))
+    }
+}
+
+fn phase_code(phase: TradingPhase) -> u8 {
+    match phase {
+        TradingPhase::PreOpen => b'P',
+        TradingPhase::OpeningAuction => b'O',
+        TradingPhase::Continuous => b'Q',
+        TradingPhase::Halted => b'H',
+        TradingPhase::ClosingAuction => b'C',
+        TradingPhase::Closed => b'M',
+    }
+}
+
+fn phase_from_code(code: u8) -> Result<TradingPhase, Error> {
+    match code {
+        b'P' => Ok(TradingPhase::PreOpen),
+        b'O' => Ok(TradingPhase::OpeningAuction),
+        b'Q' => Ok(TradingPhase::Continuous),
+        b'H' => Ok(TradingPhase::Halted),
+        b'C' => Ok(TradingPhase::ClosingAuction),
+        b'M' => Ok(TradingPhase::Closed),
+        other => bail!("Invalid system event {:?}", other as char),
+    }
+}
+
+pub(super) fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+}
+
+pub(super) fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    for i in (0..8).rev() {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn put_i64(buf: &mut Vec<u8>, value: i64) {
+    put_u64(buf, value as u64);
+}
+
+fn put_price(buf: &mut Vec<u8>, price: Price) {
+    put_u64(buf, price.units());
+    buf.push(price.scale());
+}
+
+/// Big-endian reads from one message, failing on a truncated body.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() - self.pos < len {
+            bail!("Message ends at offset {}, wanted {} bytes", self.pos, len);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(self.take(8)?
+            .iter()
+            .fold(0u64, |value, b| (value << 8) | u64::from(*b)))
+    }
+
+    fn price(&mut self) -> Result<Price, Error> {
+        let units = self.u64()?;
+        let scale = self.u8()?;
+        if scale > MAX_SCALE {
+            bail!("Invalid price scale {}", scale);
+        }
+        Ok(Price::new(units, scale))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::wire_px as px;
+    use chrono::TimeZone;
+
+    #[test]
+    fn round_trip() {
+        let timestamp = Utc.ymd(2018, 4, 2).and_hms_nano(9, 30, 0, 123_456_789);
+        let events = [
+            OrderFeedEvent::PhaseChange {
+                phase: TradingPhase::OpeningAuction,
+            },
+            OrderFeedEvent::Add {
+                id: 1,
+                side: Side::Bid,
+                price: px(7512),
+                qty: 100,
+            },
+            OrderFeedEvent::Add {
+                id: u64::max_value(),
+                side: Side::Ask,
+                price: px(7513),
+                qty: 5,
+            },
+            OrderFeedEvent::Executed {
+                id: 1,
+                qty: 40,
+                price: px(7512),
+                printable: true,
+            },
+            OrderFeedEvent::Executed {
+                id: 1,
+                qty: 10,
+                price: px(7511),
+                printable: false,
+            },
+            OrderFeedEvent::Cancel { id: 1, qty: 20 },
+            OrderFeedEvent::Replace {
+                id: 1,
+                price: px(7510),
+                qty: 30,
+            },
+            OrderFeedEvent::Delete { id: 1 },
+            OrderFeedEvent::Trade {
+                price: px(7511),
+                qty: 400,
+            },
+        ];
+
+        let mut buf = Vec::new();
+        for event in &events {
+            FeedMessage::new(timestamp, *event).encode(&mut buf);
+        }
+        let mut pos = 0;
+        for event in &events {
+            let (message, len) = FeedMessage::decode(&buf[pos..]).unwrap().unwrap();
+            assert_eq!(message, FeedMessage::new(timestamp, *event));
+            pos += len;
+        }
+        assert_eq!(pos, buf.len());
+        assert_eq!(FeedMessage::decode(&buf[pos..]).unwrap(), None);
+    }
+
+    #[test]
+    fn waits_for_whole_message_and_rejects_bad_ones() {
+        let before_epoch = Utc.ymd(1969, 12, 31).and_hms_milli(23, 59, 59, 500);
+        let mut buf = Vec::new();
+        FeedMessage::new(before_epoch, OrderFeedEvent::Delete { id: 7 }).encode(&mut buf);
+        assert_eq!(&buf[..3], &[0, 17, b'D']);
+        for len in 0..buf.len() {
+            assert_eq!(FeedMessage::decode(&buf[..len]).unwrap(), None);
+        }
+        let (message, _) = FeedMessage::decode(&buf).unwrap().unwrap();
+        assert_eq!(message.timestamp(), before_epoch);
+
+        // Too short to hold the order reference
+        let mut short = buf[..15].to_vec();
+        short[1] = 13;
+        assert!(FeedMessage::decode(&short).is_err());
+
+        buf[2] = b'Z';
+        assert!(FeedMessage::decode(&buf).is_err());
+
+        // A price scale no price can have
+        let mut trade = Vec::new();
+        let event = OrderFeedEvent::Trade { price: px(7512), qty: 10 };
+        FeedMessage::new(before_epoch, event).encode(&mut trade);
+        let last = trade.len() - 1;
+        trade[last] = 19;
+        assert!(FeedMessage::decode(&trade).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+//! ITCH-style order-by-order market data.
+//!
+//! Every change to a displayed order in the book goes out as one binary message, so a
+//! consumer can keep its own copy of the book instead of polling for snapshots. Orders
+//! are referred to by their id in the book. Messages are framed as
+//! `[len: u16][type: u8][timestamp: i64][body]`, big-endian, where `len` counts the bytes
+//! after itself and the timestamp is in nanoseconds since the Unix epoch. Prices are
+//! `[units: u64][scale: u8]` and quantities u64. The bodies are:
+//!
+//! ```text
+//! S  system event    [phase: u8]  P pre-open, O opening auction, Q continuous,
+//!                                 H halted, C closing auction, M closed
+//! A  add order       [ref: u64][side: u8 B or S][qty][price]
+//! E  order executed  [ref: u64][qty][printable: u8 Y or N][price]
+//! X  order cancel    [ref: u64][cancelled qty]
+//! D  order delete    [ref: u64]
+//! U  order replace   [ref: u64][qty][price]
+//! P  trade           [qty][price]
+//! ```
+//!
+//! An execution or cancel that leaves nothing of an order takes it off the book. A replace
+//! sends the order to the back of the queue at its new price.
+//!
+//! `FeedPublisher` sends the feed over UDP multicast with sequence numbers, see
+//! `multicast` for the packets, and `FeedSubscriber` rebuilds the book from it.
+
+pub mod book;
+pub mod message;
+pub mod multicast;
+pub mod subscriber;
+
+pub use self::book::FeedBook;
+pub use self::message::FeedMessage;
+pub use self::multicast::{ChannelConfig, FeedPublisher};
+pub use self::subscriber::FeedSubscriber;
+
+use crate::order_book::OrderBook;
+
+/// Encodes the order feed events emitted by `book` since the last call onto `buf`,
+/// stamped with the book's clock. Returns the number of messages written.
+pub fn publish(book: &mut OrderBook, buf: &mut Vec<u8>) -> usize {
+    let now = book.now();
+    let events = book.drain_order_feed();
+    for event in &events {
+        FeedMessage::new(now, *event).encode(buf);
+    }
+    events.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::model::{px, Instrument, OrderEvent, OrderFeedEvent, SelfTradePrevention, Side,
+                       TimeInForce, TradingPhase};
+    use chrono::{Duration, TimeZone, Utc};
+
+    /// Publishes what changed in `book` and checks the rebuilt book against it.
+    fn sync(book: &mut OrderBook, feed: &mut FeedBook) {
+        let mut buf = Vec::new();
+        publish(book, &mut buf);
+        let mut pos = 0;
+        while let Some((message, len)) = FeedMessage::decode(&buf[pos..]).unwrap() {
+            assert_eq!(message.timestamp(), book.now());
+            feed.apply(&message.event()).unwrap();
+            pos += len;
+        }
+        assert_eq!(pos, buf.len());
+        for side in &[Side::Bid, Side::Ask] {
+            assert_eq!(feed.full_depth(*side), book.full_depth(*side));
+        }
+        assert_eq!(feed.phase(), book.phase());
+        assert_eq!(feed.last_traded_price(), book.last_traded_price());
+    }
+
+    #[test]
+    fn rebuilt_book_matches_the_engine() {
+        ::crate::model::test_setup();
+
+        let clock = ManualClock::new(Utc.ymd(2018, 4, 2).and_hms(9, 0, 0));
+        let instrument = Instrument::new("AUDUSD")
+            .with_self_trade_prevention(SelfTradePrevention::DecrementAndCancel);
+        let mut book = OrderBook::with_clock(instrument, Box::new(clock.clone()));
+        let mut feed = FeedBook::new();
+
+        let continuous = vec![
+            OrderEvent::limit(1, Side::Bid, px(100), 50).with_owner(7),
+            OrderEvent::limit(2, Side::Bid, px(100), 30),
+            OrderEvent::iceberg(3, Side::Ask, px(102), 100, 20),
+            OrderEvent::limit(4, Side::Ask, px(103), 40),
+            // Takes the iceberg's peak twice and half of the third
+            OrderEvent::market(5, Side::Bid, 50),
+            // Keeps its place
+            OrderEvent::replace(2, Side::Bid, px(100), 10),
+            // Moves without trading
+            OrderEvent::replace(1, Side::Bid, px(101), 50),
+            // Trades on the way to its new price
+            OrderEvent::replace(4, Side::Ask, px(101), 40),
+            // Decrements order 1 of the same owner, then trades with order 2
+            OrderEvent::limit(6, Side::Ask, px(100), 25).with_owner(7),
+            OrderEvent::Cancel { id: 3 },
+            OrderEvent::limit_with_tif(7, Side::Bid, px(98), 5, TimeInForce::Day),
+        ];
+        for event in continuous {
+            book.event(event).unwrap();
+            sync(&mut book, &mut feed);
+        }
+        assert_eq!(feed.order_qty(6), Some(5));
+
+        clock.advance(Duration::days(1));
+        assert_eq!(book.expire_orders().unwrap(), 1);
+        sync(&mut book, &mut feed);
+        assert_eq!(feed.order_qty(7), None);
+
+        book.set_phase(TradingPhase::Halted).unwrap();
+        sync(&mut book, &mut feed);
+        book.set_phase(TradingPhase::OpeningAuction).unwrap();
+        sync(&mut book, &mut feed);
+        let auction = vec![
+            OrderEvent::limit(8, Side::Bid, px(100), 40),
+            OrderEvent::iceberg(9, Side::Ask, px(99), 80, 10),
+            // Held for the uncross, never shown
+            OrderEvent::market(10, Side::Bid, 20),
+        ];
+        for event in auction {
+            book.event(event).unwrap();
+            sync(&mut book, &mut feed);
+        }
+        assert!(!book.set_phase(TradingPhase::Continuous).unwrap().is_empty());
+        sync(&mut book, &mut feed);
+        // The iceberg shows a new peak after the uncross
+        assert_eq!(feed.order_qty(9), Some(10));
+    }
+
+    #[test]
+    fn feed_book_rejects_unknown_orders() {
+        let mut feed = FeedBook::new();
+        let add = OrderFeedEvent::Add {
+            id: 1,
+            side: Side::Bid,
+            price: px(100),
+            qty: 10,
+        };
+        feed.apply(&add).unwrap();
+        assert!(feed.apply(&add).is_err());
+        assert!(feed.apply(&OrderFeedEvent::Cancel { id: 1, qty: 11 }).is_err());
+        assert!(feed.apply(&OrderFeedEvent::Delete { id: 2 }).is_err());
+
+        feed.apply(&OrderFeedEvent::Cancel { id: 1, qty: 10 }).unwrap();
+        assert_eq!(feed.order_qty(1), None);
+        assert!(feed.full_depth(Side::Bid).is_empty());
+    }
+}
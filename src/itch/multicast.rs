@@ -0,0 +1,526 @@
+//! The order feed over UDP multicast, with TCP retransmission and snapshots.
+//!
+//! Each book's feed goes out on its own channel, numbered by the operator. A channel has
+//! three parts:
+//!
+//! * The incremental group carries the feed messages, numbered in sequence from 1. A
+//!   packet is `[channel: u16][sequence: u64][count: u16][messages]`, where `sequence`
+//!   numbers its first message. A packet without messages is a heartbeat carrying the
+//!   next sequence number, so that lost packets are noticed while the book is quiet.
+//! * The retransmission service takes TCP requests `[channel: u16][sequence: u64]`
+//!   `[count: u16]` and answers each with `[len: u16][packet]` frames holding the
+//!   messages asked for. Only the latest messages are kept: a reply that starts after the
+//!   sequence asked for means the messages before it are gone.
+//! * The snapshot group repeats the whole book every so often, as built by
+//!   `FeedBook::snapshot`, in packets `[channel: u16][sequence: u64][part: u16]`
+//!   `[parts: u16][count: u16][messages]`. Here `sequence` is the last incremental
+//!   message the snapshot includes.
+//!
+//! Integers are big-endian and the messages are framed as in `FeedMessage::encode`. A
+//! subscriber that joins late, or misses more than the retransmission service keeps,
+//! starts over from the next snapshot.
+
+use super::book::FeedBook;
+use super::message::{put_u16, put_u64, FeedMessage};
+use crate::clock::{Clock, SystemClock};
+use crate::net;
+use crate::order_book::OrderBook;
+use chrono::{DateTime, Duration, Utc};
+use failure::Error;
+use std::collections::VecDeque;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
+
+/// Most bytes of messages in one packet, so that packets fit an Ethernet frame.
+pub const MAX_PAYLOAD_LEN: usize = 1400;
+const HEADER_LEN: usize = 12;
+const SNAPSHOT_HEADER_LEN: usize = 16;
+const REQUEST_LEN: usize = 12;
+
+const DEFAULT_HISTORY_LEN: usize = 100_000;
+
+/// Where the parts of a channel are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelConfig {
+    channel: u16,
+    incremental: SocketAddrV4,
+    snapshot: SocketAddrV4,
+    retransmit: SocketAddr,
+    interface: Ipv4Addr,
+    snapshot_interval: Duration,
+}
+
+impl ChannelConfig {
+    /// A channel sending to the `incremental` and `snapshot` groups, with the
+    /// retransmission service on `retransmit`.
+    pub fn new(
+        channel: u16,
+        incremental: SocketAddrV4,
+        snapshot: SocketAddrV4,
+        retransmit: SocketAddr,
+    ) -> Self {
+        Self {
+            channel,
+            incremental,
+            snapshot,
+            retransmit,
+            interface: Ipv4Addr::new(0, 0, 0, 0),
+            snapshot_interval: Duration::seconds(1),
+        }
+    }
+
+    /// Sets the address of the network interface to send and receive on, such as
+    /// 127.0.0.1 for loopback. The system picks one by default.
+    pub fn with_interface(mut self, interface: Ipv4Addr) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    pub fn with_retransmit(mut self, retransmit: SocketAddr) -> Self {
+        self.retransmit = retransmit;
+        self
+    }
+
+    /// Sets how often the snapshot and a heartbeat go out.
+    pub fn with_snapshot_interval(mut self, snapshot_interval: Duration) -> Self {
+        self.snapshot_interval = snapshot_interval;
+        self
+    }
+
+    pub fn channel(&self) -> u16 {
+        self.channel
+    }
+
+    pub fn incremental(&self) -> SocketAddrV4 {
+        self.incremental
+    }
+
+    pub fn snapshot(&self) -> SocketAddrV4 {
+        self.snapshot
+    }
+
+    pub fn retransmit(&self) -> SocketAddr {
+        self.retransmit
+    }
+
+    pub fn interface(&self) -> Ipv4Addr {
+        self.interface
+    }
+
+    pub fn snapshot_interval(&self) -> Duration {
+        self.snapshot_interval
+    }
+}
+
+/// A packet of the incremental or snapshot group, or of a retransmission.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Packet {
+    crate channel: u16,
+    crate sequence: u64,
+    // Of a snapshot, zero and one otherwise
+    crate part: u16,
+    crate parts: u16,
+    crate messages: Vec<FeedMessage>,
+}
+
+impl Packet {
+    /// Reads an incremental packet, or a snapshot packet if `snapshot` is set.
+    crate fn decode(data: &[u8], snapshot: bool) -> Result<Packet, Error> {
+        let header_len = if snapshot {
+            SNAPSHOT_HEADER_LEN
+        } else {
+            HEADER_LEN
+        };
+        if data.len() < header_len {
+            bail!("Packet of {} bytes is shorter than its header", data.len());
+        }
+        let (part, parts) = if snapshot {
+            (get_u16(data, 10), get_u16(data, 12))
+        } else {
+            (0, 1)
+        };
+        let count = get_u16(data, header_len - 2);
+        let mut messages = Vec::with_capacity(usize::from(count));
+        let mut pos = header_len;
+        for _ in 0..count {
+            match FeedMessage::decode(&data[pos..])? {
+                Some((message, len)) => {
+                    messages.push(message);
+                    pos += len;
+                }
+                None => bail!("Packet ends inside message {} of {}", messages.len(), count),
+            }
+        }
+        if pos != data.len() {
+            bail!("Packet has {} bytes left over", data.len() - pos);
+        }
+        Ok(Packet {
+            channel: get_u16(data, 0),
+            sequence: get_u64(data, 2),
+            part,
+            parts,
+            messages,
+        })
+    }
+}
+
+/// Writes an incremental packet holding already encoded messages.
+fn encode_packet(buf: &mut Vec<u8>, channel: u16, sequence: u64, messages: &[Vec<u8>]) {
+    put_u16(buf, channel);
+    put_u64(buf, sequence);
+    put_u16(buf, messages.len() as u16);
+    for message in messages {
+        buf.extend_from_slice(message);
+    }
+}
+
+/// Writes a retransmission request for `count` messages from `sequence`.
+pub(super) fn encode_request(buf: &mut Vec<u8>, channel: u16, sequence: u64, count: u16) {
+    put_u16(buf, channel);
+    put_u64(buf, sequence);
+    put_u16(buf, count);
+}
+
+pub(super) fn get_u16(data: &[u8], at: usize) -> u16 {
+    (u16::from(data[at]) << 8) | u16::from(data[at + 1])
+}
+
+fn get_u64(data: &[u8], at: usize) -> u64 {
+    data[at..at + 8]
+        .iter()
+        .fold(0u64, |value, b| (value << 8) | u64::from(*b))
+}
+
+/// Splits encoded messages into runs that fit one packet each.
+fn batches(messages: &[Vec<u8>]) -> Vec<&[Vec<u8>]> {
+    let mut batches = Vec::new();
+    let (mut start, mut len) = (0, 0);
+    for (i, message) in messages.iter().enumerate() {
+        if i > start && len + message.len() > MAX_PAYLOAD_LEN {
+            batches.push(&messages[start..i]);
+            start = i;
+            len = 0;
+        }
+        len += message.len();
+    }
+    if start < messages.len() {
+        batches.push(&messages[start..]);
+    }
+    batches
+}
+
+/// Sends the order feed of one book on a multicast channel and serves retransmissions.
+///
+/// Everything runs on the thread calling `publish` and `poll`, the sockets are
+/// non-blocking. `poll` answers retransmission requests and sends the snapshot and a
+/// heartbeat when they are due. The publisher keeps its own copy of the book for the
+/// snapshots, so it has to see the book's feed from the start.
+#[derive(Debug)]
+pub struct FeedPublisher {
+    config: ChannelConfig,
+    socket: UdpSocket,
+    listener: TcpListener,
+    connections: Vec<net::Connection<()>>,
+    book: FeedBook,
+    next_sequence: u64,
+    // The latest messages as sent, the last one numbered `next_sequence - 1`
+    history: VecDeque<Vec<u8>>,
+    history_len: usize,
+    last_snapshot: Option<DateTime<Utc>>,
+    clock: Box<Clock>,
+}
+
+impl FeedPublisher {
+    pub fn bind(config: ChannelConfig) -> Result<Self, Error> {
+        // Multicast goes out on the interface the socket is bound to
+        let socket = UdpSocket::bind(SocketAddrV4::new(config.interface(), 0))?;
+        socket.set_multicast_loop_v4(true)?;
+        let listener = TcpListener::bind(config.retransmit())?;
+        listener.set_nonblocking(true)?;
+        info!(
+            "Channel {} publishing to {}, snapshots to {}, retransmitting on {}",
+            config.channel(),
+            config.incremental(),
+            config.snapshot(),
+            listener.local_addr()?
+        );
+        Ok(Self {
+            config,
+            socket,
+            listener,
+            connections: Vec::new(),
+            book: FeedBook::new(),
+            next_sequence: 1,
+            history: VecDeque::new(),
+            history_len: DEFAULT_HISTORY_LEN,
+            last_snapshot: None,
+            clock: Box::new(SystemClock),
+        })
+    }
+
+    /// Replaces the clock used for snapshot timestamps and intervals, returning the
+    /// previous one.
+    pub fn set_clock(&mut self, clock: Box<Clock>) -> Box<Clock> {
+        mem::replace(&mut self.clock, clock)
+    }
+
+    /// Sets how many of the latest messages are kept for retransmission.
+    pub fn set_history_len(&mut self, history_len: usize) {
+        self.history_len = history_len;
+        while self.history.len() > history_len {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn config(&self) -> &ChannelConfig {
+        &self.config
+    }
+
+    /// The address of the retransmission service, with the port picked if it was bound
+    /// to port zero.
+    pub fn retransmit_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// The sequence number the next message will get.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Sends the order feed events emitted by `book` since the last call, stamped with
+    /// the book's clock. Returns the number of messages sent.
+    pub fn publish(&mut self, book: &mut OrderBook) -> Result<usize, Error> {
+        let now = book.now();
+        let events = book.drain_order_feed();
+        let mut messages = Vec::with_capacity(events.len());
+        for event in &events {
+            self.book.apply(event)?;
+            let mut message = Vec::new();
+            FeedMessage::new(now, *event).encode(&mut message);
+            messages.push(message);
+        }
+
+        let mut packet = Vec::new();
+        for batch in batches(&messages) {
+            packet.clear();
+            encode_packet(&mut packet, self.config.channel(), self.next_sequence, batch);
+            self.socket.send_to(&packet, self.config.incremental())?;
+            self.next_sequence += batch.len() as u64;
+        }
+        for message in messages {
+            if self.history.len() == self.history_len {
+                self.history.pop_front();
+            }
+            if self.history_len > 0 {
+                self.history.push_back(message);
+            }
+        }
+        Ok(events.len())
+    }
+
+    /// Sends a packet without messages, telling subscribers the next sequence number.
+    pub fn send_heartbeat(&mut self) -> Result<(), Error> {
+        let mut packet = Vec::with_capacity(HEADER_LEN);
+        encode_packet(&mut packet, self.config.channel(), self.next_sequence, &[]);
+        self.socket.send_to(&packet, self.config.incremental())?;
+        Ok(())
+    }
+
+    /// Sends the whole book on the snapshot group.
+    pub fn send_snapshot(&mut self) -> Result<(), Error> {
+        let now = self.clock.now();
+        let messages: Vec<Vec<u8>> = self.book
+            .snapshot()
+            .into_iter()
+            .map(|event| {
+                let mut message = Vec::new();
+                FeedMessage::new(now, event).encode(&mut message);
+                message
+            })
+            .collect();
+        let batches = batches(&messages);
+        let mut packet = Vec::new();
+        for (part, batch) in batches.iter().enumerate() {
+            packet.clear();
+            put_u16(&mut packet, self.config.channel());
+            put_u64(&mut packet, self.next_sequence - 1);
+            put_u16(&mut packet, part as u16);
+            put_u16(&mut packet, batches.len() as u16);
+            put_u16(&mut packet, batch.len() as u16);
+            for message in batch.iter() {
+                packet.extend_from_slice(message);
+            }
+            self.socket.send_to(&packet, self.config.snapshot())?;
+        }
+        self.last_snapshot = Some(now);
+        Ok(())
+    }
+
+    /// Answers retransmission requests, and sends the snapshot and a heartbeat when they
+    /// are due. Returns the number of requests answered.
+    pub fn poll(&mut self) -> Result<usize, Error> {
+        self.connections.extend(net::accept(&self.listener, "retransmission")?);
+
+        let mut count = 0;
+        for connection in &mut self.connections {
+            if !connection.read() {
+                connection.closed = true;
+            }
+            let mut pos = 0;
+            while connection.inbound.len() - pos >= REQUEST_LEN {
+                let request = &connection.inbound[pos..pos + REQUEST_LEN];
+                pos += REQUEST_LEN;
+                if get_u16(request, 0) != self.config.channel() {
+                    warn!("Dropping {}, request for another channel", connection.peer);
+                    connection.closed = true;
+                    break;
+                }
+                let (sequence, len) = (get_u64(request, 2), get_u16(request, 10));
+                retransmit(
+                    &mut connection.outbound,
+                    self.config.channel(),
+                    &self.history,
+                    self.next_sequence,
+                    sequence,
+                    len,
+                );
+                count += 1;
+            }
+            connection.inbound.drain(..pos);
+            if !connection.flush() {
+                connection.closed = true;
+            }
+        }
+        self.connections.retain(|connection| {
+            if connection.closed {
+                info!("Closing retransmission connection from {}", connection.peer);
+            }
+            !connection.closed
+        });
+
+        let now = self.clock.now();
+        let interval = self.config.snapshot_interval();
+        let due = self.last_snapshot
+            .map_or(true, |last| now.signed_duration_since(last) >= interval);
+        if due {
+            self.send_snapshot()?;
+            self.send_heartbeat()?;
+        }
+        Ok(count)
+    }
+}
+
+/// Writes the frames answering a request for `count` messages from `sequence`, starting
+/// later if the first ones are gone from `history`.
+fn retransmit(
+    out: &mut Vec<u8>,
+    channel: u16,
+    history: &VecDeque<Vec<u8>>,
+    next_sequence: u64,
+    sequence: u64,
+    count: u16,
+) {
+    let first_kept = next_sequence - history.len() as u64;
+    let start = sequence.max(first_kept).min(next_sequence);
+    let end = sequence
+        .saturating_add(u64::from(count))
+        .min(next_sequence)
+        .max(start);
+    let messages: Vec<Vec<u8>> = history
+        .iter()
+        .skip((start - first_kept) as usize)
+        .take((end - start) as usize)
+        .cloned()
+        .collect();
+    let mut batches = batches(&messages);
+    if batches.is_empty() {
+        batches.push(&[]);
+    }
+    let mut sequence = start;
+    for batch in batches {
+        let mut packet = Vec::new();
+        encode_packet(&mut packet, channel, sequence, batch);
+        put_u16(out, packet.len() as u16);
+        out.extend_from_slice(&packet);
+        sequence += batch.len() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{OrderFeedEvent, Price, Side, TradingPhase};
+    use chrono::TimeZone;
+
+    #[test]
+    fn retransmits_what_is_left_of_a_range() {
+        let timestamp = Utc.ymd(2018, 4, 2).and_hms(9, 30, 0);
+        let history: VecDeque<Vec<u8>> = (1..201)
+            .map(|id| {
+                let mut message = Vec::new();
+                let event = OrderFeedEvent::Add {
+                    id,
+                    side: Side::Bid,
+                    price: Price::new(7512, 4),
+                    qty: 100,
+                };
+                FeedMessage::new(timestamp, event).encode(&mut message);
+                message
+            })
+            .collect();
+        // Messages 101 to 300 are kept
+        let frames = |sequence, count| {
+            let mut out = Vec::new();
+            retransmit(&mut out, 3, &history, 301, sequence, count);
+            let mut packets = Vec::new();
+            let mut pos = 0;
+            while pos < out.len() {
+                let len = usize::from(get_u16(&out, pos));
+                packets.push(Packet::decode(&out[pos + 2..pos + 2 + len], false).unwrap());
+                pos += 2 + len;
+            }
+            packets
+        };
+
+        let packets = frames(150, 100);
+        assert!(packets.len() > 1);
+        let mut sequence = 150;
+        for packet in &packets {
+            assert_eq!((packet.channel, packet.sequence), (3, sequence));
+            for message in &packet.messages {
+                match message.event() {
+                    OrderFeedEvent::Add { id, .. } => assert_eq!(id + 100, sequence),
+                    other => panic!("Retransmitted {:?}", other),
+                }
+                sequence += 1;
+            }
+        }
+        assert_eq!(sequence, 250);
+
+        let packets = frames(90, 20);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].sequence, 101);
+        assert_eq!(packets[0].messages.len(), 9);
+
+        let packets = frames(301, 10);
+        assert_eq!((packets[0].sequence, packets[0].messages.len()), (301, 0));
+        let packets = frames(u64::max_value(), 10);
+        assert_eq!((packets[0].sequence, packets[0].messages.len()), (301, 0));
+
+        let mut snapshot = Vec::new();
+        put_u16(&mut snapshot, 3);
+        put_u64(&mut snapshot, 300);
+        put_u16(&mut snapshot, 1);
+        put_u16(&mut snapshot, 2);
+        put_u16(&mut snapshot, 1);
+        let phase = OrderFeedEvent::PhaseChange {
+            phase: TradingPhase::Continuous,
+        };
+        FeedMessage::new(timestamp, phase).encode(&mut snapshot);
+        let packet = Packet::decode(&snapshot, true).unwrap();
+        assert_eq!((packet.sequence, packet.part, packet.parts), (300, 1, 2));
+        assert_eq!(packet.messages, vec![FeedMessage::new(timestamp, phase)]);
+        assert!(Packet::decode(&snapshot[..snapshot.len() - 1], true).is_err());
+        assert!(Packet::decode(&snapshot, false).is_err());
+    }
+}
@@ -0,0 +1,467 @@
+//! The receiving end of a multicast channel.
+
+use super::book::FeedBook;
+use super::message::FeedMessage;
+use super::multicast::{encode_request, get_u16, ChannelConfig, Packet};
+use crate::net;
+use failure::Error;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, TcpStream, UdpSocket};
+
+// Room for the largest packet the publisher sends
+const MAX_DATAGRAM_LEN: usize = 2048;
+
+/// The parts of a snapshot received so far.
+#[derive(Debug)]
+struct PartialSnapshot {
+    sequence: u64,
+    parts: Vec<Option<Vec<FeedMessage>>>,
+}
+
+/// Keeps a copy of a book from a multicast channel.
+///
+/// Messages are applied in sequence. Missing ones are asked for from the retransmission
+/// service. A subscriber that joins after the first message, or misses messages the
+/// service no longer has, waits for the next snapshot and goes on from there. Everything
+/// runs on the thread calling `poll`, the sockets are non-blocking. The standard library
+/// cannot share a port, so there is one subscriber per channel on a host.
+#[derive(Debug)]
+pub struct FeedSubscriber {
+    config: ChannelConfig,
+    incremental: UdpSocket,
+    snapshot: UdpSocket,
+    retransmit: Option<net::Connection<()>>,
+    book: FeedBook,
+    // The next message to apply, None until the book is in sync
+    next_sequence: Option<u64>,
+    // The message after the last one known to be sent
+    known_sequence: u64,
+    // Messages received ahead of the next one to apply
+    pending: BTreeMap<u64, FeedMessage>,
+    // The messages asked for and not received yet, first and one past the last
+    requested: Option<(u64, u64)>,
+    partial_snapshot: Option<PartialSnapshot>,
+}
+
+impl FeedSubscriber {
+    /// Joins the channel's incremental and snapshot groups.
+    pub fn join(config: ChannelConfig) -> Result<Self, Error> {
+        let incremental = join_group(config.incremental(), config.interface())?;
+        let snapshot = join_group(config.snapshot(), config.interface())?;
+        info!(
+            "Joined channel {} on {} and {}",
+            config.channel(),
+            config.incremental(),
+            config.snapshot()
+        );
+        Ok(Self {
+            config,
+            incremental,
+            snapshot,
+            retransmit: None,
+            book: FeedBook::new(),
+            next_sequence: None,
+            known_sequence: 1,
+            pending: BTreeMap::new(),
+            requested: None,
+            partial_snapshot: None,
+        })
+    }
+
+    pub fn book(&self) -> &FeedBook {
+        &self.book
+    }
+
+    /// The sequence number of the next message to apply, None until the book is in sync.
+    pub fn next_sequence(&self) -> Option<u64> {
+        self.next_sequence
+    }
+
+    /// Reads what arrived on the channel and brings the book as far up to date as it can,
+    /// asking for whatever is missing. Returns the number of messages applied.
+    pub fn poll(&mut self) -> Result<usize, Error> {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        while let Some(len) = receive(&self.incremental, &mut buf)? {
+            match Packet::decode(&buf[..len], false) {
+                Ok(packet) => self.on_packet(packet),
+                Err(e) => warn!("Dropping packet on channel {}: {}", self.config.channel(), e),
+            }
+        }
+        while let Some(len) = receive(&self.snapshot, &mut buf)? {
+            if self.next_sequence.is_some() {
+                continue;
+            }
+            match Packet::decode(&buf[..len], true) {
+                Ok(packet) => self.on_snapshot(packet),
+                Err(e) => warn!("Dropping snapshot on channel {}: {}", self.config.channel(), e),
+            }
+        }
+        self.read_retransmissions();
+        let applied = self.apply_pending();
+        self.request_missing();
+        Ok(applied)
+    }
+
+    fn on_packet(&mut self, packet: Packet) {
+        if packet.channel != self.config.channel() {
+            return;
+        }
+        let end = packet.sequence + packet.messages.len() as u64;
+        self.known_sequence = self.known_sequence.max(end);
+        // Whoever sees the first message needs no snapshot
+        if self.next_sequence.is_none() && packet.sequence == 1 {
+            self.book = FeedBook::new();
+            self.next_sequence = Some(1);
+        }
+        let next = self.next_sequence.unwrap_or(0);
+        for (i, message) in packet.messages.into_iter().enumerate() {
+            let sequence = packet.sequence + i as u64;
+            if sequence >= next {
+                self.pending.insert(sequence, message);
+            }
+        }
+    }
+
+    fn on_snapshot(&mut self, packet: Packet) {
+        if packet.channel != self.config.channel() || packet.part >= packet.parts {
+            return;
+        }
+        let parts = usize::from(packet.parts);
+        let fresh = match self.partial_snapshot {
+            Some(ref snapshot) => {
+                snapshot.sequence != packet.sequence || snapshot.parts.len() != parts
+            }
+            None => true,
+        };
+        if fresh {
+            self.partial_snapshot = Some(PartialSnapshot {
+                sequence: packet.sequence,
+                parts: vec![None; parts],
+            });
+        }
+        let complete = {
+            let snapshot = self.partial_snapshot.as_mut().unwrap(); // Safe, set above
+            snapshot.parts[usize::from(packet.part)] = Some(packet.messages);
+            snapshot.parts.iter().all(|part| part.is_some())
+        };
+        if !complete {
+            return;
+        }
+
+        let snapshot = self.partial_snapshot.take().unwrap(); // Safe, checked above
+        let mut book = FeedBook::new();
+        for message in snapshot.parts.into_iter().flat_map(|part| part.unwrap_or_default()) {
+            if let Err(e) = book.apply(&message.event()) {
+                warn!("Dropping snapshot on channel {}: {}", self.config.channel(), e);
+                return;
+            }
+        }
+        let next = snapshot.sequence + 1;
+        info!("Channel {} in sync from message {}", self.config.channel(), next);
+        self.book = book;
+        self.next_sequence = Some(next);
+        self.known_sequence = self.known_sequence.max(next);
+        self.pending = self.pending.split_off(&next);
+    }
+
+    /// Reads the retransmission service's replies.
+    fn read_retransmissions(&mut self) {
+        let mut packets = Vec::new();
+        let mut closed = match self.retransmit {
+            Some(ref mut connection) => {
+                let open = connection.read();
+                let mut pos = 0;
+                while connection.inbound.len() - pos >= 2 {
+                    let len = usize::from(get_u16(&connection.inbound, pos));
+                    if connection.inbound.len() - pos - 2 < len {
+                        break;
+                    }
+                    let data = &connection.inbound[pos + 2..pos + 2 + len];
+                    packets.push(Packet::decode(data, false));
+                    pos += 2 + len;
+                }
+                connection.inbound.drain(..pos);
+                !open
+            }
+            None => return,
+        };
+        for packet in packets {
+            match packet {
+                Ok(packet) => self.on_retransmission(packet),
+                Err(e) => {
+                    warn!("Garbled retransmission on channel {}: {}", self.config.channel(), e);
+                    closed = true;
+                }
+            }
+        }
+        if closed {
+            self.retransmit = None;
+            self.requested = None;
+        }
+    }
+
+    fn on_retransmission(&mut self, packet: Packet) {
+        let (start, end) = match self.requested {
+            Some(range) => range,
+            None => return,
+        };
+        if packet.sequence > start {
+            warn!(
+                "Channel {} lost messages {} to {}, waiting for a snapshot",
+                self.config.channel(),
+                start,
+                packet.sequence - 1
+            );
+            self.start_over();
+            self.on_packet(packet);
+            return;
+        }
+        let start = packet.sequence + packet.messages.len() as u64;
+        self.requested = if packet.messages.is_empty() || start >= end {
+            None
+        } else {
+            Some((start, end))
+        };
+        self.on_packet(packet);
+    }
+
+    /// Applies the pending messages that follow on from the book, returning how many.
+    fn apply_pending(&mut self) -> usize {
+        let start = match self.next_sequence {
+            Some(next) => next,
+            None => return 0,
+        };
+        let mut next = start;
+        while let Some(message) = self.pending.remove(&next) {
+            if let Err(e) = self.book.apply(&message.event()) {
+                warn!(
+                    "Channel {} out of sync at message {}, waiting for a snapshot: {}",
+                    self.config.channel(),
+                    next,
+                    e
+                );
+                self.start_over();
+                return (next - start) as usize;
+            }
+            next += 1;
+        }
+        self.next_sequence = Some(next);
+        (next - start) as usize
+    }
+
+    /// Drops the book to wait for the next snapshot.
+    fn start_over(&mut self) {
+        self.book = FeedBook::new();
+        self.next_sequence = None;
+        self.requested = None;
+    }
+
+    /// Asks for the messages between the book and the next one received, or the last one
+    /// known to be sent, unless a request is still out.
+    fn request_missing(&mut self) {
+        let next = match self.next_sequence {
+            Some(next) => next,
+            None => return,
+        };
+        let end = match self.pending.keys().next() {
+            Some(sequence) => *sequence,
+            None => self.known_sequence,
+        };
+        if self.requested.is_some() || end <= next {
+            return;
+        }
+        if self.retransmit.is_none() {
+            match connect(&self.config) {
+                Ok(connection) => self.retransmit = Some(connection),
+                Err(e) => {
+                    warn!("Cannot reach {}: {}", self.config.retransmit(), e);
+                    return;
+                }
+            }
+        }
+        let count = (end - next).min(u64::from(u16::max_value())) as u16;
+        let connection = self.retransmit.as_mut().unwrap(); // Safe, connected above
+        encode_request(&mut connection.outbound, self.config.channel(), next, count);
+        if !connection.flush() {
+            self.retransmit = None;
+            return;
+        }
+        debug!(
+            "Channel {} asked for {} messages from {}",
+            self.config.channel(),
+            count,
+            next
+        );
+        self.requested = Some((next, next + u64::from(count)));
+    }
+}
+
+/// A non-blocking socket receiving what is sent to `group` on `interface`.
+fn join_group(group: SocketAddrV4, interface: Ipv4Addr) -> Result<UdpSocket, Error> {
+    let socket = UdpSocket::bind(group)?;
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn connect(config: &ChannelConfig) -> Result<net::Connection<()>, Error> {
+    let stream = TcpStream::connect(config.retransmit())?;
+    stream.set_nonblocking(true)?;
+    stream.set_nodelay(true)?;
+    Ok(net::Connection::new(stream, config.retransmit()))
+}
+
+/// The length of the next datagram waiting on `socket`, read into `buf`.
+fn receive(socket: &UdpSocket, buf: &mut [u8]) -> Result<Option<usize>, Error> {
+    loop {
+        match socket.recv(buf) {
+            Ok(len) => return Ok(Some(len)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::itch::FeedPublisher;
+    use crate::model::{px, Instrument, OrderEvent, Side};
+    use crate::order_book::OrderBook;
+    use chrono::{Duration, TimeZone, Utc};
+    use std::thread;
+    use std::time;
+
+    /// A port nothing on the host is using right now.
+    fn free_port() -> u16 {
+        UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn config() -> ChannelConfig {
+        let group = Ipv4Addr::new(239, 255, 0, 1);
+        ChannelConfig::new(
+            7,
+            SocketAddrV4::new(group, free_port()),
+            SocketAddrV4::new(group, free_port()),
+            "127.0.0.1:0".parse().unwrap(),
+        ).with_interface(Ipv4Addr::new(127, 0, 0, 1))
+    }
+
+    /// Polls both ends until `check` holds.
+    fn poll_until<F>(publisher: &mut FeedPublisher, subscriber: &mut FeedSubscriber, check: F)
+    where
+        F: Fn(&FeedPublisher, &FeedSubscriber) -> bool,
+    {
+        for _ in 0..2_000 {
+            publisher.poll().unwrap();
+            subscriber.poll().unwrap();
+            if check(&*publisher, &*subscriber) {
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        panic!(
+            "Subscriber stuck at {:?} of {}",
+            subscriber.next_sequence(),
+            publisher.next_sequence()
+        );
+    }
+
+    /// Polls until the subscriber has everything published, then checks its book.
+    fn catch_up(
+        publisher: &mut FeedPublisher,
+        subscriber: &mut FeedSubscriber,
+        book: &OrderBook,
+    ) {
+        poll_until(publisher, subscriber, |publisher, subscriber| {
+            subscriber.next_sequence() == Some(publisher.next_sequence())
+        });
+        for side in &[Side::Bid, Side::Ask] {
+            assert_eq!(subscriber.book().full_depth(*side), book.full_depth(*side));
+        }
+        assert_eq!(subscriber.book().phase(), book.phase());
+        assert_eq!(subscriber.book().last_traded_price(), book.last_traded_price());
+    }
+
+    /// Takes the next packet off the subscriber's incremental socket before it sees it.
+    fn lose_packet(subscriber: &FeedSubscriber) {
+        let mut buf = [0u8; MAX_DATAGRAM_LEN];
+        for _ in 0..2_000 {
+            if receive(&subscriber.incremental, &mut buf).unwrap().is_some() {
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        panic!("No packet to lose");
+    }
+
+    #[test]
+    fn recovers_lost_messages_and_late_joins() {
+        ::crate::model::test_setup();
+        let clock = ManualClock::new(Utc.ymd(2018, 4, 2).and_hms(9, 0, 0));
+        let instrument = Instrument::new("AUDUSD");
+        let mut book = OrderBook::with_clock(instrument, Box::new(clock.clone()));
+        let mut publisher = FeedPublisher::bind(config()).unwrap();
+        publisher.set_clock(Box::new(clock.clone()));
+        let config = publisher
+            .config()
+            .clone()
+            .with_retransmit(publisher.retransmit_addr().unwrap());
+        let mut subscriber = FeedSubscriber::join(config.clone()).unwrap();
+
+        book.event(OrderEvent::limit(1, Side::Bid, px(100), 50)).unwrap();
+        book.event(OrderEvent::limit(2, Side::Ask, px(102), 30)).unwrap();
+        publisher.publish(&mut book).unwrap();
+        catch_up(&mut publisher, &mut subscriber, &book);
+
+        // Noticed from the packet after the lost one
+        book.event(OrderEvent::limit(3, Side::Bid, px(101), 20)).unwrap();
+        publisher.publish(&mut book).unwrap();
+        lose_packet(&subscriber);
+        book.event(OrderEvent::market(4, Side::Ask, 30)).unwrap();
+        publisher.publish(&mut book).unwrap();
+        catch_up(&mut publisher, &mut subscriber, &book);
+        assert_eq!(subscriber.book().order_qty(1), Some(40));
+
+        // Noticed from the heartbeat
+        book.event(OrderEvent::Cancel { id: 1 }).unwrap();
+        publisher.publish(&mut book).unwrap();
+        lose_packet(&subscriber);
+        clock.advance(Duration::seconds(1));
+        catch_up(&mut publisher, &mut subscriber, &book);
+
+        // No longer kept for retransmission, so the subscriber starts over from a snapshot
+        publisher.set_history_len(1);
+        book.event(OrderEvent::limit(5, Side::Ask, px(103), 10)).unwrap();
+        book.event(OrderEvent::limit(6, Side::Ask, px(104), 10)).unwrap();
+        publisher.publish(&mut book).unwrap();
+        lose_packet(&subscriber);
+        book.event(OrderEvent::limit(7, Side::Bid, px(99), 10)).unwrap();
+        publisher.publish(&mut book).unwrap();
+        poll_until(&mut publisher, &mut subscriber, |_, subscriber| {
+            subscriber.next_sequence().is_none()
+        });
+        clock.advance(Duration::seconds(1));
+        catch_up(&mut publisher, &mut subscriber, &book);
+        assert_eq!(subscriber.book().order_qty(6), Some(10));
+
+        drop(subscriber);
+        book.event(OrderEvent::replace(7, Side::Bid, px(98), 5)).unwrap();
+        publisher.publish(&mut book).unwrap();
+        let mut late = FeedSubscriber::join(config).unwrap();
+        book.event(OrderEvent::limit(8, Side::Bid, px(98), 15)).unwrap();
+        publisher.publish(&mut book).unwrap();
+        poll_until(&mut publisher, &mut late, |_, late| !late.pending.is_empty());
+        assert_eq!(late.next_sequence(), None);
+        clock.advance(Duration::seconds(1));
+        catch_up(&mut publisher, &mut late, &book);
+        assert_eq!(late.book().order_qty(8), Some(15));
+    }
+}
@@ -0,0 +1,624 @@
+//! Append-only write-ahead journal of the events applied to an order book.
+//!
+//! The file starts with an 8 byte header: the `OMSJ` magic, a u16 format version and two
+//! reserved bytes. Records follow as `[len: u32][seq: u64][timestamp][record][crc: u32]`
+//! where `len` counts the bytes between the length and the checksum, and the CRC-32 is
+//! taken over those same bytes.
+
+use crate::clock::ManualClock;
+use crate::codec::{self, Decoder};
+use crate::error::OrderBookError;
+use crate::model::{ExecutionReport, MarketDataEvent, OrderEvent, OrderFeedEvent, OrderFill,
+                   TradingPhase};
+use crate::order_book::OrderBook;
+use crate::snapshot::{self, Snapshot};
+use chrono::{DateTime, Utc};
+use failure::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"OMSJ";
+const VERSION: u16 = 3;
+const HEADER_LEN: usize = 8;
+
+/// Something that changed the book and has to be applied again on recovery.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalRecord {
+    Event(OrderEvent),
+    /// A sweep of expired Day and GTD orders
+    ExpireOrders,
+    /// A move to another trading phase. Circuit breaker halts are not recorded, replaying
+    /// the trades that tripped them halts the book again.
+    Phase(TradingPhase),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JournalEntry {
+    seq: u64,
+    timestamp: DateTime<Utc>,
+    record: JournalRecord,
+}
+
+impl JournalEntry {
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn record(&self) -> JournalRecord {
+        self.record
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    next_seq: u64,
+    sync: bool,
+}
+
+impl Journal {
+    /// Starts a new, empty journal, replacing any file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        codec::put_u16(&mut header, VERSION);
+        codec::put_u16(&mut header, 0);
+        file.write_all(&header)?;
+        file.sync_all()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            next_seq: 1,
+            sync: true,
+        })
+    }
+
+    /// Opens an existing journal for appending and returns the entries already in it. A
+    /// record left half written by a crash is cut off the end of the file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Self, Vec<JournalEntry>), Error> {
+        let path = path.as_ref();
+        let (entries, valid_len) = read_entries(path)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len();
+        if valid_len < len {
+            warn!(
+                "Truncating {} bytes of torn journal tail in {:?}",
+                len - valid_len,
+                path
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        let next_seq = entries.last().map_or(1, |entry| entry.seq + 1);
+        let journal = Self {
+            path: path.to_path_buf(),
+            file,
+            next_seq,
+            sync: true,
+        };
+        Ok((journal, entries))
+    }
+
+    /// Whether each append is flushed to disk before it returns. On by default.
+    pub fn set_sync(&mut self, sync: bool) {
+        self.sync = sync;
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Writes a record to the end of the journal, returning its sequence number.
+    pub fn append(
+        &mut self,
+        timestamp: DateTime<Utc>,
+        record: &JournalRecord,
+    ) -> Result<u64, Error> {
+        let seq = self.next_seq;
+        let mut body = Vec::with_capacity(64);
+        codec::put_u64(&mut body, seq);
+        codec::put_time(&mut body, timestamp);
+        match *record {
+            JournalRecord::Event(ref event) => {
+                codec::put_u8(&mut body, 1);
+                codec::put_event(&mut body, event);
+            }
+            JournalRecord::ExpireOrders => codec::put_u8(&mut body, 2),
+            JournalRecord::Phase(phase) => {
+                codec::put_u8(&mut body, 3);
+                codec::put_phase(&mut body, phase);
+            }
+        }
+
+        let mut data = Vec::with_capacity(body.len() + 8);
+        codec::put_u32(&mut data, body.len() as u32);
+        data.extend_from_slice(&body);
+        codec::put_u32(&mut data, codec::crc32(&body));
+        self.file.write_all(&data)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        self.next_seq += 1;
+        Ok(seq)
+    }
+}
+
+/// Reads every intact entry of a journal. Returns them with the length of the file up to
+/// the end of the last intact record.
+///
+/// An incomplete record at the end of the file is taken to be a write torn by a crash and
+/// is left out. A damaged record with more data after it is reported as an error.
+pub fn read_entries<P: AsRef<Path>>(path: P) -> Result<(Vec<JournalEntry>, u64), Error> {
+    let path = path.as_ref();
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        bail!("{:?} is not a journal", path);
+    }
+    let version = Decoder::new(&data[MAGIC.len()..HEADER_LEN]).u16()?;
+    if version != VERSION {
+        bail!("Unsupported journal version {} in {:?}", version, path);
+    }
+
+    let mut entries: Vec<JournalEntry> = Vec::new();
+    let mut pos = HEADER_LEN;
+    while pos < data.len() {
+        let (entry, len) = match read_record(&data[pos..])
+            .map_err(|e| format_err!("Journal {:?} corrupted at offset {}: {}", path, pos, e))?
+        {
+            Some(record) => record,
+            None => break,
+        };
+        if let Some(last) = entries.last() {
+            if entry.seq != last.seq + 1 {
+                bail!(
+                    "Journal {:?} jumps from sequence {} to {}",
+                    path,
+                    last.seq,
+                    entry.seq
+                );
+            }
+        }
+        entries.push(entry);
+        pos += len;
+    }
+    Ok((entries, pos as u64))
+}
+
+/// Decodes the record at the start of `data`, returning it with its length on disk, or
+/// None if the record is torn.
+fn read_record(data: &[u8]) -> Result<Option<(JournalEntry, usize)>, Error> {
+    if data.len() < 4 {
+        return Ok(None);
+    }
+    let len = Decoder::new(&data[..4]).u32()? as usize;
+    let total_len = 4 + len + 4;
+    if data.len() < total_len {
+        return Ok(None);
+    }
+    let body = &data[4..4 + len];
+    let crc = Decoder::new(&data[4 + len..total_len]).u32()?;
+    if crc != codec::crc32(body) {
+        if data.len() == total_len {
+            return Ok(None);
+        }
+        bail!("checksum mismatch");
+    }
+
+    let mut decoder = Decoder::new(body);
+    let seq = decoder.u64()?;
+    let timestamp = decoder.time()?;
+    let record = match decoder.u8()? {
+        1 => JournalRecord::Event(decoder.event()?),
+        2 => JournalRecord::ExpireOrders,
+        3 => JournalRecord::Phase(decoder.phase()?),
+        other => bail!("unknown record type {}", other),
+    };
+    let entry = JournalEntry {
+        seq,
+        timestamp,
+        record,
+    };
+    Ok(Some((entry, total_len)))
+}
+
+/// Applies journal entries to a book. The book's clock follows the journal timestamps
+/// for the duration of the replay so that expiries happen as they did originally.
+/// Reports and market data produced by the replay are discarded.
+pub fn replay(book: &mut OrderBook, entries: &[JournalEntry]) -> Result<(), Error> {
+    let first = match entries.first() {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    let clock = ManualClock::new(first.timestamp);
+    let live_clock = book.set_clock(Box::new(clock.clone()));
+
+    let mut result = Ok(());
+    for entry in entries {
+        clock.set(entry.timestamp);
+        let applied = match entry.record {
+            JournalRecord::Event(event) => book.event(event).map(|_| ()),
+            JournalRecord::ExpireOrders => book.expire_orders().map(|_| ()),
+            JournalRecord::Phase(phase) => book.set_phase(phase).map(|_| ()),
+        };
+        if let Err(e) = applied {
+            result = Err(format_err!("Replay failed at sequence {}: {}", entry.seq, e));
+            break;
+        }
+    }
+
+    book.set_clock(live_clock);
+    book.drain_reports();
+    book.drain_market_data();
+    book.drain_order_feed();
+    result
+}
+
+/// An order book that journals every event before applying it.
+#[derive(Debug)]
+pub struct JournaledBook {
+    book: OrderBook,
+    journal: Journal,
+}
+
+impl JournaledBook {
+    /// Recovers `book`, which should be empty, from the journal at `path`. Starts a new
+    /// journal if there is no file there yet.
+    pub fn open<P: AsRef<Path>>(path: P, mut book: OrderBook) -> Result<Self, Error> {
+        let journal = if path.as_ref().exists() {
+            let (journal, entries) = Journal::open(path)?;
+            info!(
+                "Replaying {} journal entries into {}",
+                entries.len(),
+                book.instrument().symbol()
+            );
+            replay(&mut book, &entries)?;
+            journal
+        } else {
+            Journal::create(path)?
+        };
+        Ok(Self { book, journal })
+    }
+
+    /// Restores the book from `snapshot` and replays the entries of the journal at `path`
+    /// written after it was taken.
+    pub fn open_from_snapshot<P: AsRef<Path>>(path: P, snapshot: Snapshot) -> Result<Self, Error> {
+        let journal_seq = snapshot.journal_seq();
+        let mut book = snapshot.into_book();
+        let (journal, entries) = Journal::open(path)?;
+        if journal.next_seq() <= journal_seq {
+            bail!(
+                "Journal {:?} ends at sequence {}, before the snapshot at {}",
+                journal.path(),
+                journal.next_seq() - 1,
+                journal_seq
+            );
+        }
+        let tail: Vec<JournalEntry> = entries
+            .into_iter()
+            .filter(|entry| entry.seq > journal_seq)
+            .collect();
+        if let Some(first) = tail.first() {
+            if first.seq != journal_seq + 1 {
+                bail!(
+                    "Journal {:?} starts at sequence {}, after the snapshot at {}",
+                    journal.path(),
+                    first.seq,
+                    journal_seq
+                );
+            }
+        }
+        info!(
+            "Replaying {} journal entries after snapshot at {}",
+            tail.len(),
+            journal_seq
+        );
+        replay(&mut book, &tail)?;
+        Ok(Self { book, journal })
+    }
+
+    pub fn from_parts(book: OrderBook, journal: Journal) -> Self {
+        Self { book, journal }
+    }
+
+    /// Writes a snapshot of the book tagged with the last journal sequence applied to it.
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        snapshot::save(path, &self.book, self.journal.next_seq() - 1)
+    }
+
+    pub fn event(&mut self, event: OrderEvent) -> Result<Vec<OrderFill>, Error> {
+        let now = self.book.now();
+        self.journal.append(now, &JournalRecord::Event(event))?;
+        Ok(self.book.event(event)?)
+    }
+
+    pub fn expire_orders(&mut self) -> Result<usize, Error> {
+        let now = self.book.now();
+        self.journal.append(now, &JournalRecord::ExpireOrders)?;
+        Ok(self.book.expire_orders()?)
+    }
+
+    /// Moves the book to another trading phase. A move the book would refuse is not
+    /// journaled, so it cannot stop the journal from replaying.
+    pub fn set_phase(&mut self, phase: TradingPhase) -> Result<Vec<OrderFill>, Error> {
+        let current = self.book.phase();
+        if !current.can_move_to(phase) {
+            return Err(OrderBookError::InvalidPhaseTransition(current, phase).into());
+        }
+        let now = self.book.now();
+        self.journal.append(now, &JournalRecord::Phase(phase))?;
+        Ok(self.book.set_phase(phase)?)
+    }
+
+    pub fn drain_reports(&mut self) -> Vec<ExecutionReport> {
+        self.book.drain_reports()
+    }
+
+    pub fn drain_market_data(&mut self) -> Vec<MarketDataEvent> {
+        self.book.drain_market_data()
+    }
+
+    pub fn drain_order_feed(&mut self) -> Vec<OrderFeedEvent> {
+        self.book.drain_order_feed()
+    }
+
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{px, Instrument, Price, Side, TimeInForce};
+    use chrono::TimeZone;
+    use std::{env, fs, process};
+
+    fn temp_journal(name: &str) -> PathBuf {
+        ::crate::model::test_setup();
+
+        let path = env::temp_dir().join(format!("oms-{}-{}.journal", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn new_book() -> OrderBook {
+        OrderBook::new(Instrument::new("AUDUSD"))
+    }
+
+    fn events() -> Vec<OrderEvent> {
+        vec![
+            OrderEvent::limit(1, Side::Ask, px(101), 100),
+            OrderEvent::limit(2, Side::Ask, px(102), 50),
+            OrderEvent::limit(3, Side::Bid, px(99), 70),
+            OrderEvent::limit(4, Side::Bid, px(101), 30),
+            OrderEvent::replace(3, Side::Bid, px(100), 70),
+            OrderEvent::iceberg(5, Side::Bid, px(98), 60, 20),
+            OrderEvent::Cancel { id: 2 },
+            OrderEvent::market(6, Side::Ask, 10),
+        ]
+    }
+
+    fn file_len(path: &Path) -> u64 {
+        fs::metadata(path).unwrap().len()
+    }
+
+    #[test]
+    fn recovers_book_from_journal() {
+        let path = temp_journal("recover");
+        let mut live = JournaledBook::open(&path, new_book()).unwrap();
+        for event in events() {
+            live.event(event).unwrap();
+        }
+
+        let recovered = JournaledBook::open(&path, new_book()).unwrap();
+        for side in &[Side::Bid, Side::Ask] {
+            assert_eq!(
+                recovered.book().full_depth(*side),
+                live.book().full_depth(*side)
+            );
+        }
+        assert_eq!(
+            recovered.book().last_traded_price(),
+            live.book().last_traded_price()
+        );
+        assert_eq!(recovered.journal().next_seq(), 9);
+
+        // Both books keep matching the same way
+        let mut recovered = recovered;
+        let next = OrderEvent::market(7, Side::Ask, 100);
+        let expected: Vec<(u64, Price, u64)> = live.event(next)
+            .unwrap()
+            .iter()
+            .map(|f| (f.ord_id_2(), f.price(), f.qty()))
+            .collect();
+        let actual: Vec<(u64, Price, u64)> = recovered
+            .event(next)
+            .unwrap()
+            .iter()
+            .map(|f| (f.ord_id_2(), f.price(), f.qty()))
+            .collect();
+        assert_eq!(actual, expected);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn does_not_journal_refused_phase_change() {
+        let path = temp_journal("phase");
+        let mut live = JournaledBook::open(&path, new_book()).unwrap();
+        live.event(OrderEvent::limit(1, Side::Bid, px(100), 10)).unwrap();
+        assert!(live.set_phase(TradingPhase::OpeningAuction).is_err());
+        live.set_phase(TradingPhase::Closed).unwrap();
+        assert_eq!(live.journal().next_seq(), 3);
+
+        let recovered = JournaledBook::open(&path, new_book()).unwrap();
+        assert_eq!(recovered.book().phase(), TradingPhase::Closed);
+        assert_eq!(
+            recovered.book().full_depth(Side::Bid),
+            live.book().full_depth(Side::Bid)
+        );
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn truncates_record_torn_mid_write() {
+        let path = temp_journal("torn");
+        let events = events();
+        {
+            let mut journal = Journal::create(&path).unwrap();
+            for event in &events[..3] {
+                journal
+                    .append(Utc::now(), &JournalRecord::Event(*event))
+                    .unwrap();
+            }
+        }
+        let intact_len = file_len(&path);
+        {
+            let (mut journal, _) = Journal::open(&path).unwrap();
+            journal
+                .append(Utc::now(), &JournalRecord::Event(events[3]))
+                .unwrap();
+        }
+        // Simulate the process dying part way through the last write
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(intact_len + 5).unwrap();
+        drop(file);
+
+        let (mut journal, entries) = Journal::open(&path).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(file_len(&path), intact_len);
+        assert_eq!(journal.next_seq(), 4);
+        journal
+            .append(Utc::now(), &JournalRecord::Event(events[4]))
+            .unwrap();
+
+        let (entries, _) = read_entries(&path).unwrap();
+        let seqs: Vec<u64> = entries.iter().map(|e| e.seq()).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4]);
+        assert_eq!(entries[3].record(), JournalRecord::Event(events[4]));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn drops_tail_record_with_bad_checksum() {
+        let path = temp_journal("tail-crc");
+        {
+            let mut journal = Journal::create(&path).unwrap();
+            for event in &events()[..2] {
+                journal
+                    .append(Utc::now(), &JournalRecord::Event(*event))
+                    .unwrap();
+            }
+        }
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        fs::write(&path, &data).unwrap();
+
+        let (_, entries) = Journal::open(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_corruption_before_the_tail() {
+        let path = temp_journal("corrupt");
+        {
+            let mut journal = Journal::create(&path).unwrap();
+            for event in &events()[..2] {
+                journal
+                    .append(Utc::now(), &JournalRecord::Event(*event))
+                    .unwrap();
+            }
+        }
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_LEN + 6] ^= 0xFF;
+        fs::write(&path, &data).unwrap();
+
+        assert!(Journal::open(&path).is_err());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn recovers_from_snapshot_and_journal_tail() {
+        let path = temp_journal("snapshot");
+        let snapshot_path = path.with_extension("snapshot");
+        let events = events();
+        let mut live = JournaledBook::open(&path, new_book()).unwrap();
+        for event in &events[..4] {
+            live.event(*event).unwrap();
+        }
+        live.save_snapshot(&snapshot_path).unwrap();
+        for event in &events[4..] {
+            live.event(*event).unwrap();
+        }
+
+        let snapshot = snapshot::load(&snapshot_path).unwrap();
+        assert_eq!(snapshot.journal_seq(), 4);
+        let recovered = JournaledBook::open_from_snapshot(&path, snapshot).unwrap();
+        for side in &[Side::Bid, Side::Ask] {
+            assert_eq!(
+                recovered.book().full_depth(*side),
+                live.book().full_depth(*side)
+            );
+        }
+        assert_eq!(recovered.journal().next_seq(), live.journal().next_seq());
+
+        // A snapshot taken after the end of the journal cannot be used with it
+        let stale_path = temp_journal("snapshot-stale");
+        JournaledBook::open(&stale_path, new_book()).unwrap();
+        let snapshot = snapshot::load(&snapshot_path).unwrap();
+        assert!(JournaledBook::open_from_snapshot(&stale_path, snapshot).is_err());
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&snapshot_path);
+        let _ = fs::remove_file(&stale_path);
+    }
+
+    #[test]
+    fn replay_follows_journal_time() {
+        let path = temp_journal("clock");
+        let clock = ManualClock::new(Utc.ymd(2018, 4, 2).and_hms(9, 0, 0));
+        let book = OrderBook::with_clock(Instrument::new("AUDUSD"), Box::new(clock.clone()));
+        let mut live = JournaledBook::open(&path, book).unwrap();
+        live.event(OrderEvent::limit_with_tif(1, Side::Bid, px(100), 10, TimeInForce::Day))
+            .unwrap();
+        live.event(OrderEvent::limit_with_tif(2, Side::Bid, px(100), 10, TimeInForce::Day))
+            .unwrap();
+        clock.advance(::chrono::Duration::hours(2));
+        live.event(OrderEvent::market(3, Side::Ask, 5)).unwrap();
+        clock.advance(::chrono::Duration::days(1));
+        live.expire_orders().unwrap();
+        live.event(OrderEvent::limit(4, Side::Bid, px(100), 10)).unwrap();
+
+        // Replaying on a wall clock years later must not expire the Day orders early
+        let recovered = JournaledBook::open(&path, new_book()).unwrap();
+        assert_eq!(
+            recovered.book().full_depth(Side::Bid),
+            live.book().full_depth(Side::Bid)
+        );
+        assert_eq!(recovered.book().best_bid().unwrap().qty(), 10);
+        let _ = fs::remove_file(&path);
+    }
+}
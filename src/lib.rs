@@ -4,6 +4,8 @@
 #![feature(match_default_bindings)]
 
 extern crate chrono;
+extern crate csv;
+#[macro_use]
 extern crate failure;
 #[macro_use]
 extern crate lazy_static;
@@ -17,6 +19,24 @@ extern crate serde_derive;
 #[cfg(test)]
 extern crate env_logger;
 
+pub mod allocation;
+pub mod auction;
+pub mod clock;
+mod codec;
+pub mod engine;
+pub mod error;
+pub mod fix;
+pub mod generator;
+pub mod histogram;
+pub mod itch;
+pub mod journal;
+pub mod loader;
 pub mod model;
+mod net;
 pub mod order_book;
+pub mod ouch;
+pub mod price;
+pub mod replay;
+pub mod risk;
+pub mod snapshot;
 mod order_list;
@@ -0,0 +1,264 @@
+//! Reads and writes order files in the quantcup CSV layout, `Trader,Side,Price,Size`
+//! with a header row, extended by three optional columns: `Type`, `Symbol` and `Id`.
+//!
+//! Without a type, a row with a zero price cancels the order whose id is in the size
+//! column and every other row is a new limit order. `Type` is one of `Limit`, `Market`,
+//! `Cancel` or `Replace`. New orders without an `Id` are numbered from 1 in file order;
+//! a replace needs the id of the order it changes. The trader owns the new orders of a
+//! row, for self-trade prevention.
+
+use crate::model::{OrderEvent, Price, Side};
+use csv;
+use failure::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// One row of an order file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderRecord {
+    pub trader: u64,
+    pub symbol: Option<String>,
+    pub event: OrderEvent,
+}
+
+/// Loads the events of a single-instrument order file. Prices are taken as units of
+/// `price_scale` decimals.
+pub fn load_orders<P: AsRef<Path>>(path: P, price_scale: u8) -> Result<Vec<OrderEvent>, Error> {
+    let records = load_records(path, price_scale)?;
+    if let Some(first) = records.first() {
+        if records.iter().any(|record| record.symbol != first.symbol) {
+            bail!("Order file has more than one symbol");
+        }
+    }
+    Ok(records.into_iter().map(|record| record.event).collect())
+}
+
+pub fn load_records<P: AsRef<Path>>(
+    path: P,
+    price_scale: u8,
+) -> Result<Vec<OrderRecord>, Error> {
+    let file = File::open(path.as_ref())
+        .map_err(|e| format_err!("Cannot open order file {:?}: {}", path.as_ref(), e))?;
+    read_records(file, price_scale)
+}
+
+/// Reads order events from CSV, ignoring symbols.
+pub fn read_orders<R: Read>(reader: R, price_scale: u8) -> Result<Vec<OrderEvent>, Error> {
+    Ok(read_records(reader, price_scale)?
+        .into_iter()
+        .map(|record| record.event)
+        .collect())
+}
+
+pub fn read_records<R: Read>(reader: R, price_scale: u8) -> Result<Vec<OrderRecord>, Error> {
+    // Columns are read by position, the sample file has two columns named Side
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(reader);
+    let mut records: Vec<OrderRecord> = Vec::new();
+    let mut ord_id = 0;
+    for (line, result) in rdr.records().enumerate() {
+        let record = result?;
+        let order = convert_to_order(&mut ord_id, &record, price_scale)
+            .map_err(|e| format_err!("Bad order record {}: {}", line + 1, e))?;
+        records.push(order);
+    }
+    Ok(records)
+}
+
+fn column<'a>(record: &'a csv::StringRecord, index: usize) -> Option<&'a str> {
+    record.get(index).and_then(|value| {
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    })
+}
+
+fn number(record: &csv::StringRecord, index: usize, name: &str) -> Result<u64, Error> {
+    let value = column(record, index).ok_or_else(|| format_err!("Missing {}", name))?;
+    value
+        .parse()
+        .map_err(|_| format_err!("Invalid {} {:?}", name, value))
+}
+
+fn convert_to_order(
+    id: &mut u64,
+    record: &csv::StringRecord,
+    price_scale: u8,
+) -> Result<OrderRecord, Error> {
+    let trader = number(record, 0, "trader")?;
+    let side = match column(record, 1) {
+        Some("Bid") => Side::Bid,
+        Some("Ask") => Side::Ask,
+        other => bail!("Invalid side {:?}", other),
+    };
+    let price = Price::new(number(record, 2, "price")?, price_scale);
+    let size = number(record, 3, "size")?;
+    let symbol = column(record, 5).map(String::from);
+    let explicit_id = match column(record, 6) {
+        Some(_) => Some(number(record, 6, "id")?),
+        None => None,
+    };
+    let mut new_order_id = || {
+        explicit_id.unwrap_or_else(|| {
+            *id += 1;
+            *id
+        })
+    };
+
+    let order_type = match column(record, 4) {
+        Some(order_type) => order_type,
+        None if price.is_zero() => "Cancel",
+        None => "Limit",
+    };
+    let event = match order_type {
+        "Limit" => OrderEvent::limit(new_order_id(), side, price, size).with_owner(trader),
+        "Market" => OrderEvent::market(new_order_id(), side, size).with_owner(trader),
+        "Cancel" => OrderEvent::Cancel {
+            id: explicit_id.unwrap_or(size),
+        },
+        "Replace" => match explicit_id {
+            Some(id) => OrderEvent::replace(id, side, price, size),
+            None => bail!("Replace without an order id"),
+        },
+        other => bail!("Unknown order type {:?}", other),
+    };
+    Ok(OrderRecord {
+        trader,
+        symbol,
+        event,
+    })
+}
+
+/// Writes records with all optional columns. Only limit orders good till cancel, market
+/// orders, cancels and replaces can be written. Order owners are not written, the trader
+/// column stands for them when the file is read back.
+pub fn write_records<W: Write>(writer: W, records: &[OrderRecord]) -> Result<(), Error> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(&["Trader", "Side", "Price", "Size", "Type", "Symbol", "Id"])?;
+    for record in records {
+        let (side, price, size, order_type, id) = match record.event {
+            OrderEvent::Limit {
+                id,
+                side,
+                price,
+                qty,
+                tif,
+                ..
+            } if tif == Default::default() => (side, price.units(), qty, "Limit", id),
+            OrderEvent::Market { id, side, qty, .. } => (side, 0, qty, "Market", id),
+            OrderEvent::Cancel { id } => (Side::Bid, 0, id, "Cancel", id),
+            OrderEvent::Replace {
+                id,
+                side,
+                price,
+                qty,
+            } => (side, price.units(), qty, "Replace", id),
+            other => bail!("Cannot write {:?} to an order file", other),
+        };
+        wtr.write_record(&[
+            record.trader.to_string(),
+            format!("{:?}", side),
+            price.to_string(),
+            size.to_string(),
+            order_type.to_string(),
+            record.symbol.clone().unwrap_or_default(),
+            id.to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_limits_and_cancels() {
+        let data = "Trader,Side,Price,Size\n8,Bid,4799,500\n4,Ask,4801,800\n7,Bid,0,1\n";
+        let orders = read_orders(data.as_bytes(), 4).unwrap();
+        assert_eq!(
+            orders,
+            vec![
+                OrderEvent::limit(1, Side::Bid, Price::new(4799, 4), 500).with_owner(8),
+                OrderEvent::limit(2, Side::Ask, Price::new(4801, 4), 800).with_owner(4),
+                OrderEvent::Cancel { id: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_optional_columns() {
+        let data = "Trader,Side,Price,Size,Type,Symbol,Id\n\
+                    8,Bid,4799,500,Limit,AUDUSD,\n\
+                    4,Ask,0,300,Market,EURUSD,\n\
+                    8,Bid,4798,400,Replace,AUDUSD,1\n\
+                    3,Ask,4805,100,,EURUSD,20\n\
+                    8,Bid,0,1,Cancel,AUDUSD\n";
+        let records = read_records(data.as_bytes(), 4).unwrap();
+        let events: Vec<OrderEvent> = records.iter().map(|r| r.event).collect();
+        assert_eq!(
+            events,
+            vec![
+                OrderEvent::limit(1, Side::Bid, Price::new(4799, 4), 500).with_owner(8),
+                OrderEvent::market(2, Side::Ask, 300).with_owner(4),
+                OrderEvent::replace(1, Side::Bid, Price::new(4798, 4), 400),
+                OrderEvent::limit(20, Side::Ask, Price::new(4805, 4), 100).with_owner(3),
+                OrderEvent::Cancel { id: 1 },
+            ]
+        );
+        assert_eq!(records[1].symbol, Some(String::from("EURUSD")));
+        assert_eq!(records[1].trader, 4);
+        assert!(load_orders_from(data).is_err());
+    }
+
+    fn load_orders_from(data: &str) -> Result<Vec<OrderEvent>, Error> {
+        let path = ::std::env::temp_dir().join(format!("oms-loader-{}.csv", ::std::process::id()));
+        ::std::fs::write(&path, data)?;
+        let result = load_orders(&path, 4);
+        let _ = ::std::fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let records = vec![
+            OrderRecord {
+                trader: 1,
+                symbol: Some(String::from("AUDUSD")),
+                event: OrderEvent::limit(1, Side::Ask, Price::new(4801, 4), 800).with_owner(1),
+            },
+            OrderRecord {
+                trader: 2,
+                symbol: None,
+                event: OrderEvent::market(2, Side::Bid, 100).with_owner(2),
+            },
+            OrderRecord {
+                trader: 1,
+                symbol: Some(String::from("AUDUSD")),
+                event: OrderEvent::replace(1, Side::Ask, Price::new(4802, 4), 700),
+            },
+            OrderRecord {
+                trader: 1,
+                symbol: Some(String::from("AUDUSD")),
+                event: OrderEvent::Cancel { id: 1 },
+            },
+        ];
+        let mut data = Vec::new();
+        write_records(&mut data, &records).unwrap();
+        assert_eq!(read_records(&data[..], 4).unwrap(), records);
+    }
+
+    #[test]
+    fn reports_bad_records() {
+        let data = "Trader,Side,Price,Size\n8,Bid,4799,500\n4,Buy,4801,800\n";
+        assert!(read_orders(data.as_bytes(), 4).is_err());
+        let data = "Trader,Side,Price,Size,Type\n8,Bid,4799,500,Replace\n";
+        assert!(read_orders(data.as_bytes(), 4).is_err());
+    }
+}
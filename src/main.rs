@@ -1,109 +1,147 @@
 #![feature(alloc_system)]
 extern crate alloc_system;
 
-extern crate csv;
 extern crate oms;
 
-use std::fs::File;
-use oms::model::{Instrument, OrderEvent, Side};
+use oms::histogram::Histogram;
+use oms::loader;
+use oms::model::{Instrument, OrderEvent};
 use oms::order_book::OrderBook;
-use std::time::Instant;
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
 
-type Record = (u64, Side, u64, u64);
+// Prices in the order file are in units of 10^-4
+const PRICE_SCALE: u8 = 4;
 
-fn main() {
-    let file = File::open("config/orders.csv").unwrap();
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-    let mut orders: Vec<OrderEvent> = Vec::new();
-    let mut ord_id = 0;
-    for result in rdr.deserialize() {
-        let record: Record = result.unwrap();
-        orders.push(convert_to_order(&mut ord_id, record));
-    }
-
-    let total_orders = orders.len();
+const PERCENTILES: [(&str, f64); 4] = [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("p99.9", 99.9)];
 
-    let batch_size: usize = 5000;
-    let replay_count: usize = 200;
-
-    let mut latencies: Vec<u64> = Vec::with_capacity(replay_count * (total_orders / batch_size));
-
-    let mut total_time = 0;
-    for _ in 0..replay_count {
-        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+struct Options {
+    orders: String,
+    warmup_runs: usize,
+    runs: usize,
+    json: bool,
+}
 
-        let mut i = batch_size;
-        while i < total_orders {
-            let begin = Instant::now();
+fn usage() -> ! {
+    eprintln!("usage: oms [--orders FILE] [--warmup RUNS] [--runs RUNS] [--json]");
+    process::exit(2);
+}
 
-            for ord in orders.iter().take(i).skip(i - batch_size) {
-                let _new_fills = ob.event(*ord);
-            }
-            let elapsed = begin.elapsed();
-            let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
-            latencies.push(nanos);
-            total_time += nanos;
-            i += batch_size;
+fn parse_args() -> Options {
+    let mut options = Options {
+        orders: String::from("config/orders.csv"),
+        warmup_runs: 10,
+        runs: 200,
+        json: false,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--orders" => options.orders = args.next().unwrap_or_else(|| usage()),
+            "--warmup" => options.warmup_runs = count(args.next()),
+            "--runs" => options.runs = count(args.next()),
+            "--json" => options.json = true,
+            _ => usage(),
         }
     }
+    options
+}
 
-    let mean: f64 = latencies.as_slice().mean();
-    let std_dev = latencies.as_slice().std_dev();
+fn count(arg: Option<String>) -> usize {
+    arg.and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| usage())
+}
 
-    println!();
-    println!("{: <15} = {:>12} ns", "Total time", total_time);
-    println!("{: <15} = {:>12.0} ns", "Mean per batch", mean);
-    println!("{: <15} = {:>12.0} ns", "SD", std_dev);
-    println!();
+fn nanos(elapsed: Duration) -> u64 {
+    elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos())
 }
 
-fn convert_to_order(id: &mut u64, record: Record) -> OrderEvent {
-    if record.2 == 0 {
-        OrderEvent::Cancel { id: record.3 }
-    } else {
-        *id += 1;
-        OrderEvent::Limit {
-            id: *id,
-            side: record.1,
-            qty: record.3,
-            price: record.2,
-        }
+/// Runs every order through a new book, recording the latency of each event.
+fn replay(orders: &[OrderEvent], histogram: &mut Histogram) {
+    let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD").with_price_scale(PRICE_SCALE));
+    for ord in orders {
+        let begin = Instant::now();
+        let _new_fills = ob.event(*ord);
+        histogram.record(nanos(begin.elapsed()));
+        ob.drain_reports();
+        ob.drain_market_data();
+        ob.drain_order_feed();
     }
 }
 
-// Code below is directly copied from https://github.com/rust-lang/rust/blob/master/src/libtest/stats.rs
-trait Stats {
-    fn mean(&self) -> f64;
-    fn var(&self) -> f64;
-    fn std_dev(&self) -> f64;
-}
+fn main() {
+    let options = parse_args();
+    let orders: Vec<OrderEvent> = loader::load_orders(&options.orders, PRICE_SCALE)
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2);
+        });
+
+    // Warm-up runs fill caches and the allocator but are not reported
+    let mut warmup = Histogram::default();
+    for _ in 0..options.warmup_runs {
+        replay(&orders, &mut warmup);
+    }
 
-impl Stats for [u64] {
-    fn mean(&self) -> f64 {
-        assert!(!self.is_empty());
-        self.iter().sum::<u64>() as f64 / (self.len() as f64)
+    let mut histogram = Histogram::default();
+    let begin = Instant::now();
+    for _ in 0..options.runs {
+        replay(&orders, &mut histogram);
     }
-    fn var(&self) -> f64 {
-        if self.len() < 2 {
-            0.0
-        } else {
-            let mean = self.mean();
-            let mut v: f64 = 0.0;
-            for s in self {
-                let x = *s as f64 - mean;
-                v += x * x;
-            }
-            // NB: this is _supposed to be_ len-1, not len. If you
-            // change it back to len, you will be calculating a
-            // population variance, not a sample variance.
-            let denom = (self.len() - 1) as f64;
-            v / denom
-        }
+    let total_time = nanos(begin.elapsed());
+    let throughput = if total_time == 0 {
+        0.0
+    } else {
+        histogram.count() as f64 * 1e9 / total_time as f64
+    };
+
+    if options.json {
+        let percentiles: Vec<String> = PERCENTILES
+            .iter()
+            .map(|&(name, percentile)| {
+                format!(
+                    "\"{}_ns\":{}",
+                    name,
+                    histogram.value_at_percentile(percentile)
+                )
+            })
+            .collect();
+        println!(
+            "{{\"orders\":{:?},\"events_per_run\":{},\"warmup_runs\":{},\"runs\":{},\
+             \"events\":{},\"total_ns\":{},\"events_per_sec\":{:.0},\"min_ns\":{},\
+             \"mean_ns\":{:.1},{},\"max_ns\":{}}}",
+            options.orders,
+            orders.len(),
+            options.warmup_runs,
+            options.runs,
+            histogram.count(),
+            total_time,
+            throughput,
+            histogram.min(),
+            histogram.mean(),
+            percentiles.join(","),
+            histogram.max()
+        );
+        return;
     }
 
-    fn std_dev(&self) -> f64 {
-        self.var().sqrt()
+    println!();
+    println!("{: <15} = {:>12}", "Events per run", orders.len());
+    println!("{: <15} = {:>12}", "Warm-up runs", options.warmup_runs);
+    println!("{: <15} = {:>12}", "Measured runs", options.runs);
+    println!("{: <15} = {:>12} ns", "Total time", total_time);
+    println!("{: <15} = {:>12.0} /s", "Throughput", throughput);
+    println!();
+    println!("{: <15} = {:>12} ns", "Min", histogram.min());
+    println!("{: <15} = {:>12.1} ns", "Mean", histogram.mean());
+    for &(name, percentile) in PERCENTILES.iter() {
+        println!(
+            "{: <15} = {:>12} ns",
+            name,
+            histogram.value_at_percentile(percentile)
+        );
     }
+    println!("{: <15} = {:>12} ns", "Max", histogram.max());
+    println!();
 }
@@ -1,5 +1,12 @@
+use chrono::{DateTime, Duration, Utc};
+use failure::Error;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+pub use crate::allocation::AllocationPolicy;
+pub use crate::auction::Equilibrium;
+pub use crate::price::Price;
+pub use crate::risk::RiskViolation;
+
 #[derive(Debug, Default)]
 pub struct IdGen {
     seq: AtomicUsize, // Does not need to be atomic as of now.
@@ -32,16 +39,193 @@ pub fn test_setup() {
     let _ = env_logger::try_init();
 }
 
+/// Price of whole units, for tests.
+#[cfg(test)]
+pub fn px(units: u64) -> Price {
+    Price::new(units, 0)
+}
+
+/// Price in ten-thousandths, the scale of OUCH and ITCH wire prices, for tests.
+#[cfg(test)]
+pub fn wire_px(units: u64) -> Price {
+    Price::new(units, 4)
+}
+
+/// Static reference data for a tradable instrument. Every price of the instrument has
+/// `price_scale` decimals, and the tick size is in units of that scale.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Instrument {
     symbol: String,
+    price_scale: u8,
+    tick_size: u64,
+    lot_size: u64,
+    min_qty: u64,
+    max_qty: u64,
+    reference_price: Option<Price>,
+    // Allowed distance from the reference price, in basis points
+    price_band_bps: Option<u64>,
+    allocation_policy: AllocationPolicy,
+    self_trade_prevention: Option<SelfTradePrevention>,
+    circuit_breaker: Option<CircuitBreaker>,
 }
 
 impl Instrument {
     pub fn new(sym: &str) -> Self {
         Self {
             symbol: String::from(sym),
+            price_scale: 0,
+            tick_size: 1,
+            lot_size: 1,
+            min_qty: 1,
+            max_qty: u64::max_value(),
+            reference_price: None,
+            price_band_bps: None,
+            allocation_policy: AllocationPolicy::Fifo,
+            self_trade_prevention: None,
+            circuit_breaker: None,
+        }
+    }
+
+    pub fn with_price_scale(mut self, price_scale: u8) -> Self {
+        self.price_scale = price_scale;
+        self
+    }
+
+    pub fn with_tick_size(mut self, tick_size: u64) -> Self {
+        assert!(tick_size > 0, "Tick size must be positive");
+        self.tick_size = tick_size;
+        self
+    }
+
+    pub fn with_lot_size(mut self, lot_size: u64) -> Self {
+        assert!(lot_size > 0, "Lot size must be positive");
+        self.lot_size = lot_size;
+        self
+    }
+
+    pub fn with_qty_limits(mut self, min_qty: u64, max_qty: u64) -> Self {
+        assert!(min_qty <= max_qty, "Minimum quantity is above maximum");
+        self.min_qty = min_qty;
+        self.max_qty = max_qty;
+        self
+    }
+
+    pub fn with_price_band(mut self, reference_price: Price, band_bps: u64) -> Self {
+        assert_eq!(
+            reference_price.scale(),
+            self.price_scale,
+            "Reference price scale does not match the instrument"
+        );
+        self.reference_price = Some(reference_price);
+        self.price_band_bps = Some(band_bps);
+        self
+    }
+
+    /// How a price level is shared among resting orders when an order trades with it.
+    pub fn with_allocation_policy(mut self, allocation_policy: AllocationPolicy) -> Self {
+        self.allocation_policy = allocation_policy;
+        self
+    }
+
+    /// Keeps orders with the same owner from trading with each other.
+    pub fn with_self_trade_prevention(mut self, mode: SelfTradePrevention) -> Self {
+        self.self_trade_prevention = Some(mode);
+        self
+    }
+
+    /// Halts trading when a trade would move too far from the reference price.
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn price_scale(&self) -> u8 {
+        self.price_scale
+    }
+
+    /// A price of this instrument made of `units` of its smallest increment.
+    pub fn price(&self, units: u64) -> Price {
+        Price::new(units, self.price_scale)
+    }
+
+    pub fn parse_price(&self, s: &str) -> Result<Price, Error> {
+        Price::parse(s, self.price_scale)
+    }
+
+    pub fn tick_size(&self) -> u64 {
+        self.tick_size
+    }
+
+    pub fn lot_size(&self) -> u64 {
+        self.lot_size
+    }
+
+    pub fn min_qty(&self) -> u64 {
+        self.min_qty
+    }
+
+    pub fn max_qty(&self) -> u64 {
+        self.max_qty
+    }
+
+    pub fn reference_price(&self) -> Option<Price> {
+        self.reference_price
+    }
+
+    pub fn price_band_bps(&self) -> Option<u64> {
+        self.price_band_bps
+    }
+
+    pub fn allocation_policy(&self) -> AllocationPolicy {
+        self.allocation_policy
+    }
+
+    pub fn self_trade_prevention(&self) -> Option<SelfTradePrevention> {
+        self.self_trade_prevention
+    }
+
+    pub fn circuit_breaker(&self) -> Option<CircuitBreaker> {
+        self.circuit_breaker
+    }
+
+    pub fn set_reference_price(&mut self, reference_price: Price) {
+        self.reference_price = Some(reference_price);
+    }
+
+    pub fn validate_qty(&self, qty: u64) -> Result<(), RejectReason> {
+        if qty == 0 {
+            Err(RejectReason::InvalidQuantity)
+        } else if qty % self.lot_size != 0 {
+            Err(RejectReason::QtyNotOnLot)
+        } else if qty < self.min_qty {
+            Err(RejectReason::QtyBelowMinimum)
+        } else if qty > self.max_qty {
+            Err(RejectReason::QtyAboveMaximum)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn validate_price(&self, price: Price) -> Result<(), RejectReason> {
+        if price.scale() != self.price_scale || price.is_zero() {
+            return Err(RejectReason::InvalidPrice);
         }
+        if price.units() % self.tick_size != 0 {
+            return Err(RejectReason::PriceNotOnTick);
+        }
+        if let (Some(reference), Some(band_bps)) = (self.reference_price, self.price_band_bps) {
+            let distance = price
+                .distance(reference)
+                .ok_or(RejectReason::InvalidPrice)?;
+            if distance.saturating_mul(10_000) > reference.units().saturating_mul(band_bps) {
+                return Err(RejectReason::PriceOutsideBand);
+            }
+        }
+        Ok(())
     }
 }
 
@@ -51,18 +235,271 @@ pub enum Side {
     Ask,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Side {
+    pub fn opposite(&self) -> Side {
+        match *self {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        }
+    }
+}
+
+/// Aggregated quantity resting at one price.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Level {
+    price: Price,
+    qty: u64,
+    order_count: usize,
+}
+
+impl Level {
+    pub fn new(price: Price, qty: u64, order_count: usize) -> Self {
+        Self {
+            price,
+            qty,
+            order_count,
+        }
+    }
+
+    pub fn price(&self) -> Price {
+        self.price
+    }
+
+    pub fn qty(&self) -> u64 {
+        self.qty
+    }
+
+    pub fn order_count(&self) -> usize {
+        self.order_count
+    }
+}
+
+/// Incremental change to the public view of a book.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MarketDataEvent {
+    LevelAdd {
+        side: Side,
+        price: Price,
+        qty: u64,
+    },
+    LevelUpdate {
+        side: Side,
+        price: Price,
+        qty: u64,
+    },
+    LevelDelete {
+        side: Side,
+        price: Price,
+    },
+    Trade {
+        last_traded_price: Price,
+        qty: u64,
+        aggressor: Side,
+    },
+    /// Where the auction would uncross now, None if nothing would trade
+    Indicative {
+        equilibrium: Option<Equilibrium>,
+    },
+    /// The auction uncrossed, trading `qty` in total at one price
+    Uncross {
+        price: Price,
+        qty: u64,
+    },
+    PhaseChange {
+        phase: TradingPhase,
+    },
+}
+
+/// Change to one order in the public view of a book, for order-by-order feeds. Only the
+/// displayed quantity of an iceberg is visible, each refresh of its peak joins the back of
+/// the queue as a new add.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum OrderFeedEvent {
+    /// The order joined the back of the queue at its price
+    Add {
+        id: u64,
+        side: Side,
+        price: Price,
+        qty: u64,
+    },
+    /// A trade took `qty` off the order, which leaves the book when nothing is left.
+    /// Executions in an uncross are not printable, the uncross is printed once as a trade.
+    Executed {
+        id: u64,
+        qty: u64,
+        price: Price,
+        printable: bool,
+    },
+    /// `qty` was cancelled from the order, which stays on the book
+    Cancel {
+        id: u64,
+        qty: u64,
+    },
+    Delete {
+        id: u64,
+    },
+    /// The order moved to another price or quantity and lost its queue position
+    Replace {
+        id: u64,
+        price: Price,
+        qty: u64,
+    },
+    /// A trade not shown by executions of displayed orders
+    Trade {
+        price: Price,
+        qty: u64,
+    },
+    PhaseChange {
+        phase: TradingPhase,
+    },
+}
+
+/// Volatility interruption. A trade more than `band_bps` basis points away from the
+/// reference price halts the instrument before it happens. The reference is the oldest
+/// trade within the last `window`, or the last traded price if there was none, or else the
+/// instrument's reference price.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct CircuitBreaker {
+    band_bps: u64,
+    window: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(band_bps: u64, window: Duration) -> Self {
+        Self { band_bps, window }
+    }
+
+    pub fn band_bps(&self) -> u64 {
+        self.band_bps
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Whether trading at `price` would trip the breaker.
+    pub fn breaches(&self, price: Price, reference: Price) -> bool {
+        price.distance(reference).map_or(false, |distance| {
+            distance.saturating_mul(10_000) > reference.units().saturating_mul(self.band_bps)
+        })
+    }
+}
+
+/// Trading session phase of an instrument, governing which events its book accepts.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TradingPhase {
+    /// Orders other than market orders collect without matching
+    PreOpen,
+    /// Orders collect without matching and uncross when continuous trading starts
+    OpeningAuction,
+    Continuous,
+    /// Only cancels are accepted
+    Halted,
+    /// Orders collect without matching and uncross when the market closes
+    ClosingAuction,
+    /// Only cancels are accepted
+    Closed,
+}
+
+impl Default for TradingPhase {
+    fn default() -> Self {
+        TradingPhase::Continuous
+    }
+}
+
+impl TradingPhase {
+    /// Whether orders collect for an uncross instead of matching.
+    pub fn is_auction(&self) -> bool {
+        match *self {
+            TradingPhase::PreOpen
+            | TradingPhase::OpeningAuction
+            | TradingPhase::ClosingAuction => true,
+            _ => false,
+        }
+    }
+
+    pub fn accepts(&self, event: &OrderEvent) -> bool {
+        match (*self, *event) {
+            (_, OrderEvent::Cancel { .. }) | (TradingPhase::Continuous, _) => true,
+            (TradingPhase::Halted, _) | (TradingPhase::Closed, _) => false,
+            (TradingPhase::PreOpen, OrderEvent::Market { .. }) => false,
+            // Auctions have nothing to trade with immediately
+            (_, OrderEvent::Limit { tif, .. }) => match tif {
+                TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill => false,
+                _ => true,
+            },
+            _ => true,
+        }
+    }
+
+    /// Whether the session may move from this phase to `next`. A halt can interrupt any
+    /// open phase and is lifted into an auction, continuous trading or the close.
+    pub fn can_move_to(&self, next: TradingPhase) -> bool {
+        match (*self, next) {
+            (TradingPhase::Closed, TradingPhase::PreOpen)
+            | (TradingPhase::PreOpen, TradingPhase::OpeningAuction)
+            | (TradingPhase::PreOpen, TradingPhase::Closed)
+            | (TradingPhase::OpeningAuction, TradingPhase::Continuous)
+            | (TradingPhase::Continuous, TradingPhase::ClosingAuction)
+            | (TradingPhase::Continuous, TradingPhase::Closed)
+            | (TradingPhase::ClosingAuction, TradingPhase::Closed)
+            | (TradingPhase::Halted, TradingPhase::OpeningAuction)
+            | (TradingPhase::Halted, TradingPhase::Continuous)
+            | (TradingPhase::Halted, TradingPhase::Closed) => true,
+            (TradingPhase::Closed, TradingPhase::Halted)
+            | (TradingPhase::Halted, TradingPhase::Halted) => false,
+            (_, TradingPhase::Halted) => true,
+            _ => false,
+        }
+    }
+}
+
+/// What happens when an incoming order would trade with a resting order of the same owner.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum SelfTradePrevention {
+    /// The rest of the incoming order is cancelled
+    CancelNewest,
+    /// The resting order is cancelled and matching continues
+    CancelOldest,
+    /// Both orders are cancelled
+    CancelBoth,
+    /// Both orders are reduced by the smaller of their open quantities and the one left
+    /// with nothing is cancelled
+    DecrementAndCancel,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TimeInForce {
+    GoodTillCancel,
+    ImmediateOrCancel,
+    /// Filled completely on entry or not at all
+    FillOrKill,
+    /// Expires at the end of the day it was entered on
+    Day,
+    GoodTillDate(DateTime<Utc>),
+}
+
+impl Default for TimeInForce {
+    fn default() -> Self {
+        TimeInForce::GoodTillCancel
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum OrderEvent {
     Market {
         id: u64,
         side: Side,
         qty: u64,
+        owner: u64,
     },
     Limit {
         id: u64,
         side: Side,
-        price: u64,
+        price: Price,
         qty: u64,
+        tif: TimeInForce,
+        owner: u64,
     },
     Cancel {
         id: u64,
@@ -70,22 +507,155 @@ pub enum OrderEvent {
     Replace {
         id: u64,
         side: Side,
-        price: u64,
+        price: Price,
+        qty: u64,
+    },
+    /// Limit order that only shows `display_qty` at a time. When the displayed part is
+    /// filled it is refreshed from the hidden remainder and loses queue priority.
+    Iceberg {
+        id: u64,
+        side: Side,
+        price: Price,
         qty: u64,
+        display_qty: u64,
+        owner: u64,
+    },
+    /// Becomes a market order once a trade prints at or through `stop_price`
+    StopMarket {
+        id: u64,
+        side: Side,
+        stop_price: Price,
+        qty: u64,
+        owner: u64,
+    },
+    /// Becomes a limit order at `price` once a trade prints at or through `stop_price`
+    StopLimit {
+        id: u64,
+        side: Side,
+        stop_price: Price,
+        price: Price,
+        qty: u64,
+        owner: u64,
     },
 }
 
 impl OrderEvent {
     pub fn market(id: u64, side: Side, qty: u64) -> Self {
-        OrderEvent::Market { id, side, qty }
+        OrderEvent::Market {
+            id,
+            side,
+            qty,
+            owner: 0,
+        }
     }
 
-    pub fn limit(id: u64, side: Side, price: u64, qty: u64) -> Self {
+    pub fn limit(id: u64, side: Side, price: Price, qty: u64) -> Self {
+        Self::limit_with_tif(id, side, price, qty, TimeInForce::GoodTillCancel)
+    }
+
+    pub fn limit_with_tif(id: u64, side: Side, price: Price, qty: u64, tif: TimeInForce) -> Self {
         OrderEvent::Limit {
             id,
             side,
             price,
             qty,
+            tif,
+            owner: 0,
+        }
+    }
+
+    pub fn replace(id: u64, side: Side, price: Price, qty: u64) -> Self {
+        OrderEvent::Replace {
+            id,
+            side,
+            price,
+            qty,
+        }
+    }
+
+    pub fn iceberg(id: u64, side: Side, price: Price, qty: u64, display_qty: u64) -> Self {
+        OrderEvent::Iceberg {
+            id,
+            side,
+            price,
+            qty,
+            display_qty,
+            owner: 0,
+        }
+    }
+
+    pub fn stop_market(id: u64, side: Side, stop_price: Price, qty: u64) -> Self {
+        OrderEvent::StopMarket {
+            id,
+            side,
+            stop_price,
+            qty,
+            owner: 0,
+        }
+    }
+
+    pub fn stop_limit(id: u64, side: Side, stop_price: Price, price: Price, qty: u64) -> Self {
+        OrderEvent::StopLimit {
+            id,
+            side,
+            stop_price,
+            price,
+            qty,
+            owner: 0,
+        }
+    }
+
+    /// Sets the trader or account a new order belongs to. Orders of the same owner are
+    /// kept from trading with each other when the instrument has self-trade prevention.
+    /// Zero means no owner. Cancels and replaces act for the owner of their order and are
+    /// left unchanged.
+    pub fn with_owner(mut self, new_owner: u64) -> Self {
+        match self {
+            OrderEvent::Market { ref mut owner, .. }
+            | OrderEvent::Limit { ref mut owner, .. }
+            | OrderEvent::Iceberg { ref mut owner, .. }
+            | OrderEvent::StopMarket { ref mut owner, .. }
+            | OrderEvent::StopLimit { ref mut owner, .. } => *owner = new_owner,
+            OrderEvent::Cancel { .. } | OrderEvent::Replace { .. } => {}
+        }
+        self
+    }
+
+    /// Id of the order the event creates or acts on.
+    pub fn id(&self) -> u64 {
+        match *self {
+            OrderEvent::Market { id, .. }
+            | OrderEvent::Limit { id, .. }
+            | OrderEvent::Iceberg { id, .. }
+            | OrderEvent::StopMarket { id, .. }
+            | OrderEvent::StopLimit { id, .. }
+            | OrderEvent::Cancel { id }
+            | OrderEvent::Replace { id, .. } => id,
+        }
+    }
+
+    /// Side of the order, None for cancels.
+    pub fn side(&self) -> Option<Side> {
+        match *self {
+            OrderEvent::Market { side, .. }
+            | OrderEvent::Limit { side, .. }
+            | OrderEvent::Iceberg { side, .. }
+            | OrderEvent::StopMarket { side, .. }
+            | OrderEvent::StopLimit { side, .. }
+            | OrderEvent::Replace { side, .. } => Some(side),
+            OrderEvent::Cancel { .. } => None,
+        }
+    }
+
+    /// Owner of a new order, zero for cancels and replaces.
+    pub fn owner(&self) -> u64 {
+        match *self {
+            OrderEvent::Market { owner, .. }
+            | OrderEvent::Limit { owner, .. }
+            | OrderEvent::Iceberg { owner, .. }
+            | OrderEvent::StopMarket { owner, .. }
+            | OrderEvent::StopLimit { owner, .. } => owner,
+            OrderEvent::Cancel { .. } | OrderEvent::Replace { .. } => 0,
         }
     }
 }
@@ -95,12 +665,12 @@ pub struct OrderFill {
     id: u64,
     ord_id_1: u64,
     ord_id_2: u64,
-    price: u64,
+    price: Price,
     qty: u64,
 }
 
 impl OrderFill {
-    pub fn new(ord_id_1: u64, ord_id_2: u64, price: u64, qty: u64) -> Self {
+    pub fn new(ord_id_1: u64, ord_id_2: u64, price: Price, qty: u64) -> Self {
         Self {
             id: (&*ORDER_FILL_ID_GEN).next(),
             ord_id_1,
@@ -122,7 +692,7 @@ impl OrderFill {
         self.ord_id_2
     }
 
-    pub fn price(&self) -> u64 {
+    pub fn price(&self) -> Price {
         self.price
     }
 
@@ -131,29 +701,314 @@ impl OrderFill {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RejectReason {
+    UnknownOrder,
+    InvalidQuantity,
+    InvalidPrice,
+    SideMismatch,
+    ExpireTimeInPast,
+    PriceNotOnTick,
+    QtyNotOnLot,
+    QtyBelowMinimum,
+    QtyAboveMaximum,
+    PriceOutsideBand,
+    /// Another live order has the same id
+    DuplicateOrderId,
+    /// The book does not take this kind of event in its current trading phase
+    NotAcceptedInPhase,
+    /// Failed a pre-trade risk check of the owning account
+    Risk(RiskViolation),
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ExecType {
+    New,
+    PartialFill,
+    Fill,
+    Cancelled,
+    Replaced,
+    Expired,
+    /// A stop order was triggered and entered the book
+    Triggered,
+    /// The open quantity was reduced by the book, not by a fill or the owner
+    Restated,
+    Rejected(RejectReason),
+}
+
+/// Lifecycle event for a single order, emitted by the order book alongside fills.
+#[derive(Debug, Copy, Clone)]
+pub struct ExecutionReport {
+    order_id: u64,
+    // None when the order is not known to the book
+    side: Option<Side>,
+    exec_type: ExecType,
+    // Limit price of the order, zero for market orders
+    price: Price,
+    last_qty: u64,
+    last_price: Price,
+    leaves_qty: u64,
+    cum_qty: u64,
+    avg_price: Price,
+}
+
+impl ExecutionReport {
+    pub(crate) fn new(
+        order: &OrderInfo,
+        exec_type: ExecType,
+        last_qty: u64,
+        last_price: Price,
+    ) -> Self {
+        Self {
+            order_id: order.id(),
+            side: Some(order.side()),
+            exec_type,
+            price: order.price(),
+            last_qty,
+            last_price,
+            leaves_qty: order.leaves_qty(),
+            cum_qty: order.cum_qty(),
+            avg_price: order.avg_price(),
+        }
+    }
+
+    /// A report that does not carry a fill.
+    pub(crate) fn status(order: &OrderInfo, exec_type: ExecType) -> Self {
+        Self::new(order, exec_type, 0, Price::zero(order.price().scale()))
+    }
+
+    pub(crate) fn rejected(order_id: u64, side: Option<Side>, reason: RejectReason) -> Self {
+        Self {
+            order_id,
+            side,
+            exec_type: ExecType::Rejected(reason),
+            price: Price::default(),
+            last_qty: 0,
+            last_price: Price::default(),
+            leaves_qty: 0,
+            cum_qty: 0,
+            avg_price: Price::default(),
+        }
+    }
+
+    pub fn order_id(&self) -> u64 {
+        self.order_id
+    }
+
+    pub fn side(&self) -> Option<Side> {
+        self.side
+    }
+
+    pub fn exec_type(&self) -> ExecType {
+        self.exec_type
+    }
+
+    pub fn price(&self) -> Price {
+        self.price
+    }
+
+    pub fn last_qty(&self) -> u64 {
+        self.last_qty
+    }
+
+    pub fn last_price(&self) -> Price {
+        self.last_price
+    }
+
+    pub fn leaves_qty(&self) -> u64 {
+        self.leaves_qty
+    }
+
+    pub fn cum_qty(&self) -> u64 {
+        self.cum_qty
+    }
+
+    pub fn avg_price(&self) -> Price {
+        self.avg_price
+    }
+}
+
+/// A live order as seen by the book, for lookups.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct OrderState {
+    id: u64,
+    side: Side,
+    // Limit price, zero for market and stop market orders
+    price: Price,
+    // Set while a stop order waits to trigger
+    stop_price: Option<Price>,
+    leaves_qty: u64,
+    // Part of the open quantity shown in the book, less than leaves_qty for icebergs
+    display_qty: u64,
+    cum_qty: u64,
+    owner: u64,
+    // Number of orders ahead at the same price, None for stops that have not triggered
+    queue_position: Option<usize>,
+}
+
+impl OrderState {
+    pub(crate) fn resting(order: &OrderInfo, queue_position: usize) -> Self {
+        Self {
+            id: order.id(),
+            side: order.side(),
+            price: order.price(),
+            stop_price: None,
+            leaves_qty: order.leaves_qty(),
+            display_qty: order.qty(),
+            cum_qty: order.cum_qty(),
+            owner: order.owner(),
+            queue_position: Some(queue_position),
+        }
+    }
+
+    pub(crate) fn stop(order: &OrderInfo, stop_price: Price) -> Self {
+        Self {
+            id: order.id(),
+            side: order.side(),
+            price: order.price(),
+            stop_price: Some(stop_price),
+            leaves_qty: order.leaves_qty(),
+            display_qty: 0,
+            cum_qty: 0,
+            owner: order.owner(),
+            queue_position: None,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    pub fn price(&self) -> Price {
+        self.price
+    }
+
+    pub fn stop_price(&self) -> Option<Price> {
+        self.stop_price
+    }
+
+    pub fn leaves_qty(&self) -> u64 {
+        self.leaves_qty
+    }
+
+    pub fn display_qty(&self) -> u64 {
+        self.display_qty
+    }
+
+    pub fn cum_qty(&self) -> u64 {
+        self.cum_qty
+    }
+
+    pub fn owner(&self) -> u64 {
+        self.owner
+    }
+
+    pub fn queue_position(&self) -> Option<usize> {
+        self.queue_position
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct OrderInfo {
     // A persistent id - from DB
     crate id: u64,
-    crate price: u64,
+    crate side: Side,
+    crate price: Price,
+    // Open quantity visible in the book
     crate qty: u64,
+    // Display size of an iceberg order, zero for fully visible orders
+    crate peak: u64,
+    // Hidden quantity of an iceberg order
+    crate reserve: u64,
+    crate cum_qty: u64,
+    // Sum of price units * qty over all fills, used for the average price
+    crate notional: u128,
+    // Set for Day and GTD orders
+    crate expire_at: Option<DateTime<Utc>>,
+    // Trader or account for self-trade prevention, zero if none
+    crate owner: u64,
 }
 
 impl OrderInfo {
-    pub(crate) fn new(id: u64, price: u64, qty: u64) -> Self {
-        Self { id, price, qty }
+    pub(crate) fn new(id: u64, side: Side, price: Price, qty: u64) -> Self {
+        Self {
+            id,
+            side,
+            price,
+            qty,
+            peak: 0,
+            reserve: 0,
+            cum_qty: 0,
+            notional: 0,
+            expire_at: None,
+            owner: 0,
+        }
     }
 
     pub fn id(&self) -> u64 {
         self.id
     }
-    pub fn price(&self) -> u64 {
+    pub fn side(&self) -> Side {
+        self.side
+    }
+    pub fn price(&self) -> Price {
         self.price
     }
     pub fn qty(&self) -> u64 {
         self.qty
     }
-    pub fn fill(&mut self, fill_qty: u64) {
+    pub fn leaves_qty(&self) -> u64 {
+        self.qty + self.reserve
+    }
+    pub fn cum_qty(&self) -> u64 {
+        self.cum_qty
+    }
+    /// Average fill price, rounded half up to the price scale.
+    pub fn avg_price(&self) -> Price {
+        if self.cum_qty == 0 {
+            Price::zero(self.price.scale())
+        } else {
+            let cum_qty = u128::from(self.cum_qty);
+            let units = (self.notional + cum_qty / 2) / cum_qty;
+            // Safe, the average lies between the lowest and highest fill price
+            Price::new(units as u64, self.price.scale())
+        }
+    }
+    pub fn owner(&self) -> u64 {
+        self.owner
+    }
+    /// Whether trading with `other` would be a self trade.
+    pub fn same_owner(&self, other: &OrderInfo) -> bool {
+        self.owner != 0 && self.owner == other.owner
+    }
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expire_at.map_or(false, |expire_at| expire_at <= now)
+    }
+    /// Moves everything above the display size of an iceberg order into the reserve.
+    pub fn show_peak(&mut self) {
+        if self.peak > 0 {
+            let leaves_qty = self.leaves_qty();
+            self.qty = leaves_qty.min(self.peak);
+            self.reserve = leaves_qty - self.qty;
+        }
+    }
+    /// Refreshes the displayed quantity of an iceberg order from its reserve once it has
+    /// been filled. Returns true if the order was refreshed.
+    pub fn refresh_peak(&mut self) -> bool {
+        if self.qty == 0 && self.reserve > 0 {
+            self.show_peak();
+            true
+        } else {
+            false
+        }
+    }
+    pub fn fill(&mut self, fill_qty: u64, fill_price: Price) {
         self.qty -= fill_qty;
+        self.cum_qty += fill_qty;
+        self.notional += u128::from(fill_qty) * u128::from(fill_price.units());
     }
 }
@@ -0,0 +1,87 @@
+//! Non-blocking TCP connections shared by the order entry gateways.
+
+use failure::Error;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+/// A client connection with the bytes waiting to be decoded and sent. `session` names
+/// whoever the peer turned out to be once it identified itself.
+#[derive(Debug)]
+pub(crate) struct Connection<S> {
+    pub stream: TcpStream,
+    pub peer: SocketAddr,
+    pub inbound: Vec<u8>,
+    pub outbound: Vec<u8>,
+    pub session: Option<S>,
+    pub closed: bool,
+}
+
+impl<S> Connection<S> {
+    pub fn new(stream: TcpStream, peer: SocketAddr) -> Self {
+        Self {
+            stream,
+            peer,
+            inbound: Vec::new(),
+            outbound: Vec::new(),
+            session: None,
+            closed: false,
+        }
+    }
+
+    /// Reads whatever the peer sent, returning false once the connection is gone.
+    pub fn read(&mut self) -> bool {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(len) => self.inbound.extend_from_slice(&buf[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("Reading from {} failed: {}", self.peer, e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Writes as much of the pending output as the socket takes, returning false once the
+    /// connection is gone.
+    pub fn flush(&mut self) -> bool {
+        while !self.outbound.is_empty() {
+            match self.stream.write(&self.outbound) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    self.outbound.drain(..len);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("Writing to {} failed: {}", self.peer, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Takes every connection waiting on `listener`, made non-blocking.
+pub(crate) fn accept<S>(
+    listener: &TcpListener,
+    protocol: &str,
+) -> Result<Vec<Connection<S>>, Error> {
+    let mut accepted = Vec::new();
+    loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                stream.set_nonblocking(true)?;
+                stream.set_nodelay(true)?;
+                info!("Accepted {} connection from {}", protocol, peer);
+                accepted.push(Connection::new(stream, peer));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(accepted),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
@@ -1,123 +1,997 @@
+use crate::allocation;
+use crate::auction::{self, Equilibrium};
+use crate::clock::{Clock, SystemClock};
+use crate::error::OrderBookError;
 use crate::order_list::OrderList;
-use crate::model::{Instrument, OrderEvent, OrderFill, Side};
-use std::collections::BTreeMap;
+use crate::model::{AllocationPolicy, ExecType, ExecutionReport, Instrument, Level,
+                   MarketDataEvent, OrderEvent, OrderFeedEvent, OrderFill, OrderInfo, OrderState,
+                   Price, RejectReason, SelfTradePrevention, Side, TimeInForce, TradingPhase};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::collections::btree_map::Entry;
+use std::mem;
 use std::option::Option::None;
-use failure::Error;
+
+/// A stop order waiting in the trigger book. Stop market orders have no limit price.
+#[derive(Debug, Clone, Copy)]
+struct StopOrder {
+    id: u64,
+    side: Side,
+    stop_price: Price,
+    limit: Option<Price>,
+    qty: u64,
+    owner: u64,
+}
+
+impl StopOrder {
+    fn order(&self, price_scale: u8) -> OrderInfo {
+        let price = self.limit.unwrap_or_else(|| Price::zero(price_scale));
+        let mut order = OrderInfo::new(self.id, self.side, price, self.qty);
+        order.owner = self.owner;
+        order
+    }
+}
+
+/// The instrument's matching rules, fixed for the whole of one incoming order.
+#[derive(Debug, Clone, Copy)]
+struct MatchRules {
+    now: DateTime<Utc>,
+    policy: AllocationPolicy,
+    lot_size: u64,
+    stp: Option<SelfTradePrevention>,
+}
 
 #[derive(Debug)]
 pub struct OrderBook {
     instrument: Instrument,
-    last_traded_price: Option<u64>,
+    last_traded_price: Option<Price>,
     order_list: OrderList,
-    max_bid: Option<u64>,
-    min_ask: Option<u64>,
-    bids: BTreeMap<u64, Vec<usize>>,
-    asks: BTreeMap<u64, Vec<usize>>,
+    bids: BTreeMap<Price, Vec<usize>>,
+    asks: BTreeMap<Price, Vec<usize>>,
+    // Trigger book, stops at the same price are kept in arrival order
+    buy_stops: BTreeMap<Price, Vec<StopOrder>>,
+    sell_stops: BTreeMap<Price, Vec<StopOrder>>,
+    stop_index: HashMap<u64, (Side, Price)>,
+    reports: Vec<ExecutionReport>,
+    market_data: Vec<MarketDataEvent>,
+    order_feed: Vec<OrderFeedEvent>,
+    // Levels changed by the current event, with their quantity before the change
+    touched: Vec<(Side, Price, u64)>,
+    phase: TradingPhase,
+    // While set, orders collect without matching until the book is uncrossed. Stays set
+    // when an auction is halted.
+    auction: bool,
+    // Market orders waiting for the uncross, in arrival order
+    auction_market: Vec<usize>,
+    // Last published indicative uncross
+    indicative: Option<Equilibrium>,
+    // Trades within the circuit breaker window, oldest first
+    recent_trades: VecDeque<(DateTime<Utc>, Price)>,
+    clock: Box<Clock>,
 }
 
 impl OrderBook {
     pub fn new(instrument: Instrument) -> Self {
+        Self::with_clock(instrument, Box::new(SystemClock))
+    }
+
+    pub fn with_clock(instrument: Instrument, clock: Box<Clock>) -> Self {
         Self {
             instrument,
             last_traded_price: None,
             order_list: OrderList::new(),
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
-            max_bid: None,
-            min_ask: None,
+            buy_stops: BTreeMap::new(),
+            sell_stops: BTreeMap::new(),
+            stop_index: HashMap::new(),
+            reports: Vec::new(),
+            market_data: Vec::new(),
+            order_feed: Vec::new(),
+            touched: Vec::new(),
+            phase: TradingPhase::default(),
+            auction: false,
+            auction_market: Vec::new(),
+            indicative: None,
+            recent_trades: VecDeque::new(),
+            clock,
         }
     }
 
-    pub fn event(&mut self, event: OrderEvent) -> Result<Vec<OrderFill>, Error> {
+    pub fn event(&mut self, event: OrderEvent) -> Result<Vec<OrderFill>, OrderBookError> {
+        let result = self.dispatch(event).and_then(|mut fills| {
+            fills.extend(self.trigger_stops()?);
+            Ok(fills)
+        });
+        self.publish_levels();
+        result
+    }
+
+    fn dispatch(&mut self, event: OrderEvent) -> Result<Vec<OrderFill>, OrderBookError> {
+        if !self.phase.accepts(&event) {
+            info!(
+                "Order event for {:?} is not accepted in phase {:?}",
+                event.id(),
+                self.phase
+            );
+            self.reports.push(ExecutionReport::rejected(
+                event.id(),
+                event.side(),
+                RejectReason::NotAcceptedInPhase,
+            ));
+            return Ok(Vec::new());
+        }
+
+        match event {
+            OrderEvent::Cancel { .. } | OrderEvent::Replace { .. } => {}
+            _ => {
+                if self.is_live(event.id()) {
+                    info!("Order id {:?} is already live", event.id());
+                    self.reports.push(ExecutionReport::rejected(
+                        event.id(),
+                        event.side(),
+                        RejectReason::DuplicateOrderId,
+                    ));
+                    return Ok(Vec::new());
+                }
+            }
+        }
+
         match event {
-            OrderEvent::Market { id, side, qty } => self.market(id, side, qty),
+            OrderEvent::Market {
+                id,
+                side,
+                qty,
+                owner,
+            } => self.market(id, side, qty, owner),
             OrderEvent::Limit {
                 id,
                 side,
                 qty,
                 price,
-            } => self.limit(id, side, qty, price),
-            OrderEvent::Cancel { id } => {
-                let _ = self.cancel(id);
+                tif,
+                owner,
+            } => self.limit(id, side, qty, price, tif, owner),
+            OrderEvent::Iceberg {
+                id,
+                side,
+                price,
+                qty,
+                display_qty,
+                owner,
+            } => self.iceberg(id, side, qty, price, display_qty, owner),
+            OrderEvent::Cancel { id } => match self.cancel(id) {
+                // The client has been sent the rejection
+                Ok(()) | Err(OrderBookError::UnknownOrder(_)) => Ok(Vec::new()),
+                Err(e) => Err(e),
+            },
+            OrderEvent::Replace {
+                id,
+                side,
+                qty,
+                price,
+            } => self.replace(id, side, qty, price),
+            OrderEvent::StopMarket {
+                id,
+                side,
+                stop_price,
+                qty,
+                owner,
+            } => self.stop(id, side, qty, stop_price, None, owner),
+            OrderEvent::StopLimit {
+                id,
+                side,
+                stop_price,
+                price,
+                qty,
+                owner,
+            } => self.stop(id, side, qty, stop_price, Some(price), owner),
+        }
+    }
+
+    /// Swaps the clock driving order expiry, returning the previous one.
+    pub fn set_clock(&mut self, clock: Box<Clock>) -> Box<Clock> {
+        mem::replace(&mut self.clock, clock)
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    pub fn instrument(&self) -> &Instrument {
+        &self.instrument
+    }
+
+    /// Moves the centre of the instrument's price band.
+    pub fn set_reference_price(&mut self, reference_price: Price) {
+        self.instrument.set_reference_price(reference_price);
+    }
+
+    /// Takes the execution reports emitted since the last call, oldest first.
+    pub fn drain_reports(&mut self) -> Vec<ExecutionReport> {
+        mem::replace(&mut self.reports, Vec::new())
+    }
+
+    /// Takes the market data events emitted since the last call, oldest first.
+    pub fn drain_market_data(&mut self) -> Vec<MarketDataEvent> {
+        mem::replace(&mut self.market_data, Vec::new())
+    }
+
+    /// Takes the order-by-order changes emitted since the last call, oldest first.
+    pub fn drain_order_feed(&mut self) -> Vec<OrderFeedEvent> {
+        mem::replace(&mut self.order_feed, Vec::new())
+    }
+
+    pub fn last_traded_price(&self) -> Option<Price> {
+        self.last_traded_price
+    }
+
+    pub fn best_bid(&self) -> Option<Level> {
+        self.depth(Side::Bid, 1).pop()
+    }
+
+    pub fn best_ask(&self) -> Option<Level> {
+        self.depth(Side::Ask, 1).pop()
+    }
+
+    /// The best `levels` price levels of one side, best first.
+    pub fn depth(&self, side: Side, levels: usize) -> Vec<Level> {
+        let to_level = |(price, orders): (&Price, &Vec<usize>)| self.level(*price, orders);
+        match side {
+            Side::Bid => self.bids
+                .iter()
+                .rev()
+                .map(to_level)
+                .filter(|level| level.qty() > 0)
+                .take(levels)
+                .collect(),
+            Side::Ask => self.asks
+                .iter()
+                .map(to_level)
+                .filter(|level| level.qty() > 0)
+                .take(levels)
+                .collect(),
+        }
+    }
+
+    pub fn full_depth(&self, side: Side) -> Vec<Level> {
+        self.depth(side, usize::max_value())
+    }
+
+    /// Cancels a resting or stop order. Fails with `UnknownOrder` if the order is not live,
+    /// after reporting the rejection.
+    pub fn cancel(&mut self, order_id: u64) -> Result<(), OrderBookError> {
+        let removed = self.remove(order_id, ExecType::Cancelled)?;
+        self.publish_levels();
+        if !removed {
+            self.reports.push(ExecutionReport::rejected(
+                order_id,
+                None,
+                RejectReason::UnknownOrder,
+            ));
+            return Err(OrderBookError::UnknownOrder(order_id));
+        }
+        Ok(())
+    }
+
+    /// Expires resting Day and GTD orders whose expiry time has passed on the book's clock.
+    /// Returns the number of orders expired.
+    pub fn expire_orders(&mut self) -> Result<usize, OrderBookError> {
+        let result = self.expire();
+        self.publish_levels();
+        result
+    }
+
+    fn expire(&mut self) -> Result<usize, OrderBookError> {
+        let now = self.clock.now();
+        let mut expired: Vec<u64> = self.order_list
+            .iter()
+            .filter(|order| order.is_expired(now))
+            .map(|order| order.id())
+            .collect();
+        expired.sort();
+        for id in &expired {
+            self.remove(*id, ExecType::Expired)?;
+        }
+        Ok(expired.len())
+    }
+
+    pub fn phase(&self) -> TradingPhase {
+        self.phase
+    }
+
+    /// Moves the book to another trading phase, returning the fills of an uncross.
+    ///
+    /// Entering pre-open or an auction starts collecting orders without matching. The
+    /// indicative uncross is then published as market data whenever it changes. Leaving
+    /// an auction for continuous trading or the close uncrosses the book.
+    pub fn set_phase(&mut self, phase: TradingPhase) -> Result<Vec<OrderFill>, OrderBookError> {
+        if !self.phase.can_move_to(phase) {
+            return Err(OrderBookError::InvalidPhaseTransition(self.phase, phase));
+        }
+        info!(
+            "Moving {} from {:?} to {:?}",
+            self.instrument.symbol(),
+            self.phase,
+            phase
+        );
+        self.phase = phase;
+        self.market_data.push(MarketDataEvent::PhaseChange { phase });
+        self.order_feed.push(OrderFeedEvent::PhaseChange { phase });
+        let result = match phase {
+            TradingPhase::PreOpen | TradingPhase::OpeningAuction | TradingPhase::ClosingAuction => {
+                self.start_auction();
                 Ok(Vec::new())
             }
-            _ => unimplemented!("Not implemented"),
+            TradingPhase::Continuous | TradingPhase::Closed => self.uncross(),
+            TradingPhase::Halted => Ok(Vec::new()),
+        };
+        self.publish_levels();
+        result
+    }
+
+    crate fn is_auction(&self) -> bool {
+        self.auction
+    }
+
+    fn start_auction(&mut self) {
+        if !self.auction {
+            info!("Starting auction for {}", self.instrument.symbol());
+            self.auction = true;
+            self.indicative = None;
+        }
+    }
+
+    /// Where the book would uncross now, None if nothing would trade. The last traded
+    /// price, or else the instrument's reference price, breaks the final tie.
+    pub fn indicative_uncross(&self) -> Option<Equilibrium> {
+        let now = self.clock.now();
+        let market_qty = |side: Side| {
+            self.held_market(side)
+                .iter()
+                .map(|index| self.order_list[*index].leaves_qty())
+                .sum::<u64>()
+        };
+        auction::equilibrium(
+            &self.auction_levels(&self.bids, now),
+            &self.auction_levels(&self.asks, now),
+            market_qty(Side::Bid),
+            market_qty(Side::Ask),
+            self.last_traded_price
+                .or_else(|| self.instrument.reference_price()),
+        )
+    }
+
+    /// Ends an auction, trading everything that crosses at the equilibrium price, then
+    /// triggers stops if the book is matching again.
+    ///
+    /// Orders are filled in price-time priority with market orders first, including the
+    /// hidden quantity of icebergs. Market orders left over are cancelled. Self-trade
+    /// prevention does not apply to the uncross.
+    fn uncross(&mut self) -> Result<Vec<OrderFill>, OrderBookError> {
+        let mut fills = Vec::new();
+        if self.auction {
+            info!("Uncrossing {}", self.instrument.symbol());
+            fills = self.execute_uncross()?;
+        }
+        fills.extend(self.trigger_stops()?);
+        Ok(fills)
+    }
+
+    /// Whether incoming orders trade with the book.
+    fn matching(&self) -> bool {
+        !self.auction && self.phase == TradingPhase::Continuous
+    }
+
+    /// Checks a trade at `price` against the circuit breaker, halting the book if it trips.
+    fn trips_breaker(&mut self, price: Price, now: DateTime<Utc>) -> bool {
+        let breaker = match self.instrument.circuit_breaker() {
+            Some(breaker) => breaker,
+            None => return false,
+        };
+        while self.recent_trades
+            .front()
+            .map_or(false, |&(time, _)| time <= now - breaker.window())
+        {
+            self.recent_trades.pop_front();
+        }
+        let reference = self.recent_trades
+            .front()
+            .map(|&(_, price)| price)
+            .or(self.last_traded_price)
+            .or_else(|| self.instrument.reference_price());
+        match reference {
+            Some(reference) if breaker.breaches(price, reference) => {
+                warn!(
+                    "Trade at {} in {} is too far from {}, halting",
+                    price,
+                    self.instrument.symbol(),
+                    reference
+                );
+                self.phase = TradingPhase::Halted;
+                self.market_data.push(MarketDataEvent::PhaseChange {
+                    phase: TradingPhase::Halted,
+                });
+                self.order_feed.push(OrderFeedEvent::PhaseChange {
+                    phase: TradingPhase::Halted,
+                });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn record_trade(&mut self, price: Price, now: DateTime<Utc>) {
+        self.last_traded_price = Some(price);
+        if self.instrument.circuit_breaker().is_some() {
+            self.recent_trades.push_back((now, price));
+        }
+    }
+
+    /// Looks up a live order, resting in the book or waiting to trigger.
+    pub fn order(&self, order_id: u64) -> Option<OrderState> {
+        if let Some(&(side, stop_price)) = self.stop_index.get(&order_id) {
+            let stops = match side {
+                Side::Bid => &self.buy_stops,
+                Side::Ask => &self.sell_stops,
+            };
+            let price_scale = self.instrument.price_scale();
+            return stops
+                .get(&stop_price)?
+                .iter()
+                .find(|stop| stop.id == order_id)
+                .map(|stop| OrderState::stop(&stop.order(price_scale), stop_price));
+        }
+
+        let index = self.order_list.get(&order_id)?;
+        let order = &self.order_list[index];
+        if let Some(position) = self.held_market(order.side())
+            .iter()
+            .position(|i| *i == index)
+        {
+            return Some(OrderState::resting(order, position));
         }
+        let levels = match order.side() {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        };
+        let queue_position = levels
+            .get(&order.price())?
+            .iter()
+            .position(|i| *i == index)?;
+        Some(OrderState::resting(order, queue_position))
+    }
+
+    /// Every live order: market orders waiting for an uncross, then bids and asks, best
+    /// price first and in queue order within a level, followed by stop orders in the order
+    /// they would trigger.
+    pub fn open_orders(&self) -> Vec<OrderState> {
+        let price_scale = self.instrument.price_scale();
+        let held: Vec<OrderState> = [Side::Bid, Side::Ask]
+            .iter()
+            .flat_map(|side| {
+                self.held_market(*side)
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(position, index)| {
+                        OrderState::resting(&self.order_list[index], position)
+                    })
+            })
+            .collect();
+        let resting = self.bids
+            .values()
+            .rev()
+            .chain(self.asks.values())
+            .flat_map(|orders| {
+                orders
+                    .iter()
+                    .enumerate()
+                    .map(move |(position, index)| (position, *index))
+            })
+            .map(|(position, index)| OrderState::resting(&self.order_list[index], position));
+        let stops = self.buy_stops
+            .iter()
+            .chain(self.sell_stops.iter().rev())
+            .flat_map(|(stop_price, stops)| stops.iter().map(move |stop| (*stop_price, stop)))
+            .map(|(stop_price, stop)| OrderState::stop(&stop.order(price_scale), stop_price));
+        held.into_iter().chain(resting).chain(stops).collect()
+    }
+
+    fn is_live(&self, order_id: u64) -> bool {
+        self.order_list.get(&order_id).is_some() || self.stop_index.contains_key(&order_id)
+    }
+
+    /// Orders resting on one side, best price first and in queue order within a level.
+    crate fn resting_orders(&self, side: Side) -> Vec<OrderInfo> {
+        let levels: Vec<&Vec<usize>> = match side {
+            Side::Bid => self.bids.values().rev().collect(),
+            Side::Ask => self.asks.values().collect(),
+        };
+        levels
+            .into_iter()
+            .flat_map(|orders| orders.iter())
+            .map(|index| self.order_list[*index])
+            .collect()
+    }
+
+    /// Stop orders waiting in the trigger book, as the events that entered them, in the
+    /// order they would trigger.
+    crate fn stop_orders(&self) -> Vec<OrderEvent> {
+        self.buy_stops
+            .values()
+            .chain(self.sell_stops.values().rev())
+            .flat_map(|stops| stops.iter())
+            .map(|stop| {
+                let event = match stop.limit {
+                    Some(price) => OrderEvent::stop_limit(
+                        stop.id,
+                        stop.side,
+                        stop.stop_price,
+                        price,
+                        stop.qty,
+                    ),
+                    None => OrderEvent::stop_market(stop.id, stop.side, stop.stop_price, stop.qty),
+                };
+                event.with_owner(stop.owner)
+            })
+            .collect()
     }
 
-    pub fn cancel(&mut self, order_id: u64) -> Result<(), Error> {
-        let _ = self.order_list.delete(&order_id)?;
+    /// Puts an order at the back of its price level exactly as it is, without matching or
+    /// reporting it. Used to rebuild a book from a snapshot.
+    crate fn restore_order(&mut self, order: OrderInfo) -> Result<(), OrderBookError> {
+        if order.leaves_qty() == 0 {
+            return Err(OrderBookError::InvalidQuantity(0));
+        }
+        if order.price().scale() != self.instrument.price_scale() {
+            return Err(OrderBookError::InvalidPrice(order.price()));
+        }
+        let index = self.order_list.insert(order)?;
+        let levels = match order.side() {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        levels
+            .entry(order.price())
+            .or_insert_with(|| Vec::with_capacity(10))
+            .push(index);
         Ok(())
     }
 
-    fn market(&mut self, id: u64, side: Side, qty: u64) -> Result<Vec<OrderFill>, Error> {
-        let mut fills: Vec<OrderFill> = Vec::new();
-        let mut remaining_qty: u64 = qty;
+    /// Puts a stop order at the back of the trigger book without reporting it.
+    crate fn restore_stop(&mut self, event: OrderEvent) -> Result<(), OrderBookError> {
+        let stop = match event {
+            OrderEvent::StopMarket {
+                id,
+                side,
+                stop_price,
+                qty,
+                owner,
+            } => StopOrder {
+                id,
+                side,
+                stop_price,
+                limit: None,
+                qty,
+                owner,
+            },
+            OrderEvent::StopLimit {
+                id,
+                side,
+                stop_price,
+                price,
+                qty,
+                owner,
+            } => StopOrder {
+                id,
+                side,
+                stop_price,
+                limit: Some(price),
+                qty,
+                owner,
+            },
+            other => return Err(OrderBookError::NotAStopOrder(other.id())),
+        };
+        let stops = match stop.side {
+            Side::Bid => &mut self.buy_stops,
+            Side::Ask => &mut self.sell_stops,
+        };
+        stops
+            .entry(stop.stop_price)
+            .or_insert_with(Vec::new)
+            .push(stop);
+        self.stop_index.insert(stop.id, (stop.side, stop.stop_price));
+        Ok(())
+    }
 
-        match side {
-            Side::Bid => {
-                while remaining_qty > 0 && !self.asks.is_empty() {
-                    //Unwrap is safe as the list is not empty
-                    let best_price = *self.min_ask().unwrap();
-                    if let Entry::Occupied(mut entry) = self.asks.entry(best_price) {
-                        let best_orders = entry.get_mut();
-
-                        let new_fills = Self::process_order_list(
-                            &mut self.order_list,
-                            best_orders,
-                            remaining_qty,
-                            id,
-                        )?;
-
-                        if best_orders.is_empty() {
-                            entry.remove();
-                            self.min_ask = self.min_ask().cloned();
+    crate fn restore_last_traded_price(&mut self, last_traded_price: Option<Price>) {
+        self.last_traded_price = last_traded_price;
+    }
+
+    crate fn restore_phase(&mut self, phase: TradingPhase) {
+        self.phase = phase;
+    }
+
+    /// Trades inside the circuit breaker window, oldest first.
+    crate fn recent_trades(&self) -> Vec<(DateTime<Utc>, Price)> {
+        self.recent_trades.iter().cloned().collect()
+    }
+
+    crate fn restore_recent_trades(&mut self, trades: &[(DateTime<Utc>, Price)]) {
+        self.recent_trades = trades.iter().cloned().collect();
+    }
+
+    /// Market orders waiting for the uncross, in arrival order.
+    crate fn auction_orders(&self) -> Vec<OrderInfo> {
+        self.auction_market
+            .iter()
+            .map(|index| self.order_list[*index])
+            .collect()
+    }
+
+    /// Puts the book in auction mode with `market_orders` waiting for the uncross, without
+    /// reporting anything.
+    crate fn restore_auction(&mut self, market_orders: &[OrderInfo]) -> Result<(), OrderBookError> {
+        self.auction = true;
+        for order in market_orders {
+            let index = self.order_list.insert(*order)?;
+            self.auction_market.push(index);
+        }
+        self.indicative = self.indicative_uncross();
+        Ok(())
+    }
+
+    fn held_market(&self, side: Side) -> Vec<usize> {
+        self.auction_market
+            .iter()
+            .cloned()
+            .filter(|index| self.order_list[*index].side() == side)
+            .collect()
+    }
+
+    /// Open quantity at each price of one side, leaving out expired orders.
+    fn auction_levels(
+        &self,
+        levels: &BTreeMap<Price, Vec<usize>>,
+        now: DateTime<Utc>,
+    ) -> Vec<(Price, u64)> {
+        levels
+            .iter()
+            .map(|(price, orders)| {
+                let qty = orders
+                    .iter()
+                    .map(|index| &self.order_list[*index])
+                    .filter(|order| !order.is_expired(now))
+                    .map(|order| order.leaves_qty())
+                    .sum();
+                (*price, qty)
+            })
+            .filter(|&(_, qty)| qty > 0)
+            .collect()
+    }
+
+    /// Orders of one side that trade at `price` in an uncross, in priority order.
+    fn uncross_queue(&self, side: Side, price: Price) -> Vec<usize> {
+        let mut queue = self.held_market(side);
+        let levels: Vec<&Vec<usize>> = match side {
+            Side::Bid => self.bids.range(price..).rev().map(|(_, orders)| orders).collect(),
+            Side::Ask => self.asks.range(..=price).map(|(_, orders)| orders).collect(),
+        };
+        queue.extend(levels.into_iter().flat_map(|orders| orders.iter().cloned()));
+        queue
+    }
+
+    fn execute_uncross(&mut self) -> Result<Vec<OrderFill>, OrderBookError> {
+        self.expire()?;
+        let equilibrium = self.indicative_uncross();
+        self.auction = false;
+        self.indicative = None;
+
+        let mut fills: Vec<OrderFill> = Vec::new();
+        if let Some(equilibrium) = equilibrium {
+            let price = equilibrium.price();
+            let bids = self.uncross_queue(Side::Bid, price);
+            let asks = self.uncross_queue(Side::Ask, price);
+            for index in bids.iter().chain(&asks) {
+                let order = self.order_list[*index];
+                self.touch(order.side(), order.price());
+            }
+            // Quantity of each order the feed still shows, held market orders are not shown
+            let mut shown: HashMap<usize, u64> = bids.iter()
+                .chain(&asks)
+                .filter(|index| !self.auction_market.contains(index))
+                .map(|index| (*index, self.order_list[*index].qty()))
+                .collect();
+
+            let mut remaining = equilibrium.volume();
+            let (mut b, mut a) = (0, 0);
+            while remaining > 0 {
+                let (bid, ask) = (bids[b], asks[a]);
+                let qty = self.order_list[bid]
+                    .leaves_qty()
+                    .min(self.order_list[ask].leaves_qty())
+                    .min(remaining);
+                for index in &[bid, ask] {
+                    let order = &mut self.order_list[*index];
+                    order.qty = order.leaves_qty();
+                    order.reserve = 0;
+                    order.fill(qty, price);
+                    self.reports.push(ExecutionReport::new(
+                        order,
+                        Self::fill_type(order),
+                        qty,
+                        price,
+                    ));
+                    if let Some(shown) = shown.get_mut(index) {
+                        let executed = qty.min(*shown);
+                        *shown -= executed;
+                        if executed > 0 {
+                            self.order_feed.push(OrderFeedEvent::Executed {
+                                id: order.id(),
+                                qty: executed,
+                                price,
+                                printable: false,
+                            });
                         }
-                        let filled_qty: u64 = new_fills.iter().map(|f| f.qty()).sum();
-                        remaining_qty -= filled_qty;
-                        fills.extend(new_fills);
-                    } else {
-                        panic!("Should not be reachable");
                     }
                 }
-                if remaining_qty > 0 {
-                    info!(
-                        "There are not enough sell orders to fulfill this order {:?}",
-                        id
-                    );
+                fills.push(OrderFill::new(
+                    self.order_list[bid].id(),
+                    self.order_list[ask].id(),
+                    price,
+                    qty,
+                ));
+                remaining -= qty;
+                if self.order_list[bid].leaves_qty() == 0 {
+                    b += 1;
+                }
+                if self.order_list[ask].leaves_qty() == 0 {
+                    a += 1;
                 }
             }
-            Side::Ask => {
-                while remaining_qty > 0 && !self.bids.is_empty() {
-                    let best_price = *self.max_bid().unwrap();
-                    if let Entry::Occupied(mut entry) = self.bids.entry(best_price) {
-                        let best_orders = entry.get_mut();
-                        let new_fills = Self::process_order_list(
-                            &mut self.order_list,
-                            best_orders,
-                            remaining_qty,
-                            id,
-                        )?;
-                        if best_orders.is_empty() {
-                            entry.remove();
-                            self.max_bid = self.max_bid().cloned();
-                        }
-                        let filled_qty: u64 = new_fills.iter().map(|fill| fill.qty()).sum();
-                        remaining_qty -= filled_qty;
-                        fills.extend(new_fills);
-                    } else {
-                        panic!("Should not be reachable");
-                    }
+
+            for index in bids.into_iter().chain(asks) {
+                let order = self.order_list[index];
+                if order.leaves_qty() > 0 {
+                    // Hide the reserve of icebergs again
+                    self.order_list[index].show_peak();
+                    let qty = self.order_list[index].qty();
+                    let event = match shown.get(&index) {
+                        Some(0) => Self::feed_add(&self.order_list[index]),
+                        Some(visible) if *visible != qty => OrderFeedEvent::Replace {
+                            id: order.id(),
+                            price: order.price(),
+                            qty,
+                        },
+                        _ => continue,
+                    };
+                    self.order_feed.push(event);
+                    continue;
+                }
+                self.unlink(order.side(), order.price(), index);
+                self.auction_market.retain(|i| *i != index);
+                self.order_list.delete(&order.id())?;
+            }
+            let now = self.clock.now();
+            self.record_trade(price, now);
+            self.market_data.push(MarketDataEvent::Uncross {
+                price,
+                qty: equilibrium.volume(),
+            });
+            self.order_feed.push(OrderFeedEvent::Trade {
+                price,
+                qty: equilibrium.volume(),
+            });
+        }
+
+        for index in mem::replace(&mut self.auction_market, Vec::new()) {
+            let mut order = self.order_list[index];
+            self.order_list.delete(&order.id())?;
+            self.kill(&mut order);
+        }
+        Ok(fills)
+    }
+
+    /// Takes a resting order off the book, reporting it with `exec_type`. Returns false if
+    /// the order is not on the book.
+    fn remove(&mut self, order_id: u64, exec_type: ExecType) -> Result<bool, OrderBookError> {
+        if let Some((side, stop_price)) = self.stop_index.remove(&order_id) {
+            let stops = match side {
+                Side::Bid => &mut self.buy_stops,
+                Side::Ask => &mut self.sell_stops,
+            };
+            let mut removed = None;
+            if let Entry::Occupied(mut entry) = stops.entry(stop_price) {
+                if let Some(pos) = entry.get().iter().position(|stop| stop.id == order_id) {
+                    removed = Some(entry.get_mut().remove(pos));
+                }
+                if entry.get().is_empty() {
+                    entry.remove();
                 }
+            }
+            if let Some(stop) = removed {
+                let mut order = stop.order(self.instrument.price_scale());
+                order.qty = 0;
+                self.reports.push(ExecutionReport::status(&order, exec_type));
+                return Ok(true);
+            }
+            return Ok(false);
+        }
 
-                if remaining_qty > 0 {
-                    info!(
-                        "There are not enough buy orders to fulfill this order {:?}",
-                        id
-                    );
+        match self.order_list.get(&order_id) {
+            Some(index) => {
+                let mut order = self.order_list[index];
+                if self.auction_market.contains(&index) {
+                    self.auction_market.retain(|i| *i != index);
+                } else {
+                    self.unlink(order.side(), order.price(), index);
+                    self.order_feed.push(OrderFeedEvent::Delete { id: order_id });
                 }
+                self.order_list.delete(&order_id)?;
+                order.qty = 0;
+                self.reports
+                    .push(ExecutionReport::status(&order, exec_type));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Cancel/replace of a resting order. `qty` is the new open quantity.
+    ///
+    /// The order keeps its queue position when only the quantity goes down. A price
+    /// change or a quantity increase takes the order out of the queue and re-enters it
+    /// as a new limit order, which may match against the opposite side.
+    fn replace(
+        &mut self,
+        id: u64,
+        side: Side,
+        qty: u64,
+        price: Price,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        let index = match self.order_list.get(&id) {
+            Some(index) => index,
+            None => {
+                info!("Replace request for unknown order {:?}", id);
+                self.reports.push(ExecutionReport::rejected(
+                    id,
+                    Some(side),
+                    RejectReason::UnknownOrder,
+                ));
+                return Ok(Vec::new());
+            }
+        };
+        let mut order = self.order_list[index];
+
+        if order.side() != side {
+            info!("Replace request for order {:?} cannot change side", id);
+            self.reports.push(ExecutionReport::rejected(
+                id,
+                Some(side),
+                RejectReason::SideMismatch,
+            ));
+            return Ok(Vec::new());
+        }
+
+        if qty == 0 {
+            self.cancel(id)?;
+            return Ok(Vec::new());
+        }
+
+        if let Err(reason) = self.validate(qty, Some(price)) {
+            info!("Replace request for order {:?} rejected: {:?}", id, reason);
+            self.reports
+                .push(ExecutionReport::rejected(id, Some(side), reason));
+            return Ok(Vec::new());
+        }
+
+        if price == order.price() && qty <= order.leaves_qty() {
+            self.touch(side, price);
+            let resting = &mut self.order_list[index];
+            resting.qty = qty.min(resting.qty);
+            resting.reserve = qty - resting.qty;
+            let cancelled = order.qty() - resting.qty;
+            let order = self.order_list[index];
+            if cancelled > 0 {
+                self.order_feed
+                    .push(OrderFeedEvent::Cancel { id, qty: cancelled });
             }
+            self.reports
+                .push(ExecutionReport::status(&order, ExecType::Replaced));
+            return Ok(Vec::new());
+        }
+
+        // A market order held for the uncross is entered again as a limit order
+        let on_book = !self.auction_market.contains(&index);
+        let feed_mark = self.order_feed.len();
+        if on_book {
+            self.unlink(side, order.price(), index);
+        } else {
+            self.auction_market.retain(|i| *i != index);
+        }
+        self.order_list.delete(&id)?;
+        order.price = price;
+        order.qty = qty;
+        order.reserve = 0;
+        self.reports
+            .push(ExecutionReport::status(&order, ExecType::Replaced));
+        let fills = self.match_order(&mut order, Some(price))?;
+        if order.qty() > 0 {
+            self.rest(order)?;
+        }
+        // The feed moves an order that did not trade in one message, otherwise it leaves
+        // the book before the trades and comes back with what is left
+        if on_book && fills.is_empty() && order.qty() > 0 {
+            if let Some(OrderFeedEvent::Add { qty, .. }) = self.order_feed.pop() {
+                self.order_feed
+                    .push(OrderFeedEvent::Replace { id, price, qty });
+            }
+        } else if on_book {
+            self.order_feed
+                .insert(feed_mark, OrderFeedEvent::Delete { id });
+        }
+        Ok(fills)
+    }
+
+    /// Removes an order from the queue at its price level, dropping the level if it
+    /// becomes empty.
+    fn unlink(&mut self, side: Side, price: Price, index: usize) {
+        self.touch(side, price);
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        if let Entry::Occupied(mut entry) = levels.entry(price) {
+            entry.get_mut().retain(|i| *i != index);
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+
+    /// Checks an order against the instrument's reference data. Market orders have no price.
+    fn validate(&self, qty: u64, price: Option<Price>) -> Result<(), RejectReason> {
+        self.instrument.validate_qty(qty)?;
+        if let Some(price) = price {
+            self.instrument.validate_price(price)?;
+        }
+        Ok(())
+    }
+
+    fn market(
+        &mut self,
+        id: u64,
+        side: Side,
+        qty: u64,
+        owner: u64,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        if let Err(reason) = self.validate(qty, None) {
+            self.reports
+                .push(ExecutionReport::rejected(id, Some(side), reason));
+            return Ok(Vec::new());
+        }
+
+        let price = Price::zero(self.instrument.price_scale());
+        let mut order = OrderInfo::new(id, side, price, qty);
+        order.owner = owner;
+        self.reports
+            .push(ExecutionReport::status(&order, ExecType::New));
+        if self.auction {
+            let index = self.order_list.insert(order)?;
+            self.auction_market.push(index);
+            return Ok(Vec::new());
+        }
+        self.execute_market(order)
+    }
+
+    fn execute_market(&mut self, mut order: OrderInfo) -> Result<Vec<OrderFill>, OrderBookError> {
+        let fills = self.match_order(&mut order, None)?;
+        if order.qty() > 0 {
+            info!(
+                "There is not enough liquidity to fulfill this order {:?}",
+                order.id()
+            );
+            self.kill(&mut order);
         }
 
         Ok(fills)
@@ -128,391 +1002,1993 @@ impl OrderBook {
         id: u64,
         side: Side,
         qty: u64,
-        price: u64,
-    ) -> Result<Vec<OrderFill>, Error> {
-        let mut fills: Vec<OrderFill> = Vec::new();
-        let mut remaining_qty: u64 = qty;
-        match side {
-            Side::Bid => {
-                trace!("In buy, Book: {:?} order: {:?}", self, id);
-                //Safe unwrap as asks not empty
-                while remaining_qty > 0 && !self.asks.is_empty()
-                    && price >= *self.min_ask().unwrap()
-                {
-                    let best_price = *self.min_ask().unwrap();
-
-                    if let Entry::Occupied(mut entry) = self.asks.entry(best_price) {
-                        let best_orders = entry.get_mut();
-
-                        let new_fills = Self::process_order_list(
-                            &mut self.order_list,
-                            best_orders,
-                            remaining_qty,
-                            id,
-                        )?;
-                        if best_orders.is_empty() {
-                            entry.remove();
-                            self.min_ask = self.min_ask().cloned();
-                        }
-                        let filled_qty: u64 = new_fills.iter().map(|f| f.qty()).sum();
-                        remaining_qty -= filled_qty;
-                        fills.extend(new_fills);
-                    }
-                }
-                if remaining_qty > 0 {
-                    let index: usize = self.order_list.insert(id, price, qty)?;
-                    self.bids
-                        .entry(price)
-                        .or_insert_with(|| Vec::with_capacity(10))
-                        .push(index);
-                }
-            }
-            Side::Ask => {
-                while remaining_qty > 0 && !self.bids.is_empty()
-                    && price <= *self.max_bid().unwrap()
-                {
-                    let best_price = *self.max_bid().unwrap();
-
-                    if let Entry::Occupied(mut entry) = self.bids.entry(best_price) {
-                        let best_orders = entry.get_mut();
-
-                        debug!(
-                            "Remaining: {:?}, best bids: {:?}",
-                            remaining_qty, best_orders
-                        );
-
-                        let new_fills = Self::process_order_list(
-                            &mut self.order_list,
-                            best_orders,
-                            remaining_qty,
-                            id,
-                        )?;
-                        if best_orders.is_empty() {
-                            entry.remove();
-                            self.max_bid = self.max_bid().cloned();
-                        }
-                        let filled_qty: u64 = new_fills.iter().map(|fill| fill.qty()).sum();
-                        remaining_qty -= filled_qty;
-                        fills.extend(new_fills);
-                    }
-                }
+        price: Price,
+        tif: TimeInForce,
+        owner: u64,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        if let Err(reason) = self.validate(qty, Some(price)) {
+            self.reports
+                .push(ExecutionReport::rejected(id, Some(side), reason));
+            return Ok(Vec::new());
+        }
 
-                if remaining_qty > 0 {
-                    debug!("Remaining: {:?}", remaining_qty);
-                    let index: usize = self.order_list.insert(id, price, qty)?;
-                    self.asks
-                        .entry(price)
-                        .or_insert_with(|| Vec::with_capacity(10))
-                        .push(index);
+        let now = self.clock.now();
+        let mut order = OrderInfo::new(id, side, price, qty);
+        order.owner = owner;
+        order.expire_at = match tif {
+            TimeInForce::Day => Some(now.date().succ().and_hms(0, 0, 0)),
+            TimeInForce::GoodTillDate(expire_at) => {
+                if expire_at <= now {
+                    self.reports.push(ExecutionReport::rejected(
+                        id,
+                        Some(side),
+                        RejectReason::ExpireTimeInPast,
+                    ));
+                    return Ok(Vec::new());
                 }
+                Some(expire_at)
             }
+            _ => None,
+        };
+        self.reports
+            .push(ExecutionReport::status(&order, ExecType::New));
+        self.execute_limit(order, tif)
+    }
+
+    fn execute_limit(
+        &mut self,
+        mut order: OrderInfo,
+        tif: TimeInForce,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        let price = order.price();
+        if tif == TimeInForce::FillOrKill
+            && self.available_qty(&order, self.clock.now()) < order.qty()
+        {
+            debug!("Not enough liquidity for fill or kill order {:?}", order.id());
+            self.kill(&mut order);
+            return Ok(Vec::new());
         }
 
+        let fills = self.match_order(&mut order, Some(price))?;
+        if order.qty() > 0 {
+            match tif {
+                TimeInForce::ImmediateOrCancel | TimeInForce::FillOrKill => self.kill(&mut order),
+                _ => self.rest(order)?,
+            }
+        }
         Ok(fills)
     }
 
-    fn process_order_list(
-        order_list: &mut OrderList,
-        opposite_orders: &mut Vec<usize>,
-        quantity_still_to_trade: u64,
+    fn iceberg(
+        &mut self,
         id: u64,
-    ) -> Result<Vec<OrderFill>, Error> {
-        /*
-          Takes an OrderList (stack of orders at one price) and an incoming order and matches
-          appropriate trades given the order's quantity.
-          **/
+        side: Side,
+        qty: u64,
+        price: Price,
+        display_qty: u64,
+        owner: u64,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        if let Err(reason) = self.validate(qty, Some(price))
+            .and_then(|_| self.instrument.validate_qty(display_qty))
+        {
+            self.reports
+                .push(ExecutionReport::rejected(id, Some(side), reason));
+            return Ok(Vec::new());
+        }
+
+        // The whole quantity is available when the order takes liquidity, only the peak
+        // is shown once it rests.
+        let mut order = OrderInfo::new(id, side, price, qty);
+        order.owner = owner;
+        if display_qty < qty {
+            order.peak = display_qty;
+        }
+        self.reports
+            .push(ExecutionReport::status(&order, ExecType::New));
+        self.execute_limit(order, TimeInForce::GoodTillCancel)
+    }
+
+    /// Parks a stop order in the trigger book until the last traded price reaches it.
+    fn stop(
+        &mut self,
+        id: u64,
+        side: Side,
+        qty: u64,
+        stop_price: Price,
+        limit: Option<Price>,
+        owner: u64,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        if let Err(reason) = self.validate(qty, Some(stop_price))
+            .and_then(|_| self.validate(qty, limit))
+        {
+            self.reports
+                .push(ExecutionReport::rejected(id, Some(side), reason));
+            return Ok(Vec::new());
+        }
+
+        let stop = StopOrder {
+            id,
+            side,
+            stop_price,
+            limit,
+            qty,
+            owner,
+        };
+        let order = stop.order(self.instrument.price_scale());
+        self.reports
+            .push(ExecutionReport::status(&order, ExecType::New));
+        let stops = match side {
+            Side::Bid => &mut self.buy_stops,
+            Side::Ask => &mut self.sell_stops,
+        };
+        stops.entry(stop_price).or_insert_with(Vec::new).push(stop);
+        self.stop_index.insert(id, (side, stop_price));
+        Ok(Vec::new())
+    }
+
+    /// Turns triggered stops into market or limit orders. Trades from those can trigger
+    /// further stops, which are processed in the same pass.
+    fn trigger_stops(&mut self) -> Result<Vec<OrderFill>, OrderBookError> {
+        let mut fills: Vec<OrderFill> = Vec::new();
+        let price_scale = self.instrument.price_scale();
+        while let Some(stop) = self.next_triggered() {
+            debug!(
+                "Stop order {:?} triggered at {:?}",
+                stop.id, self.last_traded_price
+            );
+            let order = stop.order(price_scale);
+            self.reports
+                .push(ExecutionReport::status(&order, ExecType::Triggered));
+            let new_fills = match stop.limit {
+                Some(_) => self.execute_limit(order, TimeInForce::GoodTillCancel)?,
+                None => self.execute_market(order)?,
+            };
+            fills.extend(new_fills);
+        }
+        Ok(fills)
+    }
+
+    /// Takes the oldest stop whose stop price has been reached by the last traded price.
+    /// Buy stops trigger at or above their stop price, sell stops at or below.
+    fn next_triggered(&mut self) -> Option<StopOrder> {
+        if !self.matching() {
+            return None;
+        }
+        let last = self.last_traded_price?;
+        let trigger = match self.buy_stops.keys().next() {
+            Some(stop_price) if *stop_price <= last => Some((Side::Bid, *stop_price)),
+            _ => match self.sell_stops.keys().next_back() {
+                Some(stop_price) if *stop_price >= last => Some((Side::Ask, *stop_price)),
+                _ => None,
+            },
+        };
+        let (side, stop_price) = trigger?;
+
+        let stops = match side {
+            Side::Bid => &mut self.buy_stops,
+            Side::Ask => &mut self.sell_stops,
+        };
+        let mut entry = match stops.entry(stop_price) {
+            Entry::Occupied(entry) => entry,
+            Entry::Vacant(_) => return None,
+        };
+        let stop = entry.get_mut().remove(0);
+        if entry.get().is_empty() {
+            entry.remove();
+        }
+        self.stop_index.remove(&stop.id);
+        Some(stop)
+    }
+
+    /// Cancels the unfilled part of an order that never made it onto the book.
+    fn kill(&mut self, order: &mut OrderInfo) {
+        order.qty = 0;
+        self.reports
+            .push(ExecutionReport::status(order, ExecType::Cancelled));
+    }
+
+    /// Puts the remainder of an order at the back of the queue at its price.
+    fn rest(&mut self, mut order: OrderInfo) -> Result<(), OrderBookError> {
+        order.show_peak();
+        let price = order.price();
+        let side = order.side();
+        self.touch(side, price);
+        let index: usize = self.order_list.insert(order)?;
+        let levels = match side {
+            Side::Bid => &mut self.bids,
+            Side::Ask => &mut self.asks,
+        };
+        levels
+            .entry(price)
+            .or_insert_with(|| Vec::with_capacity(10))
+            .push(index);
+        self.order_feed.push(Self::feed_add(&order));
+        Ok(())
+    }
+
+    /// Quantity on the opposite side that `order` could trade with right now at its price.
+    /// Orders of the same owner are left out when self-trade prevention would cancel them,
+    /// and nothing behind one is counted when it would stop the incoming order.
+    fn available_qty(&self, order: &OrderInfo, now: DateTime<Utc>) -> u64 {
+        let price = order.price();
+        let levels: Vec<&Vec<usize>> = match order.side() {
+            Side::Bid => self.asks.range(..=price).map(|(_, orders)| orders).collect(),
+            Side::Ask => self.bids.range(price..).rev().map(|(_, orders)| orders).collect(),
+        };
+        let stp = self.instrument.self_trade_prevention();
+        let mut available = 0;
+        for index in levels.into_iter().flat_map(|orders| orders.iter()) {
+            let resting = &self.order_list[*index];
+            if resting.qty() == 0 || resting.is_expired(now) {
+                continue;
+            }
+            if stp.is_some() && resting.same_owner(order) {
+                if stp == Some(SelfTradePrevention::CancelOldest) {
+                    continue;
+                }
+                break;
+            }
+            available += resting.leaves_qty();
+        }
+        available
+    }
+
+    /// Matches an incoming order against the opposite side, best price first, until it is
+    /// filled or no longer crosses `limit`. Market orders have no limit.
+    fn match_order(
+        &mut self,
+        order: &mut OrderInfo,
+        limit: Option<Price>,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        let mut fills: Vec<OrderFill> = Vec::new();
+        if !self.matching() {
+            return Ok(fills);
+        }
+        let now = self.clock.now();
+        let rules = MatchRules {
+            now,
+            policy: self.instrument.allocation_policy(),
+            lot_size: self.instrument.lot_size(),
+            stp: self.instrument.self_trade_prevention(),
+        };
+        trace!("Matching order: {:?} Book: {:?}", order, self);
+
+        while order.qty() > 0 {
+            let best_price = match order.side() {
+                Side::Bid => self.min_ask().cloned(),
+                Side::Ask => self.max_bid().cloned(),
+            };
+            let best_price = match best_price {
+                Some(best_price) => best_price,
+                None => break,
+            };
+            let crosses = match (order.side(), limit) {
+                (_, None) => true,
+                (Side::Bid, Some(price)) => price >= best_price,
+                (Side::Ask, Some(price)) => price <= best_price,
+            };
+            if !crosses || self.trips_breaker(best_price, now) {
+                break;
+            }
+
+            self.touch(order.side().opposite(), best_price);
+            let levels = match order.side() {
+                Side::Bid => &mut self.asks,
+                Side::Ask => &mut self.bids,
+            };
+            if let Entry::Occupied(mut entry) = levels.entry(best_price) {
+                debug!(
+                    "Remaining: {:?}, best orders: {:?}",
+                    order.qty(),
+                    entry.get()
+                );
+                let new_fills = Self::process_order_list(
+                    &mut self.order_list,
+                    entry.get_mut(),
+                    order,
+                    &mut self.reports,
+                    &mut self.order_feed,
+                    rules,
+                )?;
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+                for fill in &new_fills {
+                    self.record_trade(fill.price(), now);
+                    self.market_data.push(MarketDataEvent::Trade {
+                        last_traded_price: fill.price(),
+                        qty: fill.qty(),
+                        aggressor: order.side(),
+                    });
+                }
+                fills.extend(new_fills);
+            } else {
+                return Err(OrderBookError::MissingLevel(
+                    order.side().opposite(),
+                    best_price,
+                ));
+            }
+        }
+
+        Ok(fills)
+    }
+
+    fn process_order_list(
+        order_list: &mut OrderList,
+        opposite_orders: &mut Vec<usize>,
+        order: &mut OrderInfo,
+        reports: &mut Vec<ExecutionReport>,
+        feed: &mut Vec<OrderFeedEvent>,
+        rules: MatchRules,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        /*
+          Takes an OrderList (stack of orders at one price) and an incoming order and matches
+          appropriate trades given the order's quantity, sharing it among the resting orders
+          according to the allocation policy. Resting orders that have passed their expiry
+          time are expired instead of traded with. Iceberg orders whose peak is filled are
+          refreshed and go to the back of the queue.
+
+          Resting orders of the incoming order's owner are handled by the self-trade
+          prevention mode when the scan reaches them. With decrement and cancel they take
+          their share of the allocation like any other order, but both orders are reduced
+          by it instead of trading.
+          **/
+
+        let MatchRules { now, policy, lot_size, stp } = rules;
+        let mut fills: Vec<OrderFill> = Vec::new();
+        let fifo = policy == AllocationPolicy::Fifo;
+
+        debug!(
+            "Process order list, OrderList: {:?} order: {:?}",
+            opposite_orders,
+            order.id()
+        );
+
+        // Pro rata needs every order at the level, FIFO only as many as it will trade with
+        let mut live: Vec<usize> = Vec::new();
+        let mut live_qty: u64 = 0;
+        let mut scanned = 0;
+        let mut cancel_incoming = false;
+        for head_order_idx in opposite_orders.iter() {
+            if fifo && live_qty >= order.qty() {
+                break;
+            }
+            scanned += 1;
+            let head_order = order_list[*head_order_idx];
+            if head_order.qty() == 0 {
+                continue;
+            }
+            if head_order.is_expired(now) {
+                let mut expired = head_order;
+                expired.qty = 0;
+                order_list.delete(&expired.id())?;
+                reports.push(ExecutionReport::status(&expired, ExecType::Expired));
+                feed.push(OrderFeedEvent::Delete { id: expired.id() });
+                continue;
+            }
+            if stp.is_some() && head_order.same_owner(order) {
+                debug!(
+                    "Self trade of {:?} with {:?}, prevention {:?}",
+                    order.id(),
+                    head_order.id(),
+                    stp
+                );
+                match stp {
+                    Some(SelfTradePrevention::CancelNewest) => {
+                        // The resting order keeps its place
+                        scanned -= 1;
+                        cancel_incoming = true;
+                        break;
+                    }
+                    Some(SelfTradePrevention::CancelOldest) => {
+                        Self::cancel_resting(order_list, head_order, reports, feed)?;
+                        continue;
+                    }
+                    Some(SelfTradePrevention::CancelBoth) => {
+                        Self::cancel_resting(order_list, head_order, reports, feed)?;
+                        cancel_incoming = true;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            live.push(*head_order_idx);
+            live_qty += head_order.qty();
+        }
+
+        let resting_qtys: Vec<u64> = live.iter()
+            .map(|index| order_list[*index].qty())
+            .collect();
+        let allocations = allocation::allocate(policy, order.qty(), &resting_qtys, lot_size);
+        debug!("Allocations {:?} to {:?}", allocations, live);
+
+        let mut queue: Vec<usize> = Vec::with_capacity(opposite_orders.len());
+        let mut requeue: Vec<usize> = Vec::new();
+        for (head_order_idx, traded_quantity) in live.iter().zip(allocations) {
+            if traded_quantity == 0 {
+                queue.push(*head_order_idx);
+                continue;
+            }
+            let head_order = &mut order_list[*head_order_idx];
+            if stp == Some(SelfTradePrevention::DecrementAndCancel)
+                && head_order.same_owner(order)
+            {
+                feed.push(if traded_quantity < head_order.qty() {
+                    OrderFeedEvent::Cancel {
+                        id: head_order.id(),
+                        qty: traded_quantity,
+                    }
+                } else {
+                    OrderFeedEvent::Delete { id: head_order.id() }
+                });
+                head_order.qty -= traded_quantity;
+                order.qty -= traded_quantity;
+                let refreshed = head_order.refresh_peak();
+                let head_order = *head_order;
+                if refreshed {
+                    feed.push(Self::feed_add(&head_order));
+                }
+                if head_order.leaves_qty() == 0 {
+                    order_list.delete(&head_order.id())?;
+                    reports.push(ExecutionReport::status(&head_order, ExecType::Cancelled));
+                } else {
+                    reports.push(ExecutionReport::status(&head_order, ExecType::Restated));
+                    if refreshed {
+                        requeue.push(*head_order_idx);
+                    } else {
+                        queue.push(*head_order_idx);
+                    }
+                }
+                let incoming_exec_type = if order.qty() == 0 {
+                    ExecType::Cancelled
+                } else {
+                    ExecType::Restated
+                };
+                reports.push(ExecutionReport::status(order, incoming_exec_type));
+                continue;
+            }
+            let traded_price = head_order.price();
+            head_order.fill(traded_quantity, traded_price);
+            order.fill(traded_quantity, traded_price);
+            let refreshed = head_order.refresh_peak();
+            let head_order = *head_order;
+
+            let fill = OrderFill::new(order.id(), head_order.id(), traded_price, traded_quantity);
+            fills.push(fill);
+            reports.push(ExecutionReport::new(
+                order,
+                Self::fill_type(order),
+                traded_quantity,
+                traded_price,
+            ));
+            reports.push(ExecutionReport::new(
+                &head_order,
+                Self::fill_type(&head_order),
+                traded_quantity,
+                traded_price,
+            ));
+            feed.push(OrderFeedEvent::Executed {
+                id: head_order.id(),
+                qty: traded_quantity,
+                price: traded_price,
+                printable: true,
+            });
+            if refreshed {
+                feed.push(Self::feed_add(&head_order));
+                requeue.push(*head_order_idx);
+            } else if head_order.qty() == 0 {
+                order_list.delete(&head_order.id())?;
+            } else {
+                queue.push(*head_order_idx);
+            }
+        }
+        queue.extend_from_slice(&opposite_orders[scanned..]);
+        queue.extend(requeue);
+        *opposite_orders = queue;
+
+        if cancel_incoming && order.qty() > 0 {
+            order.qty = 0;
+            reports.push(ExecutionReport::status(order, ExecType::Cancelled));
+        }
+
+        Ok(fills)
+    }
+
+    /// Cancels a resting order to prevent a self trade.
+    fn cancel_resting(
+        order_list: &mut OrderList,
+        resting: OrderInfo,
+        reports: &mut Vec<ExecutionReport>,
+        feed: &mut Vec<OrderFeedEvent>,
+    ) -> Result<(), OrderBookError> {
+        let mut cancelled = resting;
+        cancelled.qty = 0;
+        cancelled.reserve = 0;
+        order_list.delete(&cancelled.id())?;
+        reports.push(ExecutionReport::status(&cancelled, ExecType::Cancelled));
+        feed.push(OrderFeedEvent::Delete { id: cancelled.id() });
+        Ok(())
+    }
+
+    /// Feed event for an order joining the back of its queue.
+    fn feed_add(order: &OrderInfo) -> OrderFeedEvent {
+        OrderFeedEvent::Add {
+            id: order.id(),
+            side: order.side(),
+            price: order.price(),
+            qty: order.qty(),
+        }
+    }
+
+    fn level(&self, price: Price, orders: &[usize]) -> Level {
+        let (qty, order_count) = orders
+            .iter()
+            .map(|index| self.order_list[*index].qty())
+            .filter(|qty| *qty > 0)
+            .fold((0, 0), |(qty, count), order_qty| (qty + order_qty, count + 1));
+        Level::new(price, qty, order_count)
+    }
+
+    fn level_qty(&self, side: Side, price: Price) -> u64 {
+        let levels = match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        };
+        levels
+            .get(&price)
+            .map_or(0, |orders| self.level(price, orders).qty())
+    }
+
+    /// Remembers the quantity of a level before the current event changes it.
+    fn touch(&mut self, side: Side, price: Price) {
+        if self.touched
+            .iter()
+            .any(|&(s, p, _)| s == side && p == price)
+        {
+            return;
+        }
+        let qty = self.level_qty(side, price);
+        self.touched.push((side, price, qty));
+    }
+
+    /// Emits level changes for everything touched since the last call.
+    fn publish_levels(&mut self) {
+        for (side, price, old_qty) in mem::replace(&mut self.touched, Vec::new()) {
+            let qty = self.level_qty(side, price);
+            let event = match (old_qty, qty) {
+                (0, 0) => continue,
+                (0, qty) => MarketDataEvent::LevelAdd { side, price, qty },
+                (_, 0) => MarketDataEvent::LevelDelete { side, price },
+                (old_qty, qty) if old_qty != qty => {
+                    MarketDataEvent::LevelUpdate { side, price, qty }
+                }
+                _ => continue,
+            };
+            self.market_data.push(event);
+        }
+        if self.auction {
+            let indicative = self.indicative_uncross();
+            if indicative != self.indicative {
+                self.indicative = indicative;
+                self.market_data.push(MarketDataEvent::Indicative {
+                    equilibrium: indicative,
+                });
+            }
+        }
+    }
+
+    fn fill_type(order: &OrderInfo) -> ExecType {
+        if order.leaves_qty() == 0 {
+            ExecType::Fill
+        } else {
+            ExecType::PartialFill
+        }
+    }
+
+    fn max_bid(&self) -> Option<&Price> {
+        self.bids.keys().next_back()
+    }
+
+    fn min_ask(&self) -> Option<&Price> {
+        self.asks.keys().next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::model::{px, CircuitBreaker};
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    pub fn market_order_insertion_with_no_previous_order() {
+        ::crate::core::test_setup();
+
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+
+        let o1 = OrderEvent::market(1, Side::Bid, 100u64);
+        let o2 = OrderEvent::market(1, Side::Ask, 100u64);
+
+        let filled = ob.event(o1);
+        assert_eq!(filled.unwrap().len(), 0, "Order is not filled");
+        let filled = ob.event(o2);
+        assert_eq!(filled.unwrap().len(), 0, "Order is not filled");
+    }
+
+    #[test]
+    pub fn limit_order_insertion_with_no_previous_order() {
+        ::crate::core::test_setup();
+
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+
+        let o1 = OrderEvent::limit(1, Side::Bid, px(10), 100u64);
+        let o2 = OrderEvent::limit(2, Side::Ask, px(10), 100u64);
+
+        let filled = ob.event(o1);
+        assert_eq!(filled.unwrap().len(), 0, "Order is not filled");
+        //assert_eq!(ob.bids.len(), 1, "There should be one bid");
+        //assert_eq!(ob.asks.len(), 0, "There should be zero asks");
+        let filled = ob.event(o2);
+        assert_eq!(filled.unwrap().len(), 1, "Order should be filled");
+        //assert_eq!(ob.bids.len(), 0, "There should be zero bids");
+        //assert_eq!(ob.asks.len(), 0, "There should be zero asks");
+    }
+
+    struct TestData {
+        pub orders: Vec<OrderEvent>,
+        pub cancels: Vec<u64>,
+        pub orders2: Vec<OrderEvent>,
+        pub expected: Vec<OrderFill>,
+    }
+
+    fn sell_1_101x100() -> OrderEvent {
+        OrderEvent::limit(1, Side::Ask, px(101), 100u64)
+    }
+
+    fn buy_2_101x100() -> OrderEvent {
+        OrderEvent::limit(2, Side::Bid, px(101), 100u64)
+    }
+
+    fn sell_3_101x50() -> OrderEvent {
+        OrderEvent::limit(3, Side::Ask, px(101), 50u64)
+    }
+
+    fn buy_4_101x50() -> OrderEvent {
+        OrderEvent::limit(4, Side::Bid, px(101), 50u64)
+    }
+
+    fn sell_5_101x25() -> OrderEvent {
+        OrderEvent::limit(5, Side::Ask, px(101), 25u64)
+    }
+
+    fn buy_6_101x25() -> OrderEvent {
+        OrderEvent::limit(6, Side::Bid, px(101), 25u64)
+    }
+
+    fn buy_7_101x25() -> OrderEvent {
+        OrderEvent::limit(7, Side::Bid, px(101), 25u64)
+    }
+
+    fn buy_8_100x25() -> OrderEvent {
+        OrderEvent::limit(8, Side::Bid, px(100), 25u64)
+    }
+
+    fn xa101x100() -> OrderFill {
+        OrderFill::new(1, 2, px(101), 100u64)
+    }
+
+    fn xa101x50() -> OrderFill {
+        OrderFill::new(1, 1, px(101), 50u64)
+    }
+
+    fn xb101x50() -> OrderFill {
+        OrderFill::new(3, 4, px(101), 50u64)
+    }
+
+    fn xa101x25() -> OrderFill {
+        OrderFill::new(5, 6, px(101), 25u64)
+    }
+
+    fn xb101x25x() -> OrderFill {
+        OrderFill::new(7, 1, px(101), 25u64)
+    }
+
+    #[test]
+    pub fn test_ask() {
+        run_test(TestData {
+            orders: vec![sell_1_101x100()],
+            cancels: vec![],
+            orders2: vec![],
+            expected: vec![],
+        });
+    }
+
+    #[test]
+    pub fn test_bid() {
+        run_test(TestData {
+            orders: vec![buy_2_101x100()],
+            cancels: vec![],
+            orders2: vec![],
+            expected: vec![],
+        })
+    }
+
+    #[test]
+    fn test_execution() {
+        run_test(TestData {
+            orders: vec![sell_1_101x100(), buy_2_101x100()],
+            cancels: vec![],
+            orders2: vec![],
+            expected: vec![OrderFill::new(2, 1, px(101), 100u64)],
+        });
+
+        run_test(TestData {
+            orders: vec![buy_2_101x100(), sell_1_101x100()],
+            cancels: vec![],
+            orders2: vec![],
+            expected: vec![OrderFill::new(1, 2, px(101), 100u64)],
+        });
+    }
+
+    #[test]
+    fn test_partial_fill1() {
+        run_test(TestData {
+            orders: vec![sell_1_101x100(), buy_4_101x50()],
+            cancels: vec![],
+            orders2: vec![],
+            expected: vec![OrderFill::new(4, 1, px(101), 50u64)],
+        });
+
+        run_test(TestData {
+            orders: vec![buy_4_101x50(), sell_1_101x100()],
+            cancels: vec![],
+            orders2: vec![],
+            expected: vec![OrderFill::new(1, 4, px(101), 50u64)],
+        });
+    }
+
+    #[test]
+    fn test_incremental_over_fill1() {
+        run_test(TestData {
+            orders: vec![
+                sell_1_101x100(),
+                buy_6_101x25(),
+                buy_6_101x25(),
+                buy_6_101x25(),
+                buy_6_101x25(),
+                buy_6_101x25(),
+            ],
+            cancels: vec![],
+            orders2: vec![],
+            expected: vec![
+                OrderFill::new(6, 1, px(101), 25u64),
+                OrderFill::new(6, 1, px(101), 25u64),
+                OrderFill::new(6, 1, px(101), 25u64),
+                OrderFill::new(6, 1, px(101), 25u64),
+            ],
+        });
+    }
+
+    #[test]
+    fn test_incremental_over_fill2() {
+        run_test(TestData {
+            orders: vec![
+                buy_2_101x100(),
+                sell_5_101x25(),
+                sell_5_101x25(),
+                sell_5_101x25(),
+                sell_5_101x25(),
+                sell_5_101x25(),
+            ],
+            cancels: vec![],
+            orders2: vec![],
+            expected: vec![
+                OrderFill::new(5, 2, px(101), 25u64),
+                OrderFill::new(5, 2, px(101), 25u64),
+                OrderFill::new(5, 2, px(101), 25u64),
+                OrderFill::new(5, 2, px(101), 25u64),
+            ],
+        });
+    }
+
+    #[test]
+    fn test_queue_position() {
+        run_test(TestData {
+            orders: vec![buy_6_101x25(), buy_7_101x25(), sell_5_101x25()],
+            cancels: vec![],
+            orders2: vec![],
+            expected: vec![OrderFill::new(5, 6, px(101), 25u64)],
+        });
+    }
+
+    #[test]
+    fn test_cancel_simple() {
+        run_test(TestData {
+            orders: vec![buy_6_101x25()],
+            cancels: vec![6],
+            orders2: vec![sell_5_101x25()],
+            expected: vec![],
+        });
+    }
+
+    #[test]
+    fn test_cancel_from_front_of_queue() {
+        run_test(TestData {
+            orders: vec![buy_6_101x25(), buy_7_101x25()],
+            cancels: vec![6],
+            orders2: vec![sell_5_101x25()],
+            expected: vec![OrderFill::new(5, 7, px(101), 25u64)],
+        });
+    }
+
+    #[test]
+    fn test_cancel_front_back_out_of_order_then_partial_execution() {
+        run_test(TestData {
+            orders: vec![
+                buy_2_101x100(),
+                buy_4_101x50(),
+                buy_7_101x25(),
+                buy_6_101x25(),
+            ],
+            cancels: vec![7, 2, 7],
+            orders2: vec![sell_5_101x25()],
+            expected: vec![OrderFill::new(5, 4, px(101), 25)],
+        });
+    }
+
+    #[test]
+    fn test_replace_reduce_qty_keeps_priority() {
+        run_test(TestData {
+            orders: vec![buy_6_101x25(), buy_7_101x25()],
+            cancels: vec![],
+            orders2: vec![
+                OrderEvent::replace(6, Side::Bid, px(101), 10u64),
+                sell_5_101x25(),
+            ],
+            expected: vec![
+                OrderFill::new(5, 6, px(101), 10u64),
+                OrderFill::new(5, 7, px(101), 15u64),
+            ],
+        });
+    }
+
+    #[test]
+    fn test_replace_increase_qty_loses_priority() {
+        run_test(TestData {
+            orders: vec![buy_6_101x25(), buy_7_101x25()],
+            cancels: vec![],
+            orders2: vec![
+                OrderEvent::replace(6, Side::Bid, px(101), 50u64),
+                sell_5_101x25(),
+            ],
+            expected: vec![OrderFill::new(5, 7, px(101), 25u64)],
+        });
+    }
+
+    #[test]
+    fn test_replace_price_change_loses_priority() {
+        run_test(TestData {
+            orders: vec![buy_6_101x25(), buy_7_101x25()],
+            cancels: vec![],
+            orders2: vec![
+                OrderEvent::replace(6, Side::Bid, px(100), 25u64),
+                OrderEvent::replace(6, Side::Bid, px(101), 25u64),
+                sell_5_101x25(),
+            ],
+            expected: vec![OrderFill::new(5, 7, px(101), 25u64)],
+        });
+    }
+
+    #[test]
+    fn test_replace_crossing_price_matches() {
+        run_test(TestData {
+            orders: vec![sell_1_101x100(), buy_8_100x25()],
+            cancels: vec![],
+            orders2: vec![OrderEvent::replace(8, Side::Bid, px(101), 25u64)],
+            expected: vec![OrderFill::new(8, 1, px(101), 25u64)],
+        });
+    }
+
+    #[test]
+    fn test_replace_crossing_price_rests_remainder() {
+        run_test(TestData {
+            orders: vec![sell_5_101x25(), buy_8_100x25()],
+            cancels: vec![],
+            orders2: vec![
+                OrderEvent::replace(8, Side::Bid, px(101), 50u64),
+                sell_3_101x50(),
+            ],
+            expected: vec![
+                OrderFill::new(8, 5, px(101), 25u64),
+                OrderFill::new(3, 8, px(101), 25u64),
+            ],
+        });
+    }
+
+    #[test]
+    fn test_replace_unknown_and_cancelled_orders() {
+        run_test(TestData {
+            orders: vec![buy_6_101x25()],
+            cancels: vec![6],
+            orders2: vec![
+                OrderEvent::replace(6, Side::Bid, px(101), 25u64),
+                OrderEvent::replace(9, Side::Bid, px(101), 25u64),
+                sell_5_101x25(),
+            ],
+            expected: vec![],
+        });
+    }
+
+    #[test]
+    fn test_replace_to_zero_cancels() {
+        run_test(TestData {
+            orders: vec![buy_6_101x25(), buy_7_101x25()],
+            cancels: vec![],
+            orders2: vec![
+                OrderEvent::replace(6, Side::Bid, px(101), 0u64),
+                sell_5_101x25(),
+            ],
+            expected: vec![OrderFill::new(5, 7, px(101), 25u64)],
+        });
+    }
+
+    fn exec_types(ob: &mut OrderBook) -> Vec<(u64, ExecType)> {
+        ob.drain_reports()
+            .iter()
+            .map(|r| (r.order_id(), r.exec_type()))
+            .collect()
+    }
+
+    #[test]
+    fn test_reports_for_resting_and_filled_orders() {
+        ::crate::core::test_setup();
+
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(sell_1_101x100()).unwrap();
+        assert_eq!(exec_types(&mut ob), vec![(1, ExecType::New)]);
+
+        ob.event(buy_4_101x50()).unwrap();
+        let reports = ob.drain_reports();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].exec_type(), ExecType::New);
+        assert_eq!(reports[1].order_id(), 4);
+        assert_eq!(reports[1].exec_type(), ExecType::Fill);
+        assert_eq!(reports[1].cum_qty(), 50);
+        assert_eq!(reports[1].leaves_qty(), 0);
+        assert_eq!(reports[2].order_id(), 1);
+        assert_eq!(reports[2].exec_type(), ExecType::PartialFill);
+        assert_eq!(reports[2].last_qty(), 50);
+        assert_eq!(reports[2].last_price(), px(101));
+        assert_eq!(reports[2].leaves_qty(), 50);
+
+        ob.event(OrderEvent::limit(2, Side::Bid, px(102), 75)).unwrap();
+        let reports = ob.drain_reports();
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[1].order_id(), 2);
+        assert_eq!(reports[1].exec_type(), ExecType::PartialFill);
+        assert_eq!(reports[1].leaves_qty(), 25);
+        assert_eq!(reports[2].order_id(), 1);
+        assert_eq!(reports[2].exec_type(), ExecType::Fill);
+        assert_eq!(reports[2].cum_qty(), 100);
+        assert_eq!(reports[2].avg_price(), px(101));
+    }
+
+    #[test]
+    fn test_avg_price_of_large_fills_at_fine_scale() {
+        ::crate::core::test_setup();
+
+        let price = |units: u64| Price::new(units, 18);
+        let instrument = Instrument::new("AUDUSD").with_price_scale(18);
+        let mut ob: OrderBook = OrderBook::new(instrument);
+        ob.event(OrderEvent::limit(1, Side::Ask, price(750_000_000_000_000_000), 4_000_000))
+            .unwrap();
+        ob.event(OrderEvent::limit(2, Side::Ask, price(750_000_000_000_000_003), 2_000_000))
+            .unwrap();
+        ob.drain_reports();
+
+        // Each fill is worth far more than u64::MAX price units
+        ob.event(OrderEvent::market(3, Side::Bid, 6_000_000)).unwrap();
+        let reports = ob.drain_reports();
+        let fill = reports
+            .iter()
+            .find(|r| r.order_id() == 3 && r.exec_type() == ExecType::Fill)
+            .unwrap();
+        assert_eq!(fill.cum_qty(), 6_000_000);
+        assert_eq!(fill.avg_price(), price(750_000_000_000_000_001));
+    }
+
+    #[test]
+    fn test_reports_for_market_remainder() {
+        ::crate::core::test_setup();
+
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(sell_5_101x25()).unwrap();
+        ob.event(OrderEvent::limit(3, Side::Ask, px(103), 25)).unwrap();
+        ob.drain_reports();
+
+        ob.event(OrderEvent::market(9, Side::Bid, 75)).unwrap();
+        let reports = ob.drain_reports();
+        let types: Vec<(u64, ExecType)> = reports
+            .iter()
+            .map(|r| (r.order_id(), r.exec_type()))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                (9, ExecType::New),
+                (9, ExecType::PartialFill),
+                (5, ExecType::Fill),
+                (9, ExecType::PartialFill),
+                (3, ExecType::Fill),
+                (9, ExecType::Cancelled),
+            ]
+        );
+        assert_eq!(reports[5].cum_qty(), 50);
+        assert_eq!(reports[5].leaves_qty(), 0);
+        assert_eq!(reports[5].avg_price(), px(102));
+    }
+
+    #[test]
+    fn test_reports_for_cancel_and_replace() {
+        ::crate::core::test_setup();
+
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(buy_6_101x25()).unwrap();
+        ob.event(buy_7_101x25()).unwrap();
+        ob.event(OrderEvent::replace(6, Side::Bid, px(101), 10)).unwrap();
+        ob.event(OrderEvent::replace(7, Side::Ask, px(101), 10)).unwrap();
+        ob.event(OrderEvent::Cancel { id: 7 }).unwrap();
+        ob.event(OrderEvent::Cancel { id: 7 }).unwrap();
+        ob.event(OrderEvent::limit(8, Side::Bid, px(101), 0)).unwrap();
+
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (6, ExecType::New),
+                (7, ExecType::New),
+                (6, ExecType::Replaced),
+                (7, ExecType::Rejected(RejectReason::SideMismatch)),
+                (7, ExecType::Cancelled),
+                (7, ExecType::Rejected(RejectReason::UnknownOrder)),
+                (8, ExecType::Rejected(RejectReason::InvalidQuantity)),
+            ]
+        );
+    }
+
+    fn book_with_clock() -> (OrderBook, ManualClock) {
+        ::crate::core::test_setup();
+
+        let clock = ManualClock::new(Utc.ymd(2018, 4, 2).and_hms(9, 0, 0));
+        let ob = OrderBook::with_clock(Instrument::new("AUDUSD"), Box::new(clock.clone()));
+        (ob, clock)
+    }
+
+    #[test]
+    fn test_ioc_cancels_remainder() {
+        let (mut ob, _) = book_with_clock();
+        ob.event(sell_5_101x25()).unwrap();
+        ob.drain_reports();
+
+        let ioc = OrderEvent::limit_with_tif(4, Side::Bid, px(101), 50, TimeInForce::ImmediateOrCancel);
+        let fills = ob.event(ioc).unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].qty(), 25);
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (4, ExecType::New),
+                (4, ExecType::PartialFill),
+                (5, ExecType::Fill),
+                (4, ExecType::Cancelled),
+            ]
+        );
+
+        let fills = ob.event(sell_3_101x50()).unwrap();
+        assert_eq!(fills.len(), 0, "IOC remainder must not rest");
+    }
+
+    #[test]
+    fn test_fok_kills_without_touching_book() {
+        let (mut ob, _) = book_with_clock();
+        ob.event(sell_5_101x25()).unwrap();
+        ob.event(OrderEvent::limit(3, Side::Ask, px(102), 50)).unwrap();
+        ob.drain_reports();
+
+        let fok = OrderEvent::limit_with_tif(4, Side::Bid, px(101), 50, TimeInForce::FillOrKill);
+        assert_eq!(ob.event(fok).unwrap().len(), 0);
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![(4, ExecType::New), (4, ExecType::Cancelled)]
+        );
+
+        let fok = OrderEvent::limit_with_tif(6, Side::Bid, px(102), 75, TimeInForce::FillOrKill);
+        let fills = ob.event(fok).unwrap();
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[0].ord_id_2(), 5);
+        assert_eq!(fills[1].ord_id_2(), 3);
+        assert_eq!(fills[1].qty(), 50);
+    }
 
-        let mut fills: Vec<OrderFill> = Vec::new();
-        let mut qty_to_fill = quantity_still_to_trade;
-        let mut filled_index = None;
+    #[test]
+    fn test_gtd_expires_in_matching_loop() {
+        let (mut ob, clock) = book_with_clock();
+        let expire_at = clock.now() + Duration::minutes(5);
+        ob.event(OrderEvent::limit_with_tif(
+            6,
+            Side::Bid,
+            px(101),
+            25,
+            TimeInForce::GoodTillDate(expire_at),
+        )).unwrap();
+        ob.event(buy_7_101x25()).unwrap();
+        ob.drain_reports();
+
+        clock.advance(Duration::minutes(5));
+        let fills = ob.event(sell_5_101x25()).unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].ord_id_2(), 7);
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (5, ExecType::New),
+                (6, ExecType::Expired),
+                (5, ExecType::Fill),
+                (7, ExecType::Fill),
+            ]
+        );
+    }
 
-        debug!(
-            "Process order list, OrderList: {:?} order: {:?}",
-            opposite_orders, id
+    #[test]
+    fn test_gtd_in_the_past_is_rejected() {
+        let (mut ob, clock) = book_with_clock();
+        let expire_at = clock.now() - Duration::seconds(1);
+        ob.event(OrderEvent::limit_with_tif(
+            6,
+            Side::Bid,
+            px(101),
+            25,
+            TimeInForce::GoodTillDate(expire_at),
+        )).unwrap();
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![(6, ExecType::Rejected(RejectReason::ExpireTimeInPast))]
         );
+    }
 
-        for (index, head_order_idx) in opposite_orders.iter_mut().enumerate() {
-            if qty_to_fill == 0 {
-                break;
-            }
-            let head_order = &mut order_list[*head_order_idx];
-            let traded_price = head_order.price();
-            let available_qty = head_order.qty();
-            if available_qty == 0 {
-                filled_index = Some(index);
-                continue;
-            }
-            let traded_quantity: u64;
+    #[test]
+    fn test_day_orders_expire_at_end_of_day() {
+        let (mut ob, clock) = book_with_clock();
+        ob.event(OrderEvent::limit_with_tif(6, Side::Bid, px(101), 25, TimeInForce::Day))
+            .unwrap();
+        ob.event(buy_7_101x25()).unwrap();
+        ob.drain_reports();
+
+        clock.advance(Duration::hours(14));
+        assert_eq!(ob.expire_orders().unwrap(), 0);
+
+        clock.advance(Duration::hours(1));
+        assert_eq!(ob.expire_orders().unwrap(), 1);
+        assert_eq!(exec_types(&mut ob), vec![(6, ExecType::Expired)]);
+
+        let fills = ob.event(sell_5_101x25()).unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].ord_id_2(), 7);
+    }
 
-            debug!(
-                "Quantity to trade: {:?} available_qty: {:?}",
-                qty_to_fill, available_qty
-            );
+    #[test]
+    fn test_reference_data_rejections() {
+        ::crate::core::test_setup();
 
-            if qty_to_fill >= available_qty {
-                traded_quantity = available_qty;
-                qty_to_fill -= available_qty;
-                filled_index = Some(index);
-            } else {
-                traded_quantity = qty_to_fill;
-                qty_to_fill = 0u64;
-            }
-            head_order.fill(traded_quantity);
-            let fill: OrderFill;
-            fill = OrderFill::new(id, head_order.id(), traded_price, traded_quantity);
-            fills.push(fill);
-        }
-        debug!("Filled index {:?}", filled_index);
-        if let Some(index) = filled_index {
-            opposite_orders.drain(0..index + 1);
-        }
+        let instrument = Instrument::new("AUDUSD")
+            .with_tick_size(5)
+            .with_lot_size(10)
+            .with_qty_limits(20, 1000)
+            .with_price_band(px(1000), 500);
+        let mut ob: OrderBook = OrderBook::new(instrument);
+
+        ob.event(OrderEvent::limit(1, Side::Bid, px(1002), 100)).unwrap();
+        ob.event(OrderEvent::limit(2, Side::Bid, px(1000), 105)).unwrap();
+        ob.event(OrderEvent::limit(3, Side::Bid, px(1000), 10)).unwrap();
+        ob.event(OrderEvent::limit(4, Side::Bid, px(1000), 2000)).unwrap();
+        ob.event(OrderEvent::limit(5, Side::Bid, px(945), 100)).unwrap();
+        ob.event(OrderEvent::limit(6, Side::Ask, px(1055), 100)).unwrap();
+        ob.event(OrderEvent::market(7, Side::Ask, 5000)).unwrap();
+        ob.event(OrderEvent::limit(8, Side::Bid, px(950), 100)).unwrap();
+        ob.event(OrderEvent::replace(8, Side::Bid, px(940), 100)).unwrap();
+
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (1, ExecType::Rejected(RejectReason::PriceNotOnTick)),
+                (2, ExecType::Rejected(RejectReason::QtyNotOnLot)),
+                (3, ExecType::Rejected(RejectReason::QtyBelowMinimum)),
+                (4, ExecType::Rejected(RejectReason::QtyAboveMaximum)),
+                (5, ExecType::Rejected(RejectReason::PriceOutsideBand)),
+                (6, ExecType::Rejected(RejectReason::PriceOutsideBand)),
+                (7, ExecType::Rejected(RejectReason::QtyAboveMaximum)),
+                (8, ExecType::New),
+                (8, ExecType::Rejected(RejectReason::PriceOutsideBand)),
+            ]
+        );
 
-        Ok(fills)
+        ob.set_reference_price(px(900));
+        ob.event(OrderEvent::limit(9, Side::Bid, px(945), 100)).unwrap();
+        assert_eq!(exec_types(&mut ob), vec![(9, ExecType::New)]);
     }
 
-    fn max_bid(&self) -> Option<&u64> {
-        self.bids.keys().max()
-    }
+    #[test]
+    fn test_depth_queries() {
+        ::crate::core::test_setup();
 
-    fn min_ask(&self) -> Option<&u64> {
-        self.asks.keys().min()
-    }
-}
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        assert_eq!(ob.best_bid(), None);
+        assert_eq!(ob.best_ask(), None);
+
+        ob.event(buy_2_101x100()).unwrap();
+        ob.event(buy_4_101x50()).unwrap();
+        ob.event(buy_8_100x25()).unwrap();
+        ob.event(OrderEvent::limit(9, Side::Bid, px(99), 10)).unwrap();
+        ob.event(OrderEvent::limit(10, Side::Ask, px(103), 30)).unwrap();
+        ob.event(OrderEvent::limit(11, Side::Ask, px(102), 40)).unwrap();
+
+        assert_eq!(ob.best_bid(), Some(Level::new(px(101), 150, 2)));
+        assert_eq!(ob.best_ask(), Some(Level::new(px(102), 40, 1)));
+        assert_eq!(
+            ob.depth(Side::Bid, 2),
+            vec![Level::new(px(101), 150, 2), Level::new(px(100), 25, 1)]
+        );
+        assert_eq!(
+            ob.full_depth(Side::Ask),
+            vec![Level::new(px(102), 40, 1), Level::new(px(103), 30, 1)]
+        );
+        assert_eq!(ob.full_depth(Side::Bid).len(), 3);
+        assert_eq!(ob.last_traded_price(), None);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        ob.event(OrderEvent::market(12, Side::Ask, 120)).unwrap();
+        assert_eq!(ob.best_bid(), Some(Level::new(px(101), 30, 1)));
+        assert_eq!(ob.last_traded_price(), Some(px(101)));
+    }
 
     #[test]
-    pub fn market_order_insertion_with_no_previous_order() {
+    fn test_market_data_events() {
         ::crate::core::test_setup();
 
         let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(buy_6_101x25()).unwrap();
+        ob.event(buy_7_101x25()).unwrap();
+        ob.event(OrderEvent::limit(3, Side::Ask, px(103), 50)).unwrap();
+        assert_eq!(
+            ob.drain_market_data(),
+            vec![
+                MarketDataEvent::LevelAdd {
+                    side: Side::Bid,
+                    price: px(101),
+                    qty: 25,
+                },
+                MarketDataEvent::LevelUpdate {
+                    side: Side::Bid,
+                    price: px(101),
+                    qty: 50,
+                },
+                MarketDataEvent::LevelAdd {
+                    side: Side::Ask,
+                    price: px(103),
+                    qty: 50,
+                },
+            ]
+        );
 
-        let o1 = OrderEvent::market(1, Side::Bid, 100u64);
-        let o2 = OrderEvent::market(1, Side::Ask, 100u64);
+        // Sweeps the bid level and rests the remainder on the ask side
+        ob.event(OrderEvent::limit(5, Side::Ask, px(101), 60)).unwrap();
+        assert_eq!(
+            ob.drain_market_data(),
+            vec![
+                MarketDataEvent::Trade {
+                    last_traded_price: px(101),
+                    qty: 25,
+                    aggressor: Side::Ask,
+                },
+                MarketDataEvent::Trade {
+                    last_traded_price: px(101),
+                    qty: 25,
+                    aggressor: Side::Ask,
+                },
+                MarketDataEvent::LevelDelete {
+                    side: Side::Bid,
+                    price: px(101),
+                },
+                MarketDataEvent::LevelAdd {
+                    side: Side::Ask,
+                    price: px(101),
+                    qty: 10,
+                },
+            ]
+        );
 
-        let filled = ob.event(o1);
-        assert_eq!(filled.unwrap().len(), 0, "Order is not filled");
-        let filled = ob.event(o2);
-        assert_eq!(filled.unwrap().len(), 0, "Order is not filled");
+        ob.event(OrderEvent::replace(3, Side::Ask, px(103), 20)).unwrap();
+        ob.cancel(5).unwrap();
+        assert_eq!(ob.cancel(5), Err(OrderBookError::UnknownOrder(5)));
+        assert_eq!(
+            ob.drain_market_data(),
+            vec![
+                MarketDataEvent::LevelUpdate {
+                    side: Side::Ask,
+                    price: px(103),
+                    qty: 20,
+                },
+                MarketDataEvent::LevelDelete {
+                    side: Side::Ask,
+                    price: px(101),
+                },
+            ]
+        );
     }
 
     #[test]
-    pub fn limit_order_insertion_with_no_previous_order() {
+    fn test_stop_limit_triggers_and_rests() {
         ::crate::core::test_setup();
 
         let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(OrderEvent::limit(50, Side::Ask, px(105), 10)).unwrap();
+        ob.event(OrderEvent::limit(51, Side::Ask, px(107), 10)).unwrap();
+        ob.event(OrderEvent::stop_limit(60, Side::Bid, px(105), px(106), 20))
+            .unwrap();
+        assert_eq!(ob.best_bid(), None, "Stops are not in the visible book");
+        ob.drain_reports();
+
+        let fills = ob.event(OrderEvent::limit(61, Side::Bid, px(105), 5)).unwrap();
+        assert_eq!(fills.len(), 2);
+        assert_eq!(fills[1].ord_id_1(), 60);
+        assert_eq!(fills[1].ord_id_2(), 50);
+        assert_eq!(fills[1].qty(), 5);
+        assert_eq!(ob.best_bid(), Some(Level::new(px(106), 15, 1)));
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (61, ExecType::New),
+                (61, ExecType::Fill),
+                (50, ExecType::PartialFill),
+                (60, ExecType::Triggered),
+                (60, ExecType::PartialFill),
+                (50, ExecType::Fill),
+            ]
+        );
+    }
 
-        let o1 = OrderEvent::limit(1, Side::Bid, 10u64, 100u64);
-        let o2 = OrderEvent::limit(2, Side::Ask, 10u64, 100u64);
+    #[test]
+    fn test_stop_market_cascade() {
+        ::crate::core::test_setup();
 
-        let filled = ob.event(o1);
-        assert_eq!(filled.unwrap().len(), 0, "Order is not filled");
-        //assert_eq!(ob.bids.len(), 1, "There should be one bid");
-        //assert_eq!(ob.asks.len(), 0, "There should be zero asks");
-        let filled = ob.event(o2);
-        assert_eq!(filled.unwrap().len(), 1, "Order should be filled");
-        //assert_eq!(ob.bids.len(), 0, "There should be zero bids");
-        //assert_eq!(ob.asks.len(), 0, "There should be zero asks");
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(OrderEvent::limit(20, Side::Bid, px(100), 10)).unwrap();
+        ob.event(OrderEvent::limit(21, Side::Bid, px(98), 10)).unwrap();
+        ob.event(OrderEvent::limit(22, Side::Bid, px(96), 10)).unwrap();
+        ob.event(OrderEvent::stop_market(30, Side::Ask, px(99), 10)).unwrap();
+        ob.event(OrderEvent::stop_market(31, Side::Ask, px(97), 10)).unwrap();
+
+        let fills = ob.event(OrderEvent::market(40, Side::Ask, 10)).unwrap();
+        assert_eq!(fills.len(), 1, "Trade at 100 does not reach any stop");
+
+        let fills = ob.event(OrderEvent::limit(41, Side::Ask, px(98), 5)).unwrap();
+        let summary: Vec<(u64, u64, Price, u64)> = fills
+            .iter()
+            .map(|f| (f.ord_id_1(), f.ord_id_2(), f.price(), f.qty()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (41, 21, px(98), 5),
+                (30, 21, px(98), 5),
+                (30, 22, px(96), 5),
+                (31, 22, px(96), 5),
+            ]
+        );
+        assert_eq!(ob.last_traded_price(), Some(px(96)));
+        assert_eq!(ob.best_bid(), None);
     }
 
-    struct TestData {
-        pub orders: Vec<OrderEvent>,
-        pub cancels: Vec<u64>,
-        pub orders2: Vec<OrderEvent>,
-        pub expected: Vec<OrderFill>,
+    #[test]
+    fn test_cancelled_stop_does_not_trigger() {
+        ::crate::core::test_setup();
+
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(OrderEvent::limit(20, Side::Bid, px(100), 10)).unwrap();
+        ob.event(OrderEvent::stop_market(30, Side::Ask, px(100), 10)).unwrap();
+        ob.event(OrderEvent::Cancel { id: 30 }).unwrap();
+        ob.drain_reports();
+
+        let fills = ob.event(OrderEvent::limit(41, Side::Ask, px(100), 5)).unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (41, ExecType::New),
+                (41, ExecType::Fill),
+                (20, ExecType::PartialFill),
+            ]
+        );
     }
 
-    fn sell_1_101x100() -> OrderEvent {
-        OrderEvent::limit(1, Side::Ask, 101u64, 100u64)
+    #[test]
+    fn test_iceberg_refreshes_to_back_of_queue() {
+        ::crate::core::test_setup();
+
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(OrderEvent::iceberg(1, Side::Ask, px(101), 70, 20)).unwrap();
+        ob.event(OrderEvent::limit(2, Side::Ask, px(101), 30)).unwrap();
+        assert_eq!(ob.best_ask(), Some(Level::new(px(101), 50, 2)));
+
+        let fills = ob.event(OrderEvent::limit(3, Side::Bid, px(101), 25)).unwrap();
+        let summary: Vec<(u64, u64)> = fills.iter().map(|f| (f.ord_id_2(), f.qty())).collect();
+        assert_eq!(summary, vec![(1, 20), (2, 5)]);
+        assert_eq!(ob.best_ask(), Some(Level::new(px(101), 45, 2)));
+
+        ob.drain_reports();
+        let fills = ob.event(OrderEvent::limit(4, Side::Bid, px(101), 60)).unwrap();
+        let summary: Vec<(u64, u64)> = fills.iter().map(|f| (f.ord_id_2(), f.qty())).collect();
+        assert_eq!(summary, vec![(2, 25), (1, 20), (1, 15)]);
+        assert_eq!(ob.best_ask(), Some(Level::new(px(101), 5, 1)));
+
+        let reports = ob.drain_reports();
+        let last = reports.last().unwrap();
+        assert_eq!(last.order_id(), 1);
+        assert_eq!(last.exec_type(), ExecType::PartialFill);
+        assert_eq!(last.cum_qty(), 55);
+        assert_eq!(last.leaves_qty(), 15);
     }
 
-    fn buy_2_101x100() -> OrderEvent {
-        OrderEvent::limit(2, Side::Bid, 101u64, 100u64)
+    #[test]
+    fn test_aggressive_iceberg_uses_full_qty_then_shows_peak() {
+        ::crate::core::test_setup();
+
+        let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(OrderEvent::limit(1, Side::Ask, px(101), 20)).unwrap();
+        let fills = ob.event(OrderEvent::iceberg(2, Side::Bid, px(101), 50, 10))
+            .unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].qty(), 20);
+        assert_eq!(ob.best_bid(), Some(Level::new(px(101), 10, 1)));
+
+        // Reducing the total keeps the order where it is
+        ob.event(OrderEvent::limit(3, Side::Bid, px(101), 10)).unwrap();
+        ob.event(OrderEvent::replace(2, Side::Bid, px(101), 15)).unwrap();
+        let fills = ob.event(OrderEvent::market(4, Side::Ask, 25)).unwrap();
+        let summary: Vec<(u64, u64)> = fills.iter().map(|f| (f.ord_id_2(), f.qty())).collect();
+        assert_eq!(summary, vec![(2, 10), (3, 10), (2, 5)]);
+        assert_eq!(ob.best_bid(), None);
     }
 
-    fn sell_3_101x50() -> OrderEvent {
-        OrderEvent::limit(3, Side::Ask, 101u64, 50u64)
+    fn pro_rata_book(policy: AllocationPolicy) -> OrderBook {
+        let mut ob = OrderBook::new(Instrument::new("ES").with_allocation_policy(policy));
+        ob.event(OrderEvent::limit(1, Side::Ask, px(101), 40)).unwrap();
+        ob.event(OrderEvent::limit(2, Side::Ask, px(101), 100)).unwrap();
+        ob.event(OrderEvent::limit(3, Side::Ask, px(101), 300)).unwrap();
+        ob.event(OrderEvent::limit(4, Side::Ask, px(102), 50)).unwrap();
+        ob
     }
 
-    fn buy_4_101x50() -> OrderEvent {
-        OrderEvent::limit(4, Side::Bid, 101u64, 50u64)
+    #[test]
+    fn test_pro_rata_allocation() {
+        ::crate::core::test_setup();
+
+        let mut ob = pro_rata_book(AllocationPolicy::ProRata);
+        let fills = ob.event(OrderEvent::market(5, Side::Bid, 110)).unwrap();
+        let summary: Vec<(u64, u64)> = fills.iter().map(|f| (f.ord_id_2(), f.qty())).collect();
+        // Exact shares are 10, 25 and 75
+        assert_eq!(summary, vec![(1, 10), (2, 25), (3, 75)]);
+        assert_eq!(ob.best_ask(), Some(Level::new(px(101), 330, 3)));
+
+        // Sweeping past the level fills it completely before moving on
+        let fills = ob.event(OrderEvent::limit(6, Side::Bid, px(102), 340)).unwrap();
+        let summary: Vec<(u64, u64)> = fills.iter().map(|f| (f.ord_id_2(), f.qty())).collect();
+        assert_eq!(summary, vec![(1, 30), (2, 75), (3, 225), (4, 10)]);
+        assert_eq!(ob.best_ask(), Some(Level::new(px(102), 40, 1)));
     }
 
-    fn sell_5_101x25() -> OrderEvent {
-        OrderEvent::limit(5, Side::Ask, 101u64, 25u64)
+    #[test]
+    fn test_priority_pro_rata_allocation() {
+        ::crate::core::test_setup();
+
+        let mut ob = pro_rata_book(AllocationPolicy::PriorityProRata);
+        let fills = ob.event(OrderEvent::market(5, Side::Bid, 100)).unwrap();
+        let summary: Vec<(u64, u64)> = fills.iter().map(|f| (f.ord_id_2(), f.qty())).collect();
+        assert_eq!(summary, vec![(1, 40), (2, 15), (3, 45)]);
+
+        // Order 2 is now at the front of the queue
+        let fills = ob.event(OrderEvent::market(6, Side::Bid, 100)).unwrap();
+        let summary: Vec<(u64, u64)> = fills.iter().map(|f| (f.ord_id_2(), f.qty())).collect();
+        assert_eq!(summary, vec![(2, 85), (3, 15)]);
     }
 
-    fn buy_6_101x25() -> OrderEvent {
-        OrderEvent::limit(6, Side::Bid, 101u64, 25u64)
+    #[test]
+    fn test_pro_rata_minimum_allocation() {
+        ::crate::core::test_setup();
+
+        let mut ob = pro_rata_book(AllocationPolicy::ProRataWithMinimum(10));
+        let fills = ob.event(OrderEvent::market(5, Side::Bid, 22)).unwrap();
+        let summary: Vec<(u64, u64)> = fills.iter().map(|f| (f.ord_id_2(), f.qty())).collect();
+        // Exact shares are 2, 5 and 15, only order 3 reaches the minimum and the rest is
+        // handed out in queue order
+        assert_eq!(summary, vec![(1, 7), (3, 15)]);
     }
 
-    fn buy_7_101x25() -> OrderEvent {
-        OrderEvent::limit(7, Side::Bid, 101u64, 25u64)
+    fn stp_book(mode: SelfTradePrevention) -> OrderBook {
+        let instrument = Instrument::new("AUDUSD").with_self_trade_prevention(mode);
+        let mut ob = OrderBook::new(instrument);
+        ob.event(OrderEvent::limit(1, Side::Ask, px(101), 30).with_owner(7))
+            .unwrap();
+        ob.event(OrderEvent::limit(2, Side::Ask, px(101), 30).with_owner(8))
+            .unwrap();
+        ob.event(OrderEvent::limit(3, Side::Ask, px(102), 30).with_owner(7))
+            .unwrap();
+        ob.drain_reports();
+        ob
     }
 
-    fn xa101x100() -> OrderFill {
-        OrderFill::new(1, 2, 101u64, 100u64)
+    fn buy_5_102x50_owner_8() -> OrderEvent {
+        OrderEvent::limit(5, Side::Bid, px(102), 50).with_owner(8)
     }
 
-    fn xa101x50() -> OrderFill {
-        OrderFill::new(1, 1, 101u64, 50u64)
+    fn fill_summary(fills: &[OrderFill]) -> Vec<(u64, u64)> {
+        fills.iter().map(|f| (f.ord_id_2(), f.qty())).collect()
     }
 
-    fn xb101x50() -> OrderFill {
-        OrderFill::new(3, 4, 101u64, 50u64)
+    #[test]
+    fn test_stp_cancel_newest() {
+        ::crate::core::test_setup();
+
+        let mut ob = stp_book(SelfTradePrevention::CancelNewest);
+        let fok = OrderEvent::limit_with_tif(6, Side::Bid, px(102), 50, TimeInForce::FillOrKill)
+            .with_owner(8);
+        assert!(ob.event(fok).unwrap().is_empty());
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![(6, ExecType::New), (6, ExecType::Cancelled)]
+        );
+
+        let fills = ob.event(buy_5_102x50_owner_8()).unwrap();
+        assert_eq!(fill_summary(&fills), vec![(1, 30)]);
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (5, ExecType::New),
+                (5, ExecType::PartialFill),
+                (1, ExecType::Fill),
+                (5, ExecType::Cancelled),
+            ]
+        );
+        assert_eq!(ob.best_ask(), Some(Level::new(px(101), 30, 1)));
+        assert_eq!(ob.best_bid(), None);
     }
 
-    fn xa101x25() -> OrderFill {
-        OrderFill::new(5, 6, 101u64, 25u64)
+    #[test]
+    fn test_stp_cancel_oldest() {
+        ::crate::core::test_setup();
+
+        let mut ob = stp_book(SelfTradePrevention::CancelOldest);
+        let fills = ob.event(buy_5_102x50_owner_8()).unwrap();
+        assert_eq!(fill_summary(&fills), vec![(1, 30), (3, 20)]);
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (5, ExecType::New),
+                (2, ExecType::Cancelled),
+                (5, ExecType::PartialFill),
+                (1, ExecType::Fill),
+                (5, ExecType::Fill),
+                (3, ExecType::PartialFill),
+            ]
+        );
+        assert_eq!(ob.best_ask(), Some(Level::new(px(102), 10, 1)));
     }
 
-    fn xb101x25x() -> OrderFill {
-        OrderFill::new(7, 1, 101u64, 25u64)
+    #[test]
+    fn test_stp_cancel_both() {
+        ::crate::core::test_setup();
+
+        let mut ob = stp_book(SelfTradePrevention::CancelBoth);
+        let fills = ob.event(buy_5_102x50_owner_8()).unwrap();
+        assert_eq!(fill_summary(&fills), vec![(1, 30)]);
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (5, ExecType::New),
+                (2, ExecType::Cancelled),
+                (5, ExecType::PartialFill),
+                (1, ExecType::Fill),
+                (5, ExecType::Cancelled),
+            ]
+        );
+        assert_eq!(ob.best_ask(), Some(Level::new(px(102), 30, 1)));
+        assert_eq!(ob.best_bid(), None);
     }
 
     #[test]
-    pub fn test_ask() {
-        run_test(TestData {
-            orders: vec![sell_1_101x100()],
-            cancels: vec![],
-            orders2: vec![],
-            expected: vec![],
-        });
+    fn test_stp_decrement_and_cancel() {
+        ::crate::core::test_setup();
+
+        let mut ob = stp_book(SelfTradePrevention::DecrementAndCancel);
+        let fills = ob.event(buy_5_102x50_owner_8()).unwrap();
+        assert_eq!(fill_summary(&fills), vec![(1, 30)]);
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (5, ExecType::New),
+                (5, ExecType::PartialFill),
+                (1, ExecType::Fill),
+                (2, ExecType::Restated),
+                (5, ExecType::Cancelled),
+            ]
+        );
+        assert_eq!(ob.best_ask(), Some(Level::new(px(101), 10, 1)));
+
+        // This time the resting order is the smaller one and is cancelled
+        let fills = ob.event(OrderEvent::market(6, Side::Bid, 15).with_owner(8))
+            .unwrap();
+        assert_eq!(fill_summary(&fills), vec![(3, 5)]);
+        assert_eq!(
+            exec_types(&mut ob),
+            vec![
+                (6, ExecType::New),
+                (2, ExecType::Cancelled),
+                (6, ExecType::Restated),
+                (6, ExecType::Fill),
+                (3, ExecType::PartialFill),
+            ]
+        );
+        assert_eq!(ob.best_ask(), Some(Level::new(px(102), 25, 1)));
     }
 
     #[test]
-    pub fn test_bid() {
-        run_test(TestData {
-            orders: vec![buy_2_101x100()],
-            cancels: vec![],
-            orders2: vec![],
-            expected: vec![],
-        })
+    fn test_same_owner_trades_without_stp() {
+        let mut ob = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(OrderEvent::limit(1, Side::Ask, px(101), 30).with_owner(8))
+            .unwrap();
+        let fills = ob.event(OrderEvent::market(2, Side::Bid, 30).with_owner(8))
+            .unwrap();
+        assert_eq!(fill_summary(&fills), vec![(1, 30)]);
     }
 
     #[test]
-    fn test_execution() {
-        run_test(TestData {
-            orders: vec![sell_1_101x100(), buy_2_101x100()],
-            cancels: vec![],
-            orders2: vec![],
-            expected: vec![OrderFill::new(2, 1, 101u64, 100u64)],
-        });
+    fn test_duplicate_order_id_rejected() {
+        ::crate::core::test_setup();
 
-        run_test(TestData {
-            orders: vec![buy_2_101x100(), sell_1_101x100()],
-            cancels: vec![],
-            orders2: vec![],
-            expected: vec![OrderFill::new(1, 2, 101u64, 100u64)],
-        });
+        let mut ob = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(buy_6_101x25()).unwrap();
+        ob.event(OrderEvent::stop_market(9, Side::Bid, px(105), 10))
+            .unwrap();
+        ob.drain_reports();
+
+        ob.event(OrderEvent::limit(6, Side::Ask, px(101), 25)).unwrap();
+        ob.event(OrderEvent::market(9, Side::Ask, 10)).unwrap();
+        let rejects: Vec<ExecType> = ob.drain_reports()
+            .iter()
+            .map(|report| report.exec_type())
+            .collect();
+        assert_eq!(
+            rejects,
+            vec![
+                ExecType::Rejected(RejectReason::DuplicateOrderId),
+                ExecType::Rejected(RejectReason::DuplicateOrderId),
+            ]
+        );
+        assert_eq!(ob.order(6).unwrap().leaves_qty(), 25);
+        assert!(ob.best_ask().is_none());
+
+        // The id is free again once the order is gone
+        ob.cancel(6).unwrap();
+        ob.event(OrderEvent::limit(6, Side::Ask, px(102), 25)).unwrap();
+        assert_eq!(ob.order(6).unwrap().side(), Side::Ask);
     }
 
     #[test]
-    fn test_partial_fill1() {
-        run_test(TestData {
-            orders: vec![sell_1_101x100(), buy_4_101x50()],
-            cancels: vec![],
-            orders2: vec![],
-            expected: vec![OrderFill::new(4, 1, 101u64, 50u64)],
-        });
+    fn test_order_lookup() {
+        ::crate::core::test_setup();
 
-        run_test(TestData {
-            orders: vec![buy_4_101x50(), sell_1_101x100()],
-            cancels: vec![],
-            orders2: vec![],
-            expected: vec![OrderFill::new(1, 4, 101u64, 50u64)],
-        });
+        let mut ob = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.event(buy_6_101x25()).unwrap();
+        ob.event(buy_7_101x25()).unwrap();
+        ob.event(buy_8_100x25()).unwrap();
+        ob.event(OrderEvent::iceberg(10, Side::Ask, px(103), 100, 20))
+            .unwrap();
+        ob.event(OrderEvent::stop_limit(11, Side::Ask, px(99), px(98), 5))
+            .unwrap();
+        ob.event(OrderEvent::limit(12, Side::Ask, px(101), 10).with_owner(3))
+            .unwrap();
+
+        let order = ob.order(6).unwrap();
+        assert_eq!(order.price(), px(101));
+        assert_eq!(order.leaves_qty(), 15);
+        assert_eq!(order.cum_qty(), 10);
+        assert_eq!(order.queue_position(), Some(0));
+        assert_eq!(ob.order(7).unwrap().queue_position(), Some(1));
+
+        let iceberg = ob.order(10).unwrap();
+        assert_eq!(iceberg.leaves_qty(), 100);
+        assert_eq!(iceberg.display_qty(), 20);
+
+        let stop = ob.order(11).unwrap();
+        assert_eq!(stop.stop_price(), Some(px(99)));
+        assert_eq!(stop.price(), px(98));
+        assert_eq!(stop.queue_position(), None);
+
+        assert!(ob.order(12).is_none(), "Filled orders are not live");
+        assert!(ob.order(99).is_none());
+
+        let ids: Vec<u64> = ob.open_orders().iter().map(|order| order.id()).collect();
+        assert_eq!(ids, vec![6, 7, 8, 10, 11]);
     }
 
     #[test]
-    fn test_incremental_over_fill1() {
-        run_test(TestData {
-            orders: vec![
-                sell_1_101x100(),
-                buy_6_101x25(),
-                buy_6_101x25(),
-                buy_6_101x25(),
-                buy_6_101x25(),
-                buy_6_101x25(),
-            ],
-            cancels: vec![],
-            orders2: vec![],
-            expected: vec![
-                OrderFill::new(6, 1, 101u64, 25u64),
-                OrderFill::new(6, 1, 101u64, 25u64),
-                OrderFill::new(6, 1, 101u64, 25u64),
-                OrderFill::new(6, 1, 101u64, 25u64),
-            ],
-        });
+    fn test_auction_uncross() {
+        ::crate::core::test_setup();
+
+        let mut ob = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.set_phase(TradingPhase::Closed).unwrap();
+        ob.set_phase(TradingPhase::PreOpen).unwrap();
+        ob.event(OrderEvent::market(8, Side::Bid, 10)).unwrap();
+        assert_eq!(
+            ob.drain_reports()[0].exec_type(),
+            ExecType::Rejected(RejectReason::NotAcceptedInPhase)
+        );
+        ob.set_phase(TradingPhase::OpeningAuction).unwrap();
+        let events = vec![
+            OrderEvent::limit(1, Side::Bid, px(102), 100),
+            OrderEvent::limit(2, Side::Bid, px(101), 200),
+            OrderEvent::limit(3, Side::Ask, px(100), 150),
+            OrderEvent::limit(4, Side::Ask, px(101), 100),
+            OrderEvent::market(5, Side::Bid, 50),
+        ];
+        for event in events {
+            assert_eq!(ob.event(event).unwrap().len(), 0, "Auctions do not match");
+        }
+        ob.event(OrderEvent::limit_with_tif(
+            6,
+            Side::Ask,
+            px(100),
+            10,
+            TimeInForce::ImmediateOrCancel,
+        )).unwrap();
+        assert_eq!(
+            ob.drain_reports().last().unwrap().exec_type(),
+            ExecType::Rejected(RejectReason::NotAcceptedInPhase)
+        );
+        assert_eq!(ob.best_bid().unwrap().price(), px(102));
+        assert_eq!(ob.best_ask().unwrap().price(), px(100));
+        assert_eq!(ob.order(5).unwrap().queue_position(), Some(0));
+
+        let indicative = ob.indicative_uncross().unwrap();
+        assert_eq!(indicative.price(), px(101));
+        assert_eq!(indicative.volume(), 250);
+        assert_eq!(indicative.surplus(), 100);
+        assert_eq!(indicative.surplus_side(), Some(Side::Bid));
+        assert!(
+            ob.drain_market_data().contains(&MarketDataEvent::Indicative {
+                equilibrium: Some(indicative),
+            })
+        );
+
+        let fills = ob.set_phase(TradingPhase::Continuous).unwrap();
+        let fills: Vec<(u64, u64, Price, u64)> = fills
+            .iter()
+            .map(|f| (f.ord_id_1(), f.ord_id_2(), f.price(), f.qty()))
+            .collect();
+        assert_eq!(
+            fills,
+            vec![
+                (5, 3, px(101), 50),
+                (1, 3, px(101), 100),
+                (2, 4, px(101), 100),
+            ]
+        );
+        assert_eq!(ob.phase(), TradingPhase::Continuous);
+        assert_eq!(ob.last_traded_price(), Some(px(101)));
+        assert_eq!(ob.best_bid(), Some(Level::new(px(101), 100, 1)));
+        assert_eq!(ob.best_ask(), None);
+        assert!(ob.drain_market_data().contains(&MarketDataEvent::Uncross {
+            price: px(101),
+            qty: 250,
+        }));
+
+        // Back to continuous matching
+        let fills = ob.event(OrderEvent::limit(7, Side::Ask, px(101), 100))
+            .unwrap();
+        assert_eq!(fill_summary(&fills), vec![(2, 100)]);
     }
 
     #[test]
-    fn test_incremental_over_fill2() {
-        run_test(TestData {
-            orders: vec![
-                buy_2_101x100(),
-                sell_5_101x25(),
-                sell_5_101x25(),
-                sell_5_101x25(),
-                sell_5_101x25(),
-                sell_5_101x25(),
-            ],
-            cancels: vec![],
-            orders2: vec![],
-            expected: vec![
-                OrderFill::new(5, 2, 101u64, 25u64),
-                OrderFill::new(5, 2, 101u64, 25u64),
-                OrderFill::new(5, 2, 101u64, 25u64),
-                OrderFill::new(5, 2, 101u64, 25u64),
-            ],
-        });
+    fn test_auction_cancels_unmatched_market_orders() {
+        ::crate::core::test_setup();
+
+        let mut ob = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.set_phase(TradingPhase::ClosingAuction).unwrap();
+        ob.event(OrderEvent::market(1, Side::Bid, 50)).unwrap();
+        ob.event(OrderEvent::limit(2, Side::Bid, px(99), 50)).unwrap();
+        ob.event(OrderEvent::limit(3, Side::Bid, px(98), 50)).unwrap();
+        assert_eq!(ob.indicative_uncross(), None);
+        ob.drain_reports();
+
+        assert_eq!(ob.set_phase(TradingPhase::Closed).unwrap().len(), 0);
+        let reports: Vec<(u64, ExecType)> = ob.drain_reports()
+            .iter()
+            .map(|r| (r.order_id(), r.exec_type()))
+            .collect();
+        assert_eq!(reports, vec![(1, ExecType::Cancelled)]);
+        assert_eq!(ob.open_orders().len(), 2);
+        assert_eq!(ob.last_traded_price(), None);
     }
 
     #[test]
-    fn test_queue_position() {
-        run_test(TestData {
-            orders: vec![buy_6_101x25(), buy_7_101x25(), sell_5_101x25()],
-            cancels: vec![],
-            orders2: vec![],
-            expected: vec![OrderFill::new(5, 6, 101u64, 25u64)],
-        });
+    fn test_auction_replace_of_held_market_order() {
+        ::crate::core::test_setup();
+
+        let mut ob = OrderBook::new(Instrument::new("AUDUSD"));
+        ob.set_phase(TradingPhase::ClosingAuction).unwrap();
+        ob.event(OrderEvent::market(1, Side::Bid, 100)).unwrap();
+        ob.event(OrderEvent::limit(2, Side::Ask, px(100), 100)).unwrap();
+        ob.event(OrderEvent::replace(1, Side::Bid, px(101), 100)).unwrap();
+
+        assert_eq!(ob.indicative_uncross().unwrap().volume(), 100);
+        let mut ids: Vec<u64> = ob.open_orders().iter().map(|o| o.id()).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(ob.best_bid(), Some(Level::new(px(101), 100, 1)));
+
+        let fills = ob.set_phase(TradingPhase::Closed).unwrap();
+        assert_eq!(fill_summary(&fills), vec![(2, 100)]);
+        assert!(ob.open_orders().is_empty());
     }
 
     #[test]
-    fn test_cancel_simple() {
-        run_test(TestData {
-            orders: vec![buy_6_101x25()],
-            cancels: vec![6],
-            orders2: vec![sell_5_101x25()],
-            expected: vec![],
-        });
+    fn test_trading_phases() {
+        ::crate::core::test_setup();
+
+        let mut ob = OrderBook::new(Instrument::new("AUDUSD"));
+        assert_eq!(ob.phase(), TradingPhase::Continuous);
+        ob.event(OrderEvent::limit(1, Side::Ask, px(101), 100)).unwrap();
+        assert_eq!(
+            ob.set_phase(TradingPhase::OpeningAuction).err(),
+            Some(OrderBookError::InvalidPhaseTransition(
+                TradingPhase::Continuous,
+                TradingPhase::OpeningAuction
+            ))
+        );
+
+        ob.set_phase(TradingPhase::Closed).unwrap();
+        ob.event(OrderEvent::limit(2, Side::Bid, px(101), 100)).unwrap();
+        ob.event(OrderEvent::Cancel { id: 1 }).unwrap();
+        let reports: Vec<(u64, ExecType)> = ob.drain_reports()
+            .iter()
+            .map(|r| (r.order_id(), r.exec_type()))
+            .collect();
+        assert_eq!(
+            reports,
+            vec![
+                (1, ExecType::New),
+                (2, ExecType::Rejected(RejectReason::NotAcceptedInPhase)),
+                (1, ExecType::Cancelled),
+            ]
+        );
+        assert!(
+            ob.drain_market_data()
+                .contains(&MarketDataEvent::PhaseChange {
+                    phase: TradingPhase::Closed,
+                })
+        );
+        assert!(ob.set_phase(TradingPhase::Continuous).is_err());
     }
 
     #[test]
-    fn test_cancel_from_front_of_queue() {
-        run_test(TestData {
-            orders: vec![buy_6_101x25(), buy_7_101x25()],
-            cancels: vec![6],
-            orders2: vec![sell_5_101x25()],
-            expected: vec![OrderFill::new(5, 7, 101u64, 25u64)],
-        });
+    fn test_circuit_breaker_halts_trading() {
+        ::crate::core::test_setup();
+
+        let clock = ManualClock::new(Utc.ymd(2018, 4, 2).and_hms(9, 0, 0));
+        let instrument = Instrument::new("AUDUSD")
+            .with_circuit_breaker(CircuitBreaker::new(500, Duration::seconds(60)));
+        let mut ob = OrderBook::with_clock(instrument, Box::new(clock.clone()));
+        ob.event(OrderEvent::limit(1, Side::Ask, px(100), 10)).unwrap();
+        ob.event(OrderEvent::limit(2, Side::Bid, px(100), 10)).unwrap();
+        ob.event(OrderEvent::limit(3, Side::Ask, px(101), 10)).unwrap();
+        ob.event(OrderEvent::limit(4, Side::Ask, px(104), 10)).unwrap();
+        ob.event(OrderEvent::limit(5, Side::Ask, px(106), 10)).unwrap();
+        ob.drain_reports();
+        ob.drain_market_data();
+
+        // 106 is more than 5% away from the first trade in the window
+        let fills: Vec<(u64, Price)> = ob.event(OrderEvent::market(6, Side::Bid, 30))
+            .unwrap()
+            .iter()
+            .map(|f| (f.ord_id_2(), f.price()))
+            .collect();
+        assert_eq!(fills, vec![(3, px(101)), (4, px(104))]);
+        assert_eq!(ob.phase(), TradingPhase::Halted);
+        assert!(
+            ob.drain_market_data()
+                .contains(&MarketDataEvent::PhaseChange {
+                    phase: TradingPhase::Halted,
+                })
+        );
+        assert_eq!(ob.order(5).unwrap().leaves_qty(), 10);
+        ob.drain_reports();
+
+        ob.event(OrderEvent::limit(7, Side::Bid, px(106), 10)).unwrap();
+        assert_eq!(
+            ob.drain_reports()[0].exec_type(),
+            ExecType::Rejected(RejectReason::NotAcceptedInPhase)
+        );
+
+        // Once the window has moved on the last trade is the reference
+        ob.set_phase(TradingPhase::Continuous).unwrap();
+        clock.set(clock.now() + Duration::seconds(90));
+        let fills = ob.event(OrderEvent::limit(8, Side::Bid, px(106), 10))
+            .unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(ob.phase(), TradingPhase::Continuous);
+        assert_eq!(ob.last_traded_price(), Some(px(106)));
     }
 
     #[test]
-    fn test_cancel_front_back_out_of_order_then_partial_execution() {
-        run_test(TestData {
-            orders: vec![
-                buy_2_101x100(),
-                buy_4_101x50(),
-                buy_7_101x25(),
-                buy_6_101x25(),
-            ],
-            cancels: vec![7, 2, 7],
-            orders2: vec![sell_5_101x25()],
-            expected: vec![OrderFill::new(5, 4, 101u64, 25)],
-        });
+    fn test_order_feed() {
+        ::crate::core::test_setup();
+
+        let mut ob = OrderBook::new(Instrument::new("AUDUSD"));
+        let executed = |id, qty| OrderFeedEvent::Executed {
+            id,
+            qty,
+            price: px(101),
+            printable: true,
+        };
+        let refreshed = OrderFeedEvent::Add {
+            id: 1,
+            side: Side::Ask,
+            price: px(101),
+            qty: 10,
+        };
+        ob.event(OrderEvent::iceberg(1, Side::Ask, px(101), 30, 10))
+            .unwrap();
+        assert_eq!(ob.drain_order_feed(), vec![refreshed]);
+
+        // The aggressor never shows, the iceberg rejoins the queue with a new peak
+        ob.event(OrderEvent::limit(2, Side::Bid, px(101), 15)).unwrap();
+        assert_eq!(
+            ob.drain_order_feed(),
+            vec![executed(1, 10), refreshed, executed(1, 5)]
+        );
+
+        ob.event(OrderEvent::limit(3, Side::Bid, px(100), 20)).unwrap();
+        ob.event(OrderEvent::replace(3, Side::Bid, px(100), 5)).unwrap();
+        ob.event(OrderEvent::replace(3, Side::Bid, px(99), 5)).unwrap();
+        assert_eq!(
+            ob.drain_order_feed(),
+            vec![
+                OrderFeedEvent::Add {
+                    id: 3,
+                    side: Side::Bid,
+                    price: px(100),
+                    qty: 20,
+                },
+                OrderFeedEvent::Cancel { id: 3, qty: 15 },
+                OrderFeedEvent::Replace {
+                    id: 3,
+                    price: px(99),
+                    qty: 5,
+                },
+            ]
+        );
+
+        // A replace that trades leaves the book first
+        ob.event(OrderEvent::replace(3, Side::Bid, px(101), 10))
+            .unwrap();
+        assert_eq!(
+            ob.drain_order_feed(),
+            vec![
+                OrderFeedEvent::Delete { id: 3 },
+                executed(1, 5),
+                refreshed,
+                executed(1, 5),
+            ]
+        );
     }
 
     fn run_test(mut data: TestData) {
@@ -521,19 +2997,24 @@ mod tests {
         let mut ob: OrderBook = OrderBook::new(Instrument::new("AUDUSD"));
         let mut fills: Vec<OrderFill> = Vec::new();
 
-        for index in 0..data.orders.len() {
-            let ord = data.orders.remove(0);
+        for ord in data.orders.drain(..) {
             let mut new_fills = ob.event(ord).unwrap();
             fills.append(&mut new_fills);
         }
 
-        for index in 0..data.cancels.len() {
-            let ord_id = data.cancels.remove(0);
-            ob.cancel(ord_id);
+        // Cancelling an order again fails, any other cancel has to go through
+        let mut cancelled = Vec::new();
+        for ord_id in data.cancels.drain(..) {
+            let result = ob.cancel(ord_id);
+            if cancelled.contains(&ord_id) {
+                assert_eq!(result, Err(OrderBookError::UnknownOrder(ord_id)));
+            } else {
+                result.unwrap();
+                cancelled.push(ord_id);
+            }
         }
 
-        for index in 0..data.orders2.len() {
-            let ord = data.orders2.remove(0);
+        for ord in data.orders2.drain(..) {
             let mut new_fills = ob.event(ord).unwrap();
             fills.append(&mut new_fills);
         }
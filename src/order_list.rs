@@ -1,8 +1,8 @@
 use std::collections::HashMap;
-use failure::Error;
 use std::ops::{Index, IndexMut};
 
-use crate::model::OrderInfo;
+use crate::error::OrderBookError;
+use crate::model::{OrderInfo, Price, Side};
 
 #[derive(Debug)]
 pub(crate) struct OrderList {
@@ -24,42 +24,46 @@ impl OrderList {
 
         //Preallocate
         for i in 0..max_size {
-            list.orders.push(OrderInfo::new(0, 0, 0));
+            list.orders.push(OrderInfo::new(0, Side::Bid, Price::zero(0), 0));
             list.free.push(i);
         }
         list
     }
 
-    pub fn insert(&mut self, id: u64, price: u64, qty: u64) -> Result<usize, Error> {
-        //set size to zero
-
+    pub fn insert(&mut self, order: OrderInfo) -> Result<usize, OrderBookError> {
+        let id = order.id();
+        if self.order_map.contains_key(&id) {
+            return Err(OrderBookError::DuplicateOrderId(id));
+        }
         if self.free.is_empty() {
-            self.orders.push(OrderInfo::new(id, price, qty));
+            self.orders.push(order);
             let index = self.orders.len() - 1;
             self.order_map.insert(id, index);
             Ok(index)
         } else {
             let index = self.free.pop().unwrap(); // Safe
-            let ord = &mut self.orders[index];
-            ord.id = id;
-            ord.qty = qty;
-            ord.price = price;
+            self.orders[index] = order;
             self.order_map.insert(id, index);
             Ok(index)
         }
     }
 
-    pub fn delete(&mut self, id: &u64) -> Result<bool, Error> {
+    pub fn get(&self, id: &u64) -> Option<usize> {
+        self.order_map.get(id).cloned()
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a OrderInfo> + 'a {
+        self.order_map.values().map(move |index| &self.orders[*index])
+    }
+
+    pub fn delete(&mut self, id: &u64) -> Result<(), OrderBookError> {
         //set size to zero
-        if let Some(idx) = self.order_map.remove(id) {
-            if let Some(mut ord) = self.orders.get_mut(idx) {
-                //let result = ord.clone();
-                self.free.push(idx);
-                ord.qty = 0;
-                return Ok(true);
-            }
-        }
-        Ok(false)
+        let idx = self.order_map
+            .remove(id)
+            .ok_or(OrderBookError::UnknownOrder(*id))?;
+        self.free.push(idx);
+        self.orders[idx].qty = 0;
+        Ok(())
     }
 }
 
@@ -0,0 +1,839 @@
+//! Fixed-length binary messages, read and written in place.
+//!
+//! Each message type is a view over its bytes in a receive buffer, with getters that read
+//! a field at its offset. The `encode` functions write the fields straight into the send
+//! buffer. Offsets below are from the message type byte.
+
+use crate::clock::{from_nanos, to_nanos};
+use crate::model::{ExecutionReport, OrderEvent, Price, Side, TimeInForce};
+use chrono::{DateTime, Utc};
+use failure::Error;
+use std::str;
+
+pub const SYMBOL_LEN: usize = 8;
+pub const USERNAME_LEN: usize = 8;
+
+/// Why the exchange cancelled quantity of an order.
+pub mod cancel_reason {
+    /// Asked for by the client
+    pub const USER: u8 = b'U';
+    /// The order's time in force ran out
+    pub const EXPIRED: u8 = b'T';
+    /// Immediate orders that could not trade, self-trade prevention and auction leftovers
+    pub const EXCHANGE: u8 = b'E';
+}
+
+/// Why a request was rejected.
+pub mod reject_reason {
+    pub const UNKNOWN_SYMBOL: u8 = b'S';
+    pub const INVALID_QUANTITY: u8 = b'Z';
+    pub const INVALID_PRICE: u8 = b'X';
+    /// Halted, or not accepted in the current trading phase
+    pub const CLOSED: u8 = b'C';
+    pub const DUPLICATE_TOKEN: u8 = b'D';
+    pub const UNKNOWN_ORDER: u8 = b'N';
+    pub const RISK: u8 = b'R';
+    pub const UNSUPPORTED: u8 = b'V';
+    pub const OTHER: u8 = b'O';
+}
+
+/// A message from a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    Login(Login<'a>),
+    EnterOrder(EnterOrder<'a>),
+    ReplaceOrder(ReplaceOrder<'a>),
+    CancelOrder(CancelOrder<'a>),
+}
+
+impl<'a> Request<'a> {
+    /// Reads the first message in `data` without copying it. Returns the message and the
+    /// number of bytes it took, or None if `data` does not hold a whole message yet.
+    pub fn decode(data: &'a [u8]) -> Result<Option<(Request<'a>, usize)>, Error> {
+        let (body, len) = match frame(data)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let request = match body[0] {
+            Login::TYPE => Request::Login(Login::new(body)?),
+            EnterOrder::TYPE => Request::EnterOrder(EnterOrder::new(body)?),
+            ReplaceOrder::TYPE => Request::ReplaceOrder(ReplaceOrder::new(body)?),
+            CancelOrder::TYPE => Request::CancelOrder(CancelOrder::new(body)?),
+            other => bail!("Unknown request type {:?}", other as char),
+        };
+        Ok(Some((request, len)))
+    }
+}
+
+/// A message from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response<'a> {
+    LoginAccepted(LoginReply<'a>),
+    LoginRejected(LoginReply<'a>),
+    Accepted(Accepted<'a>),
+    Replaced(Replaced<'a>),
+    Executed(Executed<'a>),
+    Canceled(Canceled<'a>),
+    Rejected(Rejected<'a>),
+}
+
+impl<'a> Response<'a> {
+    /// Reads the first message in `data` without copying it, as `Request::decode`.
+    pub fn decode(data: &'a [u8]) -> Result<Option<(Response<'a>, usize)>, Error> {
+        let (body, len) = match frame(data)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        let response = match body[0] {
+            LoginReply::ACCEPTED => Response::LoginAccepted(LoginReply::new(body)?),
+            LoginReply::REJECTED => Response::LoginRejected(LoginReply::new(body)?),
+            Accepted::TYPE => Response::Accepted(Accepted::new(body)?),
+            Replaced::TYPE => Response::Replaced(Replaced::new(body)?),
+            Executed::TYPE => Response::Executed(Executed::new(body)?),
+            Canceled::TYPE => Response::Canceled(Canceled::new(body)?),
+            Rejected::TYPE => Response::Rejected(Rejected::new(body)?),
+            other => bail!("Unknown response type {:?}", other as char),
+        };
+        Ok(Some((response, len)))
+    }
+}
+
+/// `[username: 8]`, space padded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Login<'a>(&'a [u8]);
+
+impl<'a> Login<'a> {
+    pub const TYPE: u8 = b'L';
+    pub const LEN: usize = 9;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        text(&body[1..9])?;
+        Ok(Login(body))
+    }
+
+    pub fn encode(buf: &mut Vec<u8>, username: &str) -> Result<(), Error> {
+        let body = append(buf, Self::TYPE, Self::LEN);
+        put_text(body, 1, USERNAME_LEN, username)
+    }
+
+    pub fn username(&self) -> &'a str {
+        get_text(self.0, 1, USERNAME_LEN)
+    }
+}
+
+/// `[token: u64 @1][side: B or S @9][qty: u64 @10][symbol: 8 @18][price: u64 @26]`
+/// `[time in force @34][display qty: u64 @35]`.
+///
+/// A zero price enters a market order. Time in force is D for day, G for good till
+/// cancel, I for immediate or cancel and F for fill or kill. A display quantity below the
+/// order quantity enters an iceberg, which has to be good till cancel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnterOrder<'a>(&'a [u8]);
+
+impl<'a> EnterOrder<'a> {
+    pub const TYPE: u8 = b'O';
+    pub const LEN: usize = 43;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        side_from_code(body[9])?;
+        text(&body[18..26])?;
+        tif_from_code(body[34])?;
+        Ok(EnterOrder(body))
+    }
+
+    /// Writes a market, limit or iceberg order event as an enter order message. The
+    /// event's id is not sent, the token names the order instead.
+    pub fn encode(
+        buf: &mut Vec<u8>,
+        token: u64,
+        symbol: &str,
+        event: &OrderEvent,
+    ) -> Result<(), Error> {
+        let (side, qty, price, tif, display_qty) = match *event {
+            OrderEvent::Market { side, qty, .. } => {
+                (side, qty, 0, TimeInForce::ImmediateOrCancel, 0)
+            }
+            OrderEvent::Limit {
+                side,
+                price,
+                qty,
+                tif,
+                ..
+            } => (side, qty, price.units(), tif, 0),
+            OrderEvent::Iceberg {
+                side,
+                price,
+                qty,
+                display_qty,
+                ..
+            } => (side, qty, price.units(), TimeInForce::GoodTillCancel, display_qty),
+            _ => bail!("Cannot enter {:?}", event),
+        };
+        let tif = tif_code(tif)?;
+        let body = append(buf, Self::TYPE, Self::LEN);
+        put_u64(body, 1, token);
+        body[9] = side_code(side);
+        put_u64(body, 10, qty);
+        put_text(body, 18, SYMBOL_LEN, symbol)?;
+        put_u64(body, 26, price);
+        body[34] = tif;
+        put_u64(body, 35, display_qty);
+        Ok(())
+    }
+
+    pub fn token(&self) -> u64 {
+        get_u64(self.0, 1)
+    }
+
+    pub fn side(&self) -> Side {
+        side_from_code(self.0[9]).unwrap() // Safe, checked on decode
+    }
+
+    pub fn qty(&self) -> u64 {
+        get_u64(self.0, 10)
+    }
+
+    pub fn symbol(&self) -> &'a str {
+        get_text(self.0, 18, SYMBOL_LEN)
+    }
+
+    pub fn price(&self, price_scale: u8) -> Price {
+        Price::new(get_u64(self.0, 26), price_scale)
+    }
+
+    pub fn tif(&self) -> TimeInForce {
+        tif_from_code(self.0[34]).unwrap() // Safe, checked on decode
+    }
+
+    pub fn display_qty(&self) -> u64 {
+        get_u64(self.0, 35)
+    }
+
+    /// The order event entering this order as `id`. None for an iceberg that is not good
+    /// till cancel.
+    pub fn to_event(&self, id: u64, price_scale: u8) -> Option<OrderEvent> {
+        let (side, qty, price) = (self.side(), self.qty(), self.price(price_scale));
+        let event = if price.is_zero() {
+            OrderEvent::market(id, side, qty)
+        } else if self.display_qty() > 0 && self.display_qty() < qty {
+            if self.tif() != TimeInForce::GoodTillCancel {
+                return None;
+            }
+            OrderEvent::iceberg(id, side, price, qty, self.display_qty())
+        } else {
+            OrderEvent::limit_with_tif(id, side, price, qty, self.tif())
+        };
+        Some(event)
+    }
+}
+
+/// `[token: u64 @1][new token: u64 @9][qty: u64 @17][price: u64 @25]`. The quantity is
+/// the new open quantity, as in `OrderEvent::Replace`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaceOrder<'a>(&'a [u8]);
+
+impl<'a> ReplaceOrder<'a> {
+    pub const TYPE: u8 = b'U';
+    pub const LEN: usize = 33;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        Ok(ReplaceOrder(body))
+    }
+
+    /// Writes a replace event for the order known as `token`, renaming it `new_token`.
+    pub fn encode(
+        buf: &mut Vec<u8>,
+        token: u64,
+        new_token: u64,
+        event: &OrderEvent,
+    ) -> Result<(), Error> {
+        let (price, qty) = match *event {
+            OrderEvent::Replace { price, qty, .. } => (price, qty),
+            _ => bail!("{:?} is not a replace", event),
+        };
+        let body = append(buf, Self::TYPE, Self::LEN);
+        put_u64(body, 1, token);
+        put_u64(body, 9, new_token);
+        put_u64(body, 17, qty);
+        put_u64(body, 25, price.units());
+        Ok(())
+    }
+
+    pub fn token(&self) -> u64 {
+        get_u64(self.0, 1)
+    }
+
+    pub fn new_token(&self) -> u64 {
+        get_u64(self.0, 9)
+    }
+
+    pub fn qty(&self) -> u64 {
+        get_u64(self.0, 17)
+    }
+
+    pub fn price(&self, price_scale: u8) -> Price {
+        Price::new(get_u64(self.0, 25), price_scale)
+    }
+
+    pub fn to_event(&self, id: u64, side: Side, price_scale: u8) -> OrderEvent {
+        OrderEvent::replace(id, side, self.price(price_scale), self.qty())
+    }
+}
+
+/// `[token: u64 @1]`, cancels everything left of the order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CancelOrder<'a>(&'a [u8]);
+
+impl<'a> CancelOrder<'a> {
+    pub const TYPE: u8 = b'X';
+    pub const LEN: usize = 9;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        Ok(CancelOrder(body))
+    }
+
+    pub fn encode(buf: &mut Vec<u8>, token: u64) {
+        let body = append(buf, Self::TYPE, Self::LEN);
+        put_u64(body, 1, token);
+    }
+
+    pub fn token(&self) -> u64 {
+        get_u64(self.0, 1)
+    }
+}
+
+/// `[timestamp: i64 @1]`, type a when the login was accepted and j when it was not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoginReply<'a>(&'a [u8]);
+
+impl<'a> LoginReply<'a> {
+    pub const ACCEPTED: u8 = b'a';
+    pub const REJECTED: u8 = b'j';
+    pub const LEN: usize = 9;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        time(body, 1)?;
+        Ok(LoginReply(body))
+    }
+
+    pub fn encode(buf: &mut Vec<u8>, timestamp: DateTime<Utc>, accepted: bool) {
+        let msg_type = if accepted {
+            Self::ACCEPTED
+        } else {
+            Self::REJECTED
+        };
+        let body = append(buf, msg_type, Self::LEN);
+        put_time(body, 1, timestamp);
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        get_time(self.0, 1)
+    }
+}
+
+/// `[timestamp: i64 @1][token: u64 @9][order id: u64 @17][side @25][open qty: u64 @26]`
+/// `[symbol: 8 @34][price: u64 @42]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Accepted<'a>(&'a [u8]);
+
+impl<'a> Accepted<'a> {
+    pub const TYPE: u8 = b'A';
+    pub const LEN: usize = 50;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        time(body, 1)?;
+        side_from_code(body[25])?;
+        text(&body[34..42])?;
+        Ok(Accepted(body))
+    }
+
+    /// Writes the acceptance of the order a New execution report is about.
+    pub fn encode(
+        buf: &mut Vec<u8>,
+        timestamp: DateTime<Utc>,
+        token: u64,
+        symbol: &str,
+        side: Side,
+        report: &ExecutionReport,
+    ) -> Result<(), Error> {
+        let body = append(buf, Self::TYPE, Self::LEN);
+        put_time(body, 1, timestamp);
+        put_u64(body, 9, token);
+        put_u64(body, 17, report.order_id());
+        body[25] = side_code(side);
+        put_u64(body, 26, report.leaves_qty());
+        put_text(body, 34, SYMBOL_LEN, symbol)?;
+        put_u64(body, 42, report.price().units());
+        Ok(())
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        get_time(self.0, 1)
+    }
+
+    pub fn token(&self) -> u64 {
+        get_u64(self.0, 9)
+    }
+
+    pub fn order_id(&self) -> u64 {
+        get_u64(self.0, 17)
+    }
+
+    pub fn side(&self) -> Side {
+        side_from_code(self.0[25]).unwrap() // Safe, checked on decode
+    }
+
+    pub fn qty(&self) -> u64 {
+        get_u64(self.0, 26)
+    }
+
+    pub fn symbol(&self) -> &'a str {
+        get_text(self.0, 34, SYMBOL_LEN)
+    }
+
+    pub fn price(&self, price_scale: u8) -> Price {
+        Price::new(get_u64(self.0, 42), price_scale)
+    }
+}
+
+/// `[timestamp: i64 @1][token: u64 @9][previous token: u64 @17][order id: u64 @25]`
+/// `[open qty: u64 @33][price: u64 @41]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Replaced<'a>(&'a [u8]);
+
+impl<'a> Replaced<'a> {
+    pub const TYPE: u8 = b'U';
+    pub const LEN: usize = 49;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        time(body, 1)?;
+        Ok(Replaced(body))
+    }
+
+    /// Writes the outcome of a replace from its Replaced execution report.
+    pub fn encode(
+        buf: &mut Vec<u8>,
+        timestamp: DateTime<Utc>,
+        token: u64,
+        previous_token: u64,
+        report: &ExecutionReport,
+    ) {
+        let body = append(buf, Self::TYPE, Self::LEN);
+        put_time(body, 1, timestamp);
+        put_u64(body, 9, token);
+        put_u64(body, 17, previous_token);
+        put_u64(body, 25, report.order_id());
+        put_u64(body, 33, report.leaves_qty());
+        put_u64(body, 41, report.price().units());
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        get_time(self.0, 1)
+    }
+
+    pub fn token(&self) -> u64 {
+        get_u64(self.0, 9)
+    }
+
+    pub fn previous_token(&self) -> u64 {
+        get_u64(self.0, 17)
+    }
+
+    pub fn order_id(&self) -> u64 {
+        get_u64(self.0, 25)
+    }
+
+    pub fn qty(&self) -> u64 {
+        get_u64(self.0, 33)
+    }
+
+    pub fn price(&self, price_scale: u8) -> Price {
+        Price::new(get_u64(self.0, 41), price_scale)
+    }
+}
+
+/// `[timestamp: i64 @1][token: u64 @9][qty: u64 @17][price: u64 @25][match: u64 @33]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Executed<'a>(&'a [u8]);
+
+impl<'a> Executed<'a> {
+    pub const TYPE: u8 = b'E';
+    pub const LEN: usize = 41;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        time(body, 1)?;
+        Ok(Executed(body))
+    }
+
+    /// Writes the fill carried by an execution report.
+    pub fn encode(
+        buf: &mut Vec<u8>,
+        timestamp: DateTime<Utc>,
+        token: u64,
+        report: &ExecutionReport,
+        match_number: u64,
+    ) {
+        let body = append(buf, Self::TYPE, Self::LEN);
+        put_time(body, 1, timestamp);
+        put_u64(body, 9, token);
+        put_u64(body, 17, report.last_qty());
+        put_u64(body, 25, report.last_price().units());
+        put_u64(body, 33, match_number);
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        get_time(self.0, 1)
+    }
+
+    pub fn token(&self) -> u64 {
+        get_u64(self.0, 9)
+    }
+
+    pub fn qty(&self) -> u64 {
+        get_u64(self.0, 17)
+    }
+
+    pub fn price(&self, price_scale: u8) -> Price {
+        Price::new(get_u64(self.0, 25), price_scale)
+    }
+
+    pub fn match_number(&self) -> u64 {
+        get_u64(self.0, 33)
+    }
+}
+
+/// `[timestamp: i64 @1][token: u64 @9][cancelled qty: u64 @17][reason @25]`, see
+/// `cancel_reason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canceled<'a>(&'a [u8]);
+
+impl<'a> Canceled<'a> {
+    pub const TYPE: u8 = b'C';
+    pub const LEN: usize = 26;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        time(body, 1)?;
+        Ok(Canceled(body))
+    }
+
+    pub fn encode(buf: &mut Vec<u8>, timestamp: DateTime<Utc>, token: u64, qty: u64, reason: u8) {
+        let body = append(buf, Self::TYPE, Self::LEN);
+        put_time(body, 1, timestamp);
+        put_u64(body, 9, token);
+        put_u64(body, 17, qty);
+        body[25] = reason;
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        get_time(self.0, 1)
+    }
+
+    pub fn token(&self) -> u64 {
+        get_u64(self.0, 9)
+    }
+
+    pub fn qty(&self) -> u64 {
+        get_u64(self.0, 17)
+    }
+
+    pub fn reason(&self) -> u8 {
+        self.0[25]
+    }
+}
+
+/// `[timestamp: i64 @1][token: u64 @9][reason @17]`, see `reject_reason`. The token is
+/// the one the rejected request named, the new token of a replace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rejected<'a>(&'a [u8]);
+
+impl<'a> Rejected<'a> {
+    pub const TYPE: u8 = b'J';
+    pub const LEN: usize = 18;
+
+    fn new(body: &'a [u8]) -> Result<Self, Error> {
+        check_len(body, Self::LEN)?;
+        time(body, 1)?;
+        Ok(Rejected(body))
+    }
+
+    pub fn encode(buf: &mut Vec<u8>, timestamp: DateTime<Utc>, token: u64, reason: u8) {
+        let body = append(buf, Self::TYPE, Self::LEN);
+        put_time(body, 1, timestamp);
+        put_u64(body, 9, token);
+        body[17] = reason;
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        get_time(self.0, 1)
+    }
+
+    pub fn token(&self) -> u64 {
+        get_u64(self.0, 9)
+    }
+
+    pub fn reason(&self) -> u8 {
+        self.0[17]
+    }
+}
+
+/// Splits the first message off `data`: `[len: u16][type: u8][fields]`, where `len` counts
+/// the bytes after itself.
+fn frame(data: &[u8]) -> Result<Option<(&[u8], usize)>, Error> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+    let len = (usize::from(data[0]) << 8) | usize::from(data[1]);
+    if len == 0 {
+        bail!("Empty message");
+    }
+    if data.len() < 2 + len {
+        return Ok(None);
+    }
+    Ok(Some((&data[2..2 + len], 2 + len)))
+}
+
+/// Appends a zeroed message of `len` bytes with its length prefix and type, returning it
+/// for the fields to be written in place.
+fn append(buf: &mut Vec<u8>, msg_type: u8, len: usize) -> &mut [u8] {
+    buf.push((len >> 8) as u8);
+    buf.push(len as u8);
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    let body = &mut buf[start..];
+    body[0] = msg_type;
+    body
+}
+
+fn check_len(body: &[u8], len: usize) -> Result<(), Error> {
+    if body.len() != len {
+        bail!(
+            "Message type {:?} has {} bytes, expected {}",
+            body[0] as char,
+            body.len(),
+            len
+        );
+    }
+    Ok(())
+}
+
+fn get_u64(body: &[u8], at: usize) -> u64 {
+    body[at..at + 8]
+        .iter()
+        .fold(0u64, |value, b| (value << 8) | u64::from(*b))
+}
+
+fn put_u64(body: &mut [u8], at: usize, value: u64) {
+    for (i, b) in body[at..at + 8].iter_mut().enumerate() {
+        *b = (value >> (8 * (7 - i))) as u8;
+    }
+}
+
+/// Checks that a timestamp field is in range.
+fn time(body: &[u8], at: usize) -> Result<DateTime<Utc>, Error> {
+    from_nanos(get_u64(body, at) as i64)
+}
+
+fn get_time(body: &[u8], at: usize) -> DateTime<Utc> {
+    // Safe, checked on decode
+    time(body, at).unwrap()
+}
+
+fn put_time(body: &mut [u8], at: usize, time: DateTime<Utc>) {
+    put_u64(body, at, to_nanos(time) as u64);
+}
+
+/// Checks that a text field is printable ASCII.
+fn text(field: &[u8]) -> Result<&str, Error> {
+    if !field.iter().all(|b| *b >= b' ' && *b <= b'~') {
+        bail!("Text field {:?} is not printable ASCII", field);
+    }
+    Ok(str::from_utf8(field)?)
+}
+
+fn get_text(body: &[u8], at: usize, len: usize) -> &str {
+    // Safe, checked on decode
+    text(&body[at..at + len]).unwrap().trim_right()
+}
+
+/// Writes `value` left justified and space padded.
+fn put_text(body: &mut [u8], at: usize, len: usize, value: &str) -> Result<(), Error> {
+    if value.len() > len || text(value.as_bytes()).is_err() {
+        bail!("{:?} does not fit a {} character field", value, len);
+    }
+    let field = &mut body[at..at + len];
+    for b in field.iter_mut() {
+        *b = b' ';
+    }
+    field[..value.len()].copy_from_slice(value.as_bytes());
+    Ok(())
+}
+
+fn side_code(side: Side) -> u8 {
+    match side {
+        Side::Bid => b'B',
+        Side::Ask => b'S',
+    }
+}
+
+fn side_from_code(code: u8) -> Result<Side, Error> {
+    match code {
+        b'B' => Ok(Side::Bid),
+        b'S' => Ok(Side::Ask),
+        other => bail!("Invalid side {:?}", other as char),
+    }
+}
+
+fn tif_code(tif: TimeInForce) -> Result<u8, Error> {
+    match tif {
+        TimeInForce::Day => Ok(b'D'),
+        TimeInForce::GoodTillCancel => Ok(b'G'),
+        TimeInForce::ImmediateOrCancel => Ok(b'I'),
+        TimeInForce::FillOrKill => Ok(b'F'),
+        TimeInForce::GoodTillDate(_) => bail!("Good till date orders cannot be entered"),
+    }
+}
+
+fn tif_from_code(code: u8) -> Result<TimeInForce, Error> {
+    match code {
+        b'D' => Ok(TimeInForce::Day),
+        b'G' => Ok(TimeInForce::GoodTillCancel),
+        b'I' => Ok(TimeInForce::ImmediateOrCancel),
+        b'F' => Ok(TimeInForce::FillOrKill),
+        other => bail!("Invalid time in force {:?}", other as char),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{wire_px as px, ExecType, OrderInfo};
+    use chrono::TimeZone;
+
+    fn request(buf: &[u8]) -> Request {
+        let (request, len) = Request::decode(buf).unwrap().unwrap();
+        assert_eq!(len, buf.len());
+        request
+    }
+
+    #[test]
+    fn requests_round_trip_order_events() {
+        let events = [
+            OrderEvent::market(0, Side::Bid, 100),
+            OrderEvent::limit_with_tif(0, Side::Ask, px(7512), 10, TimeInForce::Day),
+            OrderEvent::limit_with_tif(0, Side::Bid, px(7511), 5, TimeInForce::FillOrKill),
+            OrderEvent::iceberg(0, Side::Ask, px(7520), 1000, 100),
+        ];
+        for (n, event) in events.iter().enumerate() {
+            let mut buf = Vec::new();
+            EnterOrder::encode(&mut buf, n as u64 + 1, "AUDUSD", event).unwrap();
+            assert_eq!(buf.len(), 2 + EnterOrder::LEN);
+            match request(&buf) {
+                Request::EnterOrder(order) => {
+                    assert_eq!(order.token(), n as u64 + 1);
+                    assert_eq!(order.symbol(), "AUDUSD");
+                    assert_eq!(order.to_event(0, 4), Some(*event));
+                }
+                other => panic!("Decoded {:?}", other),
+            }
+        }
+
+        let mut buf = Vec::new();
+        let replace = OrderEvent::replace(9, Side::Bid, px(7510), 30);
+        ReplaceOrder::encode(&mut buf, 1, 2, &replace).unwrap();
+        match request(&buf) {
+            Request::ReplaceOrder(order) => {
+                assert_eq!((order.token(), order.new_token()), (1, 2));
+                assert_eq!(order.to_event(9, Side::Bid, 4), replace);
+            }
+            other => panic!("Decoded {:?}", other),
+        }
+
+        let mut buf = Vec::new();
+        CancelOrder::encode(&mut buf, 2);
+        Login::encode(&mut buf, "TRADER1").unwrap();
+        let (cancel, len) = Request::decode(&buf).unwrap().unwrap();
+        match cancel {
+            Request::CancelOrder(cancel) => assert_eq!(cancel.token(), 2),
+            other => panic!("Decoded {:?}", other),
+        }
+        match request(&buf[len..]) {
+            Request::Login(login) => assert_eq!(login.username(), "TRADER1"),
+            other => panic!("Decoded {:?}", other),
+        }
+
+        let stop = OrderEvent::stop_market(0, Side::Bid, px(7600), 10);
+        assert!(EnterOrder::encode(&mut Vec::new(), 1, "AUDUSD", &stop).is_err());
+        let order = OrderEvent::limit(0, Side::Bid, px(7600), 10);
+        assert!(EnterOrder::encode(&mut Vec::new(), 1, "AUDUSD.LONG", &order).is_err());
+    }
+
+    #[test]
+    fn responses_are_read_in_place() {
+        let timestamp = Utc.ymd(2018, 4, 2).and_hms_nano(9, 30, 0, 123_456_789);
+        let mut order = OrderInfo::new(5, Side::Bid, px(7512), 100);
+        let accepted = ExecutionReport::status(&order, ExecType::New);
+        order.fill(40, px(7511));
+        let fill = ExecutionReport::new(&order, ExecType::PartialFill, 40, px(7511));
+
+        let mut buf = Vec::new();
+        Accepted::encode(&mut buf, timestamp, 1, "AUDUSD", Side::Bid, &accepted).unwrap();
+        Executed::encode(&mut buf, timestamp, 1, &fill, 77);
+        Canceled::encode(&mut buf, timestamp, 1, 60, cancel_reason::USER);
+        Rejected::encode(&mut buf, timestamp, 2, reject_reason::DUPLICATE_TOKEN);
+
+        let mut pos = 0;
+        let mut responses = Vec::new();
+        while let Some((response, len)) = Response::decode(&buf[pos..]).unwrap() {
+            responses.push(response);
+            pos += len;
+        }
+        assert_eq!(pos, buf.len());
+        assert_eq!(responses.len(), 4);
+        match responses[0] {
+            Response::Accepted(accepted) => {
+                assert_eq!(accepted.timestamp(), timestamp);
+                assert_eq!((accepted.token(), accepted.order_id()), (1, 5));
+                assert_eq!((accepted.side(), accepted.qty()), (Side::Bid, 100));
+                assert_eq!(accepted.symbol(), "AUDUSD");
+                assert_eq!(accepted.price(4), px(7512));
+            }
+            other => panic!("Decoded {:?}", other),
+        }
+        match responses[1] {
+            Response::Executed(executed) => {
+                assert_eq!((executed.token(), executed.qty()), (1, 40));
+                assert_eq!(executed.price(4), px(7511));
+                assert_eq!(executed.match_number(), 77);
+            }
+            other => panic!("Decoded {:?}", other),
+        }
+        match responses[2] {
+            Response::Canceled(canceled) => {
+                assert_eq!((canceled.qty(), canceled.reason()), (60, cancel_reason::USER));
+            }
+            other => panic!("Decoded {:?}", other),
+        }
+        match responses[3] {
+            Response::Rejected(rejected) => {
+                assert_eq!(rejected.token(), 2);
+                assert_eq!(rejected.reason(), reject_reason::DUPLICATE_TOKEN);
+            }
+            other => panic!("Decoded {:?}", other),
+        }
+
+        // Truncated, then with a length that does not match the type
+        assert_eq!(Response::decode(&buf[..20]).unwrap(), None);
+        let mut short = buf[buf.len() - 2 - Rejected::LEN..].to_vec();
+        short[2] = Canceled::TYPE;
+        assert!(Response::decode(&short).is_err());
+    }
+}
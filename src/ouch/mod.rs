@@ -0,0 +1,29 @@
+//! OUCH-style binary order entry over TCP.
+//!
+//! A lighter alternative to FIX for clients that care about latency. Every message has a
+//! fixed length, is framed as `[len: u16][type: u8][fields]` and is read and written in
+//! place, without parsing into an intermediate form. Integers are big-endian, prices are
+//! u64 units at the instrument's price scale, text fields are ASCII padded with spaces and
+//! timestamps are nanoseconds since the Unix epoch. The messages are:
+//!
+//! ```text
+//! Client                             Server
+//! L  login            [username]     a  login accepted   j  login rejected
+//! O  enter order                     A  accepted
+//! U  replace order                   U  replaced
+//! X  cancel order                    E  executed
+//!                                    C  canceled
+//!                                    J  rejected
+//! ```
+//!
+//! The first message on a connection has to be a login. Clients name their orders with
+//! tokens they choose, unique per user among the user's live orders. A replace gives the
+//! order a new token. There is no sequencing or replay, a client that reconnects does not
+//! get what it missed. See `message` for the fields of each message.
+
+pub mod message;
+pub mod order_entry;
+pub mod server;
+
+pub use self::message::{Request, Response};
+pub use self::server::OuchServer;
@@ -0,0 +1,342 @@
+//! Translation between OUCH requests and the matching engine.
+//!
+//! Enter, replace and cancel requests become order events. The engine's execution reports
+//! are encoded as accepted, replaced, executed, canceled and rejected messages for the user
+//! that owns the order. Order ids in the engine are assigned here, users refer to their
+//! orders by token.
+
+use super::message::{cancel_reason, reject_reason, Accepted, CancelOrder, Canceled,
+                     EnterOrder, Executed, Rejected, ReplaceOrder, Replaced, Request};
+use crate::engine::MatchingEngine;
+use crate::error::OrderBookError;
+use crate::model::{ExecType, ExecutionReport, OrderEvent, RejectReason, Side};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+/// Encoded messages waiting to be sent, by username.
+pub type Outbound = HashMap<String, Vec<u8>>;
+
+/// A cancel or replace sent to the engine and not yet answered.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Pending {
+    Cancel,
+    Replace { new_token: u64 },
+}
+
+#[derive(Debug, Clone)]
+struct Order {
+    user: String,
+    symbol: String,
+    token: u64,
+    side: Side,
+    // As of the last report, what a cancel takes off
+    leaves_qty: u64,
+    pending: Option<Pending>,
+}
+
+/// Live orders entered over OUCH and the tokens they are known by.
+#[derive(Debug, Default)]
+pub struct OrderEntry {
+    orders: HashMap<u64, Order>,
+    // (user, token) of each live order
+    tokens: HashMap<(String, u64), u64>,
+    next_order_id: u64,
+    next_match_number: u64,
+}
+
+impl OrderEntry {
+    pub fn new() -> Self {
+        Self {
+            orders: HashMap::new(),
+            tokens: HashMap::new(),
+            next_order_id: 1,
+            next_match_number: 1,
+        }
+    }
+
+    pub fn open_orders(&self) -> usize {
+        self.orders.len()
+    }
+
+    /// Handles a request from `user`, encoding the responses into `outbound`, including
+    /// reports on other users' orders that traded.
+    pub fn handle(
+        &mut self,
+        user: &str,
+        owner: u64,
+        request: &Request,
+        engine: &mut MatchingEngine,
+        now: DateTime<Utc>,
+        outbound: &mut Outbound,
+    ) {
+        let out = outbound.entry(user.to_string()).or_insert_with(Vec::new);
+        match *request {
+            Request::EnterOrder(order) => self.enter(user, owner, order, engine, now, out),
+            Request::ReplaceOrder(replace) => self.replace(user, replace, engine, now, out),
+            Request::CancelOrder(cancel) => self.cancel(user, cancel, engine, now, out),
+            // Session messages are for the server
+            Request::Login(_) => {}
+        }
+        self.drain_reports(engine, now, outbound);
+    }
+
+    /// Encodes the engine's execution reports for the users that own the orders. Reports
+    /// on orders that were not entered over OUCH are dropped.
+    pub fn drain_reports(
+        &mut self,
+        engine: &mut MatchingEngine,
+        now: DateTime<Utc>,
+        outbound: &mut Outbound,
+    ) {
+        for (_, report) in engine.drain_reports() {
+            self.report(&report, now, outbound);
+        }
+    }
+
+    fn enter(
+        &mut self,
+        user: &str,
+        owner: u64,
+        order: EnterOrder,
+        engine: &mut MatchingEngine,
+        now: DateTime<Utc>,
+        out: &mut Vec<u8>,
+    ) {
+        let token = order.token();
+        let key = (user.to_string(), token);
+        if self.tokens.contains_key(&key) {
+            Rejected::encode(out, now, token, reject_reason::DUPLICATE_TOKEN);
+            return;
+        }
+        let price_scale = match engine.book(order.symbol()) {
+            Some(book) => book.instrument().price_scale(),
+            None => {
+                Rejected::encode(out, now, token, reject_reason::UNKNOWN_SYMBOL);
+                return;
+            }
+        };
+        let id = self.next_order_id;
+        let event = match order.to_event(id, price_scale) {
+            Some(event) => event,
+            None => {
+                Rejected::encode(out, now, token, reject_reason::UNSUPPORTED);
+                return;
+            }
+        };
+
+        self.next_order_id += 1;
+        self.orders.insert(
+            id,
+            Order {
+                user: user.to_string(),
+                symbol: order.symbol().to_string(),
+                token,
+                side: order.side(),
+                leaves_qty: order.qty(),
+                pending: None,
+            },
+        );
+        self.tokens.insert(key, id);
+        if let Err(e) = engine.event(order.symbol(), event.with_owner(owner)) {
+            self.forget(id);
+            Rejected::encode(out, now, token, error_reason(&e));
+        }
+    }
+
+    fn replace(
+        &mut self,
+        user: &str,
+        replace: ReplaceOrder,
+        engine: &mut MatchingEngine,
+        now: DateTime<Utc>,
+        out: &mut Vec<u8>,
+    ) {
+        let new_token = replace.new_token();
+        let id = match self.request_target(user, replace.token(), Some(new_token)) {
+            Ok(id) => id,
+            Err(reason) => {
+                Rejected::encode(out, now, new_token, reason);
+                return;
+            }
+        };
+
+        let (symbol, side) = {
+            let order = &self.orders[&id];
+            (order.symbol.clone(), order.side)
+        };
+        let price_scale = engine
+            .book(&symbol)
+            .map_or(0, |book| book.instrument().price_scale());
+        self.set_pending(id, Pending::Replace { new_token });
+        if let Err(e) = engine.event(&symbol, replace.to_event(id, side, price_scale)) {
+            self.clear_pending(id);
+            Rejected::encode(out, now, new_token, error_reason(&e));
+        }
+    }
+
+    fn cancel(
+        &mut self,
+        user: &str,
+        cancel: CancelOrder,
+        engine: &mut MatchingEngine,
+        now: DateTime<Utc>,
+        out: &mut Vec<u8>,
+    ) {
+        let token = cancel.token();
+        let id = match self.request_target(user, token, None) {
+            Ok(id) => id,
+            Err(reason) => {
+                Rejected::encode(out, now, token, reason);
+                return;
+            }
+        };
+
+        let symbol = self.orders[&id].symbol.clone();
+        self.set_pending(id, Pending::Cancel);
+        if let Err(e) = engine.event(&symbol, OrderEvent::Cancel { id }) {
+            self.clear_pending(id);
+            Rejected::encode(out, now, token, error_reason(&e));
+        }
+    }
+
+    /// Finds the live order a cancel or replace refers to, or the reason to reject it.
+    fn request_target(
+        &self,
+        user: &str,
+        token: u64,
+        new_token: Option<u64>,
+    ) -> Result<u64, u8> {
+        if let Some(new_token) = new_token {
+            if self.tokens.contains_key(&(user.to_string(), new_token)) {
+                return Err(reject_reason::DUPLICATE_TOKEN);
+            }
+        }
+        match self.tokens.get(&(user.to_string(), token)) {
+            // A cancel or replace is already pending
+            Some(&id) if self.orders[&id].pending.is_some() => Err(reject_reason::OTHER),
+            Some(&id) => Ok(id),
+            None => Err(reject_reason::UNKNOWN_ORDER),
+        }
+    }
+
+    fn set_pending(&mut self, id: u64, pending: Pending) {
+        if let Some(order) = self.orders.get_mut(&id) {
+            order.pending = Some(pending);
+        }
+    }
+
+    fn clear_pending(&mut self, id: u64) {
+        if let Some(order) = self.orders.get_mut(&id) {
+            order.pending = None;
+        }
+    }
+
+    /// Encodes the message for an execution report on one of our orders. Orders that are
+    /// done are forgotten.
+    fn report(
+        &mut self,
+        report: &ExecutionReport,
+        now: DateTime<Utc>,
+        outbound: &mut Outbound,
+    ) {
+        let id = report.order_id();
+        let mut order = match self.orders.remove(&id) {
+            Some(order) => order,
+            None => return,
+        };
+        let out = outbound.entry(order.user.clone()).or_insert_with(Vec::new);
+        let mut done = false;
+        match (report.exec_type(), order.pending) {
+            (ExecType::New, _) => {
+                Accepted::encode(out, now, order.token, &order.symbol, order.side, report)
+                    .unwrap(); // Safe, the symbol came in an enter order
+            }
+            (ExecType::PartialFill, _) | (ExecType::Fill, _) => {
+                let match_number = self.next_match_number;
+                self.next_match_number += 1;
+                Executed::encode(out, now, order.token, report, match_number);
+                done = report.exec_type() == ExecType::Fill;
+            }
+            (ExecType::Cancelled, pending) => {
+                let reason = match pending {
+                    Some(Pending::Cancel) => cancel_reason::USER,
+                    _ => cancel_reason::EXCHANGE,
+                };
+                Canceled::encode(out, now, order.token, order.leaves_qty, reason);
+                done = true;
+            }
+            (ExecType::Expired, _) => {
+                let qty = order.leaves_qty;
+                Canceled::encode(out, now, order.token, qty, cancel_reason::EXPIRED);
+                done = true;
+            }
+            (ExecType::Replaced, Some(Pending::Replace { new_token })) => {
+                Replaced::encode(out, now, new_token, order.token, report);
+                self.tokens.remove(&(order.user.clone(), order.token));
+                self.tokens.insert((order.user.clone(), new_token), id);
+                order.token = new_token;
+                order.pending = None;
+            }
+            (ExecType::Restated, _) => {
+                let qty = order.leaves_qty.saturating_sub(report.leaves_qty());
+                Canceled::encode(out, now, order.token, qty, cancel_reason::EXCHANGE);
+            }
+            // A cancel or replace the book would not take, the order is still live
+            (ExecType::Rejected(reason), Some(pending)) => {
+                let token = match pending {
+                    Pending::Cancel => order.token,
+                    Pending::Replace { new_token } => new_token,
+                };
+                Rejected::encode(out, now, token, reject_code(reason));
+                order.pending = None;
+                self.orders.insert(id, order);
+                return;
+            }
+            (ExecType::Rejected(reason), None) => {
+                Rejected::encode(out, now, order.token, reject_code(reason));
+                done = true;
+            }
+            (ExecType::Replaced, _) | (ExecType::Triggered, _) => {}
+        }
+
+        if done {
+            self.tokens.remove(&(order.user, order.token));
+        } else {
+            order.leaves_qty = report.leaves_qty();
+            self.orders.insert(id, order);
+        }
+    }
+
+    fn forget(&mut self, id: u64) {
+        if let Some(order) = self.orders.remove(&id) {
+            self.tokens.remove(&(order.user, order.token));
+        }
+    }
+}
+
+/// The reject reason for a request the engine refused outright.
+fn error_reason(error: &OrderBookError) -> u8 {
+    match *error {
+        OrderBookError::UnknownInstrument(_) => reject_reason::UNKNOWN_SYMBOL,
+        _ => match error.reject_reason() {
+            Some(reason) => reject_code(reason),
+            None => reject_reason::OTHER,
+        },
+    }
+}
+
+fn reject_code(reason: RejectReason) -> u8 {
+    match reason {
+        RejectReason::UnknownOrder => reject_reason::UNKNOWN_ORDER,
+        RejectReason::InvalidQuantity
+        | RejectReason::QtyNotOnLot
+        | RejectReason::QtyBelowMinimum
+        | RejectReason::QtyAboveMaximum => reject_reason::INVALID_QUANTITY,
+        RejectReason::InvalidPrice
+        | RejectReason::PriceNotOnTick
+        | RejectReason::PriceOutsideBand => reject_reason::INVALID_PRICE,
+        RejectReason::NotAcceptedInPhase => reject_reason::CLOSED,
+        RejectReason::Risk(_) => reject_reason::RISK,
+        _ => reject_reason::OTHER,
+    }
+}
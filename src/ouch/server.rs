@@ -0,0 +1,497 @@
+//! TCP acceptor for OUCH users in front of a matching engine.
+
+use super::message::{LoginReply, Request};
+use super::order_entry::{OrderEntry, Outbound};
+use crate::clock::{Clock, SystemClock};
+use crate::engine::MatchingEngine;
+use crate::net;
+use failure::Error;
+use std::collections::HashMap;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// A peer that sends this much without completing a message is dropped, the longest
+// request is an enter order of 45 bytes
+const MAX_MESSAGE_LEN: usize = 64;
+
+// Connections are known by the username they logged in with
+type Connection = net::Connection<String>;
+
+#[derive(Debug)]
+struct User {
+    owner: u64,
+    connected: bool,
+}
+
+/// Accepts OUCH connections and routes the orders they carry to a matching engine.
+///
+/// Everything runs on the thread calling `poll` or `run`, the sockets are non-blocking.
+/// The first message on a connection has to be a login for a user added beforehand, and
+/// a user can only be logged in once. Responses for a user that is not connected are
+/// dropped, there is no replay.
+#[derive(Debug)]
+pub struct OuchServer {
+    listener: TcpListener,
+    engine: MatchingEngine,
+    orders: OrderEntry,
+    users: HashMap<String, User>,
+    connections: Vec<Connection>,
+    clock: Box<Clock>,
+}
+
+impl OuchServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, engine: MatchingEngine) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        info!("OUCH server listening on {}", listener.local_addr()?);
+        Ok(Self {
+            listener,
+            engine,
+            orders: OrderEntry::new(),
+            users: HashMap::new(),
+            connections: Vec::new(),
+            clock: Box::new(SystemClock),
+        })
+    }
+
+    /// Replaces the clock used for timestamps, returning the previous one.
+    pub fn set_clock(&mut self, clock: Box<Clock>) -> Box<Clock> {
+        mem::replace(&mut self.clock, clock)
+    }
+
+    /// Lets `username` log in. Its orders carry `owner` for self-trade prevention.
+    pub fn add_user(&mut self, username: &str, owner: u64) -> Result<(), Error> {
+        if self.users.contains_key(username) {
+            bail!("User {} already exists", username);
+        }
+        self.users.insert(
+            username.to_string(),
+            User {
+                owner,
+                connected: false,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn is_connected(&self, username: &str) -> bool {
+        self.users
+            .get(username)
+            .map_or(false, |user| user.connected)
+    }
+
+    pub fn engine(&self) -> &MatchingEngine {
+        &self.engine
+    }
+
+    /// The engine, for changes such as halts. Reports they cause go out on the next poll.
+    pub fn engine_mut(&mut self) -> &mut MatchingEngine {
+        &mut self.engine
+    }
+
+    /// Accepts new connections, handles the requests that arrived and writes the
+    /// responses. Returns the number of requests received.
+    pub fn poll(&mut self) -> Result<usize, Error> {
+        let now = self.clock.now();
+        self.connections.extend(net::accept(&self.listener, "OUCH")?);
+
+        let mut count = 0;
+        let mut outbound = Outbound::new();
+        for connection in &mut self.connections {
+            if !connection.read() {
+                connection.closed = true;
+            }
+            // Requests are handled where they sit in the receive buffer
+            let mut pos = 0;
+            while !connection.closed {
+                let (request, len) = match Request::decode(&connection.inbound[pos..]) {
+                    Ok(Some(decoded)) => decoded,
+                    Ok(None) => {
+                        if connection.inbound.len() - pos > MAX_MESSAGE_LEN {
+                            warn!("Dropping {}, message too long", connection.peer);
+                            connection.closed = true;
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Dropping {}, garbled message: {}", connection.peer, e);
+                        connection.closed = true;
+                        break;
+                    }
+                };
+                pos += len;
+                count += 1;
+                match (request, connection.session.clone()) {
+                    (Request::Login(login), None) => {
+                        let username = login.username();
+                        let accepted = match self.users.get_mut(username) {
+                            Some(user) => !mem::replace(&mut user.connected, true),
+                            None => false,
+                        };
+                        LoginReply::encode(&mut connection.outbound, now, accepted);
+                        if accepted {
+                            info!("{} logged in from {}", username, connection.peer);
+                            connection.session = Some(username.to_string());
+                        } else {
+                            // The reply still goes out before the connection is closed
+                            warn!("Refusing login of {:?} from {}", username, connection.peer);
+                            connection.closed = true;
+                        }
+                    }
+                    (_, None) | (Request::Login(_), Some(_)) => {
+                        warn!("Dropping {}, unexpected {:?}", connection.peer, request);
+                        connection.closed = true;
+                    }
+                    (request, Some(username)) => {
+                        let owner = self.users[&username].owner;
+                        self.orders.handle(
+                            &username,
+                            owner,
+                            &request,
+                            &mut self.engine,
+                            now,
+                            &mut outbound,
+                        );
+                    }
+                }
+            }
+            connection.inbound.drain(..pos);
+        }
+
+        self.orders.drain_reports(&mut self.engine, now, &mut outbound);
+        self.flush(outbound);
+        Ok(count)
+    }
+
+    /// Polls until `stop` is set, sleeping briefly whenever nothing arrived.
+    pub fn run(&mut self, stop: &AtomicBool) -> Result<(), Error> {
+        while !stop.load(Ordering::Relaxed) {
+            if self.poll()? == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the responses to the users' connections and closes the connections that are
+    /// done.
+    fn flush(&mut self, mut outbound: Outbound) {
+        for connection in &mut self.connections {
+            if let Some(ref username) = connection.session {
+                if let Some(data) = outbound.remove(username) {
+                    connection.outbound.extend_from_slice(&data);
+                }
+            }
+            if !connection.flush() {
+                connection.closed = true;
+            }
+        }
+        for (username, data) in outbound {
+            if !data.is_empty() {
+                debug!("{} is not connected, dropping {} bytes", username, data.len());
+            }
+        }
+
+        let users = &mut self.users;
+        self.connections.retain(|connection| {
+            if !connection.closed {
+                return true;
+            }
+            info!("Closing OUCH connection from {}", connection.peer);
+            if let Some(user) = connection
+                .session
+                .as_ref()
+                .and_then(|username| users.get_mut(username))
+            {
+                user.connected = false;
+            }
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{wire_px as px, Instrument, OrderEvent, Side, TimeInForce};
+    use crate::ouch::message::{cancel_reason, reject_reason, CancelOrder, EnterOrder, Login,
+                               ReplaceOrder, Response};
+    use std::io::{self, Read, Write};
+    use std::net::TcpStream;
+
+    struct Client {
+        stream: TcpStream,
+        inbound: Vec<u8>,
+    }
+
+    impl Client {
+        fn connect(server: &OuchServer) -> Self {
+            let stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+            stream.set_nonblocking(true).unwrap();
+            Self {
+                stream,
+                inbound: Vec::new(),
+            }
+        }
+
+        fn login(server: &mut OuchServer, username: &str) -> Self {
+            let mut client = Self::connect(server);
+            let mut buf = Vec::new();
+            Login::encode(&mut buf, username).unwrap();
+            client.send(&buf);
+            client.expect(server, |response| match response {
+                Response::LoginAccepted(_) => true,
+                _ => false,
+            });
+            client
+        }
+
+        fn send(&mut self, data: &[u8]) {
+            self.stream.write_all(data).unwrap();
+        }
+
+        /// Polls the server until a response arrives and checks it, false if the
+        /// connection was closed.
+        fn receive<F: FnOnce(Response) -> bool>(
+            &mut self,
+            server: &mut OuchServer,
+            check: F,
+        ) -> Option<bool> {
+            let mut buf = [0u8; 4096];
+            for _ in 0..2_000 {
+                if let Some((response, len)) = Response::decode(&self.inbound).unwrap() {
+                    let matched = check(response);
+                    self.inbound.drain(..len);
+                    return Some(matched);
+                }
+                server.poll().unwrap();
+                match self.stream.read(&mut buf) {
+                    Ok(0) => return None,
+                    Ok(len) => self.inbound.extend_from_slice(&buf[..len]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    Err(e) => panic!("Read failed: {}", e),
+                }
+            }
+            panic!("Nothing received from the server");
+        }
+
+        fn expect<F: FnOnce(Response) -> bool>(&mut self, server: &mut OuchServer, check: F) {
+            let matched = self.receive(server, check).expect("Connection closed");
+            assert!(matched, "Unexpected response");
+        }
+
+        fn closed(&mut self, server: &mut OuchServer) -> bool {
+            self.receive(server, |_| true).is_none()
+        }
+    }
+
+    fn server() -> OuchServer {
+        ::crate::model::test_setup();
+        let mut engine = MatchingEngine::new();
+        engine
+            .add_instrument(Instrument::new("AUDUSD").with_price_scale(4))
+            .unwrap();
+        let mut server = OuchServer::bind("127.0.0.1:0", engine).unwrap();
+        server.add_user("ALICE", 1).unwrap();
+        server.add_user("BOB", 2).unwrap();
+        server
+    }
+
+    fn enter(token: u64, symbol: &str, event: OrderEvent) -> Vec<u8> {
+        let mut buf = Vec::new();
+        EnterOrder::encode(&mut buf, token, symbol, &event).unwrap();
+        buf
+    }
+
+    fn rejected(token: u64, reason: u8) -> impl FnOnce(Response) -> bool {
+        move |response| match response {
+            Response::Rejected(rejected) => {
+                (rejected.token(), rejected.reason()) == (token, reason)
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn enters_replaces_and_cancels_orders() {
+        let mut server = server();
+        let mut alice = Client::login(&mut server, "ALICE");
+        let mut bob = Client::login(&mut server, "BOB");
+        assert!(server.is_connected("ALICE"));
+
+        alice.send(&enter(1, "AUDUSD", OrderEvent::limit(0, Side::Bid, px(7512), 100)));
+        let mut order_id = 0;
+        alice.expect(&mut server, |response| match response {
+            Response::Accepted(accepted) => {
+                order_id = accepted.order_id();
+                (accepted.token(), accepted.qty(), accepted.price(4)) == (1, 100, px(7512))
+                    && accepted.side() == Side::Bid
+                    && accepted.symbol() == "AUDUSD"
+            }
+            _ => false,
+        });
+
+        let sell = OrderEvent::limit_with_tif(0, Side::Ask, px(7510), 40, TimeInForce::Day);
+        bob.send(&enter(1, "AUDUSD", sell));
+        bob.expect(&mut server, |response| match response {
+            Response::Accepted(accepted) => accepted.token() == 1,
+            _ => false,
+        });
+        let mut match_number = 0;
+        bob.expect(&mut server, |response| match response {
+            Response::Executed(executed) => {
+                match_number = executed.match_number();
+                (executed.token(), executed.qty(), executed.price(4)) == (1, 40, px(7512))
+            }
+            _ => false,
+        });
+        alice.expect(&mut server, |response| match response {
+            Response::Executed(executed) => {
+                (executed.token(), executed.qty()) == (1, 40)
+                    && executed.match_number() > match_number
+            }
+            _ => false,
+        });
+
+        // The replace quantity is what is left open
+        let mut buf = Vec::new();
+        let replace = OrderEvent::replace(0, Side::Bid, px(7511), 50);
+        ReplaceOrder::encode(&mut buf, 1, 2, &replace).unwrap();
+        alice.send(&buf);
+        alice.expect(&mut server, |response| match response {
+            Response::Replaced(replaced) => {
+                (replaced.token(), replaced.previous_token()) == (2, 1)
+                    && (replaced.order_id(), replaced.qty()) == (order_id, 50)
+                    && replaced.price(4) == px(7511)
+            }
+            _ => false,
+        });
+
+        // Token 1 is gone with the replace
+        let mut buf = Vec::new();
+        CancelOrder::encode(&mut buf, 1);
+        CancelOrder::encode(&mut buf, 2);
+        alice.send(&buf);
+        alice.expect(&mut server, |response| match response {
+            Response::Rejected(rejected) => {
+                (rejected.token(), rejected.reason()) == (1, reject_reason::UNKNOWN_ORDER)
+            }
+            _ => false,
+        });
+        alice.expect(&mut server, |response| match response {
+            Response::Canceled(canceled) => {
+                (canceled.token(), canceled.qty(), canceled.reason())
+                    == (2, 50, cancel_reason::USER)
+            }
+            _ => false,
+        });
+
+        // Tokens are per user, but not reused while the order is live
+        let buy = OrderEvent::limit(0, Side::Bid, px(7500), 10);
+        alice.send(&enter(1, "AUDUSD", buy));
+        alice.send(&enter(1, "AUDUSD", buy));
+        alice.send(&enter(3, "EURUSD", buy));
+        alice.expect(&mut server, |response| match response {
+            Response::Accepted(accepted) => accepted.token() == 1,
+            _ => false,
+        });
+        alice.expect(&mut server, rejected(1, reject_reason::DUPLICATE_TOKEN));
+        alice.expect(&mut server, rejected(3, reject_reason::UNKNOWN_SYMBOL));
+
+        server.engine_mut().halt("AUDUSD").unwrap();
+        alice.send(&enter(4, "AUDUSD", buy));
+        alice.expect(&mut server, rejected(4, reject_reason::CLOSED));
+        assert_eq!(server.orders.open_orders(), 1);
+    }
+
+    #[test]
+    fn reports_cancels_by_the_exchange() {
+        let mut server = server();
+        let mut alice = Client::login(&mut server, "ALICE");
+        let mut bob = Client::login(&mut server, "BOB");
+
+        alice.send(&enter(7, "AUDUSD", OrderEvent::limit(0, Side::Ask, px(7512), 30)));
+        alice.expect(&mut server, |response| match response {
+            Response::Accepted(_) => true,
+            _ => false,
+        });
+
+        // The rest of a market order that could not trade is cancelled
+        bob.send(&enter(1, "AUDUSD", OrderEvent::market(0, Side::Bid, 100)));
+        bob.expect(&mut server, |response| match response {
+            Response::Accepted(accepted) => accepted.qty() == 100,
+            _ => false,
+        });
+        bob.expect(&mut server, |response| match response {
+            Response::Executed(executed) => executed.qty() == 30,
+            _ => false,
+        });
+        bob.expect(&mut server, |response| match response {
+            Response::Canceled(canceled) => {
+                (canceled.qty(), canceled.reason()) == (70, cancel_reason::EXCHANGE)
+            }
+            _ => false,
+        });
+        alice.expect(&mut server, |response| match response {
+            Response::Executed(executed) => (executed.token(), executed.qty()) == (7, 30),
+            _ => false,
+        });
+
+        // Fills while a user is away are not replayed
+        bob.send(&enter(2, "AUDUSD", OrderEvent::limit(0, Side::Bid, px(7500), 10)));
+        bob.expect(&mut server, |response| match response {
+            Response::Accepted(accepted) => accepted.token() == 2,
+            _ => false,
+        });
+        drop(bob);
+        while server.is_connected("BOB") {
+            server.poll().unwrap();
+        }
+        server
+            .engine_mut()
+            .event("AUDUSD", OrderEvent::limit(1_000, Side::Ask, px(7500), 10))
+            .unwrap();
+        server.poll().unwrap();
+
+        // Token 2 is free again once the order is done
+        let mut bob = Client::login(&mut server, "BOB");
+        bob.send(&enter(2, "AUDUSD", OrderEvent::limit(0, Side::Bid, px(7400), 1)));
+        bob.expect(&mut server, |response| match response {
+            Response::Accepted(accepted) => accepted.token() == 2,
+            _ => false,
+        });
+        assert_eq!(server.orders.open_orders(), 1);
+    }
+
+    #[test]
+    fn refuses_unknown_and_second_logins() {
+        let mut server = server();
+        let _alice = Client::login(&mut server, "ALICE");
+
+        let mut again = Client::connect(&server);
+        let mut buf = Vec::new();
+        Login::encode(&mut buf, "ALICE").unwrap();
+        again.send(&buf);
+        let rejected = again.receive(&mut server, |response| match response {
+            Response::LoginRejected(_) => true,
+            _ => false,
+        });
+        assert_eq!(rejected, Some(true));
+        assert!(again.closed(&mut server));
+        assert!(server.is_connected("ALICE"));
+
+        // Orders before a login
+        let mut eve = Client::connect(&server);
+        eve.send(&enter(1, "AUDUSD", OrderEvent::limit(0, Side::Bid, px(7512), 1)));
+        assert!(eve.closed(&mut server));
+    }
+}
@@ -0,0 +1,178 @@
+use failure::Error;
+use std::fmt;
+
+pub(crate) const MAX_SCALE: u8 = 18;
+
+/// Fixed-point decimal price made of `units` of `10^-scale`. The scale comes from the
+/// instrument the price belongs to; arithmetic between prices of different scales fails
+/// instead of silently mixing them.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Price {
+    units: u64,
+    scale: u8,
+}
+
+impl Price {
+    pub fn new(units: u64, scale: u8) -> Self {
+        assert!(scale <= MAX_SCALE, "Price scale {} is too large", scale);
+        Self { units, scale }
+    }
+
+    pub fn zero(scale: u8) -> Self {
+        Self::new(0, scale)
+    }
+
+    /// Parses a decimal string such as "0.7512". Fails if it has more significant decimals
+    /// than `scale` allows.
+    pub fn parse(s: &str, scale: u8) -> Result<Self, Error> {
+        let s = s.trim();
+        let (int_part, frac_part) = match s.find('.') {
+            Some(pos) => (&s[..pos], &s[pos + 1..]),
+            None => (s, ""),
+        };
+        if int_part.is_empty() && frac_part.is_empty() {
+            bail!("Invalid price {:?}", s);
+        }
+        if !int_part.chars().all(|c| c.is_ascii_digit())
+            || !frac_part.chars().all(|c| c.is_ascii_digit())
+        {
+            bail!("Invalid price {:?}", s);
+        }
+
+        let frac_part = frac_part.trim_right_matches('0');
+        if frac_part.len() > scale as usize {
+            bail!("Price {:?} has more than {} decimals", s, scale);
+        }
+
+        let mut units: u64 = 0;
+        for c in int_part.chars().chain(frac_part.chars()) {
+            let digit = u64::from(c.to_digit(10).unwrap()); // Safe, checked above
+            units = units
+                .checked_mul(10)
+                .and_then(|u| u.checked_add(digit))
+                .ok_or_else(|| format_err!("Price {:?} is out of range", s))?;
+        }
+        for _ in frac_part.len()..scale as usize {
+            units = units
+                .checked_mul(10)
+                .ok_or_else(|| format_err!("Price {:?} is out of range", s))?;
+        }
+        Ok(Self::new(units, scale))
+    }
+
+    pub fn units(&self) -> u64 {
+        self.units
+    }
+
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.units == 0
+    }
+
+    pub fn checked_add(self, other: Price) -> Option<Price> {
+        if self.scale != other.scale {
+            return None;
+        }
+        self.units
+            .checked_add(other.units)
+            .map(|units| Self::new(units, self.scale))
+    }
+
+    pub fn checked_sub(self, other: Price) -> Option<Price> {
+        if self.scale != other.scale {
+            return None;
+        }
+        self.units
+            .checked_sub(other.units)
+            .map(|units| Self::new(units, self.scale))
+    }
+
+    pub fn checked_mul(self, qty: u64) -> Option<Price> {
+        self.units
+            .checked_mul(qty)
+            .map(|units| Self::new(units, self.scale))
+    }
+
+    /// Distance between two prices in units, regardless of which is higher.
+    pub fn distance(self, other: Price) -> Option<u64> {
+        if self.scale != other.scale {
+            None
+        } else if self.units > other.units {
+            Some(self.units - other.units)
+        } else {
+            Some(other.units - self.units)
+        }
+    }
+}
+
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.scale == 0 {
+            return write!(f, "{}", self.units);
+        }
+        let divisor = 10u64.pow(u32::from(self.scale));
+        write!(
+            f,
+            "{}.{:0width$}",
+            self.units / divisor,
+            self.units % divisor,
+            width = self.scale as usize
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        assert_eq!(Price::parse("0.7512", 4).unwrap(), Price::new(7512, 4));
+        assert_eq!(Price::parse("1.5", 4).unwrap(), Price::new(15000, 4));
+        assert_eq!(Price::parse("12", 2).unwrap(), Price::new(1200, 2));
+        assert_eq!(Price::parse("1.2500", 2).unwrap(), Price::new(125, 2));
+        assert_eq!(Price::parse(".5", 1).unwrap(), Price::new(5, 1));
+        assert_eq!(Price::parse("4799", 0).unwrap(), Price::new(4799, 0));
+
+        assert_eq!(Price::new(7512, 4).to_string(), "0.7512");
+        assert_eq!(Price::new(15000, 4).to_string(), "1.5000");
+        assert_eq!(Price::new(5, 3).to_string(), "0.005");
+        assert_eq!(Price::new(4799, 0).to_string(), "4799");
+
+        for s in &["0.0001", "123.4567", "0.0000", "99999.9999"] {
+            assert_eq!(Price::parse(s, 4).unwrap().to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_bad_input() {
+        assert!(Price::parse("1.23456", 4).is_err());
+        assert!(Price::parse("", 4).is_err());
+        assert!(Price::parse(".", 4).is_err());
+        assert!(Price::parse("-1.0", 4).is_err());
+        assert!(Price::parse("1.2.3", 4).is_err());
+        assert!(Price::parse("1e5", 4).is_err());
+        assert!(Price::parse("99999999999999999999", 0).is_err());
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let a = Price::new(7512, 4);
+        let b = Price::new(12, 4);
+        assert_eq!(a.checked_add(b), Some(Price::new(7524, 4)));
+        assert_eq!(a.checked_sub(b), Some(Price::new(7500, 4)));
+        assert_eq!(b.checked_sub(a), None);
+        assert_eq!(a.checked_mul(3), Some(Price::new(22536, 4)));
+        assert_eq!(Price::new(u64::max_value(), 0).checked_mul(2), None);
+        assert_eq!(a.distance(b), Some(7500));
+        assert_eq!(b.distance(a), Some(7500));
+
+        let other_scale = Price::new(12, 2);
+        assert_eq!(a.checked_add(other_scale), None);
+        assert_eq!(a.checked_sub(other_scale), None);
+        assert_eq!(a.distance(other_scale), None);
+    }
+}
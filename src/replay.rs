@@ -0,0 +1,177 @@
+//! Deterministic replay of an order file through a book, producing one output line per
+//! fill and execution report so that runs can be compared with a stored golden output.
+//!
+//! Each line is CSV and starts with the 1-based number of the event that produced it:
+//!
+//! ```text
+//! <event>,fill,<incoming id>,<resting id>,<price>,<qty>
+//! <event>,report,<order id>,<side>,<exec type>,<price>,<last qty>,<last price>,<leaves qty>,<cum qty>,<avg price>
+//! ```
+
+use crate::clock::ManualClock;
+use crate::model::{ExecutionReport, Instrument, OrderEvent, OrderFill};
+use crate::order_book::OrderBook;
+use chrono::{TimeZone, Utc};
+use failure::Error;
+use std::fmt;
+
+pub const HEADER: &str = "event,kind,fields...";
+
+/// Runs `events` through a new book for `instrument` and returns the output lines, header
+/// first. The book's clock is fixed so the output does not depend on when it runs.
+pub fn run(instrument: Instrument, events: &[OrderEvent]) -> Result<Vec<String>, Error> {
+    let clock = ManualClock::new(Utc.ymd(2018, 1, 1).and_hms(0, 0, 0));
+    let mut book = OrderBook::with_clock(instrument, Box::new(clock));
+    let mut lines = vec![HEADER.to_string()];
+    for (n, event) in events.iter().enumerate() {
+        let event_no = n + 1;
+        for fill in book.event(*event)? {
+            lines.push(fill_line(event_no, &fill));
+        }
+        for report in book.drain_reports() {
+            lines.push(report_line(event_no, &report));
+        }
+    }
+    Ok(lines)
+}
+
+fn fill_line(event_no: usize, fill: &OrderFill) -> String {
+    format!(
+        "{},fill,{},{},{},{}",
+        event_no,
+        fill.ord_id_1(),
+        fill.ord_id_2(),
+        fill.price(),
+        fill.qty()
+    )
+}
+
+fn report_line(event_no: usize, report: &ExecutionReport) -> String {
+    let side = match report.side() {
+        Some(side) => format!("{:?}", side),
+        None => String::new(),
+    };
+    format!(
+        "{},report,{},{},{:?},{},{},{},{},{},{}",
+        event_no,
+        report.order_id(),
+        side,
+        report.exec_type(),
+        report.price(),
+        report.last_qty(),
+        report.last_price(),
+        report.leaves_qty(),
+        report.cum_qty(),
+        report.avg_price()
+    )
+}
+
+/// The first line at which two outputs differ. A missing line means one output ended
+/// early.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Difference {
+    pub line: usize,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |line: &Option<String>| match *line {
+            Some(ref line) => line.clone(),
+            None => String::from("<end of output>"),
+        };
+        write!(
+            f,
+            "line {}:\n  expected: {}\n  actual:   {}",
+            self.line,
+            show(&self.expected),
+            show(&self.actual)
+        )
+    }
+}
+
+pub fn first_difference<S: AsRef<str>, T: AsRef<str>>(
+    expected: &[S],
+    actual: &[T],
+) -> Option<Difference> {
+    let len = expected.len().max(actual.len());
+    (0..len)
+        .find(|i| {
+            expected.get(*i).map(|s| s.as_ref()) != actual.get(*i).map(|s| s.as_ref())
+        })
+        .map(|i| Difference {
+            line: i + 1,
+            expected: expected.get(i).map(|s| s.as_ref().to_string()),
+            actual: actual.get(i).map(|s| s.as_ref().to_string()),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader;
+    use crate::model::{Price, Side};
+    use std::fs;
+
+    fn events() -> Vec<OrderEvent> {
+        vec![
+            OrderEvent::limit(1, Side::Ask, Price::new(101, 0), 100),
+            OrderEvent::limit(2, Side::Bid, Price::new(101, 0), 40),
+            OrderEvent::Cancel { id: 7 },
+        ]
+    }
+
+    #[test]
+    fn writes_fills_and_reports() {
+        let lines = run(Instrument::new("AUDUSD"), &events()).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                HEADER,
+                "1,report,1,Ask,New,101,0,0,100,0,0",
+                "2,fill,2,1,101,40",
+                "2,report,2,Bid,New,101,0,0,40,0,0",
+                "2,report,2,Bid,Fill,101,40,101,0,40,101",
+                "2,report,1,Ask,PartialFill,101,40,101,60,40,101",
+                "3,report,7,,Rejected(UnknownOrder),0,0,0,0,0,0",
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_first_difference() {
+        let expected = vec!["a", "b", "c"];
+        assert_eq!(first_difference(&expected, &expected), None);
+        assert_eq!(
+            first_difference(&expected, &["a", "x", "c"]),
+            Some(Difference {
+                line: 2,
+                expected: Some("b".to_string()),
+                actual: Some("x".to_string()),
+            })
+        );
+        assert_eq!(
+            first_difference(&expected, &["a", "b"]),
+            Some(Difference {
+                line: 3,
+                expected: Some("c".to_string()),
+                actual: None,
+            })
+        );
+    }
+
+    #[test]
+    fn matches_golden_output() {
+        let dir = env!("CARGO_MANIFEST_DIR");
+        // The order file's prices are in units of 10^-4, as the replay tool loads them
+        let events = loader::load_orders(format!("{}/config/orders.csv", dir), 4).unwrap();
+        let instrument = Instrument::new("AUDUSD").with_price_scale(4);
+        let lines = run(instrument, &events).unwrap();
+        let golden = fs::read_to_string(format!("{}/config/orders.golden.csv", dir)).unwrap();
+        let golden: Vec<&str> = golden.lines().collect();
+        if let Some(difference) = first_difference(&golden, &lines) {
+            panic!("Output differs from config/orders.golden.csv at {}", difference);
+        }
+    }
+}
@@ -0,0 +1,684 @@
+//! Pre-trade risk checks in front of an order book.
+//!
+//! Every new order and amendment is checked against the limits of the account that owns
+//! it before it reaches matching. Orders of accounts without limits are rejected. Fills
+//! and order state changes reported by the book feed back into each account's position,
+//! open orders and credit usage. Cancels are never blocked.
+
+use crate::error::OrderBookError;
+use crate::model::{ExecType, ExecutionReport, MarketDataEvent, OrderEvent, OrderFeedEvent,
+                   OrderFill, Price, RejectReason, Side, TradingPhase};
+use crate::order_book::OrderBook;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+
+/// The check an order failed.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RiskViolation {
+    /// The owner has no risk limits set up
+    UnknownAccount,
+    OrderQtyLimit,
+    NotionalLimit,
+    OpenOrderLimit,
+    PositionLimit,
+    CreditLimit,
+    OrderRateLimit,
+    /// A market order with nothing to value it by, while notional or credit is limited
+    NoReferencePrice,
+}
+
+/// Limits of one account. Notional values are price units times quantity, in the price
+/// scale of the instrument. Limits that are not set are not checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RiskLimits {
+    max_order_qty: Option<u64>,
+    max_notional: Option<u64>,
+    max_open_orders: Option<usize>,
+    max_position: Option<u64>,
+    credit_limit: Option<u64>,
+    // At most this many orders and amendments within the window
+    order_rate: Option<(usize, Duration)>,
+}
+
+impl RiskLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_order_qty(mut self, max_order_qty: u64) -> Self {
+        self.max_order_qty = Some(max_order_qty);
+        self
+    }
+
+    /// Caps the value of a single order.
+    pub fn with_max_notional(mut self, max_notional: u64) -> Self {
+        self.max_notional = Some(max_notional);
+        self
+    }
+
+    pub fn with_max_open_orders(mut self, max_open_orders: usize) -> Self {
+        self.max_open_orders = Some(max_open_orders);
+        self
+    }
+
+    /// Caps the net position the account would reach if all its orders on one side filled.
+    pub fn with_max_position(mut self, max_position: u64) -> Self {
+        self.max_position = Some(max_position);
+        self
+    }
+
+    /// Caps the value traded plus the value of open orders.
+    pub fn with_credit_limit(mut self, credit_limit: u64) -> Self {
+        self.credit_limit = Some(credit_limit);
+        self
+    }
+
+    pub fn with_order_rate(mut self, max_orders: usize, window: Duration) -> Self {
+        self.order_rate = Some((max_orders, window));
+        self
+    }
+
+    pub fn max_order_qty(&self) -> Option<u64> {
+        self.max_order_qty
+    }
+
+    pub fn max_notional(&self) -> Option<u64> {
+        self.max_notional
+    }
+
+    pub fn max_open_orders(&self) -> Option<usize> {
+        self.max_open_orders
+    }
+
+    pub fn max_position(&self) -> Option<u64> {
+        self.max_position
+    }
+
+    pub fn credit_limit(&self) -> Option<u64> {
+        self.credit_limit
+    }
+
+    pub fn order_rate(&self) -> Option<(usize, Duration)> {
+        self.order_rate
+    }
+}
+
+/// Running exposure of one account.
+#[derive(Debug, Clone, Default)]
+struct Account {
+    limits: RiskLimits,
+    // Net filled quantity, positive when long
+    position: i64,
+    // Value of everything filled so far
+    traded_notional: u64,
+    open_orders: usize,
+    // Sums over open orders, wide enough that they cannot overflow
+    open_bid_qty: u128,
+    open_ask_qty: u128,
+    open_notional: u128,
+    // Arrival times of recent orders for the rate throttle
+    recent: VecDeque<DateTime<Utc>>,
+}
+
+impl Account {
+    fn add_open(&mut self, order: &OpenOrder) {
+        self.open_orders += 1;
+        match order.side {
+            Side::Bid => self.open_bid_qty += u128::from(order.leaves_qty),
+            Side::Ask => self.open_ask_qty += u128::from(order.leaves_qty),
+        }
+        self.open_notional += order.notional();
+    }
+
+    fn remove_open(&mut self, order: &OpenOrder) {
+        self.open_orders -= 1;
+        match order.side {
+            Side::Bid => self.open_bid_qty -= u128::from(order.leaves_qty),
+            Side::Ask => self.open_ask_qty -= u128::from(order.leaves_qty),
+        }
+        self.open_notional -= order.notional();
+    }
+
+    /// Position if every open order on `side` and an extra `qty` filled.
+    fn worst_position(&self, side: Side, qty: u64) -> u128 {
+        let position = i128::from(self.position);
+        let worst = match side {
+            Side::Bid => position + (self.open_bid_qty + u128::from(qty)) as i128,
+            Side::Ask => position - (self.open_ask_qty + u128::from(qty)) as i128,
+        };
+        worst.abs() as u128
+    }
+
+    /// Value traded plus the value of open orders.
+    fn credit_used(&self) -> u128 {
+        u128::from(self.traded_notional) + self.open_notional
+    }
+}
+
+/// An order of a checked account that has not been filled or taken off the book yet.
+#[derive(Debug, Clone, Copy)]
+struct OpenOrder {
+    account: u64,
+    side: Side,
+    // Limit price, or the estimate used at entry for market and stop market orders
+    price_units: u64,
+    leaves_qty: u64,
+}
+
+impl OpenOrder {
+    fn notional(&self) -> u128 {
+        u128::from(self.price_units) * u128::from(self.leaves_qty)
+    }
+}
+
+/// Wraps an order book and only lets orders through that pass the owner's risk limits.
+/// Rejected orders get a `Rejected(RejectReason::Risk(..))` report and never reach the
+/// book.
+#[derive(Debug)]
+pub struct RiskGate {
+    book: OrderBook,
+    accounts: HashMap<u64, Account>,
+    orders: HashMap<u64, OpenOrder>,
+    reports: Vec<ExecutionReport>,
+}
+
+impl RiskGate {
+    pub fn new(book: OrderBook) -> Self {
+        Self {
+            book,
+            accounts: HashMap::new(),
+            orders: HashMap::new(),
+            reports: Vec::new(),
+        }
+    }
+
+    /// Sets or replaces the limits of an account, keeping its current exposure.
+    pub fn set_limits(&mut self, account: u64, limits: RiskLimits) {
+        info!("Setting risk limits of account {}: {:?}", account, limits);
+        self.accounts
+            .entry(account)
+            .or_insert_with(Account::default)
+            .limits = limits;
+    }
+
+    pub fn limits(&self, account: u64) -> Option<&RiskLimits> {
+        self.accounts.get(&account).map(|account| &account.limits)
+    }
+
+    /// Net filled quantity of the account, positive when long.
+    pub fn position(&self, account: u64) -> i64 {
+        self.accounts.get(&account).map_or(0, |account| account.position)
+    }
+
+    pub fn open_orders(&self, account: u64) -> usize {
+        self.accounts
+            .get(&account)
+            .map_or(0, |account| account.open_orders)
+    }
+
+    /// Value traded plus the value of open orders, as counted against the credit limit.
+    pub fn credit_used(&self, account: u64) -> u64 {
+        self.accounts.get(&account).map_or(0, |account| {
+            account.credit_used().min(u128::from(u64::max_value())) as u64
+        })
+    }
+
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    pub fn into_book(self) -> OrderBook {
+        self.book
+    }
+
+    pub fn event(&mut self, event: OrderEvent) -> Result<Vec<OrderFill>, OrderBookError> {
+        let now = self.book.now();
+        let (id, side) = match event {
+            OrderEvent::Cancel { .. } => return self.forward(event, None),
+            OrderEvent::Replace { id, side, .. } => (id, side),
+            OrderEvent::Market { id, side, .. }
+            | OrderEvent::Limit { id, side, .. }
+            | OrderEvent::Iceberg { id, side, .. }
+            | OrderEvent::StopMarket { id, side, .. }
+            | OrderEvent::StopLimit { id, side, .. } => (id, side),
+        };
+
+        let order = match event {
+            OrderEvent::Replace { id, qty, price, .. } => match self.orders.get(&id) {
+                Some(existing) => OpenOrder {
+                    price_units: price.units(),
+                    leaves_qty: qty,
+                    ..*existing
+                },
+                // Unknown to the gate, the book rejects it if it is not resting there
+                None => return self.forward(event, None),
+            },
+            // The book rejects the id as a duplicate, the live order keeps its exposure
+            _ if self.orders.contains_key(&id) => return self.forward(event, None),
+            _ => {
+                let price_units = match self.estimate_price(&event) {
+                    Some(price) => price.units(),
+                    None if self.needs_price(event.owner()) => {
+                        self.reject(id, side, RiskViolation::NoReferencePrice);
+                        return Ok(Vec::new());
+                    }
+                    None => 0,
+                };
+                OpenOrder {
+                    account: event.owner(),
+                    side,
+                    price_units,
+                    leaves_qty: event_qty(&event),
+                }
+            }
+        };
+
+        let replaced = match event {
+            OrderEvent::Replace { .. } => self.orders.get(&id).cloned(),
+            _ => None,
+        };
+        if let Err(violation) = self.check(&order, replaced.as_ref(), now) {
+            self.reject(id, side, violation);
+            return Ok(Vec::new());
+        }
+
+        let account = self.accounts.get_mut(&order.account).unwrap(); // Safe, checked above
+        account.recent.push_back(now);
+        if replaced.is_none() {
+            account.add_open(&order);
+            self.orders.insert(id, order);
+            self.forward(event, Some(id))
+        } else {
+            self.forward(event, None)
+        }
+    }
+
+    /// Expires Day and GTD orders in the book, releasing their exposure.
+    pub fn expire_orders(&mut self) -> Result<usize, OrderBookError> {
+        let expired = self.book.expire_orders()?;
+        self.apply_reports(None);
+        Ok(expired)
+    }
+
+    /// Moves the book to another trading phase, booking the fills of an uncross against
+    /// the accounts.
+    pub fn set_phase(&mut self, phase: TradingPhase) -> Result<Vec<OrderFill>, OrderBookError> {
+        let result = self.book.set_phase(phase);
+        self.apply_reports(None);
+        result
+    }
+
+    pub fn drain_reports(&mut self) -> Vec<ExecutionReport> {
+        self.reports.drain(..).collect()
+    }
+
+    pub fn drain_market_data(&mut self) -> Vec<MarketDataEvent> {
+        self.book.drain_market_data()
+    }
+
+    pub fn drain_order_feed(&mut self) -> Vec<OrderFeedEvent> {
+        self.book.drain_order_feed()
+    }
+
+    fn check(
+        &mut self,
+        order: &OpenOrder,
+        replaced: Option<&OpenOrder>,
+        now: DateTime<Utc>,
+    ) -> Result<(), RiskViolation> {
+        let account = self.accounts
+            .get_mut(&order.account)
+            .ok_or(RiskViolation::UnknownAccount)?;
+        let limits = account.limits;
+
+        if let Some((max_orders, window)) = limits.order_rate {
+            while account
+                .recent
+                .front()
+                .map_or(false, |time| *time <= now - window)
+            {
+                account.recent.pop_front();
+            }
+            if account.recent.len() >= max_orders {
+                return Err(RiskViolation::OrderRateLimit);
+            }
+        }
+
+        if limits.max_order_qty.map_or(false, |max| order.leaves_qty > max) {
+            return Err(RiskViolation::OrderQtyLimit);
+        }
+        if limits
+            .max_notional
+            .map_or(false, |max| order.notional() > u128::from(max))
+        {
+            return Err(RiskViolation::NotionalLimit);
+        }
+
+        // An amendment is checked as if the order it replaces were already gone
+        let mut account = account.clone();
+        match replaced {
+            Some(replaced) => account.remove_open(replaced),
+            None => {
+                if limits
+                    .max_open_orders
+                    .map_or(false, |max| account.open_orders >= max)
+                {
+                    return Err(RiskViolation::OpenOrderLimit);
+                }
+            }
+        }
+        if limits.max_position.map_or(false, |max| {
+            account.worst_position(order.side, order.leaves_qty) > u128::from(max)
+        }) {
+            return Err(RiskViolation::PositionLimit);
+        }
+        let credit = account.credit_used() + order.notional();
+        if limits.credit_limit.map_or(false, |max| credit > u128::from(max)) {
+            return Err(RiskViolation::CreditLimit);
+        }
+        Ok(())
+    }
+
+    fn reject(&mut self, id: u64, side: Side, violation: RiskViolation) {
+        info!("Order {:?} failed risk check: {:?}", id, violation);
+        self.reports.push(ExecutionReport::rejected(
+            id,
+            Some(side),
+            RejectReason::Risk(violation),
+        ));
+    }
+
+    /// Whether orders of the account have to be valued to be checked.
+    fn needs_price(&self, account: u64) -> bool {
+        self.accounts.get(&account).map_or(false, |account| {
+            account.limits.max_notional.is_some() || account.limits.credit_limit.is_some()
+        })
+    }
+
+    /// Passes an accepted event to the book and books the resulting reports. `new_id` is
+    /// the id of a new order tracked ahead of the book accepting it.
+    fn forward(
+        &mut self,
+        event: OrderEvent,
+        new_id: Option<u64>,
+    ) -> Result<Vec<OrderFill>, OrderBookError> {
+        let result = self.book.event(event);
+        self.apply_reports(new_id);
+        result
+    }
+
+    fn apply_reports(&mut self, new_id: Option<u64>) {
+        for report in self.book.drain_reports() {
+            self.apply(&report, new_id);
+            self.reports.push(report);
+        }
+    }
+
+    fn apply(&mut self, report: &ExecutionReport, new_id: Option<u64>) {
+        let id = report.order_id();
+        let order = match self.orders.get(&id) {
+            Some(order) => *order,
+            None => return,
+        };
+        let account = match self.accounts.get_mut(&order.account) {
+            Some(account) => account,
+            None => return,
+        };
+
+        if report.last_qty() > 0 {
+            match order.side {
+                Side::Bid => account.position += report.last_qty() as i64,
+                Side::Ask => account.position -= report.last_qty() as i64,
+            }
+            account.traded_notional = account.traded_notional.saturating_add(
+                report
+                    .last_price()
+                    .units()
+                    .saturating_mul(report.last_qty()),
+            );
+        }
+
+        let mut updated = order;
+        match report.exec_type() {
+            // A rejected amendment leaves the order as it was
+            ExecType::Rejected(_) if new_id != Some(id) => return,
+            ExecType::Rejected(_) => updated.leaves_qty = 0,
+            ExecType::Replaced => {
+                updated.price_units = report.price().units();
+                updated.leaves_qty = report.leaves_qty();
+            }
+            _ => updated.leaves_qty = report.leaves_qty(),
+        }
+
+        account.remove_open(&order);
+        if updated.leaves_qty > 0 {
+            account.add_open(&updated);
+            self.orders.insert(id, updated);
+        } else {
+            self.orders.remove(&id);
+        }
+    }
+
+    /// Price used to value a new order. Market orders are valued at the prices they would
+    /// sweep on the opposite side, or the last traded price when there is nothing to
+    /// sweep. Stop market orders are valued at their stop price. None if a market order
+    /// has neither.
+    fn estimate_price(&self, event: &OrderEvent) -> Option<Price> {
+        match *event {
+            OrderEvent::Limit { price, .. }
+            | OrderEvent::Iceberg { price, .. }
+            | OrderEvent::StopLimit { price, .. }
+            | OrderEvent::Replace { price, .. } => Some(price),
+            OrderEvent::StopMarket { stop_price, .. } => Some(stop_price),
+            OrderEvent::Market { side, qty, .. } => {
+                let mut remaining = qty;
+                let mut worst = None;
+                for level in self.book.full_depth(side.opposite()) {
+                    if remaining == 0 {
+                        break;
+                    }
+                    worst = Some(level.price());
+                    remaining -= remaining.min(level.qty());
+                }
+                worst.or_else(|| self.book.last_traded_price())
+            }
+            OrderEvent::Cancel { .. } => None,
+        }
+    }
+}
+
+fn event_qty(event: &OrderEvent) -> u64 {
+    match *event {
+        OrderEvent::Market { qty, .. }
+        | OrderEvent::Limit { qty, .. }
+        | OrderEvent::Iceberg { qty, .. }
+        | OrderEvent::StopMarket { qty, .. }
+        | OrderEvent::StopLimit { qty, .. }
+        | OrderEvent::Replace { qty, .. } => qty,
+        OrderEvent::Cancel { .. } => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::model::{px, Instrument};
+    use chrono::TimeZone;
+
+    const TRADER: u64 = 7;
+    const OTHER: u64 = 8;
+
+    fn gate(limits: RiskLimits) -> (RiskGate, ManualClock) {
+        ::crate::model::test_setup();
+
+        let clock = ManualClock::new(Utc.ymd(2018, 1, 1).and_hms(9, 0, 0));
+        let book = OrderBook::with_clock(Instrument::new("AUDUSD"), Box::new(clock.clone()));
+        let mut gate = RiskGate::new(book);
+        gate.set_limits(TRADER, limits);
+        gate.set_limits(OTHER, RiskLimits::new());
+        (gate, clock)
+    }
+
+    fn rejection(gate: &mut RiskGate) -> Option<RiskViolation> {
+        gate.drain_reports()
+            .iter()
+            .filter_map(|report| match report.exec_type() {
+                ExecType::Rejected(RejectReason::Risk(violation)) => Some(violation),
+                _ => None,
+            })
+            .next()
+    }
+
+    fn limit(id: u64, side: Side, price: u64, qty: u64, owner: u64) -> OrderEvent {
+        OrderEvent::limit(id, side, px(price), qty).with_owner(owner)
+    }
+
+    #[test]
+    fn rejects_unknown_accounts() {
+        let (mut gate, _) = gate(RiskLimits::new());
+        gate.event(limit(1, Side::Bid, 100, 10, 99)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::UnknownAccount));
+        assert!(gate.book().best_bid().is_none());
+
+        gate.event(limit(2, Side::Bid, 100, 10, 0)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::UnknownAccount));
+    }
+
+    #[test]
+    fn order_size_and_notional_limits() {
+        let (mut gate, _) = gate(
+            RiskLimits::new()
+                .with_max_order_qty(100)
+                .with_max_notional(5_000),
+        );
+        gate.event(limit(1, Side::Bid, 10, 101, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::OrderQtyLimit));
+
+        gate.event(limit(2, Side::Bid, 51, 100, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::NotionalLimit));
+
+        gate.event(limit(3, Side::Bid, 50, 100, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), None);
+        assert_eq!(gate.book().best_bid().unwrap().qty(), 100);
+
+        // Market orders are valued at the prices they would take
+        gate.event(limit(4, Side::Ask, 60, 100, OTHER)).unwrap();
+        gate.event(OrderEvent::market(5, Side::Bid, 100).with_owner(TRADER))
+            .unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::NotionalLimit));
+    }
+
+    #[test]
+    fn market_orders_need_a_price_to_be_valued() {
+        let (mut gate, _) = gate(RiskLimits::new().with_credit_limit(10_000));
+        gate.event(OrderEvent::market(1, Side::Bid, 10).with_owner(TRADER))
+            .unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::NoReferencePrice));
+
+        // Without notional or credit limits there is nothing to value it for
+        gate.event(OrderEvent::market(2, Side::Bid, 10).with_owner(OTHER))
+            .unwrap();
+        assert_eq!(rejection(&mut gate), None);
+
+        // Once there is a last traded price, the order is valued at it
+        gate.event(limit(3, Side::Ask, 200, 10, OTHER)).unwrap();
+        gate.event(limit(4, Side::Bid, 200, 10, OTHER)).unwrap();
+        gate.event(OrderEvent::market(5, Side::Bid, 100).with_owner(TRADER))
+            .unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::CreditLimit));
+    }
+
+    #[test]
+    fn huge_orders_do_not_overflow_the_position_check() {
+        let (mut gate, _) = gate(RiskLimits::new().with_max_position(1_000));
+        gate.event(limit(1, Side::Bid, 100, 600, TRADER)).unwrap();
+        gate.event(limit(2, Side::Bid, 100, u64::max_value(), TRADER))
+            .unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::PositionLimit));
+        gate.event(limit(3, Side::Ask, 100, u64::max_value(), TRADER))
+            .unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::PositionLimit));
+        assert_eq!(gate.open_orders(TRADER), 1);
+    }
+
+    #[test]
+    fn open_orders_are_released_by_fills_and_cancels() {
+        let (mut gate, _) = gate(RiskLimits::new().with_max_open_orders(2));
+        gate.event(limit(1, Side::Bid, 100, 10, TRADER)).unwrap();
+        gate.event(limit(2, Side::Bid, 99, 10, TRADER)).unwrap();
+        gate.event(limit(3, Side::Bid, 98, 10, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::OpenOrderLimit));
+        assert_eq!(gate.open_orders(TRADER), 2);
+
+        gate.event(limit(4, Side::Ask, 100, 10, OTHER)).unwrap();
+        assert_eq!(gate.open_orders(TRADER), 1);
+        gate.event(OrderEvent::Cancel { id: 2 }).unwrap();
+        assert_eq!(gate.open_orders(TRADER), 0);
+
+        gate.event(limit(5, Side::Bid, 98, 10, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), None);
+        assert_eq!(gate.open_orders(TRADER), 1);
+    }
+
+    #[test]
+    fn position_limit_counts_fills_and_open_orders() {
+        let (mut gate, _) = gate(RiskLimits::new().with_max_position(100));
+        gate.event(limit(1, Side::Ask, 100, 60, OTHER)).unwrap();
+        gate.event(limit(2, Side::Bid, 100, 60, TRADER)).unwrap();
+        assert_eq!(gate.position(TRADER), 60);
+
+        gate.event(limit(3, Side::Bid, 99, 30, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), None);
+        gate.event(limit(4, Side::Bid, 98, 20, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::PositionLimit));
+
+        // Selling reduces the position, so it is allowed up to a short of 100
+        gate.event(limit(5, Side::Ask, 101, 160, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), None);
+        gate.event(limit(6, Side::Ask, 101, 1, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::PositionLimit));
+
+        // Amendments are checked against the order they replace
+        gate.event(OrderEvent::replace(3, Side::Bid, px(99), 40)).unwrap();
+        assert_eq!(rejection(&mut gate), None);
+        gate.event(OrderEvent::replace(3, Side::Bid, px(99), 41)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::PositionLimit));
+    }
+
+    #[test]
+    fn credit_limit_counts_traded_and_open_value() {
+        let (mut gate, _) = gate(RiskLimits::new().with_credit_limit(10_000));
+        gate.event(limit(1, Side::Ask, 100, 50, OTHER)).unwrap();
+        gate.event(limit(2, Side::Bid, 100, 50, TRADER)).unwrap();
+        gate.event(limit(3, Side::Bid, 90, 50, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), None);
+        assert_eq!(gate.credit_used(TRADER), 9_500);
+
+        gate.event(limit(4, Side::Ask, 110, 5, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::CreditLimit));
+
+        gate.event(OrderEvent::Cancel { id: 3 }).unwrap();
+        assert_eq!(gate.credit_used(TRADER), 5_000);
+        gate.event(limit(5, Side::Ask, 110, 5, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), None);
+    }
+
+    #[test]
+    fn order_rate_throttle() {
+        let (mut gate, clock) = gate(RiskLimits::new().with_order_rate(2, Duration::seconds(1)));
+        gate.event(limit(1, Side::Bid, 100, 10, TRADER)).unwrap();
+        gate.event(limit(2, Side::Bid, 100, 10, TRADER)).unwrap();
+        gate.event(limit(3, Side::Bid, 100, 10, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), Some(RiskViolation::OrderRateLimit));
+
+        // Cancels are never throttled
+        gate.event(OrderEvent::Cancel { id: 1 }).unwrap();
+        assert_eq!(rejection(&mut gate), None);
+
+        clock.advance(Duration::seconds(1));
+        gate.event(limit(4, Side::Bid, 100, 10, TRADER)).unwrap();
+        assert_eq!(rejection(&mut gate), None);
+        assert_eq!(gate.open_orders(TRADER), 2);
+    }
+}
@@ -0,0 +1,515 @@
+//! Point-in-time image of an order book, so that recovery can start from a recent
+//! checkpoint and replay only the journal written after it.
+//!
+//! A snapshot file holds an 8 byte header (the `OMSS` magic, a u16 format version and two
+//! reserved bytes), then `[len: u32][body][crc: u32]` with the CRC-32 taken over the body.
+//! The body records the journal sequence the snapshot was taken at, the instrument, the
+//! last traded price, every resting order of each side in queue order, the stop orders
+//! in trigger order, the trading phase, whether the book is in an auction along with the
+//! market orders waiting for the uncross, and the trades inside the circuit breaker
+//! window.
+
+use crate::codec::{self, Decoder};
+use crate::model::{AllocationPolicy, CircuitBreaker, Instrument, OrderInfo, Price,
+                   SelfTradePrevention, Side};
+use crate::order_book::OrderBook;
+use chrono::Duration;
+use failure::Error;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8] = b"OMSS";
+const VERSION: u16 = 6;
+const HEADER_LEN: usize = 8;
+
+/// An order book restored from a snapshot.
+#[derive(Debug)]
+pub struct Snapshot {
+    journal_seq: u64,
+    book: OrderBook,
+}
+
+impl Snapshot {
+    /// Sequence number of the last journal entry applied to the book when the snapshot
+    /// was taken, zero if there was no journal.
+    pub fn journal_seq(&self) -> u64 {
+        self.journal_seq
+    }
+
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    pub fn book_mut(&mut self) -> &mut OrderBook {
+        &mut self.book
+    }
+
+    pub fn into_book(self) -> OrderBook {
+        self.book
+    }
+}
+
+/// Serializes the state of `book` into a snapshot.
+pub fn encode(book: &OrderBook, journal_seq: u64) -> Vec<u8> {
+    let mut body = Vec::with_capacity(1024);
+    codec::put_u64(&mut body, journal_seq);
+    put_instrument(&mut body, book.instrument());
+    put_opt_price(&mut body, book.last_traded_price());
+    for side in &[Side::Bid, Side::Ask] {
+        let orders = book.resting_orders(*side);
+        codec::put_u32(&mut body, orders.len() as u32);
+        for order in &orders {
+            put_order(&mut body, order);
+        }
+    }
+    let stops = book.stop_orders();
+    codec::put_u32(&mut body, stops.len() as u32);
+    for stop in &stops {
+        codec::put_event(&mut body, stop);
+    }
+    codec::put_phase(&mut body, book.phase());
+    codec::put_u8(&mut body, book.is_auction() as u8);
+    let held = book.auction_orders();
+    codec::put_u32(&mut body, held.len() as u32);
+    for order in &held {
+        put_order(&mut body, order);
+    }
+    let trades = book.recent_trades();
+    codec::put_u32(&mut body, trades.len() as u32);
+    for &(time, price) in &trades {
+        codec::put_time(&mut body, time);
+        codec::put_price(&mut body, price);
+    }
+
+    let mut data = Vec::with_capacity(HEADER_LEN + body.len() + 8);
+    data.extend_from_slice(MAGIC);
+    codec::put_u16(&mut data, VERSION);
+    codec::put_u16(&mut data, 0);
+    codec::put_u32(&mut data, body.len() as u32);
+    data.extend_from_slice(&body);
+    codec::put_u32(&mut data, codec::crc32(&body));
+    data
+}
+
+/// Rebuilds an order book from a snapshot. The book runs on the system clock.
+pub fn decode(data: &[u8]) -> Result<Snapshot, Error> {
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        bail!("Not a snapshot");
+    }
+    let mut decoder = Decoder::new(&data[MAGIC.len()..]);
+    let version = decoder.u16()?;
+    if version != VERSION {
+        bail!("Unsupported snapshot version {}", version);
+    }
+    decoder.u16()?;
+    let body = decoder.bytes()?;
+    let crc = decoder.u32()?;
+    if crc != codec::crc32(body) {
+        bail!("Snapshot checksum mismatch");
+    }
+    if decoder.remaining() > 0 {
+        bail!("{} unexpected bytes after snapshot", decoder.remaining());
+    }
+
+    let mut decoder = Decoder::new(body);
+    let journal_seq = decoder.u64()?;
+    let mut book = OrderBook::new(read_instrument(&mut decoder)?);
+    book.restore_last_traded_price(read_opt_price(&mut decoder)?);
+    for side in &[Side::Bid, Side::Ask] {
+        let count = decoder.u32()?;
+        for _ in 0..count {
+            let order = read_order(&mut decoder)?;
+            if order.side() != *side {
+                bail!("Order {} is on the wrong side of the snapshot", order.id());
+            }
+            book.restore_order(order)?;
+        }
+    }
+    let count = decoder.u32()?;
+    for _ in 0..count {
+        book.restore_stop(decoder.event()?)?;
+    }
+    book.restore_phase(decoder.phase()?);
+    let auction = decoder.u8()? != 0;
+    let count = decoder.u32()?;
+    let mut held = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        held.push(read_order(&mut decoder)?);
+    }
+    if auction {
+        book.restore_auction(&held)?;
+    } else if !held.is_empty() {
+        bail!("Market orders are waiting outside an auction");
+    }
+    let count = decoder.u32()?;
+    let mut trades = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        trades.push((decoder.time()?, decoder.price()?));
+    }
+    book.restore_recent_trades(&trades);
+    Ok(Snapshot { journal_seq, book })
+}
+
+/// Writes a snapshot of `book` to `path`. The file is written next to its destination and
+/// renamed into place, so a crash never leaves a partial snapshot behind.
+pub fn save<P: AsRef<Path>>(path: P, book: &OrderBook, journal_seq: u64) -> Result<(), Error> {
+    let path = path.as_ref();
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&encode(book, journal_seq))?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Snapshot, Error> {
+    let mut data = Vec::new();
+    File::open(path.as_ref())?.read_to_end(&mut data)?;
+    decode(&data).map_err(|e| format_err!("Cannot load snapshot {:?}: {}", path.as_ref(), e))
+}
+
+fn put_opt_price(buf: &mut Vec<u8>, price: Option<Price>) {
+    match price {
+        Some(price) => {
+            codec::put_u8(buf, 1);
+            codec::put_price(buf, price);
+        }
+        None => codec::put_u8(buf, 0),
+    }
+}
+
+fn read_opt_price(decoder: &mut Decoder) -> Result<Option<Price>, Error> {
+    match decoder.u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(decoder.price()?)),
+        other => bail!("Invalid option tag {}", other),
+    }
+}
+
+fn put_instrument(buf: &mut Vec<u8>, instrument: &Instrument) {
+    codec::put_bytes(buf, instrument.symbol().as_bytes());
+    codec::put_u8(buf, instrument.price_scale());
+    codec::put_u64(buf, instrument.tick_size());
+    codec::put_u64(buf, instrument.lot_size());
+    codec::put_u64(buf, instrument.min_qty());
+    codec::put_u64(buf, instrument.max_qty());
+    put_opt_price(buf, instrument.reference_price());
+    match instrument.price_band_bps() {
+        Some(band_bps) => {
+            codec::put_u8(buf, 1);
+            codec::put_u64(buf, band_bps);
+        }
+        None => codec::put_u8(buf, 0),
+    }
+    match instrument.allocation_policy() {
+        AllocationPolicy::Fifo => codec::put_u8(buf, 0),
+        AllocationPolicy::ProRata => codec::put_u8(buf, 1),
+        AllocationPolicy::PriorityProRata => codec::put_u8(buf, 2),
+        AllocationPolicy::ProRataWithMinimum(min_qty) => {
+            codec::put_u8(buf, 3);
+            codec::put_u64(buf, min_qty);
+        }
+    }
+    codec::put_u8(
+        buf,
+        match instrument.self_trade_prevention() {
+            None => 0,
+            Some(SelfTradePrevention::CancelNewest) => 1,
+            Some(SelfTradePrevention::CancelOldest) => 2,
+            Some(SelfTradePrevention::CancelBoth) => 3,
+            Some(SelfTradePrevention::DecrementAndCancel) => 4,
+        },
+    );
+    match instrument.circuit_breaker() {
+        Some(breaker) => {
+            codec::put_u8(buf, 1);
+            codec::put_u64(buf, breaker.band_bps());
+            codec::put_i64(buf, breaker.window().num_milliseconds());
+        }
+        None => codec::put_u8(buf, 0),
+    }
+}
+
+fn read_instrument(decoder: &mut Decoder) -> Result<Instrument, Error> {
+    let symbol = String::from_utf8(decoder.bytes()?.to_vec())?;
+    let price_scale = decoder.u8()?;
+    let tick_size = decoder.u64()?;
+    let lot_size = decoder.u64()?;
+    let min_qty = decoder.u64()?;
+    let max_qty = decoder.u64()?;
+    let reference_price = read_opt_price(decoder)?;
+    let band_bps = match decoder.u8()? {
+        0 => None,
+        1 => Some(decoder.u64()?),
+        other => bail!("Invalid option tag {}", other),
+    };
+    let allocation_policy = match decoder.u8()? {
+        0 => AllocationPolicy::Fifo,
+        1 => AllocationPolicy::ProRata,
+        2 => AllocationPolicy::PriorityProRata,
+        3 => AllocationPolicy::ProRataWithMinimum(decoder.u64()?),
+        other => bail!("Invalid allocation policy {}", other),
+    };
+    let self_trade_prevention = match decoder.u8()? {
+        0 => None,
+        1 => Some(SelfTradePrevention::CancelNewest),
+        2 => Some(SelfTradePrevention::CancelOldest),
+        3 => Some(SelfTradePrevention::CancelBoth),
+        4 => Some(SelfTradePrevention::DecrementAndCancel),
+        other => bail!("Invalid self-trade prevention mode {}", other),
+    };
+    let circuit_breaker = match decoder.u8()? {
+        0 => None,
+        1 => Some(CircuitBreaker::new(
+            decoder.u64()?,
+            Duration::milliseconds(decoder.i64()?),
+        )),
+        other => bail!("Invalid option tag {}", other),
+    };
+    if tick_size == 0 || lot_size == 0 || min_qty > max_qty {
+        bail!("Invalid reference data for {}", symbol);
+    }
+
+    let mut instrument = Instrument::new(&symbol)
+        .with_price_scale(price_scale)
+        .with_tick_size(tick_size)
+        .with_lot_size(lot_size)
+        .with_qty_limits(min_qty, max_qty)
+        .with_allocation_policy(allocation_policy);
+    if let Some(mode) = self_trade_prevention {
+        instrument = instrument.with_self_trade_prevention(mode);
+    }
+    if let Some(breaker) = circuit_breaker {
+        instrument = instrument.with_circuit_breaker(breaker);
+    }
+    match (reference_price, band_bps) {
+        (Some(reference_price), Some(band_bps)) => {
+            if reference_price.scale() != price_scale {
+                bail!("Reference price scale does not match {}", symbol);
+            }
+            instrument = instrument.with_price_band(reference_price, band_bps);
+        }
+        (Some(reference_price), None) => instrument.set_reference_price(reference_price),
+        (None, None) => {}
+        (None, Some(_)) => bail!("Price band without a reference price for {}", symbol),
+    }
+    Ok(instrument)
+}
+
+fn put_order(buf: &mut Vec<u8>, order: &OrderInfo) {
+    codec::put_u64(buf, order.id);
+    codec::put_side(buf, order.side);
+    codec::put_price(buf, order.price);
+    codec::put_u64(buf, order.qty);
+    codec::put_u64(buf, order.peak);
+    codec::put_u64(buf, order.reserve);
+    codec::put_u64(buf, order.cum_qty);
+    codec::put_u128(buf, order.notional);
+    match order.expire_at {
+        Some(expire_at) => {
+            codec::put_u8(buf, 1);
+            codec::put_time(buf, expire_at);
+        }
+        None => codec::put_u8(buf, 0),
+    }
+    codec::put_u64(buf, order.owner);
+}
+
+fn read_order(decoder: &mut Decoder) -> Result<OrderInfo, Error> {
+    let mut order = OrderInfo::new(
+        decoder.u64()?,
+        decoder.side()?,
+        decoder.price()?,
+        decoder.u64()?,
+    );
+    order.peak = decoder.u64()?;
+    order.reserve = decoder.u64()?;
+    order.cum_qty = decoder.u64()?;
+    order.notional = decoder.u128()?;
+    order.expire_at = match decoder.u8()? {
+        0 => None,
+        1 => Some(decoder.time()?),
+        other => bail!("Invalid option tag {}", other),
+    };
+    order.owner = decoder.u64()?;
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::model::{px, ExecType, OrderEvent, TimeInForce, TradingPhase};
+    use chrono::{Duration, TimeZone, Utc};
+
+    fn instrument() -> Instrument {
+        Instrument::new("AUDUSD")
+            .with_lot_size(5)
+            .with_qty_limits(5, 1_000)
+            .with_price_band(px(100), 2_000)
+            .with_allocation_policy(AllocationPolicy::ProRataWithMinimum(10))
+            .with_self_trade_prevention(SelfTradePrevention::CancelOldest)
+            .with_circuit_breaker(CircuitBreaker::new(1_000, Duration::minutes(5)))
+    }
+
+    fn populated_book(clock: &ManualClock) -> OrderBook {
+        let mut book = OrderBook::with_clock(instrument(), Box::new(clock.clone()));
+        let expire_at = clock.now() + Duration::minutes(30);
+        let events = vec![
+            OrderEvent::limit(1, Side::Ask, px(101), 100),
+            OrderEvent::iceberg(2, Side::Ask, px(101), 200, 50),
+            OrderEvent::limit(3, Side::Ask, px(103), 40),
+            OrderEvent::limit(4, Side::Bid, px(99), 70).with_owner(9),
+            OrderEvent::limit_with_tif(
+                5,
+                Side::Bid,
+                px(99),
+                30,
+                TimeInForce::GoodTillDate(expire_at),
+            ),
+            OrderEvent::limit(6, Side::Bid, px(97), 25),
+            // Partially fills the iceberg's peak and sets the last traded price
+            OrderEvent::market(7, Side::Bid, 120),
+            OrderEvent::stop_market(8, Side::Ask, px(98), 20),
+            OrderEvent::stop_limit(9, Side::Bid, px(103), px(104), 15),
+            OrderEvent::stop_market(10, Side::Bid, px(103), 10),
+        ];
+        for event in events {
+            book.event(event).unwrap();
+        }
+        book.drain_reports();
+        book.drain_market_data();
+        book.drain_order_feed();
+        book
+    }
+
+    fn run(book: &mut OrderBook, events: &[OrderEvent]) -> Vec<(u64, u64, Price, u64)> {
+        let mut fills = Vec::new();
+        for event in events {
+            for fill in book.event(*event).unwrap() {
+                fills.push((fill.ord_id_1(), fill.ord_id_2(), fill.price(), fill.qty()));
+            }
+        }
+        fills
+    }
+
+    fn exec_types(book: &mut OrderBook) -> Vec<(u64, ExecType, u64)> {
+        book.drain_reports()
+            .iter()
+            .map(|r| (r.order_id(), r.exec_type(), r.leaves_qty()))
+            .collect()
+    }
+
+    #[test]
+    fn restored_book_matches_like_the_original() {
+        ::crate::model::test_setup();
+        let clock = ManualClock::new(Utc.ymd(2018, 4, 2).and_hms(9, 0, 0));
+        let mut original = populated_book(&clock);
+
+        let mut snapshot = decode(&encode(&original, 42)).unwrap();
+        assert_eq!(snapshot.journal_seq(), 42);
+        snapshot.book_mut().set_clock(Box::new(clock.clone()));
+        let mut restored = snapshot.into_book();
+
+        assert_eq!(restored.instrument(), original.instrument());
+        assert_eq!(restored.last_traded_price(), original.last_traded_price());
+        assert_eq!(restored.recent_trades(), original.recent_trades());
+        for side in &[Side::Bid, Side::Ask] {
+            assert_eq!(restored.full_depth(*side), original.full_depth(*side));
+        }
+
+        clock.advance(Duration::hours(1));
+        let events = vec![
+            // Sweeps the bids past the expired GTD order and triggers the sell stop
+            OrderEvent::market(11, Side::Ask, 90),
+            // Clears the iceberg and triggers both buy stops
+            OrderEvent::limit(12, Side::Bid, px(103), 200),
+            OrderEvent::Cancel { id: 3 },
+            OrderEvent::limit(13, Side::Ask, px(99), 100),
+        ];
+        let expected = run(&mut original, &events);
+        assert!(!expected.is_empty());
+        assert_eq!(run(&mut restored, &events), expected);
+        assert_eq!(exec_types(&mut restored), exec_types(&mut original));
+        for side in &[Side::Bid, Side::Ask] {
+            assert_eq!(restored.full_depth(*side), original.full_depth(*side));
+        }
+    }
+
+    #[test]
+    fn restores_auction_in_progress() {
+        ::crate::model::test_setup();
+        let mut original = OrderBook::new(Instrument::new("AUDUSD"));
+        for phase in &[
+            TradingPhase::Closed,
+            TradingPhase::PreOpen,
+            TradingPhase::OpeningAuction,
+        ] {
+            original.set_phase(*phase).unwrap();
+        }
+        let events = vec![
+            OrderEvent::limit(1, Side::Bid, px(101), 100),
+            OrderEvent::market(2, Side::Bid, 30),
+            OrderEvent::limit(3, Side::Ask, px(100), 80),
+            OrderEvent::market(4, Side::Ask, 20),
+        ];
+        for event in events {
+            original.event(event).unwrap();
+        }
+
+        let mut restored = decode(&encode(&original, 0)).unwrap().into_book();
+        assert_eq!(restored.phase(), TradingPhase::OpeningAuction);
+        assert_eq!(restored.indicative_uncross(), original.indicative_uncross());
+        assert_eq!(restored.open_orders(), original.open_orders());
+
+        let fills = |book: &mut OrderBook| -> Vec<(u64, u64, Price, u64)> {
+            book.set_phase(TradingPhase::Continuous)
+                .unwrap()
+                .iter()
+                .map(|f| (f.ord_id_1(), f.ord_id_2(), f.price(), f.qty()))
+                .collect()
+        };
+        let expected = fills(&mut original);
+        assert_eq!(expected.len(), 3);
+        assert_eq!(fills(&mut restored), expected);
+    }
+
+    #[test]
+    fn rejects_damaged_snapshots() {
+        let clock = ManualClock::new(Utc.ymd(2018, 4, 2).and_hms(9, 0, 0));
+        let data = encode(&populated_book(&clock), 0);
+
+        let mut corrupt = data.clone();
+        let last_body_byte = corrupt.len() - 5;
+        corrupt[last_body_byte] ^= 0xFF;
+        assert!(decode(&corrupt).is_err());
+
+        let mut future = data.clone();
+        future[MAGIC.len()] = 99;
+        assert!(decode(&future).is_err());
+
+        assert!(decode(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn saves_and_loads_a_file() {
+        let path = ::std::env::temp_dir().join(format!(
+            "oms-snapshot-{}.snapshot",
+            ::std::process::id()
+        ));
+        let clock = ManualClock::new(Utc.ymd(2018, 4, 2).and_hms(9, 0, 0));
+        let book = populated_book(&clock);
+        save(&path, &book, 7).unwrap();
+
+        let snapshot = load(&path).unwrap();
+        assert_eq!(snapshot.journal_seq(), 7);
+        assert_eq!(
+            snapshot.book().full_depth(Side::Ask),
+            book.full_depth(Side::Ask)
+        );
+        let _ = fs::remove_file(&path);
+    }
+}